@@ -1,15 +1,53 @@
 use std::{mem, sync::Arc};
 
 use game::{
-    brick::{BrickMap, ExpandedBrick, TraceBrick},
+    brick::{
+        BrickAlloc, BrickMap, BrickRelocation, ExpandedBrick, MaterialBrick, ReleasedBrick,
+        TraceBrick,
+    },
+    brush::{BrickEdit, Brush, BrushResult},
+    history::{BrickSnapshot, BrickState},
     material::{ExpandedMaterialMapping, MaterialId, MaterialRegistry},
+    mip::BrickMip,
     palette::PaletteRegistry,
+    transparency::expanded_has_transparent_voxels,
     BrickHandle,
 };
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 
 use crate::GPUContext;
 
+/// Usage of one [`cvk::GPUFreeList`].
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct GPUBufferStats {
+    pub capacity: u64,
+    /// Bytes handed out, freed blocks included.
+    pub used: u64,
+    pub free_blocks: usize,
+    pub free_bytes: u64,
+}
+
+impl GPUBufferStats {
+    fn new(freelist: &cvk::GPUFreeList) -> Self {
+        let (free_blocks, free_bytes) = freelist.free_blocks();
+        Self {
+            capacity: freelist.capacity(),
+            used: freelist.used(),
+            free_blocks,
+            free_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct GPUBrickMapStats {
+    pub handle_bytes: u64,
+    pub trace_bricks: GPUBufferStats,
+    pub bricks: GPUBufferStats,
+    pub materials: GPUBufferStats,
+    pub palettes: GPUBufferStats,
+}
+
 pub struct GPUBrickMap {
     pub context: Arc<GPUContext>,
     pub brickmap: cvk::Buffer,
@@ -23,6 +61,8 @@ pub struct GPUBrickMap {
     pub device: Arc<cvk::Device>,
     pub queue: Arc<cvk::Queue>,
     pub staging_buffers: Mutex<Vec<(cvk::Buffer, u64)>>,
+    /// Brick uploads hold this shared, compaction exclusively.
+    relocating: RwLock<()>,
 }
 
 impl GPUBrickMap {
@@ -32,12 +72,11 @@ impl GPUBrickMap {
         palette_registry: Arc<PaletteRegistry>,
         material_registry: Arc<MaterialRegistry>,
     ) -> Self {
-        let dims = brickmap.dimensions();
         let device = context.device.clone();
         let queue = context.transfer_queue.clone();
 
         // TODO: make this aware of limits
-        let brickmap_buffer = Self::create_brickmap_buffer(&device, dims);
+        let brickmap_buffer = Self::create_brickmap_buffer(&device, brickmap.handle_count());
         let trace_bricks = cvk::GPUFreeList::new(
             device.clone(),
             queue.clone(),
@@ -83,6 +122,7 @@ impl GPUBrickMap {
             device,
             queue,
             staging_buffers: Mutex::new(Vec::new()),
+            relocating: RwLock::new(()),
         };
 
         new.rebind_brick_descriptors();
@@ -91,8 +131,8 @@ impl GPUBrickMap {
         new
     }
 
-    fn create_brickmap_buffer(device: &cvk::Device, dims: na::Vector3<u32>) -> cvk::Buffer {
-        let size = (dims.x * dims.y * dims.z) as u64 * mem::size_of::<BrickHandle>() as u64;
+    fn create_brickmap_buffer(device: &cvk::Device, handle_count: usize) -> cvk::Buffer {
+        let size = handle_count as u64 * mem::size_of::<BrickHandle>() as u64;
 
         device.create_buffer(&cvk::BufferInfo {
             size,
@@ -122,6 +162,16 @@ impl GPUBrickMap {
         })
     }
 
+    pub fn stats(&self) -> GPUBrickMapStats {
+        GPUBrickMapStats {
+            handle_bytes: self.brickmap.size,
+            trace_bricks: GPUBufferStats::new(&self.trace_bricks),
+            bricks: GPUBufferStats::new(&self.bricks),
+            materials: GPUBufferStats::new(&self.materials),
+            palettes: GPUBufferStats::new(&self.palettes),
+        }
+    }
+
     pub fn try_drop_staging(&self) {
         let mut buffers = self.staging_buffers.lock();
         let timeline = self.queue.current_timeline();
@@ -130,11 +180,10 @@ impl GPUBrickMap {
     }
 
     pub fn transfer_all_handles(&self) {
-        let dims = self.cpu.dimensions();
-        let size = (dims.x * dims.y * dims.z) as u64 * mem::size_of::<BrickHandle>() as u64;
+        let size = self.cpu.handle_count() as u64 * mem::size_of::<BrickHandle>() as u64;
         let staging = Self::create_staging_buffer(&self.device, size, "Handles Staging Buffer");
-        let handles = self.cpu.handles();
-        staging.upload(bytemuck::cast_slice(handles), 0);
+        let handles = self.cpu.read().snapshot_handles();
+        staging.upload(bytemuck::cast_slice(&handles), 0);
 
         let mut recorder = self.queue.record();
 
@@ -193,8 +242,8 @@ impl GPUBrickMap {
         let aligned_offset = byte_offset & !(32 - 1);
         let start_handle_index = aligned_offset / mem::size_of::<BrickHandle>() as u64;
 
-        let dims = self.cpu.dimensions().cast::<u64>();
-        let total_elements = dims.x * dims.y * dims.z;
+        let dims = self.cpu.dimensions();
+        let total_elements = self.cpu.handle_count() as u64;
 
         // Calculate how many handles we need to write, but ensure we don't exceed the buffer
         let handles_to_write =
@@ -208,18 +257,69 @@ impl GPUBrickMap {
             if current_index == target_index {
                 handles.push(handle);
             } else {
-                let x = current_index % dims.x;
-                let y = (current_index / dims.x) % dims.y;
-                let z = current_index / (dims.x * dims.y);
-                let pos = na::Point3::new(x, y, z);
-                let existing_handle = self.cpu.get_handle(pos.cast::<u32>());
-                handles.push(existing_handle);
+                let pos = self.cpu.position(current_index as usize);
+                // the Morton layout pads the map, those handles are never written
+                if pos.x < dims.x && pos.y < dims.y && pos.z < dims.z {
+                    handles.push(self.cpu.get_handle(pos));
+                } else {
+                    handles.push(BrickHandle::empty());
+                }
             }
         }
 
         (handles, aligned_offset)
     }
 
+    /// Bytes a material brick and its mip levels take in the brick buffer.
+    fn material_brick_size(material_brick: &MaterialBrick) -> usize {
+        // the mip levels sit right behind the packed voxels, see `get_mip_offset` in the shader
+        let mip_offset = mem::size_of::<u32>() + material_brick.data().len();
+        let mip_end =
+            (mip_offset + mem::size_of::<BrickMip>()).next_multiple_of(mem::size_of::<u32>());
+        material_brick.size().max(mip_end)
+    }
+
+    fn transfer_handle(&self, handle: BrickHandle, at: na::Point3<u32>) {
+        let mut recorder = self.queue.record();
+        let (aligned_handles, aligned_handle_offset) = self.prepare_transfer_handle(handle, at);
+        let aligned_handles_size = aligned_handles.len() * mem::size_of::<BrickHandle>();
+        let staging_buffer = Self::create_staging_buffer(
+            &self.device,
+            aligned_handles_size as u64,
+            "Handles Staging Buffer",
+        );
+        staging_buffer.upload(bytemuck::cast_slice(&aligned_handles), 0);
+        recorder.copy_buffer(
+            &staging_buffer,
+            &self.brickmap,
+            0,
+            aligned_handle_offset as usize,
+            aligned_handles_size,
+        );
+        let submit = self.queue.submit_express(&[recorder.finish()]).unwrap();
+        let mut staging_buffers = self.staging_buffers.lock();
+        staging_buffers.push((staging_buffer, submit));
+    }
+
+    /// Frees the brick buffer space of a slot once no position references it anymore.
+    fn release_material_brick(&self, released: Option<ReleasedBrick>) {
+        let Some(ReleasedBrick {
+            trace: trace_brick,
+            material: Some(material_brick),
+        }) = released
+        else {
+            return;
+        };
+        let offset = trace_brick.get_brick_offset() as u64 * mem::size_of::<u32>() as u64;
+        let size = Self::material_brick_size(&material_brick) as u64;
+        self.bricks
+            .deallocate_size(offset, size, |old, _new, submit| {
+                let mut staging = self.staging_buffers.lock();
+                staging.push((old, submit));
+                self.rebind_brick_descriptors();
+            });
+    }
+
     pub fn setup_full_brick(
         &self,
         at: na::Point3<u32>,
@@ -227,49 +327,45 @@ impl GPUBrickMap {
         material: Option<MaterialId>,
         material_mapping: &ExpandedMaterialMapping,
     ) {
-        let mut recorder = self.queue.record();
+        let _relocating = self.relocating.read();
+        let old_handle = self.cpu.get_handle(at);
+
         let Some(expanded_brick) = expanded_brick else {
-            let handle = if let Some(material) = material {
-                let mut handle = BrickHandle::empty();
-                handle.set_lod(true);
-                handle.set_empty_value(material.0);
-                handle
-            } else {
-                BrickHandle::empty()
+            let (handle, released) = match material {
+                Some(material) => self.cpu.set_lod(at, material),
+                None => self.cpu.set_empty(at),
             };
-            self.cpu.set_handle(handle, at);
-            let (aligned_handles, aligned_handle_offset) = self.prepare_transfer_handle(handle, at);
-            let aligned_handles_size = aligned_handles.len() * mem::size_of::<BrickHandle>();
-            let staging_buffer = Self::create_staging_buffer(
-                &self.device,
-                aligned_handles_size as u64,
-                "Handles Staging Buffer",
-            );
-            staging_buffer.upload(bytemuck::cast_slice(&aligned_handles), 0);
-            recorder.copy_buffer(
-                &staging_buffer,
-                &self.brickmap,
-                0,
-                aligned_handle_offset as usize,
-                aligned_handles_size,
-            );
-            let submit = self.queue.submit_express(&[recorder.finish()]).unwrap();
-            let mut staging_buffers = self.staging_buffers.lock();
-            staging_buffers.push((staging_buffer, submit));
+            self.release_material_brick(released);
+            self.transfer_handle(handle, at);
             return;
         };
 
-        let mut trace_brick = expanded_brick.to_trace_brick();
-        let trace_brick_size = mem::size_of::<TraceBrick>();
+        let (handle, alloc, released) = self.cpu.set_expanded_brick(
+            at,
+            expanded_brick,
+            material_mapping,
+            &self.palette_registry,
+        );
 
-        let (mut material_brick, materials) = expanded_brick.compress(material_mapping);
+        if alloc == BrickAlloc::Shared {
+            // an identical brick is already resident, only the handle changes
+            if handle.as_raw() != old_handle.as_raw() {
+                self.release_material_brick(released);
+                self.transfer_handle(handle, at);
+            }
+            return;
+        }
+        // the slot was either rewritten in place or the old one dropped its last reference
+        self.release_material_brick(released);
 
-        let palette_id = self.palette_registry.register_palette(materials);
-        material_brick.set_meta_value(palette_id.0);
+        let mut recorder = self.queue.record();
+        let material_brick = self.cpu.get_material_brick(handle).unwrap();
+        let mip = self.cpu.get_mip(handle).unwrap_or(BrickMip::EMPTY);
+        let trace_brick_size = mem::size_of::<TraceBrick>();
 
         let material_brick_data = material_brick.data();
-
-        let material_brick_size = material_brick.size();
+        let mip_offset = mem::size_of::<u32>() + material_brick_data.len();
+        let material_brick_size = Self::material_brick_size(&material_brick);
         let material_brick_offset = self
             .bricks
             .allocate_size(material_brick_size as u64, |old, _new, submit| {
@@ -279,11 +375,18 @@ impl GPUBrickMap {
             })
             .unwrap();
 
-        trace_brick.set_brick_offset(material_brick_offset as u32 / mem::size_of::<u32>() as u32);
-
-        let (handle, needs_alloc) = self.cpu.set_brick(trace_brick, at);
+        let transparent = expanded_has_transparent_voxels(
+            expanded_brick,
+            material_mapping,
+            &self.material_registry,
+        );
+        self.cpu.modify_brick(handle, |brick| {
+            brick.set_brick_offset(material_brick_offset as u32 / mem::size_of::<u32>() as u32);
+            brick.set_transparent(transparent);
+        });
+        let trace_brick = self.cpu.get_brick(handle).unwrap();
 
-        if needs_alloc {
+        if alloc == BrickAlloc::New {
             self.trace_bricks
                 .allocate::<TraceBrick, _>(|old, _new, submit| {
                     let mut staging = self.staging_buffers.lock();
@@ -308,6 +411,7 @@ impl GPUBrickMap {
         );
         staging_buffer.upload(bytemuck::cast_slice(&[material_brick.meta()]), 0);
         staging_buffer.upload(material_brick_data, mem::size_of::<u32>());
+        staging_buffer.upload(bytemuck::cast_slice(&[mip]), mip_offset);
         staging_buffer.upload(bytemuck::cast_slice(&[trace_brick]), material_brick_size);
         staging_buffer.upload(
             bytemuck::cast_slice(&aligned_handles),
@@ -340,6 +444,100 @@ impl GPUBrickMap {
         self.staging_buffers.lock().push((staging_buffer, submit));
     }
 
+    /// Writes the bricks of a [`Brush::plan`] and uploads them, see [`BrickMap::apply_edits`].
+    pub fn apply_edits(&self, edits: &[BrickEdit]) -> BrushResult {
+        let touched = edits
+            .iter()
+            .map(|edit| {
+                match edit.to_expanded() {
+                    Some((expanded, mapping)) => {
+                        self.setup_full_brick(edit.at, Some(&expanded), None, &mapping)
+                    }
+                    None => {
+                        self.setup_full_brick(edit.at, None, None, &ExpandedMaterialMapping::new())
+                    }
+                }
+                (edit.at, edit.change)
+            })
+            .collect();
+
+        BrushResult {
+            touched,
+            dropped: 0,
+        }
+    }
+
+    pub fn apply_brush(&self, brush: &Brush) -> BrushResult {
+        let plan = brush.plan(&self.cpu, &self.palette_registry);
+        BrushResult {
+            dropped: plan.dropped,
+            ..self.apply_edits(&plan.edits)
+        }
+    }
+
+    /// Writes and uploads bricks handed back by an undo or redo, see [`BrickMap::restore`]. The
+    /// GPU needs a material for every voxel, occupancy only bricks are uploaded as `fallback`.
+    pub fn restore(&self, bricks: &[BrickSnapshot], fallback: MaterialId) {
+        let no_mapping = ExpandedMaterialMapping::new();
+        for snapshot in bricks {
+            match &snapshot.state {
+                BrickState::Empty => self.setup_full_brick(snapshot.at, None, None, &no_mapping),
+                BrickState::Lod(material) => {
+                    self.setup_full_brick(snapshot.at, None, Some(*material), &no_mapping)
+                }
+                state => {
+                    let (expanded, mapping) = state.to_expanded_or(fallback).unwrap();
+                    self.setup_full_brick(snapshot.at, Some(&expanded), None, &mapping);
+                }
+            }
+        }
+    }
+
+    /// Compacts the CPU brick storage and applies the relocation to the GPU buffers. Material
+    /// bricks are moved with one copy per contiguous run, trace bricks and handles are uploaded
+    /// again since their contents changed. Waits for running brick uploads.
+    pub fn compact(&self) -> BrickRelocation {
+        let _relocating = self.relocating.write();
+        let relocation = self
+            .cpu
+            .compact(|material| Self::material_brick_size(material) as u64);
+
+        let copies = relocation
+            .material_copies
+            .iter()
+            .map(|range| (range.from, range.to, range.size))
+            .collect::<Vec<_>>();
+        self.bricks
+            .repack(&copies, relocation.material_bytes, |old, _new, submit| {
+                let mut staging = self.staging_buffers.lock();
+                staging.push((old, submit));
+            });
+
+        let trace_bricks = self.cpu.read().snapshot_bricks();
+        let trace_bytes: &[u8] = bytemuck::cast_slice(&trace_bricks);
+        self.trace_bricks
+            .repack(&[], trace_bytes.len() as u64, |old, _new, submit| {
+                let mut staging = self.staging_buffers.lock();
+                staging.push((old, submit));
+            });
+        if !trace_bytes.is_empty() {
+            let (staging, submit) = self.trace_bricks.write_slice(0, trace_bytes);
+            self.staging_buffers.lock().push((staging, submit));
+        }
+
+        self.transfer_all_handles();
+        self.rebind_brick_descriptors();
+
+        log::debug!(
+            "Compacted bricks: {} slots, {} material bytes in {} copies",
+            relocation.slot_count,
+            relocation.material_bytes,
+            copies.len()
+        );
+
+        relocation
+    }
+
     pub fn rebind_brick_descriptors(&self) {
         self.queue.wait_idle();
         self.context.render_queue.wait_idle();
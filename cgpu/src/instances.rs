@@ -0,0 +1,176 @@
+use std::{mem, sync::Arc};
+
+use game::{instances::InstanceTable, palette::PaletteRegistry};
+use parking_lot::Mutex;
+
+use crate::{
+    objects::{
+        create_host_buffer, expand_voxels, reserve_host_buffer, GPUVoxelObject, HEADER_OFFSET,
+    },
+    GPUContext,
+};
+
+/// BVH node read by the raytracer, matches `BvhNode` in raytrace.wgsl.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct GPUBvhNode {
+    min: [f32; 3],
+    /// Left child of an inner node, first instance header of a leaf.
+    first: u32,
+    max: [f32; 3],
+    /// Instances in a leaf, 0 for inner nodes.
+    count: u32,
+}
+
+/// An [`InstanceTable`] for the raytracer: the models expanded once, the instances as headers
+/// pointing into them in BVH leaf order.
+///
+/// Call [`GPUInstances::upload_models`] after models were added and
+/// [`GPUInstances::upload_instances`] whenever [`InstanceTable::update`] rebuilt the BVH.
+pub struct GPUInstances {
+    context: Arc<GPUContext>,
+    nodes: Mutex<cvk::Buffer>,
+    headers: Mutex<cvk::Buffer>,
+    voxels: Mutex<cvk::Buffer>,
+    /// First voxel and size in voxels of every uploaded model, by model id.
+    models: Mutex<Vec<(u32, na::Vector3<u32>)>>,
+}
+
+impl GPUInstances {
+    pub fn new(context: Arc<GPUContext>) -> Self {
+        let nodes = create_host_buffer(
+            &context.device,
+            (HEADER_OFFSET + 128 * mem::size_of::<GPUBvhNode>()) as u64,
+            "Instance BVH Buffer",
+        );
+        // the shader reads the node count in front of the nodes, start out empty
+        nodes.upload(bytemuck::cast_slice(&[0u32; 4]), 0);
+        let headers = create_host_buffer(
+            &context.device,
+            (64 * mem::size_of::<GPUVoxelObject>()) as u64,
+            "Instance Header Buffer",
+        );
+        let voxels = create_host_buffer(&context.device, 4 << 20, "Model Voxel Buffer");
+
+        let new = Self {
+            context,
+            nodes: Mutex::new(nodes),
+            headers: Mutex::new(headers),
+            voxels: Mutex::new(voxels),
+            models: Mutex::new(Vec::new()),
+        };
+        new.rebind_descriptors(&new.nodes.lock(), &new.headers.lock(), &new.voxels.lock());
+        new
+    }
+
+    /// Expands the voxels of all models of the table, replacing everything uploaded before.
+    pub fn upload_models(&self, table: &InstanceTable, palettes: &PaletteRegistry) {
+        let mut models = self.models.lock();
+        models.clear();
+
+        let mut data = Vec::new();
+        for model in table.models() {
+            let offset = data.len() as u32;
+            let size = expand_voxels(model, palettes, &mut data);
+            models.push((offset, size));
+        }
+
+        let bytes: &[u8] = bytemuck::cast_slice(&data);
+        let nodes = self.nodes.lock();
+        let headers = self.headers.lock();
+        let mut voxels = self.voxels.lock();
+        if reserve_host_buffer(
+            &self.context,
+            &mut voxels,
+            bytes.len(),
+            "Model Voxel Buffer",
+        ) {
+            self.rebind_descriptors(&nodes, &headers, &voxels);
+        }
+        voxels.upload(bytes, 0);
+    }
+
+    /// Writes the BVH of the table and a header per instance, instances of models that weren't
+    /// uploaded yet are left out.
+    pub fn upload_instances(&self, table: &InstanceTable) {
+        let models = self.models.lock();
+        let bvh = table.bvh();
+
+        let instances: Vec<_> = bvh
+            .indices()
+            .iter()
+            .map(|&item| {
+                let instance = table.get(table.bvh_instance(item))?;
+                let (offset, size) = *models.get(instance.model.0 as usize)?;
+                let to_local = table.to_world(instance).try_inverse()?;
+                Some(GPUVoxelObject::new(&to_local, size, offset))
+            })
+            // an empty volume keeps the leaf ranges intact for skipped instances
+            .map(|header| header.unwrap_or(bytemuck::Zeroable::zeroed()))
+            .collect();
+        let bvh_nodes: Vec<_> = bvh
+            .nodes()
+            .iter()
+            .map(|node| GPUBvhNode {
+                min: *node.bounds.min.coords.as_ref(),
+                first: node.first,
+                max: *node.bounds.max.coords.as_ref(),
+                count: node.count,
+            })
+            .collect();
+
+        let mut node_data = vec![0u8; HEADER_OFFSET];
+        node_data[..4].copy_from_slice(&(bvh_nodes.len() as u32).to_le_bytes());
+        node_data.extend_from_slice(bytemuck::cast_slice(&bvh_nodes));
+        let header_data: &[u8] = bytemuck::cast_slice(&instances);
+
+        let mut nodes = self.nodes.lock();
+        let mut headers = self.headers.lock();
+        let voxels = self.voxels.lock();
+        let grown = reserve_host_buffer(
+            &self.context,
+            &mut nodes,
+            node_data.len(),
+            "Instance BVH Buffer",
+        ) | reserve_host_buffer(
+            &self.context,
+            &mut headers,
+            header_data.len(),
+            "Instance Header Buffer",
+        );
+        if grown {
+            self.rebind_descriptors(&nodes, &headers, &voxels);
+        }
+        nodes.upload(&node_data, 0);
+        headers.upload(header_data, 0);
+    }
+
+    fn rebind_descriptors(&self, nodes: &cvk::Buffer, headers: &cvk::Buffer, voxels: &cvk::Buffer) {
+        self.context.render_queue.wait_idle();
+        let _lock = self.context.render_queue.lock();
+
+        self.context.descriptors.write(&[
+            cvk::DescriptorWrite::StorageBuffer {
+                binding: 10,
+                buffer: nodes,
+                offset: 0,
+                range: nodes.size,
+                array_element: None,
+            },
+            cvk::DescriptorWrite::StorageBuffer {
+                binding: 11,
+                buffer: headers,
+                offset: 0,
+                range: headers.size,
+                array_element: None,
+            },
+            cvk::DescriptorWrite::StorageBuffer {
+                binding: 12,
+                buffer: voxels,
+                offset: 0,
+                range: voxels.size,
+                array_element: None,
+            },
+        ]);
+    }
+}
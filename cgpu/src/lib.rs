@@ -4,8 +4,14 @@ use std::sync::Arc;
 use anyhow::Result;
 
 mod brickmap;
+mod instances;
+mod lights;
+mod objects;
 mod sdf;
-pub use brickmap::GPUBrickMap;
+pub use brickmap::{GPUBrickMap, GPUBrickMapStats, GPUBufferStats};
+pub use instances::GPUInstances;
+pub use lights::GPULights;
+pub use objects::GPUObjects;
 pub use sdf::SDFOptimizer;
 
 pub struct GPUContext {
@@ -109,6 +115,41 @@ impl GPUContext {
                     10,
                     cvk::ShaderStageFlags::FRAGMENT,
                 ),
+                cvk::DescriptorBinding::unique(
+                    8,
+                    cvk::DescriptorType::StorageBuffer,
+                    cvk::ShaderStageFlags::COMPUTE,
+                ),
+                cvk::DescriptorBinding::unique(
+                    9,
+                    cvk::DescriptorType::StorageBuffer,
+                    cvk::ShaderStageFlags::COMPUTE,
+                ),
+                cvk::DescriptorBinding::unique(
+                    10,
+                    cvk::DescriptorType::StorageBuffer,
+                    cvk::ShaderStageFlags::COMPUTE,
+                ),
+                cvk::DescriptorBinding::unique(
+                    11,
+                    cvk::DescriptorType::StorageBuffer,
+                    cvk::ShaderStageFlags::COMPUTE,
+                ),
+                cvk::DescriptorBinding::unique(
+                    12,
+                    cvk::DescriptorType::StorageBuffer,
+                    cvk::ShaderStageFlags::COMPUTE,
+                ),
+                cvk::DescriptorBinding::unique(
+                    13,
+                    cvk::DescriptorType::StorageBuffer,
+                    cvk::ShaderStageFlags::COMPUTE,
+                ),
+                cvk::DescriptorBinding::unique(
+                    14,
+                    cvk::DescriptorType::StorageBuffer,
+                    cvk::ShaderStageFlags::COMPUTE,
+                ),
             ],
             ..Default::default()
         });
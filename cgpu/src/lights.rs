@@ -0,0 +1,104 @@
+use std::{mem, sync::Arc};
+
+use game::point_lights::{LightGrid, PointLight};
+use parking_lot::Mutex;
+
+use crate::{
+    objects::{create_host_buffer, reserve_host_buffer, HEADER_OFFSET},
+    GPUContext,
+};
+
+/// Grid header in front of the cells, matches `LightGrid` in raytrace.wgsl.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct GPULightGridHeader {
+    size: [u32; 3],
+    cell_size: f32,
+}
+
+/// Point lights and their [`LightGrid`] for the raytracer.
+///
+/// The grid stores the first index and count of every cell, followed by the light indices of
+/// all cells. Upload both together whenever a light was added, removed or moved.
+pub struct GPULights {
+    context: Arc<GPUContext>,
+    lights: Mutex<cvk::Buffer>,
+    grid: Mutex<cvk::Buffer>,
+}
+
+impl GPULights {
+    pub fn new(context: Arc<GPUContext>) -> Self {
+        let lights = create_host_buffer(
+            &context.device,
+            (HEADER_OFFSET + 256 * mem::size_of::<PointLight>()) as u64,
+            "Light Buffer",
+        );
+        // the shader reads the light count in front of the lights, start out without any
+        lights.upload(bytemuck::cast_slice(&[0u32; 4]), 0);
+        let grid = create_host_buffer(&context.device, 64 << 10, "Light Grid Buffer");
+        grid.upload(bytemuck::cast_slice(&[0u32; 4]), 0);
+
+        let new = Self {
+            context,
+            lights: Mutex::new(lights),
+            grid: Mutex::new(grid),
+        };
+        new.rebind_descriptors(&new.lights.lock(), &new.grid.lock());
+        new
+    }
+
+    pub fn upload(&self, lights: &[PointLight], grid: &LightGrid) {
+        let mut light_data = vec![0u8; HEADER_OFFSET];
+        light_data[..4].copy_from_slice(&(lights.len() as u32).to_le_bytes());
+        light_data.extend_from_slice(bytemuck::cast_slice(lights));
+
+        let header = GPULightGridHeader {
+            size: *grid.size().as_ref(),
+            cell_size: grid.cell_size(),
+        };
+        let mut grid_data = bytemuck::bytes_of(&header).to_vec();
+        grid_data.extend_from_slice(bytemuck::cast_slice(grid.cells()));
+        grid_data.extend_from_slice(bytemuck::cast_slice(grid.indices()));
+
+        let mut light_buffer = self.lights.lock();
+        let mut grid_buffer = self.grid.lock();
+        let grown = reserve_host_buffer(
+            &self.context,
+            &mut light_buffer,
+            light_data.len(),
+            "Light Buffer",
+        ) | reserve_host_buffer(
+            &self.context,
+            &mut grid_buffer,
+            grid_data.len(),
+            "Light Grid Buffer",
+        );
+        if grown {
+            self.rebind_descriptors(&light_buffer, &grid_buffer);
+        }
+        light_buffer.upload(&light_data, 0);
+        grid_buffer.upload(&grid_data, 0);
+    }
+
+    fn rebind_descriptors(&self, lights: &cvk::Buffer, grid: &cvk::Buffer) {
+        self.context.render_queue.wait_idle();
+        let _lock = self.context.render_queue.lock();
+
+        self.context.descriptors.write(&[
+            cvk::DescriptorWrite::StorageBuffer {
+                binding: 13,
+                buffer: lights,
+                offset: 0,
+                range: lights.size,
+                array_element: None,
+            },
+            cvk::DescriptorWrite::StorageBuffer {
+                binding: 14,
+                buffer: grid,
+                offset: 0,
+                range: grid.size,
+                array_element: None,
+            },
+        ]);
+    }
+}
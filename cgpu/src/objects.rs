@@ -0,0 +1,197 @@
+use std::{collections::HashMap, mem, sync::Arc};
+
+use game::{
+    brick::BRICK_SIZE,
+    objects::{ObjectId, VoxelObjects},
+    palette::PaletteRegistry,
+    BrickMap,
+};
+use parking_lot::Mutex;
+
+use crate::GPUContext;
+
+/// Object count in front of the headers, padded to the alignment of the header array.
+pub(crate) const HEADER_OFFSET: usize = 16;
+
+/// Per volume header read by the raytracer, matches `VoxelObject` in raytrace.wgsl. Used for
+/// objects and instances alike.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct GPUVoxelObject {
+    /// World space to voxels of the volume.
+    pub world_to_voxel: [[f32; 4]; 4],
+    pub size: [u32; 3],
+    /// Index of the first voxel in the voxel buffer.
+    pub offset: u32,
+}
+
+impl GPUVoxelObject {
+    pub fn new(to_local: &na::Matrix4<f32>, size: na::Vector3<u32>, offset: u32) -> Self {
+        let scale = na::Matrix4::new_scaling(BRICK_SIZE as f32);
+        Self {
+            world_to_voxel: *(scale * to_local).as_ref(),
+            size: *size.as_ref(),
+            offset,
+        }
+    }
+}
+
+/// Appends one material id per voxel of `brickmap` to `data` and returns its size in voxels.
+pub(crate) fn expand_voxels(
+    brickmap: &BrickMap,
+    palettes: &PaletteRegistry,
+    data: &mut Vec<u32>,
+) -> na::Vector3<u32> {
+    let size = brickmap.dimensions() * BRICK_SIZE;
+    let view = brickmap.read();
+    data.reserve((size.x * size.y * size.z) as usize);
+    for z in 0..size.z {
+        for y in 0..size.y {
+            for x in 0..size.x {
+                data.push(view.get_voxel(palettes, na::Point3::new(x, y, z)).0);
+            }
+        }
+    }
+    size
+}
+
+/// Host visible storage buffer, written directly by the CPU.
+pub(crate) fn create_host_buffer(device: &cvk::Device, size: u64, label: &str) -> cvk::Buffer {
+    device.create_buffer(&cvk::BufferInfo {
+        size,
+        usage: cvk::BufferUsageFlags::STORAGE_BUFFER,
+        usage_locality: cvk::MemoryUsage::AutoPreferDevice,
+        allocation_locality: cvk::MemoryPropertyFlags::HOST_VISIBLE
+            | cvk::MemoryPropertyFlags::HOST_COHERENT,
+        host_access: Some(cvk::AllocationCreateFlags::HOST_ACCESS_SEQUENTIAL_WRITE),
+        label: Some(label),
+        ..Default::default()
+    })
+}
+
+/// Replaces `buffer` with a larger one if `size` bytes don't fit, returns whether it did.
+pub(crate) fn reserve_host_buffer(
+    context: &GPUContext,
+    buffer: &mut cvk::Buffer,
+    size: usize,
+    label: &str,
+) -> bool {
+    if size as u64 <= buffer.size {
+        return false;
+    }
+    // the raytracer may still read the old buffer
+    context.render_queue.wait_idle();
+    *buffer = create_host_buffer(&context.device, (size as u64).next_power_of_two(), label);
+    true
+}
+
+/// Dense copies of [`VoxelObjects`] for the raytracer, one material id per voxel.
+///
+/// Voxels only change with [`GPUObjects::upload_voxels`], call it after objects were added,
+/// removed or edited. Transforms are cheap and meant to be refreshed every frame with
+/// [`GPUObjects::upload_transforms`].
+pub struct GPUObjects {
+    context: Arc<GPUContext>,
+    headers: Mutex<cvk::Buffer>,
+    voxels: Mutex<cvk::Buffer>,
+    /// First voxel and size in voxels of every uploaded object.
+    uploaded: Mutex<HashMap<ObjectId, (u32, na::Vector3<u32>)>>,
+}
+
+impl GPUObjects {
+    pub fn new(context: Arc<GPUContext>) -> Self {
+        let headers = create_host_buffer(
+            &context.device,
+            (HEADER_OFFSET + 64 * mem::size_of::<GPUVoxelObject>()) as u64,
+            "Object Header Buffer",
+        );
+        // the shader reads the count in front of the headers, start out without objects
+        headers.upload(bytemuck::cast_slice(&[0u32; 4]), 0);
+        let voxels = create_host_buffer(&context.device, 4 << 20, "Object Voxel Buffer");
+
+        let new = Self {
+            context,
+            headers: Mutex::new(headers),
+            voxels: Mutex::new(voxels),
+            uploaded: Mutex::new(HashMap::new()),
+        };
+        new.rebind_descriptors(&new.headers.lock(), &new.voxels.lock());
+        new
+    }
+
+    /// Expands the voxels of all objects, replacing everything uploaded before.
+    pub fn upload_voxels(&self, objects: &VoxelObjects, palettes: &PaletteRegistry) {
+        let mut uploaded = self.uploaded.lock();
+        uploaded.clear();
+
+        let mut data = Vec::new();
+        for (id, object) in objects.iter() {
+            let offset = data.len() as u32;
+            let size = expand_voxels(object.brickmap(), palettes, &mut data);
+            uploaded.insert(id, (offset, size));
+        }
+
+        let bytes: &[u8] = bytemuck::cast_slice(&data);
+        let headers = self.headers.lock();
+        let mut voxels = self.voxels.lock();
+        if reserve_host_buffer(
+            &self.context,
+            &mut voxels,
+            bytes.len(),
+            "Object Voxel Buffer",
+        ) {
+            self.rebind_descriptors(&headers, &voxels);
+        }
+        voxels.upload(bytes, 0);
+    }
+
+    /// Writes the current transforms of all objects uploaded with [`GPUObjects::upload_voxels`].
+    pub fn upload_transforms(&self, objects: &VoxelObjects) {
+        let uploaded = self.uploaded.lock();
+        let objects: Vec<_> = objects
+            .iter()
+            .filter_map(|(id, object)| {
+                let (offset, size) = uploaded.get(&id)?;
+                Some(GPUVoxelObject::new(&object.to_local(), *size, *offset))
+            })
+            .collect();
+
+        let mut data = vec![0u8; HEADER_OFFSET];
+        data[..4].copy_from_slice(&(objects.len() as u32).to_le_bytes());
+        data.extend_from_slice(bytemuck::cast_slice(&objects));
+
+        let mut headers = self.headers.lock();
+        let voxels = self.voxels.lock();
+        if reserve_host_buffer(
+            &self.context,
+            &mut headers,
+            data.len(),
+            "Object Header Buffer",
+        ) {
+            self.rebind_descriptors(&headers, &voxels);
+        }
+        headers.upload(&data, 0);
+    }
+
+    fn rebind_descriptors(&self, headers: &cvk::Buffer, voxels: &cvk::Buffer) {
+        self.context.render_queue.wait_idle();
+        let _lock = self.context.render_queue.lock();
+
+        self.context.descriptors.write(&[
+            cvk::DescriptorWrite::StorageBuffer {
+                binding: 8,
+                buffer: headers,
+                offset: 0,
+                range: headers.size,
+                array_element: None,
+            },
+            cvk::DescriptorWrite::StorageBuffer {
+                binding: 9,
+                buffer: voxels,
+                offset: 0,
+                range: voxels.size,
+                array_element: None,
+            },
+        ]);
+    }
+}
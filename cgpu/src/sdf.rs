@@ -8,6 +8,7 @@ pub struct SDFPushConstants {
     pub dimensions: [u32; 3],
     pub num_steps: u32,
     pub current_step: u32,
+    pub handle_layout: u32,
 }
 
 pub struct SDFOptimizer {
@@ -61,6 +62,7 @@ impl SDFOptimizer {
             dimensions: *self.brickmap.cpu.dimensions().as_ref(),
             num_steps: steps,
             current_step: 0,
+            handle_layout: self.brickmap.cpu.layout().as_raw(),
         };
 
         {
@@ -2,6 +2,7 @@ struct PushConstants {
     dimensions: vec3<i32>,
     num_steps: u32,
     current_step: u32,
+    handle_layout: u32,
 }
 
 struct BrickHandle {
@@ -50,7 +51,82 @@ fn brick_handle_with_empty_value(value: u32) -> BrickHandle {
 }
 
 
+const LAYOUT_MORTON: u32 = 1u;
+
+// bits needed to address every coordinate of an axis, see `HandleIndexer::axis_bits`
+fn axis_bits(dim: i32) -> u32 {
+    if dim <= 1 {
+        return 0u;
+    }
+    return firstLeadingBit(u32(dim - 1)) + 1u;
+}
+
+// spreads the low 16 bits of `v` to every second bit
+fn part1by1(v: u32) -> u32 {
+    var x = v & 0x0000ffffu;
+    x = (x | (x << 8u)) & 0x00ff00ffu;
+    x = (x | (x << 4u)) & 0x0f0f0f0fu;
+    x = (x | (x << 2u)) & 0x33333333u;
+    return (x | (x << 1u)) & 0x55555555u;
+}
+
+// spreads the low 10 bits of `v` to every third bit
+fn part1by2(v: u32) -> u32 {
+    var x = v & 0x000003ffu;
+    x = (x | (x << 16u)) & 0xff0000ffu;
+    x = (x | (x << 8u)) & 0x0300f00fu;
+    x = (x | (x << 4u)) & 0x030c30c3u;
+    return (x | (x << 2u)) & 0x09249249u;
+}
+
 fn brick_handle_index(pos: vec3<i32>) -> u32 {
+    if pc.handle_layout == LAYOUT_MORTON {
+        let bits = vec3<u32>(
+            axis_bits(pc.dimensions.x),
+            axis_bits(pc.dimensions.y),
+            axis_bits(pc.dimensions.z),
+        );
+        let p = vec3<u32>(pos);
+        var index = 0u;
+        var shift = 0u;
+        var low = 0u;
+        // x takes the lowest bit of every round, an axis drops out once its bits are used up.
+        // Interleaves the rounds in at most three stages, one per number of axes left, with
+        // magic numbers instead of a loop over every bit
+        for (var stage = 0; stage < 3; stage++) {
+            var high = 32u;
+            var axes = 0u;
+            for (var axis = 0; axis < 3; axis++) {
+                if bits[axis] > low {
+                    high = min(high, bits[axis]);
+                    axes += 1u;
+                }
+            }
+            if axes == 0u {
+                break;
+            }
+
+            let mask = (1u << (high - low)) - 1u;
+            var lane = 0u;
+            for (var axis = 0; axis < 3; axis++) {
+                if bits[axis] > low {
+                    let chunk = (p[axis] >> low) & mask;
+                    var spread = chunk;
+                    if axes == 3u {
+                        spread = part1by2(chunk);
+                    } else if axes == 2u {
+                        spread = part1by1(chunk);
+                    }
+                    index |= spread << (shift + lane);
+                    lane += 1u;
+                }
+            }
+            shift += axes * (high - low);
+            low = high;
+        }
+        return index;
+    }
+
     return u32(
         pos.x + (pos.y * pc.dimensions.x) + (pos.z * pc.dimensions.x * pc.dimensions.y)
     );
@@ -11,13 +11,23 @@ use std::{
 
 use cgpu::GPUBrickMap;
 use game::{
-    brick::ExpandedBrick,
+    brick::Violation,
+    brush::{Brush, BrushOp, Shape},
+    controller::{CharacterController, ControllerInput},
+    history::EditHistory,
+    instances::{Instance, InstanceTable, ModelId},
+    light::LightMap,
     material::{ExpandedMaterialMapping, MaterialRegistry},
+    objects::{RigidBody, VoxelObject, VoxelObjects},
     palette::PaletteRegistry,
+    point_lights::{extract_emissive_lights, LightGrid, PointLight, GRID_CELL_SIZE},
+    raytrace::{self, Ray},
+    sun::Sun,
     worldgen::{GeneratedBrick, WorldGenerator},
-    BrickMap, Camera, Input,
+    BrickMap, Camera, Input, Transform,
 };
 use parking_lot::Mutex;
+use rand::Rng;
 use render::RenderContext;
 use winit::{
     dpi::PhysicalSize,
@@ -28,6 +38,7 @@ use winit::{
 };
 
 mod render;
+mod stats;
 mod ui;
 
 pub struct TimeTicker {
@@ -66,6 +77,12 @@ impl TimeTicker {
     }
 }
 
+/// Seconds a full day takes while the day cycle runs.
+const DAY_LENGTH: f32 = 600.0;
+
+/// Bytes of brick snapshots the undo history keeps before dropping the oldest edits.
+const HISTORY_BUDGET: usize = 64 * 1024 * 1024;
+
 pub struct ClientState {
     ticker: TimeTicker,
     render_ticker: TimeTicker,
@@ -74,6 +91,12 @@ pub struct ClientState {
     brickmap: Arc<BrickMap>,
     sdf_optimizer: Arc<cgpu::SDFOptimizer>,
     gpu_brickmap: Arc<cgpu::GPUBrickMap>,
+    objects: Mutex<VoxelObjects>,
+    gpu_objects: cgpu::GPUObjects,
+    instances: Mutex<InstanceTable>,
+    gpu_instances: cgpu::GPUInstances,
+    point_lights: Mutex<Vec<PointLight>>,
+    gpu_lights: cgpu::GPULights,
     gpu: Arc<cgpu::GPUContext>,
     input: Input,
     #[allow(unused)]
@@ -83,6 +106,22 @@ pub struct ClientState {
     capture: bool,
     focused: Option<Arc<Window>>,
     camera: Mutex<Camera>,
+    /// Walking player, the camera flies freely while this is `None`.
+    controller: Mutex<Option<CharacterController>>,
+    /// Hours into the day the sun is placed at, see [`Sun::at_time`].
+    time_of_day: Mutex<f32>,
+    /// Whether `time_of_day` advances on its own.
+    day_cycle: Mutex<bool>,
+    ambient_occlusion: Mutex<bool>,
+    refraction: Mutex<bool>,
+    /// Light levels of the map, computed on demand from the debug UI.
+    light: Mutex<Option<LightMap>>,
+    /// Result of the last "Validate BrickMap" run in the debug UI.
+    violations: Mutex<Option<Vec<Violation>>>,
+    /// Last stats shown in the debug UI, refreshed on demand since collecting walks the map.
+    stats: Mutex<Option<stats::WorldStats>>,
+    /// Map edits made through [`Self::edit`], undone with Ctrl+Z.
+    history: Mutex<EditHistory>,
 }
 
 impl ClientState {
@@ -122,6 +161,10 @@ impl ClientState {
             gpu.compute_queue.clone(),
         ));
 
+        let gpu_objects = cgpu::GPUObjects::new(gpu.clone());
+        let gpu_instances = cgpu::GPUInstances::new(gpu.clone());
+        let gpu_lights = cgpu::GPULights::new(gpu.clone());
+
         let new = Self {
             ticker: TimeTicker::new(time::Duration::from_secs_f64(1.0 / 60.0)),
             render_ticker: TimeTicker::new(time::Duration::from_secs_f64(1.0 / 166.0)),
@@ -130,6 +173,12 @@ impl ClientState {
             brickmap,
             gpu,
             gpu_brickmap,
+            objects: Mutex::new(VoxelObjects::new()),
+            gpu_objects,
+            instances: Mutex::new(InstanceTable::new()),
+            gpu_instances,
+            point_lights: Mutex::new(Vec::new()),
+            gpu_lights,
             sdf_optimizer,
             input: Input::new(),
             proxy: el.create_proxy(),
@@ -138,12 +187,33 @@ impl ClientState {
             focused: None,
             capture: true,
             camera: Mutex::new(camera),
+            controller: Mutex::new(None),
+            time_of_day: Mutex::new(10.0),
+            day_cycle: Mutex::new(false),
+            ambient_occlusion: Mutex::new(true),
+            refraction: Mutex::new(false),
+            light: Mutex::new(None),
+            violations: Mutex::new(None),
+            stats: Mutex::new(None),
+            history: Mutex::new(EditHistory::new(HISTORY_BUDGET)),
         };
 
         new
     }
 
+    pub fn validate_brickmap(&self) {
+        let mut violations = self.brickmap.validate(&self.palettes);
+        violations.extend(self.brickmap.validate_materials(&self.materials));
+        for violation in violations.iter().take(32) {
+            log::warn!("BrickMap: {:?}", violation);
+        }
+        log::info!("BrickMap: {} violations", violations.len());
+        *self.violations.lock() = Some(violations);
+    }
+
     pub fn generate_terrain(&self) {
+        self.history.lock().clear();
+        *self.light.lock() = None;
         let world_gen = WorldGenerator::new(Some(420), 16);
         let mut material_mapping = ExpandedMaterialMapping::new();
         let registry = self.materials.as_ref();
@@ -201,11 +271,268 @@ impl ClientState {
                 },
             );
             log::debug!("WorldGen: 100% Done");
+            let dedup = brickmap.cpu.dedup_stats();
+            log::debug!(
+                "WorldGen: {} bricks in {} slots, {} shared, {} KiB saved",
+                dedup.references,
+                dedup.slots,
+                dedup.shared_slots,
+                dedup.saved_bytes / 1024
+            );
             brickmap.transfer_all_palettes();
             optimizer.run();
         });
     }
 
+    /// Fills a ball of water where the camera looks at the terrain.
+    pub fn pour_water(&self) {
+        let Some(water) = self.materials.get_material_id("water") else {
+            log::warn!("Transparency: no water material");
+            return;
+        };
+        let ray = {
+            let camera = self.camera.lock();
+            Ray::new(camera.position, camera.rotation * -*na::Vector3::z_axis())
+        };
+        let Some(hit) = raytrace::trace_ray(&self.brickmap, &ray) else {
+            return;
+        };
+        let pool = Shape::Sphere {
+            center: hit.position,
+            radius: 1.5,
+        };
+        self.edit("Pour Water", &Brush::new(pool, BrushOp::Union, water));
+    }
+
+    /// Applies `brush` to the map as one undoable edit.
+    pub fn edit(&self, name: &str, brush: &Brush) {
+        let plan = brush.plan(&self.brickmap, &self.palettes);
+        if plan.dropped > 0 {
+            log::warn!(
+                "{name}: {} voxels dropped, brick palettes are full",
+                plan.dropped
+            );
+        }
+
+        let mut history = self.history.lock();
+        history.begin(name);
+        if let Err(err) = history.record_edits(&self.brickmap, &self.palettes, &plan.edits) {
+            log::error!("{name}: not applied, {err}");
+            return;
+        }
+        self.gpu_brickmap.apply_edits(&plan.edits);
+        history.commit();
+        self.gpu_brickmap.transfer_all_palettes();
+        let changed: Vec<_> = plan.edits.iter().map(|edit| edit.at).collect();
+        self.relight(&changed);
+    }
+
+    /// Keeps the light map in step with edited bricks, when one was computed.
+    fn relight(&self, changed: &[na::Point3<u32>]) {
+        let mut light = self.light.lock();
+        let Some(light) = light.as_mut() else {
+            return;
+        };
+        light.update(&self.brickmap, &self.palettes, &self.materials, changed);
+        // only the debug UI samples the light, nothing to upload the dirty bricks to
+        light.take_dirty();
+    }
+
+    pub fn undo(&self) {
+        // occupancy only bricks come back as stone, like the terrain they were generated from
+        let Some(stone) = self.materials.get_material_id("stone") else {
+            log::warn!("History: no stone material");
+            return;
+        };
+        let mut history = self.history.lock();
+        let name = history.undo_name().map(str::to_owned);
+        match history.undo(&self.brickmap, &self.palettes) {
+            Ok(Some(bricks)) => {
+                self.gpu_brickmap.restore(&bricks, stone);
+                self.gpu_brickmap.transfer_all_palettes();
+                let changed: Vec<_> = bricks.iter().map(|snapshot| snapshot.at).collect();
+                self.relight(&changed);
+            }
+            Ok(None) => {}
+            Err(err) => log::error!("Undo {name:?}: {err}"),
+        }
+    }
+
+    pub fn redo(&self) {
+        let Some(stone) = self.materials.get_material_id("stone") else {
+            log::warn!("History: no stone material");
+            return;
+        };
+        let mut history = self.history.lock();
+        let name = history.redo_name().map(str::to_owned);
+        match history.redo(&self.brickmap, &self.palettes) {
+            Ok(Some(bricks)) => {
+                self.gpu_brickmap.restore(&bricks, stone);
+                self.gpu_brickmap.transfer_all_palettes();
+                let changed: Vec<_> = bricks.iter().map(|snapshot| snapshot.at).collect();
+                self.relight(&changed);
+            }
+            Ok(None) => {}
+            Err(err) => log::error!("Redo {name:?}: {err}"),
+        }
+    }
+
+    /// Drops a small stone ball in front of the camera.
+    pub fn spawn_object(&self) {
+        let camera = self.camera.lock();
+        let forward = camera.rotation * -*na::Vector3::z_axis();
+        let mut transform = Transform::identity();
+        transform.position(&(camera.position.coords + forward * 4.0));
+
+        let body = RigidBody {
+            velocity: forward * 4.0,
+            angular_velocity: na::Vector3::new(0.7, 1.3, 0.0),
+            ..Default::default()
+        };
+        let object = VoxelObject::new(na::Vector3::new(1, 1, 1), transform, body);
+        let Some(stone) = self.materials.get_material_id("stone") else {
+            log::warn!("Objects: no stone material");
+            return;
+        };
+        let ball = Shape::Sphere {
+            center: na::Point3::new(0.5, 0.5, 0.5),
+            radius: 0.45,
+        };
+        object
+            .brickmap()
+            .apply_brush(&Brush::new(ball, BrushOp::Union, stone), &self.palettes);
+
+        let mut objects = self.objects.lock();
+        objects.insert(object);
+        self.gpu_objects.upload_voxels(&objects, &self.palettes);
+    }
+
+    pub fn clear_objects(&self) {
+        let mut objects = self.objects.lock();
+        *objects = VoxelObjects::new();
+        self.gpu_objects.upload_voxels(&objects, &self.palettes);
+    }
+
+    /// Small tree of 2 x 4 x 2 bricks, a trunk under a round crown.
+    fn tree_model(&self) -> BrickMap {
+        let model = BrickMap::new(na::Vector3::new(2, 4, 2));
+        let wood = self.materials.get_material_id("dirt");
+        let leaves = self.materials.get_material_id("grass");
+        let (Some(wood), Some(leaves)) = (wood, leaves) else {
+            log::warn!("Instances: no dirt or grass material");
+            return model;
+        };
+
+        let trunk = Shape::Cylinder {
+            center: na::Point3::new(1.0, 1.25, 1.0),
+            radius: 0.2,
+            half_height: 1.25,
+        };
+        let crown = Shape::Sphere {
+            center: na::Point3::new(1.0, 3.0, 1.0),
+            radius: 0.95,
+        };
+        model.apply_brush(&Brush::new(trunk, BrushOp::Union, wood), &self.palettes);
+        model.apply_brush(&Brush::new(crown, BrushOp::Union, leaves), &self.palettes);
+        model
+    }
+
+    /// Places `count` trees on the terrain at random spots, all sharing one model.
+    pub fn scatter_trees(&self, count: usize) {
+        let mut instances = self.instances.lock();
+        if instances.models().is_empty() {
+            instances.add_model(self.tree_model());
+            self.gpu_instances.upload_models(&instances, &self.palettes);
+        }
+        let model = ModelId(0);
+        let height = instances.models()[0].dimensions().y as f32;
+
+        let dims = self.brickmap.dimensions().cast::<f32>();
+        let mut rng = rand::thread_rng();
+        for _ in 0..count {
+            let top = na::Point3::new(
+                rng.gen_range(0.0..dims.x),
+                dims.y - 0.5,
+                rng.gen_range(0.0..dims.z),
+            );
+            let Some(ground) =
+                raytrace::trace_ray(&self.brickmap, &Ray::new(top, -na::Vector3::y()))
+            else {
+                continue;
+            };
+
+            let mut transform = Transform::identity();
+            transform.position(&(ground.position.coords + na::Vector3::y() * height / 2.0));
+            transform.rotate_around(&na::Vector3::y_axis(), rng.gen_range(0.0..360.0));
+            instances.insert(Instance { model, transform });
+        }
+    }
+
+    pub fn clear_instances(&self) {
+        let mut instances = self.instances.lock();
+        let ids: Vec<_> = instances.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            instances.remove(id);
+        }
+    }
+
+    /// Puts a point light of a random color at the camera.
+    pub fn place_light(&self) {
+        let position = self.camera.lock().position;
+        let mut rng = rand::thread_rng();
+        let color = [(); 3].map(|_| rng.gen_range(0.3..1.0));
+        let mut lights = self.point_lights.lock();
+        lights.push(PointLight::new(position, color, 6.0, 2.0));
+        self.upload_lights(&lights);
+    }
+
+    /// Adds a light for every brick with emissive voxels.
+    pub fn extract_lights(&self) {
+        let extracted = extract_emissive_lights(
+            &self.brickmap,
+            &self.palettes,
+            &self.materials,
+            na::Point3::origin(),
+            na::Point3::from(self.brickmap.dimensions()),
+        );
+        log::info!("Lights: {} extracted from emissive voxels", extracted.len());
+        let mut lights = self.point_lights.lock();
+        lights.extend(extracted);
+        self.upload_lights(&lights);
+    }
+
+    pub fn clear_lights(&self) {
+        let mut lights = self.point_lights.lock();
+        lights.clear();
+        self.upload_lights(&lights);
+    }
+
+    fn upload_lights(&self, lights: &[PointLight]) {
+        let grid = LightGrid::build(self.brickmap.dimensions(), GRID_CELL_SIZE, lights);
+        self.gpu_lights.upload(lights, &grid);
+    }
+
+    pub fn compute_light(&self) {
+        let start = time::Instant::now();
+        let light = LightMap::compute(&self.brickmap, &self.palettes, &self.materials);
+        log::info!("Light: computed in {:?}", start.elapsed());
+        *self.light.lock() = Some(light);
+    }
+
+    pub fn is_walking(&self) -> bool {
+        self.controller.lock().is_some()
+    }
+
+    /// Switches between the free flying camera and a walking player standing at the camera.
+    pub fn toggle_walking(&self) {
+        let position = self.camera.lock().position;
+        let mut controller = self.controller.lock();
+        *controller = match controller.take() {
+            Some(_) => None,
+            None => Some(CharacterController::from_eye(position, Default::default())),
+        };
+    }
+
     pub fn resize(&mut self, id: WindowId, size: PhysicalSize<u32>) {
         let _ = id;
         let _ = size;
@@ -216,12 +543,34 @@ impl ClientState {
         {
             let mut camera = self.camera.lock();
             camera.update_mouse(dtf32, &self.input);
-            camera.update_keyboard(dtf32, &self.input);
+            if !self.is_walking() {
+                camera.update_keyboard(dtf32, &self.input);
+            }
+        }
+        if self.input.pressed(KeyCode::KeyF) {
+            self.toggle_walking();
+        }
+        let ctrl =
+            self.input.pressing(KeyCode::ControlLeft) || self.input.pressing(KeyCode::ControlRight);
+        let shift =
+            self.input.pressing(KeyCode::ShiftLeft) || self.input.pressing(KeyCode::ShiftRight);
+        if ctrl && self.input.pressed(KeyCode::KeyZ) {
+            if shift {
+                self.redo();
+            } else {
+                self.undo();
+            }
+        }
+        if ctrl && self.input.pressed(KeyCode::KeyY) {
+            self.redo();
         }
         for (_id, render) in &self.renderes {
             let mut render = render.lock();
 
             render.update_camera(&self.camera.lock());
+            render.update_sun(&Sun::at_time(*self.time_of_day.lock()));
+            render.set_ambient_occlusion(*self.ambient_occlusion.lock());
+            render.set_refraction(*self.refraction.lock());
             if self.input.pressed(KeyCode::KeyM) {
                 render.ppc.mode += 1;
                 if render.ppc.mode == 4 {
@@ -260,13 +609,42 @@ impl ClientState {
 
         while self.ticker.accumulator >= self.ticker.rate {
             self.gpu_brickmap.try_drop_staging();
+            self.tick_controller(self.ticker.rate.as_secs_f32());
+            self.objects
+                .lock()
+                .step(&self.brickmap, self.ticker.rate.as_secs_f32());
+            if *self.day_cycle.lock() {
+                let mut hours = self.time_of_day.lock();
+                *hours = (*hours + self.ticker.rate.as_secs_f32() * 24.0 / DAY_LENGTH) % 24.0;
+            }
             self.ticker.accumulator -= self.ticker.rate;
         }
+        self.gpu_objects.upload_transforms(&self.objects.lock());
+        {
+            let mut instances = self.instances.lock();
+            if instances.update() {
+                self.gpu_instances.upload_instances(&instances);
+            }
+        }
 
         self.handle_input(dt);
         self.input.flush(self.ticker.rate);
     }
 
+    fn tick_controller(&self, dt: f32) {
+        let mut camera = self.camera.lock();
+        if let Some(controller) = self.controller.lock().as_mut() {
+            let forward = camera.rotation * -*na::Vector3::z_axis();
+            controller.tick(
+                &self.brickmap,
+                &ControllerInput::from_input(&self.input),
+                forward,
+                dt,
+            );
+            camera.position = controller.eye();
+        }
+    }
+
     pub fn fixed_render_tick(&mut self, window: WindowId) {
         if let Some(render) = self.renderes.get(&window) {
             let mut render = render.lock();
@@ -3,9 +3,13 @@ use std::{mem, sync::Arc, time};
 use anyhow::Result;
 use cgpu::GPUBrickMap;
 use cvk;
-use game::Camera;
+use game::{sun::Sun, Camera};
 use winit::{event::WindowEvent, window::Window};
 
+/// Bits of `flags0` toggling features of `raytrace.wgsl`.
+const FLAG_AMBIENT_OCCLUSION: u32 = 1;
+const FLAG_REFRACTION: u32 = 2;
+
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct PresentPushConstants {
@@ -18,17 +22,21 @@ pub struct RayTracePushConstants {
     pub camera: [[f32; 4]; 4],
     pub camera_inverse: [[f32; 4]; 4],
     pub camera_pos: [f32; 3],
-    _padding0: u32,
+    pub handle_layout: u32,
     pub dimensions: [u32; 3],
     pub packed_resolution: u32,
     pub flags0: u32,
     pub flags1: u32,
     pub dt: f32,
-    pub depth_boost: f32,
+    pub mip_distance: f32,
     pub brick_hit: [u32; 3],
     _padding1: u32,
     pub voxel_hit: [u32; 3],
     _padding2: u32,
+    pub sun_direction: [f32; 3],
+    pub sun_intensity: f32,
+    pub ambient: f32,
+    _padding3: [u32; 3],
 }
 
 impl PresentPushConstants {
@@ -43,17 +51,21 @@ impl RayTracePushConstants {
             camera: [[0.; 4]; 4],
             camera_inverse: [[0.; 4]; 4],
             camera_pos: [0.; 3],
-            _padding0: 0,
+            handle_layout: 0,
             dimensions: [0; 3],
             packed_resolution: 0,
             flags0: 0,
             flags1: 0,
             dt: 0.,
-            depth_boost: 0.,
+            mip_distance: 0.,
             brick_hit: [0; 3],
             _padding1: 0,
             voxel_hit: [0; 3],
             _padding2: 0,
+            sun_direction: [0., 1., 0.],
+            sun_intensity: 0.,
+            ambient: 1.,
+            _padding3: [0; 3],
         }
     }
 
@@ -121,6 +133,8 @@ impl RenderContext {
         let mut rtpc = RayTracePushConstants::empty();
         rtpc.set_resolution(window.inner_size().width, window.inner_size().height);
         rtpc.dimensions = *brickmap.cpu.dimensions().as_ref();
+        rtpc.handle_layout = brickmap.cpu.layout().as_raw();
+        rtpc.mip_distance = 32.0;
 
         let ppc = PresentPushConstants::empty();
 
@@ -406,6 +420,28 @@ impl RenderContext {
         self.rtpc.set_resolution(size.width, size.height);
     }
 
+    pub fn update_sun(&mut self, sun: &Sun) {
+        self.rtpc.sun_direction = *sun.direction.as_ref();
+        self.rtpc.sun_intensity = sun.intensity;
+        self.rtpc.ambient = sun.ambient;
+    }
+
+    pub fn set_ambient_occlusion(&mut self, enabled: bool) {
+        self.set_flag(FLAG_AMBIENT_OCCLUSION, enabled);
+    }
+
+    pub fn set_refraction(&mut self, enabled: bool) {
+        self.set_flag(FLAG_REFRACTION, enabled);
+    }
+
+    fn set_flag(&mut self, flag: u32, enabled: bool) {
+        if enabled {
+            self.rtpc.flags0 |= flag;
+        } else {
+            self.rtpc.flags0 &= !flag;
+        }
+    }
+
     fn create_image_resources(
         device: &cvk::Device,
         sc: &cvk::Swapchain,
@@ -417,7 +453,7 @@ impl RenderContext {
 
         let present_image = device.create_image(&cvk::ImageInfo {
             label: Some("Present Image"),
-            format: cvk::Format::R8G8B8A8_UNORM,
+            format: cvk::Format::R16G16B16A16_SFLOAT,
             width,
             height,
             usage: cvk::ImageUsageFlags::STORAGE | cvk::ImageUsageFlags::SAMPLED,
@@ -436,7 +472,7 @@ impl RenderContext {
 
         let normal_image = device.create_image(&cvk::ImageInfo {
             label: Some("Normal Image"),
-            format: cvk::Format::R8G8B8A8_UNORM,
+            format: cvk::Format::R16G16B16A16_SFLOAT,
             width,
             height,
             usage: cvk::ImageUsageFlags::STORAGE | cvk::ImageUsageFlags::SAMPLED,
@@ -13,6 +13,10 @@ struct PushConstants {
 
 var<push_constant> pc: PushConstants;
 
+// see `pbr::EXPOSURE` in the game crate
+const EXPOSURE: f32 = 1.0;
+const MODE_COLOR: u32 = 0u;
+
 @group(0) @binding(6)
 var images: binding_array<texture_2d<f32>, 10>; 
 
@@ -42,7 +46,16 @@ fn fmain(in: VertexOutput) -> FragmentOutput {
     let image = images[pc.mode];
     let image_sampler = samplers[pc.mode];
 
-    let color = textureSample(image, image_sampler, in.uv);
+    var color = textureSample(image, image_sampler, in.uv);
+    if pc.mode == MODE_COLOR {
+        color = vec4<f32>(tonemap(color.rgb), color.a);
+    }
     return FragmentOutput(color);
 }
 
+// ACES filmic fit from linear HDR to 0..1, mirrors `pbr::tonemap`.
+fn tonemap(color: vec3<f32>) -> vec3<f32> {
+    let x = max(color * EXPOSURE, vec3<f32>(0.0));
+    return clamp(x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
@@ -2,17 +2,24 @@ struct PushConstants {
     camera: mat4x4<f32>,
     camera_inverse: mat4x4<f32>,
     camera_pos: vec3<f32>,
-    _padding0: u32,
+    handle_layout: u32,
     dimensions: vec3<i32>,
     packed_resolution: u32,
     flags0: u32,
     flags1: u32,
     dt: f32,
-    depth_boost: f32,
+    mip_distance: f32,
     brick_hit: vec3<u32>,
     _padding1: u32,
     voxel_hit: vec3<u32>,
     _padding2: u32,
+    // towards the sun, see `Sun` in the game crate
+    sun_direction: vec3<f32>,
+    sun_intensity: f32,
+    ambient: f32,
+    _padding3: u32,
+    _padding4: u32,
+    _padding5: u32,
 }
 
 struct BrickHandle {
@@ -36,32 +43,82 @@ struct MaterialHandle {
     raw: u32,
 }
 
+// matches `PbrMaterial` in the game crate
 struct PbrMaterial {
     color: vec4<f32>,
-    emissive: vec3<f32>,
-    opaque: f32,
     metallic: f32,
-    roughtness: f32,
+    roughness: f32,
+    _padding1: vec2<f32>,
+    // glow in rgb, alpha cutoff in w
+    emissive: vec4<f32>,
 }
 
 struct Hit {
-    color: vec4<f32>,
+    // offset into `materials`
+    material: u32,
     pos: vec3<f32>,
     hit: bool,
     mask: vec3<f32>,
+    // in a full resolution brick flagged with transparent voxels
+    transparent: bool,
 }
 
 fn new_empty_hit() -> Hit {
     return Hit(
-        vec4<f32>(0.0, 0.0, 0.0, 0.0),
+        0u,
         vec3<f32>(0.0, 0.0, 0.0),
         false,
         vec3<f32>(0.0, 0.0, 0.0),
+        false,
     );
 }
 
-fn new_hit(color: vec4<f32>, pos: vec3<f32>, mask: vec3<f32>) -> Hit {
-    return Hit(color, pos, true, mask);
+fn new_hit(material: MaterialHandle, pos: vec3<f32>, mask: vec3<f32>) -> Hit {
+    return Hit(material.raw, pos, true, mask, false);
+}
+
+// matches `GPUVoxelObject` in cgpu
+struct VoxelObject {
+    world_to_voxel: mat4x4<f32>,
+    size: vec3<u32>,
+    offset: u32,
+}
+
+struct VoxelObjects {
+    count: u32,
+    objects: array<VoxelObject>,
+}
+
+// matches `GPUBvhNode` in cgpu, leaves address a range of `instances`
+struct BvhNode {
+    min: vec3<f32>,
+    first: u32,
+    max: vec3<f32>,
+    count: u32,
+}
+
+struct InstanceBvh {
+    node_count: u32,
+    nodes: array<BvhNode>,
+}
+
+struct PointLight {
+    position: vec3<f32>,
+    radius: f32,
+    color: vec3<f32>,
+    intensity: f32,
+}
+
+struct PointLights {
+    count: u32,
+    lights: array<PointLight>,
+}
+
+// `data` holds first index and count of every cell, then the light indices of all cells
+struct LightGrid {
+    size: vec3<u32>,
+    cell_size: f32,
+    data: array<u32>,
 }
 
 var<push_constant> pc: PushConstants;
@@ -82,11 +139,68 @@ var<storage, read> materials: array<PbrMaterial>;
 var<storage, read> palettes: array<u32>;
 
 @group(0) @binding(5)
-var images: binding_array<texture_storage_2d<rgba8unorm, write>, 10>; 
+var images: binding_array<texture_storage_2d<rgba16float, write>, 10>; 
+
+@group(0) @binding(8)
+var<storage, read> voxel_objects: VoxelObjects;
+
+// one material id per voxel, 0 is air
+@group(0) @binding(9)
+var<storage, read> object_voxels: array<u32>;
+
+@group(0) @binding(10)
+var<storage, read> instance_bvh: InstanceBvh;
+
+// one header per instance in BVH leaf order
+@group(0) @binding(11)
+var<storage, read> instances: array<VoxelObject>;
+
+// shared voxels of all models, one material id per voxel
+@group(0) @binding(12)
+var<storage, read> model_voxels: array<u32>;
+
+@group(0) @binding(13)
+var<storage, read> point_lights: PointLights;
+
+@group(0) @binding(14)
+var<storage, read> light_grid: LightGrid;
 
 const BRICK_SIZE: u32 = 8;
 const MAX_RAY_STEPS: u32 = 256;
+const MAX_OBJECT_STEPS: u32 = 192;
+const BVH_STACK_SIZE: u32 = 32;
 const EPSILON: f32 = 0.00001;
+const SHADOW_BIAS: f32 = 0.001;
+const PI: f32 = 3.14159265;
+// see the `pbr` module of the game crate
+const DIELECTRIC_F0: f32 = 0.04;
+const GROUND_AMBIENT: f32 = 0.5;
+// shadow rays stop this short of a point light, see `LIGHT_CLEARANCE` in the game crate
+const LIGHT_CLEARANCE: f32 = 0.25;
+// `LightGrid::build` keeps no more lights per cell, see `MAX_CELL_LIGHTS` in the game crate
+const MAX_CELL_LIGHTS: u32 = 16;
+// see `ambient_occlusion` in the game crate
+const AO_FLOOR: f32 = 0.35;
+
+// bits of `pc.flags0`
+const FLAG_AMBIENT_OCCLUSION: u32 = 1u;
+const FLAG_REFRACTION: u32 = 2u;
+
+// see the `transparency` module of the game crate
+const MAX_TRANSPARENT_STEPS: u32 = 64u;
+const MAX_PASS_VOXELS: u32 = 256u;
+const ABSORPTION: f32 = 0.15;
+const REFRACTIVE_INDEX: f32 = 1.33;
+const EXIT_BIAS: f32 = 0.0001;
+const OPACITY_OPAQUE: u32 = 0u;
+const OPACITY_TRANSLUCENT: u32 = 1u;
+const OPACITY_ALPHA_TESTED: u32 = 2u;
+// how `pass_voxels` ended, mirrors `WalkEnd`
+const WALK_AIR: u32 = 0u;
+const WALK_VOXEL: u32 = 1u;
+const WALK_BLOCKED: u32 = 2u;
+// top bit of `TraceBrick::brick_offset`
+const TRANSPARENT_BIT: u32 = 0x80000000u;
 
 const DATA_BIT: u32 = 0x80000000u;  // Bit 31
 const LOD_BIT: u32  = 0x40000000u;  // Bit 30 
@@ -94,6 +208,11 @@ const DATA_MASK: u32 = 0x7FFFFFFFu;  // Bits 0-30 for data
 const EMPTY_DATA_MASK: u32 = 0x1FFFFFFFu; // Bits 0-29 for empty handle values
 const MAX_DISTANCE: u32 = EMPTY_DATA_MASK;
 
+// BrickMip layout behind each material brick, in u32 words
+const MIP_LEVELS: u32 = 3u;
+const MIP_OCCUPANCY4: u32 = 0u;
+const MIP_OCCUPANCY2: u32 = 2u;
+
 fn get_resolution() -> vec2<f32> {
     let width = (pc.packed_resolution & 0xFFFFu) >> 0u;
     let height = (pc.packed_resolution & 0xFFFF0000u) >> 16u;
@@ -120,7 +239,82 @@ fn brick_handle_get_empty_value(brick_handle: BrickHandle) -> u32 {
     return brick_handle.raw & EMPTY_DATA_MASK;
 }
 
+const LAYOUT_MORTON: u32 = 1u;
+
+// bits needed to address every coordinate of an axis, see `HandleIndexer::axis_bits`
+fn axis_bits(dim: i32) -> u32 {
+    if dim <= 1 {
+        return 0u;
+    }
+    return firstLeadingBit(u32(dim - 1)) + 1u;
+}
+
+// spreads the low 16 bits of `v` to every second bit
+fn part1by1(v: u32) -> u32 {
+    var x = v & 0x0000ffffu;
+    x = (x | (x << 8u)) & 0x00ff00ffu;
+    x = (x | (x << 4u)) & 0x0f0f0f0fu;
+    x = (x | (x << 2u)) & 0x33333333u;
+    return (x | (x << 1u)) & 0x55555555u;
+}
+
+// spreads the low 10 bits of `v` to every third bit
+fn part1by2(v: u32) -> u32 {
+    var x = v & 0x000003ffu;
+    x = (x | (x << 16u)) & 0xff0000ffu;
+    x = (x | (x << 8u)) & 0x0300f00fu;
+    x = (x | (x << 4u)) & 0x030c30c3u;
+    return (x | (x << 2u)) & 0x09249249u;
+}
+
 fn brick_handle_index(pos: vec3<i32>) -> u32 {
+    if pc.handle_layout == LAYOUT_MORTON {
+        let bits = vec3<u32>(
+            axis_bits(pc.dimensions.x),
+            axis_bits(pc.dimensions.y),
+            axis_bits(pc.dimensions.z),
+        );
+        let p = vec3<u32>(pos);
+        var index = 0u;
+        var shift = 0u;
+        var low = 0u;
+        // x takes the lowest bit of every round, an axis drops out once its bits are used up.
+        // Interleaves the rounds in at most three stages, one per number of axes left, with
+        // magic numbers instead of a loop over every bit
+        for (var stage = 0; stage < 3; stage++) {
+            var high = 32u;
+            var axes = 0u;
+            for (var axis = 0; axis < 3; axis++) {
+                if bits[axis] > low {
+                    high = min(high, bits[axis]);
+                    axes += 1u;
+                }
+            }
+            if axes == 0u {
+                break;
+            }
+
+            let mask = (1u << (high - low)) - 1u;
+            var lane = 0u;
+            for (var axis = 0; axis < 3; axis++) {
+                if bits[axis] > low {
+                    let chunk = (p[axis] >> low) & mask;
+                    var spread = chunk;
+                    if axes == 3u {
+                        spread = part1by2(chunk);
+                    } else if axes == 2u {
+                        spread = part1by1(chunk);
+                    }
+                    index |= spread << (shift + lane);
+                    lane += 1u;
+                }
+            }
+            shift += axes * (high - low);
+            low = high;
+        }
+        return index;
+    }
+
     return u32(
         pos.x + (pos.y * pc.dimensions.x) + (pos.z * pc.dimensions.x * pc.dimensions.y)
     );
@@ -155,10 +349,15 @@ fn get_material(material_handle: MaterialHandle) -> PbrMaterial {
 
 fn get_brick_offset(brick_handle: BrickHandle) -> u32 {
     let trace_offset = brick_handle_get_data(brick_handle);
-    let offset = trace_bricks[trace_offset].brick_offset;
+    let offset = trace_bricks[trace_offset].brick_offset & ~TRANSPARENT_BIT;
     return offset;
 }
 
+fn brick_is_transparent(brick_handle: BrickHandle) -> bool {
+    let trace_offset = brick_handle_get_data(brick_handle);
+    return (trace_bricks[trace_offset].brick_offset & TRANSPARENT_BIT) != 0u;
+}
+
 fn get_brick_meta(brick_offset: u32) -> MaterialBrickMeta {
     let offset = brick_offset;
     let raw = material_bricks[offset];
@@ -194,6 +393,48 @@ fn get_brick_voxel(brick_offset: u32, local_pos: vec3<i32>) -> MaterialHandle {
     return MaterialHandle(material_handle_raw);
 }
 
+fn get_mip_offset(brick_offset: u32) -> u32 {
+    let brick_meta = get_brick_meta(brick_offset);
+    // meta word + 512 voxels of element_size bits
+    return brick_offset + 1u + get_brick_meta_size(brick_meta) * 16u;
+}
+
+fn select_mip_level(distance: f32) -> u32 {
+    if pc.mip_distance <= 0.0 {
+        return 0u;
+    }
+    return u32(clamp(floor(max(distance, 0.0) / pc.mip_distance), 0.0, f32(MIP_LEVELS - 1u)));
+}
+
+fn mip_cell_index(level: u32, pos: vec3<u32>) -> u32 {
+    let size = BRICK_SIZE >> level;
+    return pos.x + pos.y * size + pos.z * size * size;
+}
+
+fn get_mip_voxel(mip_offset: u32, level: u32, pos: vec3<u32>) -> bool {
+    let idx = mip_cell_index(level, pos);
+    if level == 1u {
+        let occupancy = material_bricks[mip_offset + MIP_OCCUPANCY4 + idx / 32u];
+        return (occupancy & (1u << (idx % 32u))) != 0u;
+    }
+    let occupancy = material_bricks[mip_offset + MIP_OCCUPANCY2] & 0xFFu;
+    return (occupancy & (1u << idx)) != 0u;
+}
+
+// Material of the first solid voxel below a coarse cell, mirrors `BrickMip::palette_index`
+fn get_mip_material(brick_offset: u32, level: u32, pos: vec3<u32>) -> MaterialHandle {
+    let span = 1u << level;
+    let cell_min = vec3<i32>(pos * span);
+    for (var i = 0u; i < span * span * span; i++) {
+        let offset = vec3<i32>(vec3<u32>(i % span, (i / span) % span, i / (span * span)));
+        let material_handle = get_brick_voxel(brick_offset, cell_min + offset);
+        if material_handle.raw != 0u {
+            return material_handle;
+        }
+    }
+    return MaterialHandle(0u);
+}
+
 fn step_mask(side_dist: vec3<f32>) -> vec3<f32> {
     var mask: vec3<bool>;
     let b1 = side_dist < side_dist.yzx;
@@ -242,12 +483,36 @@ fn trace_brick(brick_handle: BrickHandle, brick_pos: vec3<i32>, in_ray_pos: vec3
         if vox {
             let offset = get_brick_offset(brick_handle);
             let material_handle = get_brick_voxel(offset, pos);
-            let material = get_material(material_handle);
+            var hit = new_hit(material_handle, map_pos, mask);
+            hit.transparent = brick_is_transparent(brick_handle);
+            return hit;
+        }
+        mask = step_mask(side_dist);
+        map_pos += mask * ray_sign;
+        side_dist += mask * ray_sign * delta_dist;
+    }
+
+    return new_empty_hit();
+}
 
-            let pos = floor(map_pos) / 8.0;
-            // let color = vec4<f32>(floor(map_pos) / 8.0, 1.0);
-            let color = material.color;
-            return Hit(color, map_pos, true, mask);
+fn trace_brick_mip(brick_handle: BrickHandle, level: u32, in_ray_pos: vec3<f32>, ray_dir: vec3<f32>, world_mask: vec3<f32>) -> Hit {
+    let size = f32(BRICK_SIZE >> level);
+    let ray_pos = clamp(in_ray_pos, vec3<f32>(EPSILON), vec3<f32>(size - EPSILON));
+    var map_pos = floor(ray_pos);
+    let ray_sign = sign(ray_dir);
+    let delta_dist = 1.0 / ray_dir;
+    var side_dist = ((map_pos - ray_pos) + 0.5 + (ray_sign * 0.5)) * delta_dist;
+    var mask = world_mask;
+
+    let brick_offset = get_brick_offset(brick_handle);
+    let mip_offset = get_mip_offset(brick_offset);
+
+    while all(vec3<f32>(0.0) <= map_pos) && all(map_pos <= vec3<f32>(size - 1.0)) {
+        let pos = vec3<u32>(map_pos);
+        ray_steps = ray_steps + 1;
+        if get_mip_voxel(mip_offset, level, pos) {
+            let material_handle = get_mip_material(brick_offset, level, pos);
+            return new_hit(material_handle, map_pos, mask);
         }
         mask = step_mask(side_dist);
         map_pos += mask * ray_sign;
@@ -257,7 +522,7 @@ fn trace_brick(brick_handle: BrickHandle, brick_pos: vec3<i32>, in_ray_pos: vec3
     return new_empty_hit();
 }
 
-fn traverse_brickmap(ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> Hit {
+fn traverse_brickmap(ray_pos: vec3<f32>, ray_dir: vec3<f32>, max_t: f32) -> Hit {
     let world_min = vec3<f32>(0.0);
     let world_max = vec3<f32>(pc.dimensions);
     let bounds = intersect_box(ray_pos, ray_dir, world_min, world_max);
@@ -295,18 +560,27 @@ fn traverse_brickmap(ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> Hit {
                 local_block_coord = ray_pos - map_pos;
             }
 
-            var hit = trace_brick(brick_handle, vec3<i32>(floor(map_pos)), local_block_coord * 8.0, ray_dir, mask);
+            let level = select_mip_level(dist);
+            let level_size = f32(BRICK_SIZE >> level);
+            var hit: Hit;
+            if level == 0u {
+                hit = trace_brick(brick_handle, vec3<i32>(floor(map_pos)), local_block_coord * 8.0, ray_dir, mask);
+            } else {
+                hit = trace_brick_mip(brick_handle, level, local_block_coord * level_size, ray_dir, mask);
+            }
 
             if hit.hit {
-                let hit_local = hit.pos;
-                hit.pos = map_pos + (hit_local / 8.0);
+                // move from the hit cell to the point the ray enters it
+                let cell_min = map_pos + (hit.pos / level_size);
+                let cell = intersect_box(ray_pos, ray_dir, cell_min, cell_min + 1.0 / level_size);
+                hit.pos = ray_pos + ray_dir * max(cell.x, 0.0);
                 return hit;
             }
         } else if is_lod {
             let material_offset = brick_handle_get_empty_value(brick_handle);
             let material_handle = MaterialHandle(material_offset);
-            let material = get_material(material_handle);
-            return Hit(material.color, map_pos, true, mask);
+            let brick = intersect_box(ray_pos, ray_dir, map_pos, map_pos + 1.0);
+            return new_hit(material_handle, ray_pos + ray_dir * max(brick.x, 0.0), mask);
         } else {
             let sdf_value = brick_handle_get_empty_value(brick_handle);
 
@@ -329,14 +603,148 @@ fn traverse_brickmap(ray_pos: vec3<f32>, ray_dir: vec3<f32>) -> Hit {
         map_pos = map_pos + (mask * ray_sign);
         side_dist = ((map_pos - current_pos) + 0.5 + (ray_sign * 0.5)) * delta_dist;
 
-        if any(map_pos >= world_max) || any(map_pos < world_min) {
+        if any(map_pos >= world_max) || any(map_pos < world_min) || length(current_pos - ray_pos) > max_t {
+            break;
+        }
+    }
+
+    return new_empty_hit();
+}
+
+// one-hot vector of the largest component of `v`
+fn dominant_axis(v: vec3<f32>) -> vec3<f32> {
+    let a = abs(v);
+    if a.x >= a.y && a.x >= a.z {
+        return vec3<f32>(1.0, 0.0, 0.0);
+    }
+    if a.y >= a.z {
+        return vec3<f32>(0.0, 1.0, 0.0);
+    }
+    return vec3<f32>(0.0, 0.0, 1.0);
+}
+
+// Traces an object or instance in its voxel space. The transform is affine, so the ray
+// parameter stays the world distance as long as the direction isn't renormalized.
+fn trace_volume(object: VoxelObject, instanced: bool, ray_pos: vec3<f32>, ray_dir: vec3<f32>, max_t: f32) -> Hit {
+    let origin = (object.world_to_voxel * vec4<f32>(ray_pos, 1.0)).xyz;
+    var dir = (object.world_to_voxel * vec4<f32>(ray_dir, 0.0)).xyz;
+    dir = select(dir, dir + EPSILON, dir == vec3<f32>(0.0));
+
+    let size = vec3<f32>(object.size);
+    let t1 = (vec3<f32>(0.0) - origin) / dir;
+    let t2 = (size - origin) / dir;
+    let t_min = min(t1, t2);
+    let t_near = max(max(t_min.x, t_min.y), t_min.z);
+    let t_far = min(min(max(t1, t2).x, max(t1, t2).y), max(t1, t2).z);
+    if t_near > t_far || t_far < 0.0 || t_near > max_t {
+        return new_empty_hit();
+    }
+
+    var t = max(t_near, 0.0);
+    let start = clamp(origin + dir * t, vec3<f32>(EPSILON), size - EPSILON);
+    var map_pos = floor(start);
+    let ray_sign = sign(dir);
+    let delta_dist = abs(1.0 / dir);
+    var side_dist = t + ((map_pos + step(vec3<f32>(0.0), dir)) - start) / dir;
+    var mask = select(vec3<f32>(0.0), vec3<f32>(1.0), t_min == vec3<f32>(t_near));
+
+    for (var steps = 0u; steps < MAX_OBJECT_STEPS; steps++) {
+        if any(map_pos < vec3<f32>(0.0)) || any(map_pos >= size) || t > max_t {
             break;
         }
+        ray_steps = ray_steps + 1;
+
+        let voxel = vec3<u32>(map_pos);
+        let index = object.offset + voxel.x + voxel.y * object.size.x + voxel.z * object.size.x * object.size.y;
+        var material: u32;
+        if instanced {
+            material = model_voxels[index];
+        } else {
+            material = object_voxels[index];
+        }
+        if material != 0u {
+            // normals go back to world space with the transpose of world_to_voxel
+            let m = object.world_to_voxel;
+            let local_normal = -mask * ray_sign;
+            let normal = local_normal * mat3x3<f32>(m[0].xyz, m[1].xyz, m[2].xyz);
+            return new_hit(MaterialHandle(material), ray_pos + ray_dir * t, dominant_axis(normal));
+        }
+
+        mask = step_mask(side_dist);
+        t = dot(mask, side_dist);
+        map_pos += mask * ray_sign;
+        side_dist += mask * delta_dist;
     }
 
     return new_empty_hit();
 }
 
+// Closest object hit in front of `max_t`, the terrain distance.
+fn trace_objects(ray_pos: vec3<f32>, ray_dir: vec3<f32>, max_t: f32) -> Hit {
+    var best = new_empty_hit();
+    var best_t = max_t;
+    for (var i = 0u; i < voxel_objects.count; i++) {
+        let hit = trace_volume(voxel_objects.objects[i], false, ray_pos, ray_dir, best_t);
+        if hit.hit {
+            best = hit;
+            best_t = length(hit.pos - ray_pos);
+        }
+    }
+    return best;
+}
+
+// Closest instance hit in front of `max_t`, walking the instance BVH nearest child first.
+fn trace_instances(ray_pos: vec3<f32>, ray_dir: vec3<f32>, max_t: f32) -> Hit {
+    var best = new_empty_hit();
+    var best_t = max_t;
+    if instance_bvh.node_count == 0u {
+        return best;
+    }
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_size = 1u;
+    stack[0] = 0u;
+    while stack_size > 0u {
+        stack_size -= 1u;
+        let node = instance_bvh.nodes[stack[stack_size]];
+        let bounds = intersect_box(ray_pos, ray_dir, node.min, node.max);
+        if bounds.x > bounds.y || bounds.y < 0.0 || bounds.x > best_t {
+            continue;
+        }
+
+        if node.count > 0u {
+            for (var i = node.first; i < node.first + node.count; i++) {
+                let hit = trace_volume(instances[i], true, ray_pos, ray_dir, best_t);
+                if hit.hit {
+                    best = hit;
+                    best_t = length(hit.pos - ray_pos);
+                }
+            }
+        } else if stack_size + 2u <= BVH_STACK_SIZE {
+            // the nearer child is pushed last so it is visited first
+            let left = instance_bvh.nodes[node.first];
+            let right = instance_bvh.nodes[node.first + 1u];
+            let left_t = intersect_box(ray_pos, ray_dir, left.min, left.max).x;
+            let right_t = intersect_box(ray_pos, ray_dir, right.min, right.max).x;
+            let near_left = left_t <= right_t;
+            stack[stack_size] = select(node.first, node.first + 1u, near_left);
+            stack[stack_size + 1u] = select(node.first + 1u, node.first, near_left);
+            stack_size += 2u;
+        }
+    }
+    return best;
+}
+
+// Closest instance or object hit in front of `max_t`, both are always opaque.
+fn trace_blockers(ray_pos: vec3<f32>, ray_dir: vec3<f32>, max_t: f32) -> Hit {
+    var hit = trace_instances(ray_pos, ray_dir, max_t);
+    let hit_t = select(max_t, length(hit.pos - ray_pos), hit.hit);
+    let object_hit = trace_objects(ray_pos, ray_dir, hit_t);
+    if object_hit.hit {
+        hit = object_hit;
+    }
+    return hit;
+}
 
 @compute @workgroup_size(8, 8)
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
@@ -362,9 +770,114 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
 
     ray_dir = select(ray_dir, ray_dir + EPSILON, ray_dir == vec3<f32>(0.0));
 
-    let hit = traverse_brickmap(ray_pos, ray_dir);
+    // Continues through translucent and cut out voxels, mirrors `trace_transparent` and
+    // `composite` in the game crate. Instances and objects are always opaque.
+    let refraction = (pc.flags0 & FLAG_REFRACTION) != 0u;
+    var dir = ray_dir;
+    var radiance = vec3<f32>(0.0);
+    var transmittance = vec3<f32>(1.0);
+    var first = new_empty_hit();
+    var intensity = 0.0;
+    // translucent material the ray is inside of, 0 outside
+    var medium = 0u;
+    // where the next terrain trace starts
+    var start = ray_pos;
+    // the voxel a walk stopped in front of, taken as the next hit instead of tracing again
+    var next = new_empty_hit();
+    var has_next = false;
+    // closest instance or object along `dir` from `origin`, traced again when `dir` bends
+    var origin = ray_pos;
+    var blocker = new_empty_hit();
+    var blocker_t = 1e30;
+
+    for (var layer = 0u; layer < MAX_TRANSPARENT_STEPS; layer++) {
+        var hit = next;
+        if !has_next {
+            hit = traverse_brickmap(start, dir, 1e30);
+        }
+        has_next = false;
+        if layer == 0u {
+            // rays ending at an opaque brick only need the instances and objects in front of it
+            let reach = select(1e30, length(hit.pos - origin), hit.hit && !hit.transparent);
+            blocker = trace_blockers(origin, dir, reach);
+            blocker_t = select(1e30, length(blocker.pos - origin), blocker.hit);
+        }
+        // instances and objects aren't part of the voxel grid
+        let terrain = !blocker.hit || (hit.hit && length(hit.pos - origin) < blocker_t);
+        if !terrain {
+            hit = blocker;
+        }
+        if layer == 0u {
+            // count the primary ray only, shadow rays would drown it out
+            intensity = calculate_steps_intensity();
+            first = hit;
+        }
+        if !hit.hit {
+            break;
+        }
 
-    let color = hit.color;
+        let normal = -sign(dir) * hit.mask;
+        let material = get_material(MaterialHandle(hit.material));
+        var opacity = OPACITY_OPAQUE;
+        if terrain && hit.transparent {
+            opacity = material_opacity(material);
+        }
+        let voxel = vec3<i32>(floor(hit.pos * f32(BRICK_SIZE) - normal * 0.5));
+
+        if opacity == OPACITY_ALPHA_TESTED && is_cut(material, vec3<u32>(voxel)) {
+            medium = 0u;
+        } else if opacity == OPACITY_TRANSLUCENT {
+            let alpha = max(material.color.a, 0.0);
+            if medium != hit.material {
+                radiance += transmittance * alpha * shade(hit, dir, 1.0).rgb;
+                transmittance *= 1.0 - alpha;
+                if refraction {
+                    dir = refract_dir(dir, normal, 1.0 / REFRACTIVE_INDEX);
+                    origin = hit.pos;
+                    blocker = trace_blockers(origin, dir, 1e30);
+                    blocker_t = select(1e30, length(blocker.pos - origin), blocker.hit);
+                }
+            }
+            medium = hit.material;
+        } else {
+            var occlusion = 1.0;
+            if terrain && (pc.flags0 & FLAG_AMBIENT_OCCLUSION) != 0u {
+                occlusion = ambient_occlusion(hit.pos, normal);
+            }
+            radiance += transmittance * shade(hit, dir, occlusion).rgb;
+            transmittance = vec3<f32>(0.0);
+            break;
+        }
+
+        let walk = pass_voxels(voxel, hit.pos, dir, medium, blocker_t - length(hit.pos - origin));
+        if medium != 0u {
+            transmittance *= absorb(material, walk.distance);
+        }
+        if walk.end == WALK_VOXEL {
+            next = walk_hit(walk);
+            has_next = true;
+        } else if walk.end == WALK_BLOCKED {
+            // the instance or object comes next
+            next = new_empty_hit();
+            has_next = true;
+        } else {
+            if medium != 0u && refraction {
+                dir = refract_dir(dir, -sign(dir) * walk.mask, REFRACTIVE_INDEX);
+                origin = walk.pos;
+                blocker = trace_blockers(origin, dir, 1e30);
+                blocker_t = select(1e30, length(blocker.pos - origin), blocker.hit);
+            }
+            medium = 0u;
+            start = walk.pos + dir * EXIT_BIAS;
+        }
+    }
+
+    let intensity_color = vec4<f32>(intensity, intensity, intensity, 1.0);
+    var color = vec4<f32>(0.0);
+    if first.hit {
+        color = vec4<f32>(radiance, 1.0 - (transmittance.x + transmittance.y + transmittance.z) / 3.0);
+    }
+    let hit = first;
 
     let depth = calculate_depth(hit, ray_pos, ray_dir);
     let depth_color = vec4<f32>(depth, depth, depth, 1.0);
@@ -372,15 +885,348 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
     let normal = calculate_normal(hit.mask, ray_dir);
     let normal_color = vec4<f32>(normal, 1.0);
 
-    let intensity = calculate_steps_intensity();
-    let intensity_color = vec4<f32>(intensity, intensity, intensity, 1.0);
-
     textureStore(images[0], vec2<i32>(global_id.xy), color);
     textureStore(images[1], vec2<i32>(global_id.xy), normal_color);
     textureStore(images[2], vec2<i32>(global_id.xy), depth_color);
     textureStore(images[3], vec2<i32>(global_id.xy), intensity_color);
 }
 
+struct Walk {
+    // where the walk left the last voxel it passed
+    pos: vec3<f32>,
+    // axis of the side it left through
+    mask: vec3<f32>,
+    // the voxel behind `pos`
+    next: vec3<i32>,
+    distance: f32,
+    end: u32,
+}
+
+// Walks the voxel grid from `voxel`, entered at `pos`, through the voxels a ray passes without
+// showing a new surface: those of the translucent `medium` while inside one, cut out voxels
+// otherwise. Stops after MAX_PASS_VOXELS with WALK_VOXEL, the caller picks the walk up again
+// from there. Mirrors `pass_voxels` in the transparency module.
+fn pass_voxels(voxel: vec3<i32>, pos: vec3<f32>, dir: vec3<f32>, medium: u32, max_t: f32) -> Walk {
+    let size = 1.0 / f32(BRICK_SIZE);
+    let voxel_min = vec3<f32>(voxel) * size;
+    var side = (select(voxel_min, voxel_min + size, dir > vec3<f32>(0.0)) - pos) / dir;
+    let delta = abs(size / dir);
+    let ray_sign = vec3<i32>(sign(dir));
+
+    var walk = Walk(pos, vec3<f32>(0.0), voxel, 0.0, WALK_VOXEL);
+    for (var steps = 0u; steps < MAX_PASS_VOXELS; steps++) {
+        ray_steps = ray_steps + 1;
+        // x before y before z on ties, like the game crate
+        let mask = select(select(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0, 1.0, 0.0), side.y <= side.z), vec3<f32>(1.0, 0.0, 0.0), side.x <= side.y && side.x <= side.z);
+        let t = dot(mask, side);
+        if t >= max_t {
+            return Walk(pos + dir * max_t, mask, walk.next, max_t, WALK_BLOCKED);
+        }
+        walk.next += vec3<i32>(mask) * ray_sign;
+        side += mask * delta;
+        walk.pos = pos + dir * t;
+        walk.mask = mask;
+        walk.distance = t;
+        if !is_voxel_solid(walk.next) {
+            walk.end = WALK_AIR;
+            return walk;
+        }
+        if !voxel_passable(walk.next, medium) {
+            return walk;
+        }
+    }
+    return walk;
+}
+
+// Whether a walk through `medium` passes the solid `voxel`, mirrors `passes`.
+fn voxel_passable(voxel: vec3<i32>, medium: u32) -> bool {
+    let brick_handle = get_brick_handle(voxel / i32(BRICK_SIZE));
+    if !brick_handle_is_data(brick_handle) || !brick_is_transparent(brick_handle) {
+        return false;
+    }
+    let material_handle = get_brick_voxel(get_brick_offset(brick_handle), voxel % i32(BRICK_SIZE));
+    if medium != 0u {
+        return material_handle.raw == medium;
+    }
+    let material = get_material(material_handle);
+    return material_opacity(material) == OPACITY_ALPHA_TESTED && is_cut(material, vec3<u32>(voxel));
+}
+
+// The solid voxel a walk stopped in front of as a hit, mirrors `walk_hit`.
+fn walk_hit(walk: Walk) -> Hit {
+    let brick_handle = get_brick_handle(walk.next / i32(BRICK_SIZE));
+    if brick_handle_is_lod(brick_handle) {
+        let material_handle = MaterialHandle(brick_handle_get_empty_value(brick_handle));
+        return new_hit(material_handle, walk.pos, walk.mask);
+    }
+    let material_handle = get_brick_voxel(get_brick_offset(brick_handle), walk.next % i32(BRICK_SIZE));
+    var hit = new_hit(material_handle, walk.pos, walk.mask);
+    hit.transparent = brick_is_transparent(brick_handle);
+    return hit;
+}
+
+// What is left after `distance` world units through `material`, mirrors `absorb`.
+fn absorb(material: PbrMaterial, distance: f32) -> vec3<f32> {
+    let alpha = max(material.color.a, 0.0);
+    return exp(-alpha * ABSORPTION * (1.0 - material.color.rgb) * distance * f32(BRICK_SIZE));
+}
+
+// Light left of a ray from `origin` after `max_t`, 0 once terrain, instances or objects block
+// it. Passes cut out voxels and gets tinted by translucent ones like the primary rays, without
+// bending. Mirrors `transparency::shadow_transmittance`.
+fn trace_transmittance(in_origin: vec3<f32>, in_dir: vec3<f32>, max_t: f32) -> vec3<f32> {
+    let dir = select(in_dir, in_dir + EPSILON, in_dir == vec3<f32>(0.0));
+    if trace_blockers(in_origin, dir, max_t).hit {
+        return vec3<f32>(0.0);
+    }
+
+    var origin = in_origin;
+    var transmittance = vec3<f32>(1.0);
+    var medium = 0u;
+    var next = new_empty_hit();
+    var has_next = false;
+    for (var layer = 0u; layer < MAX_TRANSPARENT_STEPS; layer++) {
+        var hit = next;
+        if !has_next {
+            let rest = max_t - length(origin - in_origin);
+            hit = traverse_brickmap(origin, dir, rest);
+            if !hit.hit || length(hit.pos - origin) >= rest {
+                return transmittance;
+            }
+        }
+        has_next = false;
+
+        let normal = -sign(dir) * hit.mask;
+        let material = get_material(MaterialHandle(hit.material));
+        var opacity = OPACITY_OPAQUE;
+        if hit.transparent {
+            opacity = material_opacity(material);
+        }
+        let voxel = vec3<i32>(floor(hit.pos * f32(BRICK_SIZE) - normal * 0.5));
+
+        if opacity == OPACITY_ALPHA_TESTED && is_cut(material, vec3<u32>(voxel)) {
+            medium = 0u;
+        } else if opacity == OPACITY_TRANSLUCENT {
+            if medium != hit.material {
+                transmittance *= 1.0 - max(material.color.a, 0.0);
+            }
+            medium = hit.material;
+        } else {
+            return vec3<f32>(0.0);
+        }
+
+        let walk = pass_voxels(voxel, hit.pos, dir, medium, max_t - length(hit.pos - in_origin));
+        if medium != 0u {
+            transmittance *= absorb(material, walk.distance);
+        }
+        if walk.end == WALK_BLOCKED {
+            return transmittance;
+        }
+        if walk.end == WALK_VOXEL {
+            next = walk_hit(walk);
+            has_next = true;
+        } else {
+            medium = 0u;
+            origin = walk.pos + dir * EXIT_BIAS;
+        }
+    }
+    // too many surfaces to pass, as good as blocked
+    return vec3<f32>(0.0);
+}
+
+// Share of the sun reaching `pos` on a surface facing `normal` per color channel, mirrors
+// `Sun::sunlight`.
+fn trace_sunlight(pos: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    if pc.sun_intensity <= 0.0 || dot(pc.sun_direction, normal) <= 0.0 {
+        return vec3<f32>(0.0);
+    }
+    return trace_transmittance(pos + normal * SHADOW_BIAS, pc.sun_direction, 1e30);
+}
+
+// Light of the point lights binned into the grid cell of `pos` reflected towards `to_eye`, each
+// with a shadow ray that stops at the light. The falloff mirrors `PointLight::attenuation` and
+// the shadows `PointLight::transmittance`.
+fn point_light_radiance(pos: vec3<f32>, normal: vec3<f32>, to_eye: vec3<f32>, material: PbrMaterial) -> vec3<f32> {
+    var total = vec3<f32>(0.0);
+    if point_lights.count == 0u {
+        return total;
+    }
+
+    let cell = vec3<i32>(floor(pos / light_grid.cell_size));
+    if any(cell < vec3<i32>(0)) || any(cell >= vec3<i32>(light_grid.size)) {
+        return total;
+    }
+    let size = light_grid.size;
+    let index = u32(cell.x) + u32(cell.y) * size.x + u32(cell.z) * size.x * size.y;
+    let first = light_grid.data[index * 2u];
+    let count = min(light_grid.data[index * 2u + 1u], MAX_CELL_LIGHTS);
+    let indices = size.x * size.y * size.z * 2u;
+
+    let origin = pos + normal * SHADOW_BIAS;
+    for (var i = 0u; i < count; i++) {
+        let light = point_lights.lights[light_grid.data[indices + first + i]];
+        let to_light = light.position - pos;
+        let distance = length(to_light);
+        if distance <= 0.0 || distance >= light.radius {
+            continue;
+        }
+        let light_dir = to_light / distance;
+        if dot(normal, light_dir) <= 0.0 {
+            continue;
+        }
+
+        let ray = light.position - origin;
+        let ray_length = length(ray);
+        let shadow = trace_transmittance(origin, ray / ray_length, ray_length - LIGHT_CLEARANCE);
+        if all(shadow <= vec3<f32>(0.0)) {
+            continue;
+        }
+
+        let x = distance / light.radius;
+        let falloff = (1.0 - x * x) * (1.0 - x * x);
+        total += light.color * shadow * (light.intensity * falloff) * cook_torrance(material, normal, to_eye, light_dir);
+    }
+    return total;
+}
+
+// How rays treat a material, mirrors `Opacity::of`.
+fn material_opacity(material: PbrMaterial) -> u32 {
+    let cutoff = material.emissive.w;
+    if cutoff > 0.0 && cutoff < 1.0 {
+        return OPACITY_ALPHA_TESTED;
+    }
+    if material.color.a < 1.0 {
+        return OPACITY_TRANSLUCENT;
+    }
+    return OPACITY_OPAQUE;
+}
+
+// Stable noise in 0..1 per voxel, mirrors `transparency::coverage`.
+fn voxel_coverage(voxel: vec3<u32>) -> f32 {
+    var hash = (voxel.x * 0x8da6b343u) ^ (voxel.y * 0xd8163841u) ^ (voxel.z * 0xcb1ab31fu);
+    hash ^= hash >> 16u;
+    hash *= 0x7feb352du;
+    hash ^= hash >> 15u;
+    return f32(hash >> 8u) / 16777216.0;
+}
+
+fn is_cut(material: PbrMaterial, voxel: vec3<u32>) -> bool {
+    return material.color.a * voxel_coverage(voxel) < material.emissive.w;
+}
+
+// Bends `dir` through a surface facing `normal` against it, reflects on total internal
+// reflection. Mirrors `refract` in the transparency module.
+fn refract_dir(dir: vec3<f32>, normal: vec3<f32>, eta: f32) -> vec3<f32> {
+    var bent = refract(dir, normal, eta);
+    if all(bent == vec3<f32>(0.0)) {
+        bent = reflect(dir, normal);
+    }
+    bent = normalize(bent);
+    return select(bent, bent + EPSILON, bent == vec3<f32>(0.0));
+}
+
+// Occupancy of a voxel in voxels (8 per brick), empty outside of the map.
+fn is_voxel_solid(voxel: vec3<i32>) -> bool {
+    if any(voxel < vec3<i32>(0)) {
+        return false;
+    }
+    let brick_handle = get_brick_handle(voxel / i32(BRICK_SIZE));
+    if brick_handle_is_lod(brick_handle) {
+        return true;
+    }
+    if !brick_handle_is_data(brick_handle) {
+        return false;
+    }
+    return get_trace_voxel(brick_handle, voxel % i32(BRICK_SIZE));
+}
+
+// 3 for an open face corner, 0 when both sides are solid, mirrors `corner_ao`.
+fn corner_ao(side1: bool, side2: bool, corner: bool) -> f32 {
+    if side1 && side2 {
+        return 0.0;
+    }
+    return 3.0 - f32(side1) - f32(side2) - f32(corner);
+}
+
+// Classic voxel AO from the 3x3 voxels in front of the hit face, blended across the face.
+// Mirrors `ambient_occlusion` in the game crate.
+fn ambient_occlusion(pos: vec3<f32>, normal: vec3<f32>) -> f32 {
+    let voxel_pos = pos * f32(BRICK_SIZE);
+    let front = vec3<i32>(floor(voxel_pos - normal * 0.5)) + vec3<i32>(normal);
+    // the two axes after the normal axis, same order as `face_axes`
+    let u = normal.zxy * normal.zxy;
+    let v = normal.yzx * normal.yzx;
+    let iu = vec3<i32>(u);
+    let iv = vec3<i32>(v);
+
+    let s_u0 = is_voxel_solid(front - iu);
+    let s_u1 = is_voxel_solid(front + iu);
+    let s_v0 = is_voxel_solid(front - iv);
+    let s_v1 = is_voxel_solid(front + iv);
+    let c00 = corner_ao(s_u0, s_v0, is_voxel_solid(front - iu - iv));
+    let c10 = corner_ao(s_u1, s_v0, is_voxel_solid(front + iu - iv));
+    let c01 = corner_ao(s_u0, s_v1, is_voxel_solid(front - iu + iv));
+    let c11 = corner_ao(s_u1, s_v1, is_voxel_solid(front + iu + iv));
+
+    let fu = fract(dot(voxel_pos, u));
+    let fv = fract(dot(voxel_pos, v));
+    let ao = mix(mix(c00, c10, fu), mix(c01, c11, fu), fv) / 3.0;
+    return AO_FLOOR + (1.0 - AO_FLOOR) * ao;
+}
+
+// Sun, sky ambient, point lights and glow of the hit material in linear HDR color, tone mapped
+// in present.wgsl. Mirrors `Sun::shade`, `occlusion` only darkens the ambient part.
+fn shade(hit: Hit, ray_dir: vec3<f32>, occlusion: f32) -> vec4<f32> {
+    let material = get_material(MaterialHandle(hit.material));
+    let normal = -sign(ray_dir) * hit.mask;
+    let to_eye = -ray_dir;
+
+    var direct = vec3<f32>(0.0);
+    let sunlight = trace_sunlight(hit.pos, normal);
+    if any(sunlight > vec3<f32>(0.0)) {
+        direct = cook_torrance(material, normal, to_eye, pc.sun_direction) * sunlight * pc.sun_intensity;
+    }
+    let ambient = pc.ambient * occlusion * sky_ambient(normal);
+    let radiance = material.color.rgb * ambient
+        + direct
+        + point_light_radiance(hit.pos, normal, to_eye, material)
+        + material.emissive.rgb;
+    return vec4<f32>(radiance, material.color.a);
+}
+
+// Cook-Torrance BRDF (GGX, Smith, Schlick) times the cosine term, scaled by PI so a white
+// Lambert surface facing the light reflects its intensity. Mirrors `pbr::cook_torrance`.
+fn cook_torrance(material: PbrMaterial, normal: vec3<f32>, to_eye: vec3<f32>, to_light: vec3<f32>) -> vec3<f32> {
+    let n_dot_l = dot(normal, to_light);
+    if n_dot_l <= 0.0 {
+        return vec3<f32>(0.0);
+    }
+    let n_dot_v = max(dot(normal, to_eye), 1e-4);
+    let half_dir = normalize(to_eye + to_light);
+    let n_dot_h = max(dot(normal, half_dir), 0.0);
+    let roughness = max(material.roughness, 0.05);
+
+    let albedo = material.color.rgb;
+    let f0 = mix(vec3<f32>(DIELECTRIC_F0), albedo, material.metallic);
+    let fresnel = f0 + (1.0 - f0) * pow(1.0 - clamp(dot(half_dir, to_eye), 0.0, 1.0), 5.0);
+
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    let distribution = a2 / max(PI * d * d, 1e-7);
+    let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    let geometry = n_dot_v / (n_dot_v * (1.0 - k) + k) * (n_dot_l / (n_dot_l * (1.0 - k) + k));
+    let specular = distribution * geometry / (4.0 * n_dot_v * n_dot_l);
+
+    let diffuse = (1.0 - fresnel) * (1.0 - material.metallic) * albedo / PI;
+    return (diffuse + fresnel * specular) * n_dot_l * PI;
+}
+
+// Sky above a darker ground, mirrors `pbr::sky_ambient`.
+fn sky_ambient(normal: vec3<f32>) -> f32 {
+    return GROUND_AMBIENT + (1.0 - GROUND_AMBIENT) * (normal.y * 0.5 + 0.5);
+}
+
 fn sd_sphere(p: vec3<f32>, d: f32) -> f32 {
     return length(p) - d;
 }
@@ -0,0 +1,57 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use cgpu::GPUBrickMapStats;
+use game::{brick::BrickMapStats, material::MaterialStats, palette::PaletteStats};
+
+use crate::ClientState;
+
+/// Everything the debug window knows about the memory of the world.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct WorldStats {
+    pub brickmap: BrickMapStats,
+    pub palettes: PaletteStats,
+    pub materials: MaterialStats,
+    pub gpu: GPUBrickMapStats,
+}
+
+impl WorldStats {
+    /// CPU side bytes of the brickmap, palettes and materials.
+    pub fn cpu_bytes(&self) -> usize {
+        self.brickmap.handle_bytes
+            + self.brickmap.allocated_bytes
+            + self.palettes.bytes
+            + self.materials.bytes
+    }
+
+    /// Capacity of all GPU buffers backing the brickmap.
+    pub fn gpu_bytes(&self) -> u64 {
+        self.gpu.handle_bytes
+            + self.gpu.trace_bricks.capacity
+            + self.gpu.bricks.capacity
+            + self.gpu.materials.capacity
+            + self.gpu.palettes.capacity
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl ClientState {
+    pub fn collect_stats(&self) -> WorldStats {
+        WorldStats {
+            brickmap: self.brickmap.stats(),
+            palettes: self.palettes.stats(),
+            materials: self.materials.stats(),
+            gpu: self.gpu_brickmap.stats(),
+        }
+    }
+
+    pub fn export_stats(&self, path: impl AsRef<Path>) -> Result<()> {
+        let stats = self.collect_stats();
+        fs::write(path.as_ref(), stats.to_json()?)?;
+        log::info!("Stats: exported to {:?}", path.as_ref());
+        Ok(())
+    }
+}
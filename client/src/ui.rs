@@ -1,4 +1,5 @@
-use egui::{DragValue, RichText};
+use egui::{Button, DragValue, RichText, Slider};
+use game::controller::CharacterController;
 
 use crate::{render::RenderContext, ClientState};
 
@@ -7,6 +8,7 @@ impl ClientState {
         let mut pos = { self.camera.lock().position };
         let mut update_camera = false;
         let mut mode = render.ppc.mode;
+        let mut mip_distance = render.rtpc.mip_distance;
 
         let egui = &mut render.egui;
         egui.begin_frame(&render.window);
@@ -55,6 +57,12 @@ impl ClientState {
                         update_camera = true;
                     }
                 });
+                ui.horizontal(|ui| {
+                    let mut walking = self.is_walking();
+                    if ui.checkbox(&mut walking, "Walk (F)").changed() {
+                        self.toggle_walking();
+                    }
+                });
                 ui.horizontal(|ui| {
                     ui.label("Render: ");
                     egui::ComboBox::from_id_source("render_mode")
@@ -72,18 +80,198 @@ impl ClientState {
                             ui.selectable_value(&mut mode, 3, "Steps");
                         });
                 });
+                ui.horizontal(|ui| {
+                    ui.label("Mip distance: ");
+                    ui.add(
+                        DragValue::new(&mut mip_distance)
+                            .speed(0.5)
+                            .range(0.0..=512.0)
+                            .custom_formatter(|val, _| {
+                                if val <= 0.0 {
+                                    "off".to_owned()
+                                } else {
+                                    format!("{:.1}", val)
+                                }
+                            }),
+                    );
+                });
 
                 ui.separator();
                 ui.label(RichText::new("World").underline());
                 if ui.button("Regenerate Terrain").clicked() {
                     self.generate_terrain();
                 }
+                ui.horizontal(|ui| {
+                    ui.label("Time: ");
+                    ui.add(Slider::new(&mut *self.time_of_day.lock(), 0.0..=24.0).suffix(" h"));
+                    ui.checkbox(&mut self.day_cycle.lock(), "Cycle");
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.ambient_occlusion.lock(), "Ambient Occlusion");
+                    ui.checkbox(&mut self.refraction.lock(), "Refraction");
+                    if ui.button("Pour Water").clicked() {
+                        self.pour_water();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    let history = self.history.lock();
+                    let undo = history.undo_name().map(|name| format!("Undo {name}"));
+                    let redo = history.redo_name().map(|name| format!("Redo {name}"));
+                    drop(history);
+                    let undo_button = Button::new(undo.as_deref().unwrap_or("Undo"));
+                    if ui.add_enabled(undo.is_some(), undo_button).clicked() {
+                        self.undo();
+                    }
+                    let redo_button = Button::new(redo.as_deref().unwrap_or("Redo"));
+                    if ui.add_enabled(redo.is_some(), redo_button).clicked() {
+                        self.redo();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "Fragmentation: {:.1}%",
+                        self.brickmap.fragmentation() * 100.0
+                    ));
+                    if ui.button("Compact Bricks").clicked() {
+                        self.gpu_brickmap.compact();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label(format!("Objects: {}", self.objects.lock().len()));
+                    if ui.button("Spawn Object").clicked() {
+                        self.spawn_object();
+                    }
+                    if ui.button("Clear").clicked() {
+                        self.clear_objects();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label(format!("Instances: {}", self.instances.lock().len()));
+                    if ui.button("Scatter Trees").clicked() {
+                        self.scatter_trees(64);
+                    }
+                    if ui.button("Clear").clicked() {
+                        self.clear_instances();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label(format!("Lights: {}", self.point_lights.lock().len()));
+                    if ui.button("Place Light").clicked() {
+                        self.place_light();
+                    }
+                    if ui.button("From Emissive").clicked() {
+                        self.extract_lights();
+                    }
+                    if ui.button("Clear").clicked() {
+                        self.clear_lights();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Compute Light").clicked() {
+                        self.compute_light();
+                    }
+                    if let Some(light) = self.light.lock().as_ref() {
+                        let level = light.sample(self.camera.lock().position);
+                        let dark = if level.is_dark() { " (dark)" } else { "" };
+                        ui.label(format!("Sky {}, block {}{}", level.sky, level.block, dark));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Validate BrickMap").clicked() {
+                        self.validate_brickmap();
+                    }
+                    match self.violations.lock().as_deref() {
+                        Some([]) => {
+                            ui.label("OK");
+                        }
+                        Some(violations) => {
+                            ui.label(format!("{} violations", violations.len()));
+                        }
+                        None => {}
+                    }
+                });
+                if let Some(violations) = self.violations.lock().as_deref() {
+                    for violation in violations.iter().take(8) {
+                        ui.label(RichText::new(format!("{:?}", violation)).small());
+                    }
+                }
+
+                ui.separator();
+                ui.label(RichText::new("Stats").underline());
+                ui.horizontal(|ui| {
+                    if ui.button("Refresh Stats").clicked() {
+                        *self.stats.lock() = Some(self.collect_stats());
+                    }
+                    if ui.button("Export JSON").clicked() {
+                        if let Err(err) = self.export_stats("stats.json") {
+                            log::error!("Stats: export failed: {:?}", err);
+                        }
+                    }
+                });
+                if let Some(stats) = self.stats.lock().as_ref() {
+                    let brickmap = &stats.brickmap;
+                    let handles = &brickmap.handles;
+                    let bricks = &brickmap.material_bricks;
+                    ui.label(format!(
+                        "Handles: {} data, {} lod, {} sdf, {} empty",
+                        handles.data, handles.lod, handles.sdf_empty, handles.empty
+                    ));
+                    ui.label(format!(
+                        "Material bricks: {} 1b, {} 2b, {} 4b, {} 8b",
+                        bricks.bits1, bricks.bits2, bricks.bits4, bricks.bits8
+                    ));
+                    ui.label(format!(
+                        "Slots: {} ({} free, {:.1}%), {} shared",
+                        brickmap.slots,
+                        brickmap.free_slots,
+                        brickmap.fragmentation * 100.0,
+                        brickmap.dedup.shared_slots
+                    ));
+                    ui.label(format!(
+                        "Palettes: {} live, {} free, {} ({:.1}% fragmented)",
+                        stats.palettes.live_palettes,
+                        stats.palettes.free_palettes,
+                        format_bytes(stats.palettes.bytes as u64),
+                        stats.palettes.fragmentation * 100.0
+                    ));
+                    for (name, buffer) in [
+                        ("Trace bricks", &stats.gpu.trace_bricks),
+                        ("Bricks", &stats.gpu.bricks),
+                        ("Materials", &stats.gpu.materials),
+                        ("Palettes", &stats.gpu.palettes),
+                    ] {
+                        ui.label(format!(
+                            "GPU {}: {} / {}, {} free blocks",
+                            name,
+                            format_bytes(buffer.used),
+                            format_bytes(buffer.capacity),
+                            buffer.free_blocks
+                        ));
+                    }
+                    ui.label(format!(
+                        "Total: {} CPU, {} GPU",
+                        format_bytes(stats.cpu_bytes() as u64),
+                        format_bytes(stats.gpu_bytes())
+                    ));
+                }
             });
 
         render.ppc.mode = mode;
+        render.rtpc.mip_distance = mip_distance;
         if update_camera {
             let mut camera = self.camera.lock();
             camera.position = pos;
+            if let Some(controller) = self.controller.lock().as_mut() {
+                *controller = CharacterController::from_eye(pos, controller.config);
+            }
         }
     }
 }
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1048576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0),
+    }
+}
@@ -197,6 +197,46 @@ impl GPUFreeList {
         self.try_shrink(resize);
     }
 
+    /// Moves the `(src, dst, size)` ranges into a fresh buffer and drops everything else, the
+    /// first `used` bytes count as allocated afterwards. Offsets handed out before are invalid.
+    pub fn repack<F: FnOnce(Buffer, &Buffer, u64)>(
+        &self,
+        copies: &[(u64, u64, u64)],
+        used: u64,
+        resize: F,
+    ) {
+        let mut new_capacity = self.capacity.load(Ordering::SeqCst).max(used + 1);
+        while used < new_capacity / 4 && new_capacity / 2 >= 1024 * 1024 {
+            new_capacity /= 2;
+        }
+
+        let new_buffer = Self::create_buffer(
+            &self.device,
+            new_capacity,
+            self.sharing,
+            self.label.as_deref(),
+        );
+
+        let mut recorder = self.queue.record();
+
+        let old = self.buffer.replace(new_buffer);
+        let new = unsafe { self.buffer.as_ptr().as_ref().unwrap() };
+        if !copies.is_empty() {
+            let src_offsets = copies.iter().map(|c| c.0 as usize).collect::<Vec<_>>();
+            let dst_offsets = copies.iter().map(|c| c.1 as usize).collect::<Vec<_>>();
+            let sizes = copies.iter().map(|c| c.2 as usize).collect::<Vec<_>>();
+            recorder.copy_buffer_many(&old, new, &src_offsets, &dst_offsets, &sizes);
+        }
+
+        let submission = self.queue.submit_express(&[recorder.finish()]).unwrap();
+
+        self.capacity.store(new_capacity, Ordering::SeqCst);
+        self.offset.store(used, Ordering::SeqCst);
+        self.free.write().clear();
+
+        resize(old, new, submission);
+    }
+
     pub fn write<T: bytemuck::Pod>(&self, offset: u64, data: &T) -> (Buffer, u64) {
         let data_slice = std::slice::from_ref(data);
         let slice = bytemuck::cast_slice(data_slice);
@@ -252,6 +292,26 @@ impl GPUFreeList {
         self.free.write().clear();
     }
 
+    /// Size of the current buffer in bytes.
+    pub fn capacity(&self) -> u64 {
+        self.buffer().size
+    }
+
+    /// Bytes handed out so far, freed blocks included.
+    pub fn used(&self) -> u64 {
+        self.offset.load(Ordering::Acquire)
+    }
+
+    /// Number of freed blocks and the bytes they cover.
+    pub fn free_blocks(&self) -> (usize, u64) {
+        let free_blocks = self.free.read();
+        free_blocks
+            .iter()
+            .fold((0, 0), |(count, bytes), (&size, blocks)| {
+                (count + blocks.len(), bytes + size * blocks.len() as u64)
+            })
+    }
+
     pub fn buffer(&self) -> &Buffer {
         unsafe { self.buffer.as_ptr().as_ref().unwrap() }
     }
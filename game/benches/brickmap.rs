@@ -0,0 +1,77 @@
+extern crate nalgebra as na;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use game::{
+    brick::ExpandedBrick,
+    material::{ExpandedMaterialMapping, MaterialRegistry},
+    palette::PaletteRegistry,
+    worldgen::WorldGenerator,
+    BrickMap,
+};
+use rayon::{prelude::*, ThreadPoolBuilder};
+
+const SIZE: na::Vector3<u32> = na::Vector3::new(32, 16, 32);
+
+fn mapping() -> ExpandedMaterialMapping {
+    let registry = MaterialRegistry::new();
+    registry.register_default_materials();
+    let mut mapping = ExpandedMaterialMapping::new();
+    for (voxel, name) in ["air", "bedrock", "stone", "dirt", "grass", "snow"]
+        .into_iter()
+        .enumerate()
+    {
+        mapping.add_from_registry(&registry, name, voxel as u8);
+    }
+    mapping
+}
+
+/// Generates the terrain once so the benchmark only measures storing it.
+fn generate(mapping: &ExpandedMaterialMapping) -> Vec<(na::Point3<u32>, ExpandedBrick)> {
+    let world_gen = WorldGenerator::new(Some(420), 16);
+    let positions = (0..SIZE.x)
+        .flat_map(|x| (0..SIZE.y).flat_map(move |y| (0..SIZE.z).map(move |z| (x, y, z))))
+        .collect::<Vec<_>>();
+
+    positions
+        .into_par_iter()
+        .filter_map(|(x, y, z)| {
+            let brick = world_gen.generate_chunk(mapping, x, y, z);
+            (!brick.is_empty()).then(|| (na::Point3::new(x, y, z), brick))
+        })
+        .collect()
+}
+
+fn bench_parallel_store(c: &mut Criterion) {
+    let mapping = mapping();
+    let bricks = generate(&mapping);
+    let mut group = c.benchmark_group("brickmap");
+    group.sample_size(10);
+
+    for threads in [1, 4, 16] {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("parallel_store", threads),
+            &bricks,
+            |b, bricks| {
+                b.iter(|| {
+                    let brickmap = BrickMap::new(SIZE);
+                    let palettes = PaletteRegistry::new();
+                    pool.install(|| {
+                        bricks.par_iter().for_each(|(at, brick)| {
+                            brickmap.set_expanded_brick(*at, brick, &mapping, &palettes);
+                        });
+                    });
+                    brickmap
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_parallel_store);
+criterion_main!(benches);
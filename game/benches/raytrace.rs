@@ -0,0 +1,99 @@
+extern crate nalgebra as na;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use game::{
+    brick::TraceBrick,
+    layout::HandleLayout,
+    raytrace::{trace_many, trace_ray, Ray, RayMode},
+    BrickMap,
+};
+
+fn terrain(size: u32, layout: HandleLayout) -> BrickMap {
+    let brickmap = BrickMap::with_layout(na::Vector3::new(size, size / 2, size), layout);
+    let full = {
+        let mut brick = TraceBrick::empty();
+        brick.data_mut().fill(0xFF);
+        brick
+    };
+
+    for x in 0..size {
+        for z in 0..size {
+            let height = 4 + ((x as f32 * 0.3).sin() * 3.0 + (z as f32 * 0.2).cos() * 3.0) as u32;
+            for y in 0..height {
+                brickmap.set_brick(full, na::Point3::new(x, y, z));
+            }
+        }
+    }
+
+    brickmap
+}
+
+fn rays(count: usize, size: u32, mode: RayMode) -> Vec<Ray> {
+    let center = size as f32 * 0.5;
+    (0..count)
+        .map(|i| {
+            let angle = i as f32 / count as f32 * std::f32::consts::TAU;
+            let origin = na::Point3::new(center, size as f32 * 0.4, center);
+            let dir = na::Vector3::new(angle.cos(), -0.15, angle.sin());
+            Ray::new(origin, dir)
+                .with_mode(mode)
+                .with_max_distance(size as f32)
+        })
+        .collect()
+}
+
+fn bench_trace(c: &mut Criterion) {
+    let size = 64;
+    let brickmap = terrain(size, HandleLayout::Linear);
+    let mut group = c.benchmark_group("trace");
+
+    for count in [1024, 8192] {
+        for (name, mode) in [("closest", RayMode::Closest), ("any", RayMode::Any)] {
+            let rays = rays(count, size, mode);
+            group.bench_with_input(
+                BenchmarkId::new(format!("many_{name}"), count),
+                &rays,
+                |b, rays| b.iter(|| trace_many(&brickmap, rays)),
+            );
+        }
+
+        let rays = rays(count, size, RayMode::Closest);
+        group.bench_with_input(BenchmarkId::new("sequential", count), &rays, |b, rays| {
+            b.iter(|| {
+                rays.iter()
+                    .map(|ray| trace_ray(&brickmap, ray))
+                    .collect::<Vec<_>>()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_layout(c: &mut Criterion) {
+    let size = 128;
+    let rays = rays(8192, size, RayMode::Closest);
+    let mut group = c.benchmark_group("layout");
+
+    for (name, layout) in [
+        ("linear", HandleLayout::Linear),
+        ("morton", HandleLayout::Morton),
+    ] {
+        let brickmap = terrain(size, layout);
+        group.bench_with_input(BenchmarkId::new("many", name), &rays, |b, rays| {
+            b.iter(|| trace_many(&brickmap, rays))
+        });
+        group.bench_with_input(BenchmarkId::new("sequential", name), &rays, |b, rays| {
+            b.iter(|| {
+                rays.iter()
+                    .map(|ray| trace_ray(&brickmap, ray))
+                    .collect::<Vec<_>>()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_trace, bench_layout);
+criterion_main!(benches);
@@ -0,0 +1,124 @@
+use crate::{
+    brick::{BrickMapView, BRICK_SIZE},
+    raytrace::RayHit,
+};
+
+/// Ambient light left in a fully occluded corner, keeps creases from turning black.
+pub const AO_FLOOR: f32 = 0.35;
+
+/// Occlusion of a face corner from its two side neighbors and the diagonal one, 3 is open and
+/// 0 fully occluded. Two solid sides close the corner even if the diagonal is open.
+pub fn corner_ao(side1: bool, side2: bool, corner: bool) -> u8 {
+    if side1 && side2 {
+        return 0;
+    }
+    3 - side1 as u8 - side2 as u8 - corner as u8
+}
+
+/// Corner occlusion of the face of `voxel` facing `normal`, in voxels (8 per brick). Looks at
+/// the 3×3 voxels in front of the face, corners are ordered `(-u, -v), (+u, -v), (-u, +v),
+/// (+u, +v)` with `u` and `v` the two axes after the normal axis.
+pub fn face_ao(view: &BrickMapView, voxel: na::Point3<i32>, normal: na::Vector3<i32>) -> [u8; 4] {
+    let (u, v) = face_axes(&normal);
+    let front = voxel + normal;
+    let solid = |offset: na::Vector3<i32>| {
+        let at = front + offset;
+        at.iter().all(|&c| c >= 0) && view.is_voxel_solid(at.map(|c| c as u32))
+    };
+
+    [(-1, -1), (1, -1), (-1, 1), (1, 1)].map(|(su, sv)| {
+        let side_u = u * su;
+        let side_v = v * sv;
+        corner_ao(solid(side_u), solid(side_v), solid(side_u + side_v))
+    })
+}
+
+/// Ambient occlusion at a terrain hit, the corners of its face blended across the face and
+/// mapped to `AO_FLOOR..=1`. Mirrors `ambient_occlusion` in `raytrace.wgsl`.
+pub fn ambient_occlusion(view: &BrickMapView, hit: &RayHit) -> f32 {
+    let normal = hit.normal.map(|c| c.round() as i32);
+    let position = hit.position.coords * BRICK_SIZE as f32;
+    // step back into the voxel that was hit, the position lies on its face
+    let voxel = (position - hit.normal * 0.5).map(|c| c.floor() as i32);
+    let [c00, c10, c01, c11] = face_ao(view, voxel.into(), normal).map(|c| c as f32 / 3.0);
+
+    let (u, v) = face_axes(&normal);
+    let fu = position.dot(&u.cast()).rem_euclid(1.0);
+    let fv = position.dot(&v.cast()).rem_euclid(1.0);
+    let ao = (c00 * (1.0 - fu) + c10 * fu) * (1.0 - fv) + (c01 * (1.0 - fu) + c11 * fu) * fv;
+    AO_FLOOR + (1.0 - AO_FLOOR) * ao
+}
+
+/// The two unit axes spanning a face with an axis aligned `normal`.
+fn face_axes(normal: &na::Vector3<i32>) -> (na::Vector3<i32>, na::Vector3<i32>) {
+    let axis = normal.iamax();
+    let u = (axis + 1) % 3;
+    let v = (axis + 2) % 3;
+    let unit = |i| na::Vector3::from_fn(|j, _| (j == i) as i32);
+    (unit(u), unit(v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        brush::{Brush, BrushOp, Shape},
+        material::MaterialId,
+        palette::PaletteRegistry,
+        raytrace::{trace_ray, Ray},
+        BrickMap,
+    };
+
+    const STONE: MaterialId = MaterialId(1);
+
+    fn floor_with_wall() -> BrickMap {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let palettes = PaletteRegistry::new();
+        let floor = Shape::Box {
+            center: na::Point3::new(2.0, 0.5, 2.0),
+            half_extents: na::Vector3::new(2.0, 0.5, 2.0),
+        };
+        let wall = Shape::Box {
+            center: na::Point3::new(3.5, 2.0, 2.0),
+            half_extents: na::Vector3::new(0.5, 1.0, 2.0),
+        };
+        brickmap.apply_brush(&Brush::new(floor, BrushOp::Union, STONE), &palettes);
+        brickmap.apply_brush(&Brush::new(wall, BrushOp::Union, STONE), &palettes);
+        brickmap
+    }
+
+    #[test]
+    fn test_corner_ao() {
+        assert_eq!(corner_ao(false, false, false), 3);
+        assert_eq!(corner_ao(false, false, true), 2);
+        assert_eq!(corner_ao(true, false, true), 1);
+        assert_eq!(corner_ao(true, true, false), 0);
+
+        let brickmap = floor_with_wall();
+        let view = brickmap.read();
+        let up = na::Vector3::y();
+        // open floor, and the floor voxel right in front of the wall at x = 24 voxels
+        assert_eq!(face_ao(&view, na::Point3::new(8, 7, 16), up), [3; 4]);
+        assert_eq!(face_ao(&view, na::Point3::new(23, 7, 16), up), [3, 3, 1, 1]);
+        // the top of the map has nothing above it
+        assert_eq!(face_ao(&view, na::Point3::new(24, 23, 16), up), [3; 4]);
+    }
+
+    #[test]
+    fn test_ambient_occlusion_darkens_creases() {
+        let brickmap = floor_with_wall();
+        let view = brickmap.read();
+        let down = -na::Vector3::y();
+
+        let open = trace_ray(&brickmap, &Ray::new(na::Point3::new(1.0, 3.0, 2.0), down)).unwrap();
+        assert_eq!(ambient_occlusion(&view, &open), 1.0);
+
+        let crease =
+            trace_ray(&brickmap, &Ray::new(na::Point3::new(2.99, 3.0, 2.0), down)).unwrap();
+        let near = trace_ray(&brickmap, &Ray::new(na::Point3::new(2.9, 3.0, 2.0), down)).unwrap();
+        let crease_ao = ambient_occlusion(&view, &crease);
+        let near_ao = ambient_occlusion(&view, &near);
+        assert!(crease_ao < near_ao && near_ao < 1.0);
+        assert!(crease_ao >= AO_FLOOR);
+    }
+}
@@ -1,7 +1,38 @@
-use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    mem,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        OnceLock,
+    },
+};
+
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
 use rand::Rng;
 
-use crate::material::{ExpandedMaterialMapping, MaterialId};
+use crate::{
+    layout::{HandleIndexer, HandleLayout},
+    material::{ExpandedMaterialMapping, MaterialId, MaterialRegistry},
+    mip::BrickMip,
+    palette::{PaletteId, PaletteRegistry},
+};
+
+/// Voxels along each edge of a brick, one world unit.
+pub const BRICK_SIZE: u32 = 8;
+/// Voxels in a brick.
+pub const BRICK_VOLUME: usize = 512;
+/// Materials a brick palette holds at most besides air, voxel values are a byte.
+pub const MAX_BRICK_MATERIALS: usize = 255;
+/// Offsets to the six face neighbours of a voxel or brick.
+pub(crate) const NEIGHBOURS: [[i32; 3]; 6] = [
+    [-1, 0, 0],
+    [1, 0, 0],
+    [0, -1, 0],
+    [0, 1, 0],
+    [0, 0, -1],
+    [0, 0, 1],
+];
 
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -93,65 +124,436 @@ impl From<u32> for BrickHandle {
     }
 }
 
+/// How a brick write was stored, tells GPU mirrors what they have to upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrickAlloc {
+    /// A new slot was appended to the brick storage.
+    New,
+    /// A free slot or the previous slot of the position was overwritten.
+    Reused,
+    /// An identical brick already existed and its slot is now shared, nothing to upload.
+    Shared,
+}
+
+/// Bricks of a slot a write dropped the last reference to, read while the slot could not be
+/// reused yet so GPU mirrors can free their copy without racing other writers.
+#[derive(Debug, Clone, Copy)]
+pub struct ReleasedBrick {
+    pub trace: TraceBrick,
+    pub material: Option<MaterialBrick>,
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct DedupStats {
+    /// Live brick slots.
+    pub slots: usize,
+    /// Data handles pointing at those slots.
+    pub references: usize,
+    /// Slots referenced by more than one handle.
+    pub shared_slots: usize,
+    /// Trace and material brick bytes the shared slots would take without deduplication.
+    pub saved_bytes: usize,
+}
+
+/// Handle counts by kind, see [`BrickMap::stats`].
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct HandleStats {
+    pub data: usize,
+    pub lod: usize,
+    /// Empty handles carrying a distance. The SDF pass writes these on the GPU copy only.
+    pub sdf_empty: usize,
+    /// Empty handles without any value.
+    pub empty: usize,
+}
+
+/// Live material bricks by bits per voxel.
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct MaterialBrickHistogram {
+    pub bits1: usize,
+    pub bits2: usize,
+    pub bits4: usize,
+    pub bits8: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct BrickMapStats {
+    pub dimensions: [u32; 3],
+    pub handles: HandleStats,
+    /// Slots handed out, free ones included.
+    pub slots: usize,
+    pub free_slots: usize,
+    pub fragmentation: f32,
+    pub material_bricks: MaterialBrickHistogram,
+    pub dedup: DedupStats,
+    pub handle_bytes: usize,
+    /// Trace, packed material and mip bytes of the live slots.
+    pub trace_brick_bytes: usize,
+    pub material_brick_bytes: usize,
+    pub mip_bytes: usize,
+    /// Memory held by the allocated storage chunks, used or not.
+    pub allocated_bytes: usize,
+}
+
+/// An inconsistency found by [`BrickMap::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A data handle points past the brick storage or at a free slot.
+    DanglingHandle { at: na::Point3<u32>, slot: u32 },
+    /// The refcount of a slot disagrees with the number of handles pointing at it.
+    RefcountMismatch {
+        slot: u32,
+        refcount: u32,
+        handles: u32,
+    },
+    /// A slot is on the freelist while it is still referenced.
+    FreeSlotInUse { slot: u32 },
+    /// A slot is on the freelist more than once.
+    DuplicateFreeSlot { slot: u32 },
+    /// The trace bit of a voxel disagrees with its material index, only the first voxel is reported.
+    OccupancyMismatch {
+        slot: u32,
+        voxel: na::Point3<u32>,
+        traced: bool,
+    },
+    /// The format bits of the meta disagree with the packed element size.
+    FormatMismatch {
+        slot: u32,
+        meta_bits: u32,
+        bits: u32,
+    },
+    /// The palette id in the meta isn't registered or was deallocated.
+    UnknownPalette { slot: u32, palette: PaletteId },
+    /// A palette doesn't fit the format, or a voxel indexes past the end of its palette.
+    PaletteOverflow {
+        slot: u32,
+        palette: PaletteId,
+        size: u32,
+        index: u32,
+    },
+    /// A LOD handle refers to a material that isn't registered.
+    UnknownLodMaterial {
+        at: na::Point3<u32>,
+        material: MaterialId,
+    },
+}
+
+/// A contiguous run of bytes moved by [`BrickMap::compact`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelocationRange {
+    pub from: u64,
+    pub to: u64,
+    pub size: u64,
+}
+
+/// Result of [`BrickMap::compact`], describes where every live brick went.
+#[derive(Debug, Clone, Default)]
+pub struct BrickRelocation {
+    /// New slot for every old slot, `None` for slots that were free.
+    pub slots: Vec<Option<u32>>,
+    /// Runs to copy from the old material buffer into the packed one, in bytes.
+    pub material_copies: Vec<RelocationRange>,
+    /// Live slots after compaction.
+    pub slot_count: u32,
+    /// Bytes the packed material bricks take.
+    pub material_bytes: u64,
+}
+
+impl BrickRelocation {
+    /// The handle `handle` was rewritten to, non data handles are returned unchanged.
+    pub fn relocate(&self, handle: BrickHandle) -> BrickHandle {
+        if !handle.is_data() {
+            return handle;
+        }
+        self.slots
+            .get(handle.get_data_value() as usize)
+            .copied()
+            .flatten()
+            .map_or(handle, BrickHandle::new_data)
+    }
+
+    /// True if no slot moved.
+    pub fn is_identity(&self) -> bool {
+        self.slots
+            .iter()
+            .enumerate()
+            .all(|(old, new)| new.is_none_or(|new| new as usize == old))
+            && self.slots.len() == self.slot_count as usize
+    }
+
+    fn push_material_copy(&mut self, from: u64, to: u64, size: u64) {
+        if let Some(last) = self.material_copies.last_mut() {
+            if last.from + last.size == from && last.to + last.size == to {
+                last.size += size;
+                return;
+            }
+        }
+        self.material_copies
+            .push(RelocationRange { from, to, size });
+    }
+}
+
+/// Slot bookkeeping, refcounts and content hashes are indexed like `bricks`.
+#[derive(Default)]
+struct BrickSlots {
+    freelist: Vec<u32>,
+    refcounts: Vec<u32>,
+    hashes: Vec<Option<u64>>,
+    /// Content hash to the slots holding a brick with that hash.
+    table: HashMap<u64, Vec<u32>>,
+}
+
+impl BrickSlots {
+    fn refcount(&self, slot: u32) -> u32 {
+        self.refcounts.get(slot as usize).copied().unwrap_or(0)
+    }
+
+    /// Marks a slot as used by a single position, not yet findable by content.
+    fn claim(&mut self, slot: u32) {
+        let index = slot as usize;
+        if index >= self.refcounts.len() {
+            self.refcounts.resize(index + 1, 0);
+            self.hashes.resize(index + 1, None);
+        }
+        self.refcounts[index] = 1;
+        self.hashes[index] = None;
+    }
+
+    /// Makes a claimed slot findable by content once its brick is written.
+    fn track(&mut self, slot: u32, hash: u64) {
+        if self.refcount(slot) == 0 {
+            return;
+        }
+        self.hashes[slot as usize] = Some(hash);
+        self.table.entry(hash).or_default().push(slot);
+    }
+
+    fn untrack(&mut self, slot: u32) {
+        let Some(hash) = self.hashes.get_mut(slot as usize).and_then(Option::take) else {
+            return;
+        };
+        if let Some(slots) = self.table.get_mut(&hash) {
+            slots.retain(|&s| s != slot);
+            if slots.is_empty() {
+                self.table.remove(&hash);
+            }
+        }
+    }
+
+    /// Drops one reference, returns true when the slot became free.
+    fn release(&mut self, handle: BrickHandle) -> bool {
+        if !handle.is_data() {
+            return false;
+        }
+        let slot = handle.get_data_value();
+        match self.refcount(slot) {
+            0 => false,
+            1 => {
+                self.refcounts[slot as usize] = 0;
+                self.untrack(slot);
+                self.freelist.push(slot);
+                true
+            }
+            _ => {
+                self.refcounts[slot as usize] -= 1;
+                false
+            }
+        }
+    }
+}
+
+fn content_hash(trace: &TraceBrick, material: &MaterialBrick) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    trace.raw.hash(&mut hasher);
+    material.meta().hash(&mut hasher);
+    material.data().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Slots per chunk of brick storage.
+const CHUNK_SIZE: usize = 1024;
+
+/// A fixed block of brick slots behind its own lock, writers to different chunks never contend.
+struct BrickChunk {
+    bricks: Vec<TraceBrick>,
+    materials: Vec<Option<MaterialBrick>>,
+    mips: Vec<Option<BrickMip>>,
+}
+
+impl BrickChunk {
+    fn new() -> Self {
+        Self {
+            bricks: vec![TraceBrick::EMPTY; CHUNK_SIZE],
+            materials: vec![None; CHUNK_SIZE],
+            mips: vec![None; CHUNK_SIZE],
+        }
+    }
+
+    fn get(&self, slot: u32) -> (TraceBrick, Option<MaterialBrick>, Option<BrickMip>) {
+        let index = slot as usize % CHUNK_SIZE;
+        (self.bricks[index], self.materials[index], self.mips[index])
+    }
+
+    fn set(&mut self, slot: u32, trace: TraceBrick, material: Option<(MaterialBrick, BrickMip)>) {
+        let index = slot as usize % CHUNK_SIZE;
+        self.bricks[index] = trace;
+        self.materials[index] = material.map(|(material, _)| material);
+        self.mips[index] = material.map(|(_, mip)| mip);
+    }
+}
+
+/// Slot storage split into chunks that are created on first use and never move, so slots can be
+/// appended while views borrow the chunks that already exist.
+struct BrickStorage {
+    chunks: Box<[OnceLock<RwLock<BrickChunk>>]>,
+    /// Slots handed out so far, free ones included. Only grows under the slot lock.
+    len: AtomicU32,
+}
+
+impl BrickStorage {
+    /// Live slots never exceed the number of positions, so `capacity` bounds the chunk table.
+    fn new(capacity: usize) -> Self {
+        let chunks = (0..capacity.div_ceil(CHUNK_SIZE))
+            .map(|_| OnceLock::new())
+            .collect();
+        Self {
+            chunks,
+            len: AtomicU32::new(0),
+        }
+    }
+
+    fn len(&self) -> u32 {
+        self.len.load(Ordering::Acquire)
+    }
+
+    fn push(&self) -> u32 {
+        self.len.fetch_add(1, Ordering::AcqRel)
+    }
+
+    fn chunk(&self, slot: u32) -> Option<&RwLock<BrickChunk>> {
+        if slot >= self.len() {
+            return None;
+        }
+        self.chunks.get(slot as usize / CHUNK_SIZE)?.get()
+    }
+
+    fn chunk_or_init(&self, slot: u32) -> &RwLock<BrickChunk> {
+        self.chunks[slot as usize / CHUNK_SIZE].get_or_init(|| RwLock::new(BrickChunk::new()))
+    }
+
+    fn with<R>(&self, slot: u32, f: impl FnOnce(&BrickChunk, usize) -> R) -> Option<R> {
+        let chunk = self.chunk(slot)?.read();
+        Some(f(&chunk, slot as usize % CHUNK_SIZE))
+    }
+
+    fn get(&self, slot: u32) -> Option<(TraceBrick, Option<MaterialBrick>, Option<BrickMip>)> {
+        self.chunk(slot).map(|chunk| chunk.read().get(slot))
+    }
+
+    fn set(&self, slot: u32, trace: TraceBrick, material: Option<(MaterialBrick, BrickMip)>) {
+        self.chunk_or_init(slot).write().set(slot, trace, material);
+    }
+}
+
+/// Sparse brick grid. Handles are atomics and bricks live in chunked storage, so different
+/// positions can be written from many threads at once. Writing the same position from two
+/// threads at the same time is not supported.
 pub struct BrickMap {
     size: na::Vector3<u32>,
-    handles: RwLock<Vec<BrickHandle>>,
-    bricks: RwLock<Vec<TraceBrick>>,
-    freelist: Mutex<Vec<u32>>,
+    indexer: HandleIndexer,
+    handles: Box<[AtomicU32]>,
+    storage: BrickStorage,
+    slots: Mutex<BrickSlots>,
 }
 
 impl BrickMap {
     pub fn new(size: na::Vector3<u32>) -> Self {
-        let volume = size.x * size.y * size.z;
-        let handles = RwLock::new(vec![BrickHandle::EMPTY; volume as usize]);
-        let bricks = RwLock::new(vec![]);
-        let freelist = Mutex::new(Vec::new());
+        Self::with_layout(size, HandleLayout::Linear)
+    }
+
+    pub fn with_layout(size: na::Vector3<u32>, layout: HandleLayout) -> Self {
+        let volume = (size.x * size.y * size.z) as usize;
+        let indexer = HandleIndexer::new(size, layout);
+        let handles = (0..indexer.len())
+            .map(|_| AtomicU32::new(BrickHandle::EMPTY.0))
+            .collect();
+        let storage = BrickStorage::new(volume);
+        let slots = Mutex::new(BrickSlots::default());
 
         Self {
             size,
+            indexer,
             handles,
-            bricks,
-            freelist,
+            storage,
+            slots,
         }
     }
 
+    pub fn layout(&self) -> HandleLayout {
+        self.indexer.layout()
+    }
+
+    /// Number of handles including the padding of the Morton layout, the size of the GPU copy.
+    pub fn handle_count(&self) -> usize {
+        self.handles.len()
+    }
+
     pub fn index(&self, at: na::Point3<u32>) -> usize {
-        let id = at.x + (at.y * self.size.x) + (at.z * self.size.x * self.size.y);
-        id as usize
+        self.indexer.index(at)
     }
 
     pub fn get_handle(&self, at: na::Point3<u32>) -> BrickHandle {
         let id = self.index(at);
-        let handles = self.handles.read();
-        handles[id]
+        BrickHandle(self.handles[id].load(Ordering::Acquire))
     }
 
     pub fn set_handle(&self, handle: BrickHandle, at: na::Point3<u32>) {
         let id = self.index(at);
-        let mut handles = self.handles.write();
-        handles[id] = handle;
+        self.handles[id].store(handle.0, Ordering::Release);
     }
 
-    pub fn set_empty(&self, at: na::Point3<u32>) -> BrickHandle {
-        let id = self.index(at);
-        let mut handles = self.handles.write();
-        let handle = handles[id];
-        self.deallocate_brick(handle);
-        let new_handle = BrickHandle::empty();
-        handles[id] = new_handle;
-        new_handle
+    /// Drops one reference to the slot behind `handle`, returns its bricks when it was the last.
+    fn release_slot(&self, slots: &mut BrickSlots, handle: BrickHandle) -> Option<ReleasedBrick> {
+        let released = self.last_reference(slots, handle);
+        slots.release(handle);
+        released
+    }
+
+    /// Bricks of the slot behind `handle` when only one position references it.
+    fn last_reference(&self, slots: &BrickSlots, handle: BrickHandle) -> Option<ReleasedBrick> {
+        if !handle.is_data() || slots.refcount(handle.get_data_value()) != 1 {
+            return None;
+        }
+        let (trace, material, _) = self.storage.get(handle.get_data_value())?;
+        Some(ReleasedBrick { trace, material })
     }
 
-    pub fn set_lod(&self, at: na::Point3<u32>, lod: MaterialId) -> BrickHandle {
+    /// Swaps the handle at `at` for a non data handle and drops the reference of the old one.
+    fn replace_with_empty(
+        &self,
+        at: na::Point3<u32>,
+        new_handle: BrickHandle,
+    ) -> (BrickHandle, Option<ReleasedBrick>) {
         let id = self.index(at);
-        let mut handles = self.handles.write();
-        let handle = handles[id];
-        self.deallocate_brick(handle);
+        let mut slots = self.slots.lock();
+        let handle = BrickHandle(self.handles[id].swap(new_handle.0, Ordering::AcqRel));
+        (new_handle, self.release_slot(&mut slots, handle))
+    }
+
+    /// Empties `at`, also returns the bricks of the old slot if this dropped its last reference.
+    pub fn set_empty(&self, at: na::Point3<u32>) -> (BrickHandle, Option<ReleasedBrick>) {
+        self.replace_with_empty(at, BrickHandle::empty())
+    }
+
+    /// Fills `at` uniformly with `lod`, see [`Self::set_empty`] for the returned bricks.
+    pub fn set_lod(
+        &self,
+        at: na::Point3<u32>,
+        lod: MaterialId,
+    ) -> (BrickHandle, Option<ReleasedBrick>) {
         let mut new_handle = BrickHandle::empty();
         new_handle.set_lod(true);
         new_handle.set_empty_value(lod.0);
-        handles[id] = new_handle;
-        new_handle
+        self.replace_with_empty(at, new_handle)
     }
 
     pub fn is_empty(&self, at: na::Point3<u32>) -> bool {
@@ -159,107 +561,536 @@ impl BrickMap {
         handle.is_empty()
     }
 
-    pub fn bricks(&self) -> &[TraceBrick] {
-        let ptr = self.bricks.data_ptr();
+    pub fn get_brick(&self, handle: BrickHandle) -> Option<TraceBrick> {
+        if !handle.is_data() {
+            return None;
+        }
+        self.storage
+            .with(handle.get_data_value(), |chunk, index| chunk.bricks[index])
+    }
+
+    /// Modifies the slot behind `handle`, which every position sharing it sees.
+    /// Meant for GPU bookkeeping like the material offset, edits go through [`Self::modify_brick_at`].
+    pub fn modify_brick<F>(&self, handle: BrickHandle, modifier: F) -> Option<()>
+    where
+        F: FnOnce(&mut TraceBrick),
+    {
+        if !handle.is_data() {
+            return None;
+        }
+
+        let slot = handle.get_data_value();
+        let mut chunk = self.storage.chunk(slot)?.write();
+        modifier(&mut chunk.bricks[slot as usize % CHUNK_SIZE]);
 
-        let bricks = unsafe { ptr.as_ref().unwrap() };
+        Some(())
+    }
 
-        bricks
+    pub fn dimensions(&self) -> na::Vector3<u32> {
+        self.size
     }
 
-    pub fn as_ptr(&self) -> *mut BrickHandle {
-        self.handles.data_ptr() as *mut _
+    /// Stores an occupancy only brick, returns true when a new slot was appended.
+    pub fn set_brick(&self, brick: TraceBrick, at: na::Point3<u32>) -> (BrickHandle, bool) {
+        let (handle, alloc, _) = self.store(at, brick, None);
+        (handle, alloc == BrickAlloc::New)
     }
 
-    pub fn handles(&self) -> &[BrickHandle] {
-        let ptr = self.handles.data_ptr();
+    /// Writes a brick to `at`. Bricks with materials are deduplicated by content, a slot shared
+    /// with other positions is never written to, the position gets its own copy instead.
+    ///
+    /// The slot lock is only held to pick a slot, the brick itself is written under the lock of
+    /// its chunk and made visible to deduplication afterwards. The bricks the old slot held are
+    /// returned when this was its last reference, whether it was freed or is rewritten in place.
+    fn store(
+        &self,
+        at: na::Point3<u32>,
+        trace: TraceBrick,
+        material: Option<(MaterialBrick, BrickMip)>,
+    ) -> (BrickHandle, BrickAlloc, Option<ReleasedBrick>) {
+        let hash = material.map(|(material, _)| content_hash(&trace, &material));
+        let id = self.index(at);
 
-        let handles = unsafe { ptr.as_ref().unwrap() };
+        let (slot, alloc, released) = {
+            let mut slots = self.slots.lock();
+            let old = BrickHandle(self.handles[id].load(Ordering::Acquire));
+
+            if let (Some(hash), Some((material, _))) = (hash, material) {
+                let existing = slots.table.get(&hash).and_then(|candidates| {
+                    candidates.iter().copied().find(|&slot| {
+                        self.storage
+                            .with(slot, |chunk, index| {
+                                chunk.bricks[index].raw == trace.raw
+                                    && chunk.materials[index].is_some_and(|other| {
+                                        other.meta() == material.meta()
+                                            && other.data() == material.data()
+                                    })
+                            })
+                            .unwrap_or(false)
+                    })
+                });
+
+                if let Some(slot) = existing {
+                    if old.is_data() && old.get_data_value() == slot {
+                        return (old, BrickAlloc::Shared, None);
+                    }
+                    slots.refcounts[slot as usize] += 1;
+                    let handle = BrickHandle::new_data(slot);
+                    self.handles[id].store(handle.0, Ordering::Release);
+                    let released = self.release_slot(&mut slots, old);
+                    return (handle, BrickAlloc::Shared, released);
+                }
+            }
 
-        handles
-    }
+            let released = self.last_reference(&slots, old);
+            let (slot, alloc) = if released.is_some() {
+                let slot = old.get_data_value();
+                slots.untrack(slot);
+                (slot, BrickAlloc::Reused)
+            } else {
+                slots.release(old);
+                match slots.freelist.pop() {
+                    Some(slot) => (slot, BrickAlloc::Reused),
+                    None => (self.storage.push(), BrickAlloc::New),
+                }
+            };
+            slots.claim(slot);
+            (slot, alloc, released)
+        };
 
-    pub fn get_brick(&self, handle: BrickHandle) -> Option<TraceBrick> {
-        if !handle.is_data() {
-            return None;
+        self.storage.set(slot, trace, material);
+        if let Some(hash) = hash {
+            self.slots.lock().track(slot, hash);
         }
-        let bricks = self.bricks.read();
 
-        let offset = (handle.0 & BrickHandle::DATA_MASK) as usize;
+        let handle = BrickHandle::new_data(slot);
+        self.handles[id].store(handle.0, Ordering::Release);
+        (handle, alloc, released)
+    }
+
+    /// Modifies the brick at `at`, copying it first when its slot is shared with other positions.
+    /// The brick is no longer deduplicated against until it is written again.
+    pub fn modify_brick_at<F>(&self, at: na::Point3<u32>, modifier: F) -> Option<BrickAlloc>
+    where
+        F: FnOnce(&mut TraceBrick, &mut Option<MaterialBrick>),
+    {
+        let id = self.index(at);
+        let mut slots = self.slots.lock();
 
-        if offset >= bricks.len() {
+        let old = BrickHandle(self.handles[id].load(Ordering::Acquire));
+        if !old.is_data() {
             return None;
         }
+        let old_slot = old.get_data_value();
+        let (mut trace, mut material, _) = self.storage.get(old_slot)?;
+
+        let (slot, alloc) = if slots.refcount(old_slot) > 1 {
+            slots.release(old);
+            match slots.freelist.pop() {
+                Some(slot) => (slot, BrickAlloc::Reused),
+                None => (self.storage.push(), BrickAlloc::New),
+            }
+        } else {
+            slots.untrack(old_slot);
+            (old_slot, BrickAlloc::Reused)
+        };
+        slots.claim(slot);
 
-        Some(bricks[offset])
+        modifier(&mut trace, &mut material);
+        let mip = material.map(|material| BrickMip::from_material_brick(&material));
+        self.storage.set(slot, trace, material.zip(mip));
+        self.handles[id].store(BrickHandle::new_data(slot).0, Ordering::Release);
+        Some(alloc)
     }
 
-    pub fn modify_brick<F>(&self, handle: BrickHandle, modifier: F) -> Option<()>
-    where
-        F: FnOnce(&mut TraceBrick),
-    {
+    /// Number of positions sharing the slot behind `handle`, 0 for free slots and non data handles.
+    pub fn ref_count(&self, handle: BrickHandle) -> u32 {
         if !handle.is_data() {
-            return None;
+            return 0;
         }
+        self.slots.lock().refcount(handle.get_data_value())
+    }
+
+    /// Counts handles and bricks and sums up their memory. Walks the whole map, so it is meant
+    /// for debugging rather than per frame use.
+    pub fn stats(&self) -> BrickMapStats {
+        let dedup = self.dedup_stats();
+        let mut stats = BrickMapStats {
+            dimensions: self.size.into(),
+            dedup,
+            handle_bytes: self.handles.len() * mem::size_of::<BrickHandle>(),
+            ..Default::default()
+        };
 
-        let offset = (handle.0 & BrickHandle::DATA_MASK) as usize;
+        let slots = self.slots.lock();
+        let view = self.read();
+        stats.slots = view.len as usize;
+        stats.free_slots = slots.freelist.len();
+        stats.fragmentation = if view.len == 0 {
+            0.0
+        } else {
+            stats.free_slots as f32 / view.len as f32
+        };
 
-        let mut bricks = self.bricks.write();
+        for handle in view.snapshot_handles() {
+            if handle.is_data() {
+                stats.handles.data += 1;
+            } else if handle.is_lod() {
+                stats.handles.lod += 1;
+            } else if handle.get_empty_value() != 0 {
+                stats.handles.sdf_empty += 1;
+            } else {
+                stats.handles.empty += 1;
+            }
+        }
+        // padding of the Morton layout is never addressed
+        stats.handles.empty -= self.handles.len() - self.volume() as usize;
 
-        if offset >= bricks.len() {
-            return None;
+        for slot in 0..view.len {
+            if slots.refcount(slot) == 0 {
+                continue;
+            }
+            let handle = BrickHandle::new_data(slot);
+            stats.trace_brick_bytes += mem::size_of::<TraceBrick>();
+            if let Some(material) = view.get_material_brick(handle) {
+                let histogram = &mut stats.material_bricks;
+                match material {
+                    MaterialBrick::Size1(_) => histogram.bits1 += 1,
+                    MaterialBrick::Size2(_) => histogram.bits2 += 1,
+                    MaterialBrick::Size4(_) => histogram.bits4 += 1,
+                    MaterialBrick::Size8(_) => histogram.bits8 += 1,
+                }
+                stats.material_brick_bytes += mem::size_of::<u32>() + material.data().len();
+            }
+            if view.get_mip(handle).is_some() {
+                stats.mip_bytes += mem::size_of::<BrickMip>();
+            }
         }
 
-        modifier(&mut bricks[offset]);
+        let chunk_bytes = CHUNK_SIZE
+            * (mem::size_of::<TraceBrick>()
+                + mem::size_of::<Option<MaterialBrick>>()
+                + mem::size_of::<Option<BrickMip>>());
+        let chunks = self
+            .storage
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.get().is_some());
+        stats.allocated_bytes = chunks.count() * chunk_bytes;
 
-        Some(())
+        stats
     }
 
-    pub fn dimensions(&self) -> na::Vector3<u32> {
-        self.size
+    pub fn dedup_stats(&self) -> DedupStats {
+        let slots = self.slots.lock();
+        let mut stats = DedupStats::default();
+
+        for (slot, &count) in slots.refcounts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            stats.slots += 1;
+            stats.references += count as usize;
+            if count > 1 {
+                let material_bytes = self
+                    .storage
+                    .with(slot as u32, |chunk, index| chunk.materials[index])
+                    .flatten()
+                    .map_or(0, |material| mem::size_of::<u32>() + material.data().len());
+                let bytes = mem::size_of::<TraceBrick>() + material_bytes;
+                stats.shared_slots += 1;
+                stats.saved_bytes += bytes * (count as usize - 1);
+            }
+        }
+
+        stats
     }
 
-    pub fn set_brick(&self, brick: TraceBrick, at: na::Point3<u32>) -> (BrickHandle, bool) {
-        let mut bricks = self.bricks.write();
-        let old_handle = self.get_handle(at);
-        if let Some(_old_brick) = self.get_brick(old_handle) {
-            let offset = old_handle.get_data_value();
-            bricks[offset as usize] = brick;
-            return (old_handle, false);
+    /// Checks handles, slot bookkeeping and every live brick against its palette. Meant for tests
+    /// and debugging, results are only reliable while nothing writes to the map.
+    pub fn validate(&self, palettes: &PaletteRegistry) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let slots = self.slots.lock();
+        let view = self.read();
+        let live_palettes = palettes.live_palettes();
+
+        let mut references = vec![0u32; view.len as usize];
+        for (id, handle) in view.snapshot_handles().into_iter().enumerate() {
+            if !handle.is_data() {
+                continue;
+            }
+            let slot = handle.get_data_value();
+            match references.get_mut(slot as usize) {
+                Some(count) if slots.refcount(slot) > 0 => *count += 1,
+                _ => violations.push(Violation::DanglingHandle {
+                    at: self.position(id),
+                    slot,
+                }),
+            }
         }
 
-        let mut freelist = self.freelist.lock();
-        if let Some(offset) = freelist.pop() {
-            bricks[offset as usize] = brick;
-            let handle = BrickHandle::new_data(offset);
-            self.set_handle(handle, at);
-            return (handle, false);
+        for (slot, &handles) in references.iter().enumerate() {
+            let refcount = slots.refcount(slot as u32);
+            if refcount > 0 && refcount != handles {
+                violations.push(Violation::RefcountMismatch {
+                    slot: slot as u32,
+                    refcount,
+                    handles,
+                });
+            }
+        }
+
+        let mut free = vec![false; view.len as usize];
+        for &slot in &slots.freelist {
+            if slots.refcount(slot) > 0 {
+                violations.push(Violation::FreeSlotInUse { slot });
+            }
+            match free.get_mut(slot as usize) {
+                Some(seen) if *seen => violations.push(Violation::DuplicateFreeSlot { slot }),
+                Some(seen) => *seen = true,
+                None => {}
+            }
+        }
+
+        for slot in 0..view.len {
+            if slots.refcount(slot) == 0 {
+                continue;
+            }
+            let handle = BrickHandle::new_data(slot);
+            let (Some(trace), Some(material)) =
+                (view.get_brick(handle), view.get_material_brick(handle))
+            else {
+                continue;
+            };
+
+            let meta_bits = material.size_from_meta() as u32;
+            let bits = material.element_size() as u32;
+            if meta_bits != bits {
+                violations.push(Violation::FormatMismatch {
+                    slot,
+                    meta_bits,
+                    bits,
+                });
+            }
+
+            let palette = PaletteId(material.meta_value());
+            let size = live_palettes.get(&palette).copied();
+            if size.is_none() {
+                violations.push(Violation::UnknownPalette { slot, palette });
+            }
+            if let Some(size) = size.filter(|&size| size > 1 << bits) {
+                violations.push(Violation::PaletteOverflow {
+                    slot,
+                    palette,
+                    size,
+                    index: size - 1,
+                });
+            }
+
+            let mut occupancy_reported = false;
+            let mut max_index = 0;
+            for i in 0..512 {
+                let voxel = na::Point3::new(i % 8, (i / 8) % 8, i / 64);
+                let index = material.get(voxel.x, voxel.y, voxel.z) as u32;
+                max_index = max_index.max(index);
+                let traced = trace.get(voxel.x, voxel.y, voxel.z);
+                if traced != (index != 0) && !occupancy_reported {
+                    violations.push(Violation::OccupancyMismatch {
+                        slot,
+                        voxel,
+                        traced,
+                    });
+                    occupancy_reported = true;
+                }
+            }
+            if let Some(size) = size.filter(|&size| max_index >= size) {
+                violations.push(Violation::PaletteOverflow {
+                    slot,
+                    palette,
+                    size,
+                    index: max_index,
+                });
+            }
         }
 
-        let offset = bricks.len() as u32;
-        bricks.push(brick);
-        let handle = BrickHandle::new_data(offset as u32);
-        self.set_handle(handle, at);
-        (handle, true)
+        violations
     }
 
-    pub fn deallocate_brick(&self, handle: BrickHandle) -> bool {
+    /// Checks that every LOD handle refers to a registered material.
+    pub fn validate_materials(&self, materials: &MaterialRegistry) -> Vec<Violation> {
+        let count = materials.materials().len() as u32;
+        self.read()
+            .snapshot_handles()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, handle)| handle.is_lod() && handle.get_empty_value() >= count)
+            .map(|(id, handle)| Violation::UnknownLodMaterial {
+                at: self.position(id),
+                material: MaterialId(handle.get_empty_value()),
+            })
+            .collect()
+    }
+
+    /// Inverse of [`Self::index`].
+    pub fn position(&self, index: usize) -> na::Point3<u32> {
+        self.indexer.position(index)
+    }
+
+    /// Moves all live bricks to the front of the brick storage and packs their material bricks
+    /// back to back, closing the holes left by freed slots.
+    ///
+    /// `material_size` is the number of bytes a material brick takes in the GPU buffer, the
+    /// `TraceBrick::brick` offsets are rewritten accordingly. Handles are rewritten in place, the
+    /// returned table tells GPU mirrors which runs to copy. Must not run concurrently with writes.
+    pub fn compact<F>(&self, material_size: F) -> BrickRelocation
+    where
+        F: Fn(&MaterialBrick) -> u64,
+    {
+        let mut slots = self.slots.lock();
+        let len = self.storage.len() as usize;
+        let mut chunks = self
+            .storage
+            .chunks
+            .iter()
+            .take(len.div_ceil(CHUNK_SIZE))
+            .map(|chunk| chunk.get().expect("allocated slots are written").write())
+            .collect::<Vec<_>>();
+
+        let mut relocation = BrickRelocation {
+            slots: vec![None; len],
+            ..Default::default()
+        };
+        let mut refcounts = Vec::new();
+        let mut hashes = Vec::new();
+
+        for old in 0..len {
+            let refcount = slots.refcount(old as u32);
+            if refcount == 0 {
+                continue;
+            }
+            let new = relocation.slot_count as usize;
+            relocation.slots[old] = Some(new as u32);
+            relocation.slot_count += 1;
+
+            let (mut trace, material, mip) = chunks[old / CHUNK_SIZE].get(old as u32);
+            if let Some(material) = material {
+                let size = material_size(&material);
+                let from = trace.get_brick_offset() as u64 * mem::size_of::<u32>() as u64;
+                let to = relocation.material_bytes;
+                relocation.push_material_copy(from, to, size);
+                relocation.material_bytes += size;
+                trace.set_brick_offset((to / mem::size_of::<u32>() as u64) as u32);
+            }
+
+            chunks[new / CHUNK_SIZE].set(new as u32, trace, material.zip(mip));
+            refcounts.push(refcount);
+            hashes.push(slots.hashes.get(old).copied().flatten());
+        }
+
+        self.storage
+            .len
+            .store(relocation.slot_count, Ordering::Release);
+
+        for handle in self.handles.iter() {
+            let relocated = relocation.relocate(BrickHandle(handle.load(Ordering::Acquire)));
+            handle.store(relocated.0, Ordering::Release);
+        }
+
+        slots.freelist.clear();
+        slots.refcounts = refcounts;
+        slots.hashes = hashes;
+        for candidates in slots.table.values_mut() {
+            for slot in candidates.iter_mut() {
+                *slot = relocation.slots[*slot as usize].expect("hashed slot must be live");
+            }
+        }
+
+        relocation
+    }
+
+    /// Fraction of the brick storage that is free slots.
+    pub fn fragmentation(&self) -> f32 {
+        let slots = self.slots.lock();
+        let total = self.storage.len();
+        if total == 0 {
+            return 0.0;
+        }
+        slots.freelist.len() as f32 / total as f32
+    }
+
+    pub fn get_material_brick(&self, handle: BrickHandle) -> Option<MaterialBrick> {
         if !handle.is_data() {
-            return false;
+            return None;
         }
+        self.storage
+            .with(handle.get_data_value(), |chunk, index| {
+                chunk.materials[index]
+            })
+            .flatten()
+    }
 
-        let offset = handle.get_data_value() as usize;
+    pub fn get_mip(&self, handle: BrickHandle) -> Option<BrickMip> {
+        if !handle.is_data() {
+            return None;
+        }
+        self.storage
+            .with(handle.get_data_value(), |chunk, index| chunk.mips[index])
+            .flatten()
+    }
 
-        let mut freelist = self.freelist.lock();
-        freelist.push(offset as u32);
+    /// Compresses `expanded`, registers its palette and stores trace and material brick at `at`.
+    /// Identical bricks share one slot, see [`BrickAlloc`]. Also returns the bricks of the old
+    /// slot if this dropped its last reference.
+    pub fn set_expanded_brick(
+        &self,
+        at: na::Point3<u32>,
+        expanded: &ExpandedBrick,
+        material_mapping: &ExpandedMaterialMapping,
+        palettes: &PaletteRegistry,
+    ) -> (BrickHandle, BrickAlloc, Option<ReleasedBrick>) {
+        let (mut material_brick, materials) = expanded.compress(material_mapping);
+        let palette_id = palettes.register_palette(materials);
+        material_brick.set_meta_value(palette_id.0);
+
+        let mip = BrickMip::from_material_brick(&material_brick);
+        self.store(at, expanded.to_trace_brick(), Some((material_brick, mip)))
+    }
 
-        true
+    /// Drops one reference to the slot behind `handle`, returns true when it became free.
+    /// The handle itself is left untouched, use [`Self::set_empty`] for positions.
+    pub fn deallocate_brick(&self, handle: BrickHandle) -> bool {
+        self.slots.lock().release(handle)
     }
 
     pub fn volume(&self) -> u32 {
         self.size.x * self.size.y * self.size.z
     }
 
+    pub fn get_voxel(&self, palettes: &PaletteRegistry, voxel: na::Point3<u32>) -> MaterialId {
+        self.read().get_voxel(palettes, voxel)
+    }
+
+    /// Read locks every brick chunk once, for callers that do many lookups in a row. Bricks can't
+    /// change while the view is alive, handles are atomics and may still be swapped.
+    pub fn read(&self) -> BrickMapView<'_> {
+        let len = self.storage.len();
+        let chunks = self
+            .storage
+            .chunks
+            .iter()
+            .take((len as usize).div_ceil(CHUNK_SIZE))
+            .map(|chunk| chunk.get().map(RwLock::read))
+            .collect();
+
+        BrickMapView {
+            size: self.size,
+            indexer: &self.indexer,
+            handles: &self.handles,
+            chunks,
+            len,
+        }
+    }
+
     // pub fn edit_brick_no_resize(
     //     &self,
     //     handle: BrickHandle,
@@ -309,13 +1140,224 @@ impl BrickMap {
     // }
 }
 
+pub struct BrickMapView<'a> {
+    size: na::Vector3<u32>,
+    indexer: &'a HandleIndexer,
+    handles: &'a [AtomicU32],
+    chunks: Vec<Option<RwLockReadGuard<'a, BrickChunk>>>,
+    len: u32,
+}
+
+impl BrickMapView<'_> {
+    pub fn dimensions(&self) -> na::Vector3<u32> {
+        self.size
+    }
+
+    pub fn index(&self, at: na::Point3<u32>) -> usize {
+        self.indexer.index(at)
+    }
+
+    pub fn get_handle(&self, at: na::Point3<u32>) -> BrickHandle {
+        BrickHandle(self.handles[self.index(at)].load(Ordering::Acquire))
+    }
+
+    fn slot(&self, handle: BrickHandle) -> Option<(&BrickChunk, usize)> {
+        if !handle.is_data() {
+            return None;
+        }
+        let slot = handle.get_data_value();
+        if slot >= self.len {
+            return None;
+        }
+        let chunk = self.chunks.get(slot as usize / CHUNK_SIZE)?.as_deref()?;
+        Some((chunk, slot as usize % CHUNK_SIZE))
+    }
+
+    pub fn get_brick(&self, handle: BrickHandle) -> Option<&TraceBrick> {
+        self.slot(handle).map(|(chunk, index)| &chunk.bricks[index])
+    }
+
+    pub fn get_material_brick(&self, handle: BrickHandle) -> Option<&MaterialBrick> {
+        self.slot(handle)
+            .and_then(|(chunk, index)| chunk.materials[index].as_ref())
+    }
+
+    pub fn get_mip(&self, handle: BrickHandle) -> Option<&BrickMip> {
+        self.slot(handle)
+            .and_then(|(chunk, index)| chunk.mips[index].as_ref())
+    }
+
+    /// Resolves the material of a voxel inside the brick behind `handle`, LOD handles are uniform.
+    pub fn voxel_material(
+        &self,
+        palettes: &PaletteRegistry,
+        handle: BrickHandle,
+        local: na::Point3<u32>,
+    ) -> MaterialId {
+        if handle.is_lod() {
+            return MaterialId(handle.get_empty_value());
+        }
+
+        let Some(brick) = self.get_material_brick(handle) else {
+            return MaterialId::EMPTY;
+        };
+        let index = brick.get(local.x, local.y, local.z) as usize;
+        let palette = brick.meta_value() as usize;
+        palettes
+            .palette_data()
+            .get(palette + index)
+            .copied()
+            .unwrap_or(MaterialId::EMPTY)
+    }
+
+    /// Palette entries the brick behind `handle` may index, empty without a material brick.
+    /// Reads a bit past small palettes, a caller checking the materials only scans more.
+    pub fn brick_palette<'p>(
+        &self,
+        palettes: &'p PaletteRegistry,
+        handle: BrickHandle,
+    ) -> &'p [MaterialId] {
+        let Some(brick) = self.get_material_brick(handle) else {
+            return &[];
+        };
+        let palette_data = palettes.palette_data();
+        let start = (brick.meta_value() as usize).min(palette_data.len());
+        let end = (start + (1 << brick.element_size())).min(palette_data.len());
+        &palette_data[start..end]
+    }
+
+    /// Material at a voxel position, in voxels (8 per brick), air outside of the map.
+    pub fn get_voxel(&self, palettes: &PaletteRegistry, voxel: na::Point3<u32>) -> MaterialId {
+        let brick = voxel.map(|c| c / 8);
+        if brick.x >= self.size.x || brick.y >= self.size.y || brick.z >= self.size.z {
+            return MaterialId::EMPTY;
+        }
+        let handle = self.get_handle(brick);
+        self.voxel_material(palettes, handle, voxel.map(|c| c % 8))
+    }
+
+    /// Occupancy of a voxel position, in voxels (8 per brick), LOD bricks are solid throughout
+    /// and everything outside of the map is empty.
+    pub fn is_voxel_solid(&self, voxel: na::Point3<u32>) -> bool {
+        let brick = voxel.map(|c| c / 8);
+        if brick.x >= self.size.x || brick.y >= self.size.y || brick.z >= self.size.z {
+            return false;
+        }
+        let handle = self.get_handle(brick);
+        if handle.is_lod() {
+            return true;
+        }
+        let local = voxel.map(|c| c % 8);
+        self.get_brick(handle)
+            .is_some_and(|brick| brick.get(local.x, local.y, local.z))
+    }
+
+    /// Occupancy of a cell inside the brick behind `handle`, `local` is in cells of `level`.
+    /// Bricks without coarse levels fall back to scanning the full resolution brick.
+    pub fn is_solid_at_level(
+        &self,
+        handle: BrickHandle,
+        local: na::Point3<u32>,
+        level: u32,
+    ) -> bool {
+        if handle.is_lod() {
+            return true;
+        }
+        let Some(brick) = self.get_brick(handle) else {
+            return false;
+        };
+
+        match (level, self.get_mip(handle)) {
+            (1.., Some(mip)) => mip.get(level, local.x, local.y, local.z),
+            _ => {
+                let span = 8 / BrickMip::size(level);
+                let min = local * span;
+                (0..span * span * span).any(|i| {
+                    let (x, y, z) = (i % span, (i / span) % span, i / (span * span));
+                    brick.get(min.x + x, min.y + y, min.z + z)
+                })
+            }
+        }
+    }
+
+    /// Like [`Self::voxel_material`] but for a cell of a coarse level.
+    pub fn material_at_level(
+        &self,
+        palettes: &PaletteRegistry,
+        handle: BrickHandle,
+        local: na::Point3<u32>,
+        level: u32,
+    ) -> MaterialId {
+        if level == 0 || self.get_mip(handle).is_none() {
+            let scale = 8 / BrickMip::size(level);
+            return self.voxel_material(palettes, handle, local * scale);
+        }
+        let Some(brick) = self.get_material_brick(handle) else {
+            return MaterialId::EMPTY;
+        };
+
+        let index = BrickMip::palette_index(brick, level, local.x, local.y, local.z) as usize;
+        let palette = brick.meta_value() as usize;
+        palettes
+            .palette_data()
+            .get(palette + index)
+            .copied()
+            .unwrap_or(MaterialId::EMPTY)
+    }
+
+    /// Material at a cell of `level`, in cells of that level (`8 >> level` per brick).
+    pub fn get_voxel_at_level(
+        &self,
+        palettes: &PaletteRegistry,
+        cell: na::Point3<u32>,
+        level: u32,
+    ) -> MaterialId {
+        let size = BrickMip::size(level);
+        let brick = cell.map(|c| c / size);
+        if brick.x >= self.size.x || brick.y >= self.size.y || brick.z >= self.size.z {
+            return MaterialId::EMPTY;
+        }
+        let handle = self.get_handle(brick);
+        let local = cell.map(|c| c % size);
+        if !self.is_solid_at_level(handle, local, level) {
+            return MaterialId::EMPTY;
+        }
+        self.material_at_level(palettes, handle, local, level)
+    }
+
+    /// Copies all handles in memory order, see [`Self::index`]. Includes layout padding.
+    pub fn snapshot_handles(&self) -> Vec<BrickHandle> {
+        self.handles
+            .iter()
+            .map(|handle| BrickHandle(handle.load(Ordering::Acquire)))
+            .collect()
+    }
+
+    /// Copies all trace bricks, indexed by slot. Free slots are included.
+    pub fn snapshot_bricks(&self) -> Vec<TraceBrick> {
+        (0..self.len)
+            .map(|slot| {
+                self.chunks[slot as usize / CHUNK_SIZE]
+                    .as_ref()
+                    .map_or(TraceBrick::EMPTY, |chunk| {
+                        chunk.bricks[slot as usize % CHUNK_SIZE]
+                    })
+            })
+            .collect()
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct TraceBrick {
     raw: [u8; 64],
+    /// Material brick offset, the top bit flags bricks with transparent voxels.
     brick: u32,
 }
 
+/// Set on bricks with voxels rays may pass, see `transparency::has_transparent_voxels`.
+const TRANSPARENT_BIT: u32 = 1 << 31;
+
 impl TraceBrick {
     pub const EMPTY: Self = Self::empty();
     pub const fn empty() -> Self {
@@ -329,6 +1371,10 @@ impl TraceBrick {
         self.raw == Self::EMPTY.raw
     }
 
+    pub fn is_full(&self) -> bool {
+        self.raw.iter().all(|&b| b == u8::MAX)
+    }
+
     pub fn random() -> Self {
         let mut new = Self::empty();
         rand::thread_rng().fill(&mut new.raw);
@@ -370,11 +1416,25 @@ impl TraceBrick {
     }
 
     pub fn get_brick_offset(&self) -> u32 {
-        self.brick
+        self.brick & !TRANSPARENT_BIT
     }
 
     pub fn set_brick_offset(&mut self, offset: u32) {
-        self.brick = offset;
+        self.brick = (self.brick & TRANSPARENT_BIT) | (offset & !TRANSPARENT_BIT);
+    }
+
+    /// Whether some voxels of the brick are translucent or alpha tested. Tracers only look up
+    /// the materials of flagged bricks and treat every other voxel as opaque.
+    pub fn is_transparent(&self) -> bool {
+        self.brick & TRANSPARENT_BIT != 0
+    }
+
+    pub fn set_transparent(&mut self, transparent: bool) {
+        if transparent {
+            self.brick |= TRANSPARENT_BIT;
+        } else {
+            self.brick &= !TRANSPARENT_BIT;
+        }
     }
 }
 
@@ -591,7 +1651,7 @@ impl_material_brick_methods!(MaterialBrick2);
 impl_material_brick_methods!(MaterialBrick4);
 impl_material_brick_methods!(MaterialBrick8);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum MaterialBrick {
     Size1(MaterialBrick1),
     Size2(MaterialBrick2),
@@ -780,13 +1840,15 @@ impl ExpandedBrick {
         unique_values.dedup();
 
         let mut value_map = [0u8; 256];
-        let mut material_ids = vec![MaterialId(0)]; // Always start with MaterialId(0)
-        let mut next_value = 1u8; // Start from 1 for non-zero values
+        // Always start with MaterialId(0)
+        let mut material_ids = vec![MaterialId(0)];
+        // Start from 1 for non-zero values, wide enough to count a full palette
+        let mut next_value = 1u16;
 
         for &val in unique_values.iter() {
             if val != 0 {
                 // Skip 0 as it must map to 0
-                value_map[val as usize] = next_value;
+                value_map[val as usize] = next_value as u8;
                 material_ids.push(material_mapping.material(val));
                 next_value += 1;
             }
@@ -862,3 +1924,300 @@ impl ExpandedBrick {
         (material_brick, material_ids)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping() -> ExpandedMaterialMapping {
+        let mut mapping = ExpandedMaterialMapping::new();
+        mapping.insert(1, MaterialId(1));
+        mapping.insert(2, MaterialId(2));
+        mapping
+    }
+
+    #[test]
+    fn test_identical_bricks_share_a_slot() {
+        let palettes = PaletteRegistry::new();
+        let mapping = mapping();
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+
+        let mut stone = ExpandedBrick::empty();
+        stone.data_mut().fill(2);
+
+        let (a, alloc_a, _) =
+            brickmap.set_expanded_brick(na::Point3::new(0, 0, 0), &stone, &mapping, &palettes);
+        let (b, alloc_b, _) =
+            brickmap.set_expanded_brick(na::Point3::new(1, 0, 0), &stone, &mapping, &palettes);
+        assert_eq!(alloc_a, BrickAlloc::New);
+        assert_eq!(alloc_b, BrickAlloc::Shared);
+        assert_eq!(a.as_raw(), b.as_raw());
+        assert_eq!(brickmap.ref_count(a), 2);
+
+        let stats = brickmap.dedup_stats();
+        assert_eq!(stats.slots, 1);
+        assert_eq!(stats.references, 2);
+        assert!(stats.saved_bytes >= mem::size_of::<TraceBrick>());
+
+        // writing one of them copies instead of changing the other
+        let mut dirt = stone;
+        dirt.set(3, 3, 3, 1);
+        let (c, alloc_c, released) =
+            brickmap.set_expanded_brick(na::Point3::new(1, 0, 0), &dirt, &mapping, &palettes);
+        assert_eq!(alloc_c, BrickAlloc::New);
+        assert!(released.is_none());
+        assert_ne!(a.as_raw(), c.as_raw());
+        assert_eq!(brickmap.ref_count(a), 1);
+        assert_eq!(
+            brickmap.get_voxel(&palettes, na::Point3::new(3, 3, 3)),
+            MaterialId(2)
+        );
+        assert_eq!(
+            brickmap.get_voxel(&palettes, na::Point3::new(11, 3, 3)),
+            MaterialId(1)
+        );
+
+        // the last reference hands back what the slot held
+        let (_, alloc, released) =
+            brickmap.set_expanded_brick(na::Point3::new(1, 0, 0), &stone, &mapping, &palettes);
+        assert_eq!(alloc, BrickAlloc::Shared);
+        assert_eq!(released.unwrap().trace.raw, dirt.to_trace_brick().raw);
+        let (_, released) = brickmap.set_empty(na::Point3::new(0, 0, 0));
+        assert!(released.is_none());
+        let (_, released) = brickmap.set_lod(na::Point3::new(1, 0, 0), MaterialId(1));
+        assert!(released.unwrap().material.is_some());
+    }
+
+    #[test]
+    fn test_modify_shared_brick_copies() {
+        let palettes = PaletteRegistry::new();
+        let mapping = mapping();
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+
+        let mut stone = ExpandedBrick::empty();
+        stone.data_mut().fill(2);
+        for x in 0..3 {
+            brickmap.set_expanded_brick(na::Point3::new(x, 0, 0), &stone, &mapping, &palettes);
+        }
+
+        let alloc = brickmap.modify_brick_at(na::Point3::new(2, 0, 0), |brick, _| {
+            brick.set(0, 0, 0, false);
+        });
+        assert_eq!(alloc, Some(BrickAlloc::New));
+
+        let shared = brickmap.get_handle(na::Point3::new(0, 0, 0));
+        let copied = brickmap.get_handle(na::Point3::new(2, 0, 0));
+        assert_eq!(brickmap.ref_count(shared), 2);
+        assert_eq!(brickmap.ref_count(copied), 1);
+        assert!(brickmap.get_brick(shared).unwrap().get(0, 0, 0));
+        assert!(!brickmap.get_brick(copied).unwrap().get(0, 0, 0));
+
+        // freed slots are reused before growing
+        brickmap.set_empty(na::Point3::new(2, 0, 0));
+        assert_eq!(brickmap.ref_count(copied), 0);
+        let (handle, alloc, _) = brickmap.set_expanded_brick(
+            na::Point3::new(3, 0, 0),
+            &ExpandedBrick::random(3),
+            &mapping,
+            &palettes,
+        );
+        assert_eq!(alloc, BrickAlloc::Reused);
+        assert_eq!(handle.as_raw(), copied.as_raw());
+    }
+
+    #[test]
+    fn test_compact_closes_holes() {
+        let palettes = PaletteRegistry::new();
+        let mapping = mapping();
+        let brickmap = BrickMap::new(na::Vector3::new(8, 1, 1));
+        // pretend every material brick sits in a 64 byte GPU block in slot order
+        let block = 64;
+
+        for x in 0..8 {
+            let mut brick = ExpandedBrick::empty();
+            brick.set(x, 0, 0, 1);
+            let (handle, ..) =
+                brickmap.set_expanded_brick(na::Point3::new(x, 0, 0), &brick, &mapping, &palettes);
+            brickmap.modify_brick(handle, |trace| {
+                trace.set_brick_offset(handle.get_data_value() * block / 4);
+            });
+        }
+        for x in [1, 2, 5] {
+            brickmap.set_empty(na::Point3::new(x, 0, 0));
+        }
+        assert!(brickmap.fragmentation() > 0.3);
+
+        let relocation = brickmap.compact(|_| block as u64);
+        assert_eq!(relocation.slot_count, 5);
+        assert_eq!(relocation.material_bytes, 5 * block as u64);
+        assert_eq!(relocation.slots[3], Some(1));
+        assert_eq!(relocation.slots[5], None);
+        assert_eq!(
+            relocation.material_copies,
+            vec![
+                RelocationRange {
+                    from: 0,
+                    to: 0,
+                    size: 64
+                },
+                RelocationRange {
+                    from: 192,
+                    to: 64,
+                    size: 128
+                },
+                RelocationRange {
+                    from: 384,
+                    to: 192,
+                    size: 128
+                },
+            ]
+        );
+        assert_eq!(brickmap.fragmentation(), 0.0);
+
+        let view = brickmap.read();
+        for x in [0, 3, 4, 6, 7] {
+            let handle = view.get_handle(na::Point3::new(x, 0, 0));
+            assert!(handle.get_data_value() < 5);
+            let trace = view.get_brick(handle).unwrap();
+            assert!(trace.get(x, 0, 0));
+            assert_eq!(
+                trace.get_brick_offset(),
+                handle.get_data_value() * block / 4
+            );
+            assert_eq!(brickmap.ref_count(handle), 1);
+        }
+        drop(view);
+
+        // the dedup table follows the moved slots
+        let mut brick = ExpandedBrick::empty();
+        brick.set(7, 0, 0, 1);
+        let (handle, alloc, _) =
+            brickmap.set_expanded_brick(na::Point3::new(1, 0, 0), &brick, &mapping, &palettes);
+        assert_eq!(alloc, BrickAlloc::Shared);
+        assert_eq!(handle.get_data_value(), 4);
+    }
+
+    #[test]
+    fn test_parallel_writes() {
+        use rayon::prelude::*;
+
+        let palettes = PaletteRegistry::new();
+        let mapping = mapping();
+        let brickmap = BrickMap::new(na::Vector3::new(32, 8, 32));
+
+        let mut stone = ExpandedBrick::empty();
+        stone.data_mut().fill(2);
+
+        (0..32 * 8 * 32u32).into_par_iter().for_each(|i| {
+            let at = na::Point3::new(i % 32, (i / 32) % 8, i / (32 * 8));
+            if i.is_multiple_of(2) {
+                brickmap.set_expanded_brick(at, &stone, &mapping, &palettes);
+            } else {
+                let mut brick = ExpandedBrick::empty();
+                brick.set(at.x % 8, at.y, at.z % 8, 1);
+                brick.set(0, 0, 0, (at.z / 8) as u8 % 2 + 1);
+                brickmap.set_expanded_brick(at, &brick, &mapping, &palettes);
+            }
+        });
+
+        // racing writers of identical bricks may both miss the table, but no slot may leak
+        let stats = brickmap.dedup_stats();
+        assert_eq!(stats.references, 32 * 8 * 32);
+        assert!(stats.slots < 32 * 8 * 32 / 2);
+        assert_eq!(brickmap.fragmentation(), 0.0);
+
+        let view = brickmap.read();
+        assert_eq!(view.snapshot_bricks().len(), stats.slots);
+        for (i, handle) in view.snapshot_handles().into_iter().enumerate() {
+            let i = i as u32;
+            let at = na::Point3::new(i % 32, (i / 32) % 8, i / (32 * 8));
+            let brick = view.get_brick(handle).unwrap();
+            if i.is_multiple_of(2) {
+                assert!(brick.get(7, 7, 7));
+            } else {
+                assert!(brick.get(at.x % 8, at.y, at.z % 8));
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_reports_corruption() {
+        let palettes = PaletteRegistry::new();
+        let materials = MaterialRegistry::new();
+        materials.register_default_materials();
+        let mapping = mapping();
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+
+        let mut stone = ExpandedBrick::empty();
+        stone.data_mut().fill(2);
+        for x in 0..3 {
+            brickmap.set_expanded_brick(na::Point3::new(x, 0, 0), &stone, &mapping, &palettes);
+        }
+        let (freed, ..) = brickmap.set_expanded_brick(
+            na::Point3::new(0, 1, 0),
+            &ExpandedBrick::random(3),
+            &mapping,
+            &palettes,
+        );
+        brickmap.set_empty(na::Point3::new(0, 1, 0));
+        brickmap.set_lod(na::Point3::new(0, 2, 0), MaterialId(1));
+        assert_eq!(brickmap.validate(&palettes), vec![]);
+        assert_eq!(brickmap.validate_materials(&materials), vec![]);
+
+        let shared = brickmap.get_handle(na::Point3::new(0, 0, 0));
+        brickmap.set_handle(freed, na::Point3::new(3, 3, 3));
+        brickmap.modify_brick(shared, |brick| brick.set(1, 2, 3, false));
+        brickmap.set_lod(na::Point3::new(1, 2, 0), MaterialId(999));
+
+        let violations = brickmap.validate(&palettes);
+        assert!(violations.contains(&Violation::DanglingHandle {
+            at: na::Point3::new(3, 3, 3),
+            slot: freed.get_data_value(),
+        }));
+        assert!(violations.contains(&Violation::OccupancyMismatch {
+            slot: shared.get_data_value(),
+            voxel: na::Point3::new(1, 2, 3),
+            traced: false,
+        }));
+        assert_eq!(violations.len(), 2);
+        assert_eq!(
+            brickmap.validate_materials(&materials),
+            vec![Violation::UnknownLodMaterial {
+                at: na::Point3::new(1, 2, 0),
+                material: MaterialId(999),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let palettes = PaletteRegistry::new();
+        let mapping = mapping();
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+
+        let mut stone = ExpandedBrick::empty();
+        stone.data_mut().fill(2);
+        let mut mixed = ExpandedBrick::empty();
+        mixed.set(0, 0, 0, 1);
+        mixed.set(1, 0, 0, 2);
+        brickmap.set_expanded_brick(na::Point3::new(0, 0, 0), &stone, &mapping, &palettes);
+        brickmap.set_expanded_brick(na::Point3::new(1, 0, 0), &stone, &mapping, &palettes);
+        brickmap.set_expanded_brick(na::Point3::new(2, 0, 0), &mixed, &mapping, &palettes);
+        brickmap.set_lod(na::Point3::new(3, 0, 0), MaterialId(1));
+        brickmap.set_handle(
+            BrickHandle::new_empty_with_value(5),
+            na::Point3::new(0, 1, 0),
+        );
+
+        let stats = brickmap.stats();
+        assert_eq!(stats.handles.data, 3);
+        assert_eq!(stats.handles.lod, 1);
+        assert_eq!(stats.handles.sdf_empty, 1);
+        assert_eq!(stats.handles.empty, 64 - 5);
+        assert_eq!(stats.slots, 2);
+        assert_eq!(stats.material_bricks.bits1, 1);
+        assert_eq!(stats.material_bricks.bits2, 1);
+        assert_eq!(stats.dedup.shared_slots, 1);
+        assert_eq!(stats.mip_bytes, 2 * mem::size_of::<BrickMip>());
+    }
+}
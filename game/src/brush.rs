@@ -0,0 +1,545 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rayon::prelude::*;
+
+use crate::{
+    brick::{
+        BrickHandle, BrickMap, BrickMapView, ExpandedBrick, BRICK_SIZE, BRICK_VOLUME,
+        MAX_BRICK_MATERIALS,
+    },
+    material::{ExpandedMaterialMapping, MaterialId},
+    palette::PaletteRegistry,
+};
+
+/// Half the diagonal of a brick, a brick whose center is further than this from a surface lies
+/// entirely on one side of it.
+const BRICK_HALF_DIAGONAL: f32 = 0.866_025_4;
+
+/// Signed distance function in world units, negative inside.
+pub type SdfFn = Arc<dyn Fn(na::Point3<f32>) -> f32 + Send + Sync>;
+
+/// Shape of a brush in world units, one unit per brick.
+#[derive(Clone)]
+pub enum Shape {
+    Sphere {
+        center: na::Point3<f32>,
+        radius: f32,
+    },
+    Box {
+        center: na::Point3<f32>,
+        half_extents: na::Vector3<f32>,
+    },
+    /// Upright along y.
+    Cylinder {
+        center: na::Point3<f32>,
+        radius: f32,
+        half_height: f32,
+    },
+    Capsule {
+        a: na::Point3<f32>,
+        b: na::Point3<f32>,
+        radius: f32,
+    },
+    /// Any signed distance function inside `min..max`. It only has to get the sign right,
+    /// so no brick is skipped or filled based on its distance alone.
+    Sdf {
+        min: na::Point3<f32>,
+        max: na::Point3<f32>,
+        sdf: SdfFn,
+    },
+}
+
+impl Shape {
+    pub fn distance(&self, p: na::Point3<f32>) -> f32 {
+        match self {
+            Self::Sphere { center, radius } => (p - center).norm() - radius,
+            Self::Box {
+                center,
+                half_extents,
+            } => {
+                let q = (p - center).abs() - half_extents;
+                q.sup(&na::Vector3::zeros()).norm() + q.max().min(0.0)
+            }
+            Self::Cylinder {
+                center,
+                radius,
+                half_height,
+            } => {
+                let offset = p - center;
+                let d = na::Vector2::new(offset.xz().norm() - radius, offset.y.abs() - half_height);
+                d.max().min(0.0) + d.sup(&na::Vector2::zeros()).norm()
+            }
+            Self::Capsule { a, b, radius } => {
+                let pa = p - a;
+                let ba = b - a;
+                let h = (pa.dot(&ba) / ba.norm_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+                (pa - ba * h).norm() - radius
+            }
+            Self::Sdf { sdf, .. } => sdf(p),
+        }
+    }
+
+    /// Bounding box in world units.
+    pub fn bounds(&self) -> (na::Point3<f32>, na::Point3<f32>) {
+        match self {
+            Self::Sphere { center, radius } => {
+                let r = na::Vector3::repeat(*radius);
+                (center - r, center + r)
+            }
+            Self::Box {
+                center,
+                half_extents,
+            } => (center - half_extents, center + half_extents),
+            Self::Cylinder {
+                center,
+                radius,
+                half_height,
+            } => {
+                let extent = na::Vector3::new(*radius, *half_height, *radius);
+                (center - extent, center + extent)
+            }
+            Self::Capsule { a, b, radius } => {
+                let r = na::Vector3::repeat(*radius);
+                (a.inf(b) - r, a.sup(b) + r)
+            }
+            Self::Sdf { min, max, .. } => (*min, *max),
+        }
+    }
+
+    /// Whether `distance` is a true distance, which lets whole bricks be classified at once.
+    fn is_exact(&self) -> bool {
+        !matches!(self, Self::Sdf { .. })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrushOp {
+    /// Places the material inside the shape.
+    Union,
+    /// Removes everything inside the shape.
+    Subtract,
+    /// Removes everything outside the shape within the bricks it overlaps.
+    Intersect,
+    /// Changes the material of solid voxels inside the shape.
+    Paint,
+}
+
+#[derive(Clone)]
+pub struct Brush {
+    pub shape: Shape,
+    pub op: BrushOp,
+    /// Material placed by [`BrushOp::Union`] and [`BrushOp::Paint`].
+    pub material: MaterialId,
+}
+
+/// How an edit changed the handle of a brick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrickChange {
+    /// A data brick that was rewritten.
+    Modified,
+    /// An empty brick that got voxels.
+    Created,
+    /// A LOD handle that was expanded into a data brick.
+    Expanded,
+    /// A brick that lost all its voxels.
+    Emptied,
+}
+
+/// New content of one brick, produced by [`Brush::plan`].
+#[derive(Clone)]
+pub struct BrickEdit {
+    pub at: na::Point3<u32>,
+    pub change: BrickChange,
+    /// Material of every voxel, `x + y * 8 + z * 64`. Empty for [`BrickChange::Emptied`].
+    pub voxels: Box<[MaterialId]>,
+}
+
+impl BrickEdit {
+    /// Material of every voxel of the brick behind `handle`, LOD handles are uniformly solid.
+    pub(crate) fn decode(
+        view: &BrickMapView,
+        palettes: &PaletteRegistry,
+        handle: BrickHandle,
+    ) -> Box<[MaterialId]> {
+        let mut voxels = vec![MaterialId::EMPTY; BRICK_VOLUME].into_boxed_slice();
+        if handle.is_lod() {
+            voxels.fill(MaterialId(handle.get_empty_value()));
+        } else if handle.is_data() {
+            for z in 0..BRICK_SIZE {
+                for y in 0..BRICK_SIZE {
+                    for x in 0..BRICK_SIZE {
+                        voxels[ExpandedBrick::index(x, y, z)] =
+                            view.voxel_material(palettes, handle, na::Point3::new(x, y, z));
+                    }
+                }
+            }
+        }
+        voxels
+    }
+
+    /// Edit turning the brick behind `handle` from `before` into `voxels`, `None` if nothing
+    /// changed.
+    pub(crate) fn diff(
+        at: na::Point3<u32>,
+        handle: BrickHandle,
+        before: &[MaterialId],
+        mut voxels: Box<[MaterialId]>,
+    ) -> Option<Self> {
+        if *voxels == *before {
+            return None;
+        }
+
+        let emptied = voxels.iter().all(|m| *m == MaterialId::EMPTY);
+        let change = if emptied {
+            voxels = Box::new([]);
+            BrickChange::Emptied
+        } else if handle.is_lod() {
+            BrickChange::Expanded
+        } else if handle.is_data() {
+            BrickChange::Modified
+        } else {
+            BrickChange::Created
+        };
+
+        Some(Self { at, change, voxels })
+    }
+
+    /// Palette compressible form, `None` when the brick became empty.
+    pub fn to_expanded(&self) -> Option<(ExpandedBrick, ExpandedMaterialMapping)> {
+        if self.change == BrickChange::Emptied {
+            return None;
+        }
+
+        let mut expanded = ExpandedBrick::empty();
+        let mut mapping = ExpandedMaterialMapping::new();
+        let mut values = HashMap::from([(MaterialId::EMPTY, 0u8)]);
+        mapping.insert(0, MaterialId::EMPTY);
+
+        for (voxel, material) in expanded.data_mut().iter_mut().zip(self.voxels.iter()) {
+            let next = values.len() as u8;
+            *voxel = *values.entry(*material).or_insert_with(|| {
+                mapping.insert(next, *material);
+                next
+            });
+        }
+
+        Some((expanded, mapping))
+    }
+}
+
+/// Edits of a [`Brush::plan`].
+#[derive(Clone, Default)]
+pub struct BrushPlan {
+    pub edits: Vec<BrickEdit>,
+    /// Voxels a Union or Paint left out, their brick palette already held
+    /// [`MAX_BRICK_MATERIALS`] others.
+    pub dropped: usize,
+}
+
+/// Result of a brush applied to a map.
+#[derive(Debug, Clone, Default)]
+pub struct BrushResult {
+    /// Every brick whose handle or content changed.
+    pub touched: Vec<(na::Point3<u32>, BrickChange)>,
+    /// See [`BrushPlan::dropped`].
+    pub dropped: usize,
+}
+
+impl Brush {
+    pub fn new(shape: Shape, op: BrushOp, material: MaterialId) -> Self {
+        Self {
+            shape,
+            op,
+            material,
+        }
+    }
+
+    /// Bricks the shape overlaps, clamped to the map.
+    fn brick_range(&self, dims: na::Vector3<u32>) -> Option<(na::Point3<u32>, na::Point3<u32>)> {
+        let (min, max) = self.shape.bounds();
+        let min = min.map(|c| c.floor().max(0.0) as u32);
+        let max = max.map(|c| c.ceil() as i64 - 1);
+        if (0..3).any(|axis| max[axis] < min[axis] as i64 || min[axis] >= dims[axis]) {
+            return None;
+        }
+        let max = na::Point3::new(
+            (max.x as u32).min(dims.x - 1),
+            (max.y as u32).min(dims.y - 1),
+            (max.z as u32).min(dims.z - 1),
+        );
+        Some((min, max))
+    }
+
+    /// Computes the new content of every brick the brush changes, without modifying the map.
+    pub fn plan(&self, brickmap: &BrickMap, palettes: &PaletteRegistry) -> BrushPlan {
+        let Some((min, max)) = self.brick_range(brickmap.dimensions()) else {
+            return BrushPlan::default();
+        };
+
+        let positions: Vec<_> = (min.z..=max.z)
+            .flat_map(|z| {
+                (min.y..=max.y).flat_map(move |y| (min.x..=max.x).map(move |x| (x, y, z)))
+            })
+            .map(|(x, y, z)| na::Point3::new(x, y, z))
+            .collect();
+
+        let view = brickmap.read();
+        let planned: Vec<_> = positions
+            .into_par_iter()
+            .map(|at| self.plan_brick(&view, palettes, at))
+            .collect();
+
+        let dropped = planned.iter().map(|(_, dropped)| dropped).sum();
+        let edits = planned.into_iter().filter_map(|(edit, _)| edit).collect();
+        BrushPlan { edits, dropped }
+    }
+
+    /// The edit of the brick at `at`, if any, and the voxels left out for a full palette.
+    fn plan_brick(
+        &self,
+        view: &BrickMapView,
+        palettes: &PaletteRegistry,
+        at: na::Point3<u32>,
+    ) -> (Option<BrickEdit>, usize) {
+        let handle = view.get_handle(at);
+        let solid = handle.is_data() || handle.is_lod();
+
+        // whole brick on one side of the surface
+        let coverage = if self.shape.is_exact() {
+            let center = at.cast::<f32>() + na::Vector3::repeat(0.5);
+            let distance = self.shape.distance(center);
+            if distance > BRICK_HALF_DIAGONAL {
+                Some(false)
+            } else if distance < -BRICK_HALF_DIAGONAL {
+                Some(true)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        match (self.op, coverage) {
+            (BrushOp::Union | BrushOp::Subtract | BrushOp::Paint, Some(false)) => return (None, 0),
+            (BrushOp::Intersect, Some(true)) => return (None, 0),
+            (BrushOp::Subtract | BrushOp::Paint | BrushOp::Intersect, _) if !solid => {
+                return (None, 0)
+            }
+            _ => {}
+        }
+
+        let before = BrickEdit::decode(view, palettes, handle);
+        let mut voxels = before.clone();
+        let places = matches!(self.op, BrushOp::Union | BrushOp::Paint);
+        let palette_full = places && !before.contains(&self.material) && {
+            let mut materials = before.to_vec();
+            materials.sort_unstable_by_key(|m| m.0);
+            materials.dedup();
+            materials
+                .iter()
+                .filter(|m| **m != MaterialId::EMPTY)
+                .count()
+                >= MAX_BRICK_MATERIALS
+        };
+        let mut dropped = 0;
+
+        for z in 0..BRICK_SIZE {
+            for y in 0..BRICK_SIZE {
+                for x in 0..BRICK_SIZE {
+                    let index = ExpandedBrick::index(x, y, z);
+                    let inside = coverage.unwrap_or_else(|| {
+                        let local = na::Vector3::new(x, y, z).cast::<f32>().add_scalar(0.5);
+                        let p = at.cast::<f32>() + local / BRICK_SIZE as f32;
+                        self.shape.distance(p) <= 0.0
+                    });
+                    let voxel = &mut voxels[index];
+
+                    match self.op {
+                        BrushOp::Union if inside => *voxel = self.material,
+                        BrushOp::Subtract if inside => *voxel = MaterialId::EMPTY,
+                        BrushOp::Intersect if !inside => *voxel = MaterialId::EMPTY,
+                        BrushOp::Paint if inside && *voxel != MaterialId::EMPTY => {
+                            *voxel = self.material
+                        }
+                        _ => continue,
+                    }
+                    if palette_full {
+                        // undo the placement, the material doesn't fit the palette
+                        *voxel = before[index];
+                        dropped += 1;
+                    }
+                }
+            }
+        }
+
+        (BrickEdit::diff(at, handle, &before, voxels), dropped)
+    }
+}
+
+impl BrickMap {
+    /// Writes the bricks of a [`Brush::plan`].
+    pub fn apply_edits(&self, edits: &[BrickEdit], palettes: &PaletteRegistry) -> BrushResult {
+        let touched = edits
+            .iter()
+            .map(|edit| {
+                match edit.to_expanded() {
+                    Some((expanded, mapping)) => {
+                        self.set_expanded_brick(edit.at, &expanded, &mapping, palettes);
+                    }
+                    None => {
+                        self.set_empty(edit.at);
+                    }
+                }
+                (edit.at, edit.change)
+            })
+            .collect();
+
+        BrushResult {
+            touched,
+            dropped: 0,
+        }
+    }
+
+    pub fn apply_brush(&self, brush: &Brush, palettes: &PaletteRegistry) -> BrushResult {
+        let plan = brush.plan(self, palettes);
+        BrushResult {
+            dropped: plan.dropped,
+            ..self.apply_edits(&plan.edits, palettes)
+        }
+    }
+}
+
+/// Fills voxels `min..max` with a box brush, clearing them for [`MaterialId::EMPTY`], returns the
+/// touched bricks.
+#[cfg(test)]
+pub(crate) fn fill(
+    brickmap: &BrickMap,
+    palettes: &PaletteRegistry,
+    min: [u32; 3],
+    max: [u32; 3],
+    material: MaterialId,
+) -> Vec<na::Point3<u32>> {
+    let min = na::Point3::from(min).cast::<f32>() / BRICK_SIZE as f32;
+    let max = na::Point3::from(max).cast::<f32>() / BRICK_SIZE as f32;
+    let cube = Shape::Box {
+        center: na::center(&min, &max),
+        half_extents: (max - min) / 2.0,
+    };
+    let op = if material == MaterialId::EMPTY {
+        BrushOp::Subtract
+    } else {
+        BrushOp::Union
+    };
+    let result = brickmap.apply_brush(&Brush::new(cube, op, material), palettes);
+    result.touched.into_iter().map(|(at, _)| at).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STONE: MaterialId = MaterialId(1);
+    const DIRT: MaterialId = MaterialId(2);
+
+    fn count(brickmap: &BrickMap, palettes: &PaletteRegistry, material: MaterialId) -> usize {
+        let dims = brickmap.dimensions() * BRICK_SIZE;
+        let view = brickmap.read();
+        (0..dims.z)
+            .flat_map(|z| (0..dims.y).flat_map(move |y| (0..dims.x).map(move |x| (x, y, z))))
+            .filter(|&(x, y, z)| view.get_voxel(palettes, na::Point3::new(x, y, z)) == material)
+            .count()
+    }
+
+    #[test]
+    fn test_sphere_place_and_dig() {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let palettes = PaletteRegistry::new();
+        let center = na::Point3::new(2.0, 2.0, 2.0);
+
+        let sphere = Shape::Sphere {
+            center,
+            radius: 1.5,
+        };
+        let placed = brickmap.apply_brush(&Brush::new(sphere, BrushOp::Union, STONE), &palettes);
+        assert!(placed
+            .touched
+            .iter()
+            .all(|(_, change)| *change == BrickChange::Created));
+        let solid = count(&brickmap, &palettes, STONE);
+        // a sphere of radius 12 voxels
+        let expected = 4.0 / 3.0 * std::f32::consts::PI * 12f32.powi(3);
+        assert!((solid as f32 - expected).abs() / expected < 0.05);
+
+        let cube = Shape::Box {
+            center,
+            half_extents: na::Vector3::repeat(2.0),
+        };
+        let dug = brickmap.apply_brush(&Brush::new(cube, BrushOp::Subtract, STONE), &palettes);
+        assert_eq!(dug.touched.len(), placed.touched.len());
+        assert!(dug
+            .touched
+            .iter()
+            .all(|(_, change)| *change == BrickChange::Emptied));
+        assert_eq!(count(&brickmap, &palettes, STONE), 0);
+        assert!(brickmap.validate(&palettes).is_empty());
+    }
+
+    #[test]
+    fn test_full_palette_drops_voxels() {
+        let brickmap = BrickMap::new(na::Vector3::new(2, 2, 2));
+        let palettes = PaletteRegistry::new();
+        let mut brick = ExpandedBrick::empty();
+        let mut mapping = ExpandedMaterialMapping::new();
+        for (i, voxel) in brick.data_mut().iter_mut().enumerate() {
+            *voxel = (i % MAX_BRICK_MATERIALS) as u8 + 1;
+            mapping.insert(*voxel, MaterialId(100 + *voxel as u32));
+        }
+        let at = na::Point3::new(1, 1, 1);
+        brickmap.set_expanded_brick(at, &brick, &mapping, &palettes);
+
+        let cube = Shape::Box {
+            center: na::Point3::new(1.5, 1.5, 1.5),
+            half_extents: na::Vector3::repeat(0.5),
+        };
+        let result = brickmap.apply_brush(&Brush::new(cube, BrushOp::Union, STONE), &palettes);
+        assert!(result.touched.is_empty());
+        assert_eq!(result.dropped, BRICK_VOLUME);
+        assert_eq!(count(&brickmap, &palettes, STONE), 0);
+    }
+
+    #[test]
+    fn test_paint_and_lod_expansion() {
+        let brickmap = BrickMap::new(na::Vector3::new(2, 2, 2));
+        let palettes = PaletteRegistry::new();
+        brickmap.set_lod(na::Point3::new(0, 0, 0), STONE);
+
+        let capsule = Shape::Capsule {
+            a: na::Point3::new(0.5, 0.5, 0.5),
+            b: na::Point3::new(1.5, 0.5, 0.5),
+            radius: 0.25,
+        };
+        let painted = brickmap.apply_brush(&Brush::new(capsule, BrushOp::Paint, DIRT), &palettes);
+
+        // only the solid LOD brick is painted, the empty neighbour stays untouched
+        assert_eq!(
+            painted.touched,
+            vec![(na::Point3::new(0, 0, 0), BrickChange::Expanded)]
+        );
+        assert!(brickmap.get_handle(na::Point3::new(0, 0, 0)).is_data());
+        assert!(brickmap.is_empty(na::Point3::new(1, 0, 0)));
+        let dirt = count(&brickmap, &palettes, DIRT);
+        assert!(dirt > 0);
+        assert_eq!(count(&brickmap, &palettes, STONE) + dirt, BRICK_VOLUME);
+
+        let half = Shape::Sdf {
+            min: na::Point3::origin(),
+            max: na::Point3::new(1.0, 1.0, 1.0),
+            sdf: Arc::new(|p| p.y - 0.5),
+        };
+        brickmap.apply_brush(&Brush::new(half, BrushOp::Intersect, DIRT), &palettes);
+        assert_eq!(
+            count(&brickmap, &palettes, STONE) + count(&brickmap, &palettes, DIRT),
+            BRICK_VOLUME / 2
+        );
+    }
+}
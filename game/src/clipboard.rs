@@ -0,0 +1,636 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use bytemuck::Zeroable;
+
+use crate::{
+    brick::{BrickMap, ExpandedBrick, BRICK_SIZE, MAX_BRICK_MATERIALS},
+    brush::BrickEdit,
+    material::{MaterialId, MaterialRegistry, PbrMaterial},
+    palette::PaletteRegistry,
+};
+
+const PREFAB_MAGIC: &[u8; 4] = b"VXPF";
+const PREFAB_VERSION: u32 = 1;
+/// Largest volume a prefab may hold, as many voxels as a 128³ brick map.
+const MAX_VOLUME_VOXELS: usize = 1 << 30;
+/// Longest material name a prefab may hold, in bytes.
+const MAX_NAME_LEN: usize = 255;
+
+/// A material of a [`VoxelVolume`], carried along so the volume can be pasted into a registry
+/// that doesn't know it.
+#[derive(Debug, Clone)]
+pub struct VolumeMaterial {
+    /// Id in the registry the volume was copied from.
+    pub id: MaterialId,
+    pub name: Option<String>,
+    pub material: PbrMaterial,
+}
+
+/// Standalone block of voxels, used for the clipboard and as prefab on disk.
+#[derive(Debug, Clone)]
+pub struct VoxelVolume {
+    size: na::Vector3<u32>,
+    /// Index 0 is air.
+    palette: Vec<VolumeMaterial>,
+    /// Palette index per voxel, `x + y * size.x + z * size.x * size.y`.
+    voxels: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasteMode {
+    /// Writes every voxel of the volume, air included.
+    Replace,
+    /// Writes the solid voxels of the volume, air keeps what is already there.
+    Merge,
+    /// Writes the solid voxels of the volume only where the map is already solid.
+    Mask,
+}
+
+/// Signed axis permutation applied to a volume while pasting, built from 90° rotations and
+/// mirrors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Orientation(na::Matrix3<i32>);
+
+impl Default for Orientation {
+    fn default() -> Self {
+        Self(na::Matrix3::identity())
+    }
+}
+
+impl Orientation {
+    /// Rotates by `quarter_turns` times 90° counter clockwise around `axis`, 0 is x.
+    pub fn rotate(self, axis: usize, quarter_turns: i32) -> Self {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut rotation = self.0;
+        for _ in 0..quarter_turns.rem_euclid(4) {
+            let mut turn = na::Matrix3::zeros();
+            turn[(axis, axis)] = 1;
+            turn[(v, u)] = 1;
+            turn[(u, v)] = -1;
+            rotation = turn * rotation;
+        }
+        Self(rotation)
+    }
+
+    pub fn mirror(self, axis: usize) -> Self {
+        let mut flip = na::Matrix3::identity();
+        flip[(axis, axis)] = -1;
+        Self(flip * self.0)
+    }
+
+    /// Size of a volume of `size` after the orientation.
+    pub fn size(&self, size: na::Vector3<u32>) -> na::Vector3<u32> {
+        let size = self.0.abs() * size.cast::<i32>();
+        size.map(|c| c as u32)
+    }
+
+    /// Position of voxel `p` of a volume of `size` after the orientation.
+    fn apply(&self, p: na::Point3<u32>, size: na::Vector3<u32>) -> na::Point3<u32> {
+        // doubled coordinates around the center stay integer for even and odd sizes
+        let centered = p.coords.cast::<i32>() * 2 + na::Vector3::repeat(1) - size.cast::<i32>();
+        let turned = self.0 * centered;
+        let size = self.size(size).cast::<i32>();
+        ((turned + size - na::Vector3::repeat(1)) / 2)
+            .map(|c| c as u32)
+            .into()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PasteOptions {
+    pub orientation: Orientation,
+    pub mode: PasteMode,
+}
+
+impl Default for PasteOptions {
+    fn default() -> Self {
+        Self {
+            orientation: Orientation::default(),
+            mode: PasteMode::Replace,
+        }
+    }
+}
+
+impl VoxelVolume {
+    pub fn size(&self) -> na::Vector3<u32> {
+        self.size
+    }
+
+    pub fn palette(&self) -> &[VolumeMaterial] {
+        &self.palette
+    }
+
+    fn voxel_index(&self, p: na::Point3<u32>) -> usize {
+        let size = self.size.cast::<usize>();
+        p.x as usize + p.y as usize * size.x + p.z as usize * size.x * size.y
+    }
+
+    pub fn get(&self, p: na::Point3<u32>) -> Option<&VolumeMaterial> {
+        match self.voxels[self.voxel_index(p)] {
+            0 => None,
+            index => Some(&self.palette[index as usize]),
+        }
+    }
+
+    /// Copies the voxels in `min..max` of the map, in voxels (8 per brick). `None` when the
+    /// region is larger than a prefab may be.
+    pub fn copy(
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+        min: na::Point3<u32>,
+        max: na::Point3<u32>,
+    ) -> Option<Self> {
+        let size = max
+            .coords
+            .zip_map(&min.coords, |max, min| max.saturating_sub(min));
+        let mut palette = vec![VolumeMaterial {
+            id: MaterialId::EMPTY,
+            name: None,
+            material: PbrMaterial::zeroed(),
+        }];
+        let mut indices = HashMap::from([(MaterialId::EMPTY, 0u16)]);
+        let volume = size
+            .iter()
+            .try_fold(1usize, |volume, &axis| volume.checked_mul(axis as usize))
+            .filter(|&volume| volume <= MAX_VOLUME_VOXELS)?;
+        let mut voxels = Vec::with_capacity(volume);
+
+        let view = brickmap.read();
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    let id = view.get_voxel(palettes, na::Point3::new(x, y, z));
+                    let index = *indices.entry(id).or_insert_with(|| {
+                        palette.push(VolumeMaterial {
+                            id,
+                            name: materials.get_material_name(id),
+                            material: materials
+                                .get_material(id)
+                                .unwrap_or_else(PbrMaterial::zeroed),
+                        });
+                        (palette.len() - 1) as u16
+                    });
+                    voxels.push(index);
+                }
+            }
+        }
+
+        Some(Self {
+            size,
+            palette,
+            voxels,
+        })
+    }
+
+    /// Ids of the palette in `materials`. Matches the original id when the registry holds the
+    /// same material there, then the name, `None` for materials the registry doesn't know.
+    pub fn match_materials(&self, materials: &MaterialRegistry) -> Vec<Option<MaterialId>> {
+        self.palette
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                if index == 0 {
+                    return Some(MaterialId::EMPTY);
+                }
+                let same = materials.get_material(entry.id).is_some_and(|material| {
+                    bytemuck::bytes_of(&material) == bytemuck::bytes_of(&entry.material)
+                });
+                if same {
+                    return Some(entry.id);
+                }
+                entry
+                    .name
+                    .as_ref()
+                    .and_then(|name| materials.get_material_id(name))
+            })
+            .collect()
+    }
+
+    /// Registers palette entry `index` in `materials`, under its name if it has one.
+    fn register_material(&self, index: usize, materials: &MaterialRegistry) -> MaterialId {
+        let entry = &self.palette[index];
+        match &entry.name {
+            Some(name) => materials.register_named_material(name, entry.material),
+            None => materials.register_material(entry.material),
+        }
+    }
+
+    /// The volume as standalone brickmap, its size rounded up to whole bricks.
+    pub fn to_brickmap(
+        &self,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+    ) -> BrickMap {
+        let brickmap = BrickMap::new(self.size.map(|c| c.div_ceil(BRICK_SIZE).max(1)));
+        let edits = self.plan_paste(
+            &brickmap,
+            palettes,
+            materials,
+            na::Point3::origin(),
+            PasteOptions::default(),
+        );
+        brickmap.apply_edits(&edits, palettes);
+        brickmap
+    }
+
+    /// Bricks to write for pasting the volume with its minimum corner at `at`, in voxels.
+    /// Voxels outside of the map are dropped, apply with [`BrickMap::apply_edits`]. Materials
+    /// `materials` doesn't know yet are only registered once an edit writes them.
+    pub fn plan_paste(
+        &self,
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+        at: na::Point3<i32>,
+        options: PasteOptions,
+    ) -> Vec<BrickEdit> {
+        let matched = self.match_materials(materials);
+        // unknown materials stand in with ids past any registered one until the edits are known
+        let provisional = |index: usize| MaterialId(u32::MAX - index as u32);
+        let ids: Vec<_> = matched
+            .iter()
+            .enumerate()
+            .map(|(index, id)| id.unwrap_or_else(|| provisional(index)))
+            .collect();
+        let dims = brickmap.dimensions().cast::<i32>() * BRICK_SIZE as i32;
+
+        // voxels of the volume grouped by the brick they land in
+        let mut bricks: HashMap<na::Point3<u32>, Vec<(usize, MaterialId)>> = HashMap::new();
+        for z in 0..self.size.z {
+            for y in 0..self.size.y {
+                for x in 0..self.size.x {
+                    let p = na::Point3::new(x, y, z);
+                    let index = self.voxels[self.voxel_index(p)];
+                    if index == 0 && options.mode != PasteMode::Replace {
+                        continue;
+                    }
+
+                    let target = at + options.orientation.apply(p, self.size).coords.cast::<i32>();
+                    if (0..3).any(|axis| target[axis] < 0 || target[axis] >= dims[axis]) {
+                        continue;
+                    }
+                    let target = target.map(|c| c as u32);
+                    let local = target.map(|c| c % BRICK_SIZE);
+                    bricks
+                        .entry(target.map(|c| c / BRICK_SIZE))
+                        .or_default()
+                        .push((
+                            ExpandedBrick::index(local.x, local.y, local.z),
+                            ids[index as usize],
+                        ));
+                }
+            }
+        }
+
+        let view = brickmap.read();
+        let mut edits: Vec<_> = bricks
+            .into_iter()
+            .filter_map(|(brick, writes)| {
+                let handle = view.get_handle(brick);
+                let before = BrickEdit::decode(&view, palettes, handle);
+                let mut voxels = before.clone();
+                for (index, material) in writes {
+                    if options.mode == PasteMode::Mask && voxels[index] == MaterialId::EMPTY {
+                        continue;
+                    }
+                    voxels[index] = material;
+                }
+
+                let mut distinct = voxels.to_vec();
+                distinct.sort_unstable_by_key(|m| m.0);
+                distinct.dedup();
+                distinct.retain(|m| *m != MaterialId::EMPTY);
+                if distinct.len() > MAX_BRICK_MATERIALS {
+                    log::warn!(
+                        "paste skips brick {brick:?}, it would hold more than \
+                         {MAX_BRICK_MATERIALS} materials"
+                    );
+                    return None;
+                }
+
+                BrickEdit::diff(brick, handle, &before, voxels)
+            })
+            .collect();
+        edits.sort_unstable_by_key(|edit| (edit.at.z, edit.at.y, edit.at.x));
+
+        let mut registered = HashMap::new();
+        for voxel in edits.iter_mut().flat_map(|edit| edit.voxels.iter_mut()) {
+            let index = u32::MAX.wrapping_sub(voxel.0) as usize;
+            if index < matched.len() && matched[index].is_none() {
+                *voxel = *registered
+                    .entry(index)
+                    .or_insert_with(|| self.register_material(index, materials));
+            }
+        }
+        edits
+    }
+
+    /// Writes the volume as prefab, little endian: magic, version, size, the palette as name
+    /// and material per entry, then the voxels as runs of palette index and length.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        if self.voxels.len() > MAX_VOLUME_VOXELS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "volume too large for a prefab",
+            ));
+        }
+        writer.write_all(PREFAB_MAGIC)?;
+        writer.write_all(&PREFAB_VERSION.to_le_bytes())?;
+        for axis in 0..3 {
+            writer.write_all(&self.size[axis].to_le_bytes())?;
+        }
+
+        writer.write_all(&(self.palette.len() as u32).to_le_bytes())?;
+        for entry in self.palette.iter() {
+            writer.write_all(&entry.id.0.to_le_bytes())?;
+            let name = entry.name.as_deref().unwrap_or("");
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_all(bytemuck::bytes_of(&entry.material))?;
+        }
+
+        let mut runs = Vec::new();
+        for &index in self.voxels.iter() {
+            match runs.last_mut() {
+                Some((last, length)) if *last == index => *length += 1,
+                _ => runs.push((index, 1u32)),
+            }
+        }
+        writer.write_all(&(runs.len() as u32).to_le_bytes())?;
+        for (index, length) in runs {
+            writer.write_all(&index.to_le_bytes())?;
+            writer.write_all(&length.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != PREFAB_MAGIC {
+            return Err(invalid("not a prefab"));
+        }
+        if read_u32(&mut reader)? != PREFAB_VERSION {
+            return Err(invalid("unsupported prefab version"));
+        }
+        let size = na::Vector3::new(
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+        );
+
+        let palette_len = read_u32(&mut reader)?;
+        if palette_len == 0 || palette_len > u16::MAX as u32 + 1 {
+            return Err(invalid("invalid palette size"));
+        }
+        let palette = (0..palette_len)
+            .map(|_| {
+                let id = MaterialId(read_u32(&mut reader)?);
+                let name_len = read_u32(&mut reader)? as usize;
+                if name_len > MAX_NAME_LEN {
+                    return Err(invalid("material name too long"));
+                }
+                let mut name = vec![0; name_len];
+                reader.read_exact(&mut name)?;
+                let name = String::from_utf8(name).map_err(|_| invalid("invalid material name"))?;
+                let mut material = PbrMaterial::zeroed();
+                reader.read_exact(bytemuck::bytes_of_mut(&mut material))?;
+                Ok(VolumeMaterial {
+                    id,
+                    name: (!name.is_empty()).then_some(name),
+                    material,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let volume = (size.x as usize)
+            .checked_mul(size.y as usize)
+            .and_then(|area| area.checked_mul(size.z as usize))
+            .filter(|&volume| volume <= MAX_VOLUME_VOXELS)
+            .ok_or_else(|| invalid("prefab too large"))?;
+        // grows with the runs actually read, the header alone is not trusted
+        let mut voxels = Vec::new();
+        for _ in 0..read_u32(&mut reader)? {
+            let mut index = [0; 2];
+            reader.read_exact(&mut index)?;
+            let index = u16::from_le_bytes(index);
+            let length = read_u32(&mut reader)? as usize;
+            if index as u32 >= palette_len || length > volume - voxels.len() {
+                return Err(invalid("voxel data out of range"));
+            }
+            voxels.resize(voxels.len() + length, index);
+        }
+        if voxels.len() != volume {
+            return Err(invalid("voxel data too short"));
+        }
+
+        Ok(Self {
+            size,
+            palette,
+            voxels,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brush::{Brush, BrushOp, Shape};
+
+    fn setup() -> (BrickMap, PaletteRegistry, MaterialRegistry) {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 2, 4));
+        let palettes = PaletteRegistry::new();
+        let materials = MaterialRegistry::new();
+        materials.register_default_materials();
+        (brickmap, palettes, materials)
+    }
+
+    #[test]
+    fn test_orientation() {
+        let size = na::Vector3::new(3, 1, 2);
+        let turned = Orientation::default().rotate(1, 1);
+        assert_eq!(turned.size(size), na::Vector3::new(2, 1, 3));
+        // +z turns into +x around y
+        assert_eq!(
+            turned.apply(na::Point3::new(0, 0, 1), size),
+            na::Point3::new(1, 0, 2)
+        );
+        assert_eq!(turned.rotate(1, 3), Orientation::default());
+
+        let mirrored = Orientation::default().mirror(0);
+        assert_eq!(
+            mirrored.apply(na::Point3::new(0, 0, 1), size),
+            na::Point3::new(2, 0, 1)
+        );
+        assert_eq!(mirrored.mirror(0), Orientation::default());
+    }
+
+    #[test]
+    fn test_read_rejects_bad_headers() {
+        let header = |size: [u32; 3]| {
+            let mut bytes = PREFAB_MAGIC.to_vec();
+            for value in [PREFAB_VERSION, size[0], size[1], size[2]] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes
+        };
+
+        // cut off before the palette
+        assert!(VoxelVolume::read(header([1, 1, 1]).as_slice()).is_err());
+        // a volume that overflows or would not fit any map
+        let mut oversized = header([u32::MAX; 3]);
+        oversized.extend_from_slice(&1u32.to_le_bytes());
+        oversized.extend_from_slice(&[0; 4]);
+        oversized.extend_from_slice(&0u32.to_le_bytes());
+        oversized.extend_from_slice(bytemuck::bytes_of(&PbrMaterial::zeroed()));
+        let error = VoxelVolume::read(oversized.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // a material name of 4 GiB
+        let mut long_name = header([1, 1, 1]);
+        long_name.extend_from_slice(&1u32.to_le_bytes());
+        long_name.extend_from_slice(&[0; 4]);
+        long_name.extend_from_slice(&u32::MAX.to_le_bytes());
+        let error = VoxelVolume::read(long_name.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_copy_paste_prefab() {
+        let (brickmap, palettes, materials) = setup();
+        let stone = materials.get_material_id("stone").unwrap();
+        let dirt = materials.get_material_id("dirt").unwrap();
+
+        // an L of stone with a dirt tip, 4 x 2 x 1 voxels
+        for (x, y, material) in [(0, 0, stone), (1, 0, stone), (2, 0, stone), (0, 1, dirt)] {
+            let cube = Shape::Box {
+                center: na::Point3::new(x as f32 + 0.5, y as f32 + 0.5, 0.5) / 8.0,
+                half_extents: na::Vector3::repeat(0.5 / 8.0),
+            };
+            brickmap.apply_brush(&Brush::new(cube, BrushOp::Union, material), &palettes);
+        }
+
+        let volume = VoxelVolume::copy(
+            &brickmap,
+            &palettes,
+            &materials,
+            na::Point3::new(0, 0, 0),
+            na::Point3::new(4, 2, 1),
+        )
+        .unwrap();
+        let mut bytes = Vec::new();
+        volume.write(&mut bytes).unwrap();
+        let prefab = VoxelVolume::read(bytes.as_slice()).unwrap();
+        assert_eq!(prefab.size(), na::Vector3::new(4, 2, 1));
+        assert_eq!(
+            prefab
+                .get(na::Point3::new(0, 1, 0))
+                .unwrap()
+                .name
+                .as_deref(),
+            Some("dirt")
+        );
+
+        // rotated a quarter around z, the L stands up at brick (2, 0, 2)
+        let options = PasteOptions {
+            orientation: Orientation::default().rotate(2, 1),
+            mode: PasteMode::Merge,
+        };
+        let at = na::Point3::new(20, 0, 20);
+        let edits = prefab.plan_paste(&brickmap, &palettes, &materials, at, options);
+        brickmap.apply_edits(&edits, &palettes);
+
+        let voxel = |x, y| brickmap.get_voxel(&palettes, na::Point3::new(x, y, 20));
+        assert_eq!(voxel(21, 0), stone);
+        assert_eq!(voxel(21, 2), stone);
+        assert_eq!(voxel(21, 3), MaterialId::EMPTY);
+        assert_eq!(voxel(20, 0), dirt);
+        assert_eq!(voxel(20, 1), MaterialId::EMPTY);
+
+        // masking into the stone only paints what is already solid
+        let options = PasteOptions {
+            mode: PasteMode::Mask,
+            ..Default::default()
+        };
+        let solid = VoxelVolume::copy(
+            &brickmap,
+            &palettes,
+            &materials,
+            na::Point3::new(20, 0, 20),
+            na::Point3::new(21, 4, 21),
+        )
+        .unwrap();
+        let edits = solid.plan_paste(
+            &brickmap,
+            &palettes,
+            &materials,
+            na::Point3::new(21, 0, 20),
+            options,
+        );
+        brickmap.apply_edits(&edits, &palettes);
+        assert_eq!(voxel(21, 0), dirt);
+        assert_eq!(voxel(21, 1), stone);
+        assert_eq!(voxel(21, 3), MaterialId::EMPTY);
+        assert!(brickmap.validate(&palettes).is_empty());
+    }
+
+    #[test]
+    fn test_paste_registers_written_materials() {
+        let (brickmap, palettes, materials) = setup();
+        let stone = materials.get_material_id("stone").unwrap();
+        let cube = Shape::Box {
+            center: na::Point3::new(0.5, 0.5, 0.5) / 8.0,
+            half_extents: na::Vector3::repeat(0.5 / 8.0),
+        };
+        brickmap.apply_brush(&Brush::new(cube, BrushOp::Union, stone), &palettes);
+        let volume = VoxelVolume::copy(
+            &brickmap,
+            &palettes,
+            &materials,
+            na::Point3::new(0, 0, 0),
+            na::Point3::new(2, 1, 1),
+        )
+        .unwrap();
+
+        // a registry without stone learns it only from a paste that lands in the map
+        let other = MaterialRegistry::new();
+        let options = PasteOptions::default();
+        let outside = na::Point3::new(-8, 0, 0);
+        let edits = volume.plan_paste(&brickmap, &palettes, &other, outside, options);
+        assert!(edits.is_empty());
+        assert_eq!(other.stats().materials, 0);
+
+        let inside = na::Point3::new(8, 0, 0);
+        let edits = volume.plan_paste(&brickmap, &palettes, &other, inside, options);
+        let id = other.get_material_id("stone").unwrap();
+        assert_eq!(other.stats().materials, 1);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].voxels[ExpandedBrick::index(0, 0, 0)], id);
+
+        // larger than a prefab may be
+        let huge = na::Point3::new(1 << 11, 1 << 11, 1 << 9);
+        let copy = VoxelVolume::copy(&brickmap, &palettes, &materials, na::Point3::origin(), huge);
+        assert!(copy.is_none());
+    }
+}
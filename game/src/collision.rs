@@ -0,0 +1,331 @@
+use crate::brick::{BrickMap, BrickMapView, BRICK_SIZE};
+
+const VOXEL_SIZE: f32 = 1.0 / BRICK_SIZE as f32;
+/// Gap kept to the world after a contact, moving exactly onto a face could round into it.
+pub(crate) const SKIN: f32 = 1e-4;
+
+/// Axis aligned box in world units, one unit per brick.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: na::Point3<f32>,
+    pub max: na::Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: na::Point3<f32>, max: na::Point3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    pub fn from_center(center: na::Point3<f32>, half_extents: na::Vector3<f32>) -> Self {
+        Self::new(center - half_extents, center + half_extents)
+    }
+
+    /// Box of the voxel at `voxel`, in voxels.
+    pub fn voxel(voxel: na::Point3<u32>) -> Self {
+        let min = voxel.cast::<f32>() * VOXEL_SIZE;
+        Self::new(min, min + na::Vector3::repeat(VOXEL_SIZE))
+    }
+
+    pub fn center(&self) -> na::Point3<f32> {
+        na::center(&self.min, &self.max)
+    }
+
+    pub fn half_extents(&self) -> na::Vector3<f32> {
+        (self.max - self.min) / 2.0
+    }
+
+    pub fn translated(&self, offset: na::Vector3<f32>) -> Self {
+        Self::new(self.min + offset, self.max + offset)
+    }
+
+    /// Smallest box holding both.
+    pub fn union(&self, other: &Self) -> Self {
+        Self::new(self.min.inf(&other.min), self.max.sup(&other.max))
+    }
+
+    /// Overlap with a positive volume, boxes sharing only a face don't overlap.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        (0..3).all(|axis| self.min[axis] < other.max[axis] && self.max[axis] > other.min[axis])
+    }
+
+    /// Voxels the box overlaps, clamped to `dims` voxels. `None` if none are left.
+    fn voxel_range(&self, dims: na::Vector3<u32>) -> Option<(na::Point3<u32>, na::Point3<u32>)> {
+        let min = self.min.map(|c| (c * BRICK_SIZE as f32).floor());
+        let max = self.max.map(|c| (c * BRICK_SIZE as f32).ceil());
+        let mut low = na::Point3::origin();
+        let mut high = na::Point3::origin();
+        for axis in 0..3 {
+            low[axis] = min[axis].max(0.0) as u32;
+            high[axis] = max[axis].clamp(0.0, dims[axis] as f32) as u32;
+            if low[axis] >= high[axis] {
+                return None;
+            }
+        }
+        Some((low, high))
+    }
+}
+
+/// First solid voxel a swept box runs into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+    /// Fraction of the velocity travelled before the contact, in `0..=1`.
+    pub time: f32,
+    /// Surface normal of the voxel at the contact, a unit axis.
+    pub normal: na::Vector3<f32>,
+    pub voxel: na::Point3<u32>,
+}
+
+impl BrickMapView<'_> {
+    /// Calls `f` with every solid voxel the box overlaps until it returns false, empty bricks
+    /// are skipped without looking at their voxels and LOD handles are solid throughout.
+    fn visit_solid<F>(&self, aabb: &Aabb, mut f: F)
+    where
+        F: FnMut(na::Point3<u32>) -> bool,
+    {
+        let Some((low, high)) = aabb.voxel_range(self.dimensions() * BRICK_SIZE) else {
+            return;
+        };
+        let brick_low = low / BRICK_SIZE;
+        let brick_high = (high - na::Vector3::repeat(1)) / BRICK_SIZE;
+
+        for bz in brick_low.z..=brick_high.z {
+            for by in brick_low.y..=brick_high.y {
+                for bx in brick_low.x..=brick_high.x {
+                    let brick = na::Point3::new(bx, by, bz);
+                    let handle = self.get_handle(brick);
+                    if !handle.is_data() && !handle.is_lod() {
+                        continue;
+                    }
+                    let trace = self.get_brick(handle);
+
+                    // voxels of the range inside this brick
+                    let origin = brick * BRICK_SIZE;
+                    let from = low.coords.sup(&origin.coords);
+                    let to = high
+                        .coords
+                        .inf(&(origin.coords + na::Vector3::repeat(BRICK_SIZE)));
+                    for z in from.z..to.z {
+                        for y in from.y..to.y {
+                            for x in from.x..to.x {
+                                let solid = trace.is_none_or(|trace| {
+                                    trace.get(x - origin.x, y - origin.y, z - origin.z)
+                                });
+                                if solid && !f(na::Point3::new(x, y, z)) {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl BrickMap {
+    /// Whether the box overlaps any solid voxel, the outside of the map is empty.
+    pub fn overlaps_solid(&self, aabb: &Aabb) -> bool {
+        let mut hit = false;
+        self.read().visit_solid(aabb, |_| {
+            hit = true;
+            false
+        });
+        hit
+    }
+
+    /// Solid voxels the box overlaps, in voxels.
+    pub fn solid_voxels(&self, aabb: &Aabb) -> Vec<na::Point3<u32>> {
+        let mut voxels = Vec::new();
+        self.read().visit_solid(aabb, |voxel| {
+            voxels.push(voxel);
+            true
+        });
+        voxels
+    }
+
+    /// Moves the box along `velocity` and returns the first solid voxel it runs into.
+    ///
+    /// Voxels the box already overlaps at the start are ignored so it can always move out of
+    /// them. Touching a face counts as contact once the box moves into it, sliding along one
+    /// doesn't. Ties resolve towards y, so landing exactly on an edge reports the floor rather
+    /// than the wall.
+    pub fn sweep_aabb(&self, aabb: &Aabb, velocity: na::Vector3<f32>) -> Option<Contact> {
+        let swept = aabb.union(&aabb.translated(velocity));
+        let mut best: Option<Contact> = None;
+
+        self.read().visit_solid(&swept, |voxel| {
+            let target = Aabb::voxel(voxel);
+            if aabb.overlaps(&target) {
+                return true;
+            }
+
+            let mut entry = f32::NEG_INFINITY;
+            let mut exit = f32::INFINITY;
+            let mut normal = na::Vector3::zeros();
+            for axis in 0..3 {
+                let v = velocity[axis];
+                if v == 0.0 {
+                    if aabb.min[axis] >= target.max[axis] || aabb.max[axis] <= target.min[axis] {
+                        return true;
+                    }
+                    continue;
+                }
+
+                let (enter, leave) = if v > 0.0 {
+                    (
+                        target.min[axis] - aabb.max[axis],
+                        target.max[axis] - aabb.min[axis],
+                    )
+                } else {
+                    (
+                        target.max[axis] - aabb.min[axis],
+                        target.min[axis] - aabb.max[axis],
+                    )
+                };
+                let (enter, leave) = (enter / v, leave / v);
+                if enter > entry || (enter == entry && axis == 1) {
+                    entry = enter;
+                    normal = na::Vector3::zeros();
+                    normal[axis] = -v.signum();
+                }
+                exit = exit.min(leave);
+            }
+
+            if entry < exit && (0.0..=1.0).contains(&entry) {
+                let closer = best.is_none_or(|best| {
+                    entry < best.time || (entry == best.time && normal.y > best.normal.y)
+                });
+                if closer {
+                    best = Some(Contact {
+                        time: entry,
+                        normal,
+                        voxel,
+                    });
+                }
+            }
+            true
+        });
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brick::TraceBrick, material::MaterialId};
+
+    /// One brick wide floor at brick y 0 and a single voxel at the corner of four bricks.
+    fn map() -> BrickMap {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let mut floor = TraceBrick::empty();
+        for x in 0..8 {
+            for z in 0..8 {
+                floor.set(x, 7, z, true);
+            }
+        }
+        for x in 0..4 {
+            for z in 0..4 {
+                brickmap.set_brick(floor, na::Point3::new(x, 0, z));
+            }
+        }
+
+        let mut corner = TraceBrick::empty();
+        corner.set(7, 7, 7, true);
+        brickmap.set_brick(corner, na::Point3::new(1, 1, 1));
+        brickmap
+    }
+
+    fn cube(min: [f32; 3], size: f32) -> Aabb {
+        let min = na::Point3::from(min);
+        Aabb::new(min, min + na::Vector3::repeat(size))
+    }
+
+    #[test]
+    fn test_overlap_boundaries() {
+        let brickmap = map();
+
+        // the floor top is at y 1, touching it is no overlap
+        assert!(!brickmap.overlaps_solid(&cube([0.5, 1.0, 0.5], 0.5)));
+        assert!(brickmap.overlaps_solid(&cube([0.5, 0.99, 0.5], 0.5)));
+
+        // the corner voxel spans 1.875..2 on every axis, right at the boundary to bricks 2
+        let voxel = Aabb::voxel(na::Point3::new(15, 15, 15));
+        assert_eq!(
+            brickmap.solid_voxels(&voxel),
+            vec![na::Point3::new(15, 15, 15)]
+        );
+        assert!(!brickmap.overlaps_solid(&cube([2.0, 1.5, 1.5], 1.0)));
+        assert!(brickmap.overlaps_solid(&cube([1.99, 1.99, 1.99], 1.0)));
+        assert!(!brickmap.overlaps_solid(&cube([1.99, 2.0, 1.99], 1.0)));
+
+        // spanning four floor bricks at their shared corner
+        let voxels = brickmap.solid_voxels(&cube([0.9, 0.9, 0.9], 0.2));
+        assert_eq!(voxels.len(), 4);
+        assert!(voxels.iter().all(|v| v.y == 7 && (7..=8).contains(&v.x)));
+
+        // outside of the map is empty
+        assert!(!brickmap.overlaps_solid(&cube([-2.0, 0.0, -2.0], 1.5)));
+        assert!(brickmap.overlaps_solid(&cube([-2.0, 0.0, -2.0], 2.5)));
+    }
+
+    #[test]
+    fn test_lod_is_solid() {
+        let brickmap = BrickMap::new(na::Vector3::new(2, 2, 2));
+        brickmap.set_lod(na::Point3::new(1, 1, 1), MaterialId(1));
+
+        assert!(brickmap.overlaps_solid(&cube([1.9, 1.9, 1.9], 0.05)));
+        assert_eq!(
+            brickmap.solid_voxels(&cube([0.0, 0.0, 0.0], 2.0)).len(),
+            512
+        );
+        assert!(!brickmap.overlaps_solid(&cube([0.0, 0.0, 0.0], 1.0)));
+    }
+
+    #[test]
+    fn test_sweep() {
+        let brickmap = map();
+        let body = cube([0.2, 1.5, 0.2], 0.25);
+
+        // falling onto the floor
+        let contact = brickmap
+            .sweep_aabb(&body, na::Vector3::new(0.0, -1.0, 0.0))
+            .unwrap();
+        assert!((contact.time - 0.5).abs() < 1e-6);
+        assert_eq!(contact.normal, na::Vector3::new(0.0, 1.0, 0.0));
+
+        // resting on it, sliding is free and pushing down stops right away
+        let resting = body.translated(na::Vector3::new(0.0, -0.5, 0.0));
+        assert!(brickmap
+            .sweep_aabb(&resting, na::Vector3::new(2.0, 0.0, 1.0))
+            .is_none());
+        let pushed = brickmap
+            .sweep_aabb(&resting, na::Vector3::new(1.0, -0.1, 0.0))
+            .unwrap();
+        assert_eq!(pushed.time, 0.0);
+        assert_eq!(pushed.normal, na::Vector3::new(0.0, 1.0, 0.0));
+
+        // into the side of the corner voxel, across the brick boundary
+        let side = cube([1.0, 1.9, 1.9], 0.5);
+        let contact = brickmap
+            .sweep_aabb(&side, na::Vector3::new(1.0, 0.0, 0.0))
+            .unwrap();
+        assert!((contact.time - 0.375).abs() < 1e-6);
+        assert_eq!(contact.normal, na::Vector3::new(-1.0, 0.0, 0.0));
+        assert_eq!(contact.voxel, na::Point3::new(15, 15, 15));
+
+        // diagonally onto the exact edge of the voxel, the top wins the tie
+        let edge = cube([1.125, 2.25, 1.9], 0.5);
+        let contact = brickmap
+            .sweep_aabb(&edge, na::Vector3::new(0.5, -0.5, 0.0))
+            .unwrap();
+        assert!((contact.time - 0.5).abs() < 1e-6);
+        assert_eq!(contact.normal, na::Vector3::new(0.0, 1.0, 0.0));
+
+        // moving out of an overlapping voxel is not blocked
+        let inside = cube([1.8, 1.8, 1.8], 0.1);
+        assert!(brickmap
+            .sweep_aabb(&inside, na::Vector3::new(-0.5, 0.0, 0.0))
+            .is_none());
+    }
+}
@@ -0,0 +1,422 @@
+use winit::keyboard::KeyCode;
+
+use crate::{
+    collision::{Aabb, SKIN},
+    BrickMap, Input,
+};
+
+const VOXEL_SIZE: f32 = 1.0 / 8.0;
+
+/// Dimensions and tuning of a [`CharacterController`], in world units (one per brick).
+#[derive(Debug, Clone, Copy)]
+pub struct ControllerConfig {
+    pub half_width: f32,
+    pub height: f32,
+    pub crouch_height: f32,
+    /// Distance of the eyes below the top of the box.
+    pub eye_offset: f32,
+    /// Highest ledge that is walked onto without jumping.
+    pub step_height: f32,
+    pub walk_speed: f32,
+    pub crouch_speed: f32,
+    pub gravity: f32,
+    pub jump_speed: f32,
+    pub max_fall_speed: f32,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            half_width: 2.5 * VOXEL_SIZE,
+            height: 14.0 * VOXEL_SIZE,
+            crouch_height: 9.0 * VOXEL_SIZE,
+            eye_offset: 1.5 * VOXEL_SIZE,
+            step_height: VOXEL_SIZE,
+            walk_speed: 4.5,
+            crouch_speed: 2.0,
+            gravity: 25.0,
+            jump_speed: 7.5,
+            max_fall_speed: 50.0,
+        }
+    }
+}
+
+/// What the player wants to do in one tick.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ControllerInput {
+    /// Wanted horizontal direction, x to the right and y forward.
+    pub movement: na::Vector2<f32>,
+    pub jump: bool,
+    pub crouch: bool,
+}
+
+impl ControllerInput {
+    /// WASD to walk, space to jump and left shift to crouch, like flying up and down.
+    pub fn from_input(input: &Input) -> Self {
+        let mut movement = na::Vector2::zeros();
+        if input.pressing(KeyCode::KeyW) {
+            movement.y += 1.0;
+        }
+        if input.pressing(KeyCode::KeyS) {
+            movement.y -= 1.0;
+        }
+        if input.pressing(KeyCode::KeyD) {
+            movement.x += 1.0;
+        }
+        if input.pressing(KeyCode::KeyA) {
+            movement.x -= 1.0;
+        }
+
+        Self {
+            movement,
+            jump: input.pressing(KeyCode::Space),
+            crouch: input.pressing(KeyCode::ShiftLeft),
+        }
+    }
+}
+
+/// Walking player resolving its box against a [`BrickMap`], +y is up.
+///
+/// Every tick only depends on the state, the input and `dt`, so a fixed timestep replays
+/// exactly.
+#[derive(Debug, Clone)]
+pub struct CharacterController {
+    /// Center of the bottom of the box.
+    pub position: na::Point3<f32>,
+    pub velocity: na::Vector3<f32>,
+    pub on_ground: bool,
+    pub crouching: bool,
+    pub config: ControllerConfig,
+}
+
+impl CharacterController {
+    pub fn new(position: na::Point3<f32>, config: ControllerConfig) -> Self {
+        Self {
+            position,
+            velocity: na::Vector3::zeros(),
+            on_ground: false,
+            crouching: false,
+            config,
+        }
+    }
+
+    /// Places the controller so its eyes are at `eye`.
+    pub fn from_eye(eye: na::Point3<f32>, config: ControllerConfig) -> Self {
+        let position = eye - na::Vector3::y() * (config.height - config.eye_offset);
+        Self::new(position, config)
+    }
+
+    pub fn height(&self) -> f32 {
+        if self.crouching {
+            self.config.crouch_height
+        } else {
+            self.config.height
+        }
+    }
+
+    pub fn eye(&self) -> na::Point3<f32> {
+        self.position + na::Vector3::y() * (self.height() - self.config.eye_offset)
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        Self::aabb_at(self.position, self.config.half_width, self.height())
+    }
+
+    fn aabb_at(position: na::Point3<f32>, half_width: f32, height: f32) -> Aabb {
+        let extent = na::Vector3::new(half_width, 0.0, half_width);
+        Aabb::new(
+            position - extent,
+            position + extent + na::Vector3::y() * height,
+        )
+    }
+
+    /// Advances by `dt`. `forward` is the looking direction, only its horizontal part is used.
+    pub fn tick(
+        &mut self,
+        brickmap: &BrickMap,
+        input: &ControllerInput,
+        forward: na::Vector3<f32>,
+        dt: f32,
+    ) {
+        self.update_crouch(brickmap, input.crouch);
+
+        let forward = na::Vector3::new(forward.x, 0.0, forward.z)
+            .try_normalize(1e-6)
+            .unwrap_or(na::Vector3::z());
+        // same handedness as the camera, whose local up is world -y
+        let right = na::Vector3::new(forward.z, 0.0, -forward.x);
+        let wish = (right * input.movement.x + forward * input.movement.y)
+            .try_normalize(1e-6)
+            .unwrap_or(na::Vector3::zeros());
+        let speed = if self.crouching {
+            self.config.crouch_speed
+        } else {
+            self.config.walk_speed
+        };
+        self.velocity.x = wish.x * speed;
+        self.velocity.z = wish.z * speed;
+
+        if input.jump && self.on_ground && !self.crouching {
+            self.velocity.y = self.config.jump_speed;
+        }
+        self.velocity.y =
+            (self.velocity.y - self.config.gravity * dt).max(-self.config.max_fall_speed);
+
+        let delta = self.velocity * dt;
+        let mut aabb = self.aabb();
+
+        // vertical first, so the ground contact is known before stepping
+        let (moved, hit) = Self::move_axis(brickmap, aabb, 1, delta.y);
+        aabb = moved;
+        if hit {
+            self.on_ground = delta.y < 0.0;
+            self.velocity.y = 0.0;
+        } else {
+            self.on_ground = false;
+        }
+
+        let horizontal = na::Vector2::new(delta.x, delta.z);
+        let (mut moved, blocked) = Self::slide(brickmap, aabb, horizontal);
+        if blocked && self.on_ground {
+            if let Some(stepped) = self.step_up(brickmap, aabb, horizontal) {
+                let progress = |to: &Aabb| (to.min - aabb.min).xz().norm_squared();
+                if progress(&stepped) > progress(&moved) {
+                    moved = stepped;
+                }
+            }
+        }
+        // walls stop the velocity into them
+        let travelled = moved.min - aabb.min;
+        if (travelled.x - delta.x).abs() > 1e-6 {
+            self.velocity.x = 0.0;
+        }
+        if (travelled.z - delta.z).abs() > 1e-6 {
+            self.velocity.z = 0.0;
+        }
+
+        self.position = na::Point3::new(
+            (moved.min.x + moved.max.x) / 2.0,
+            moved.min.y,
+            (moved.min.z + moved.max.z) / 2.0,
+        );
+    }
+
+    /// Crouches right away, stands back up once there is room above.
+    fn update_crouch(&mut self, brickmap: &BrickMap, crouch: bool) {
+        if crouch {
+            self.crouching = true;
+        } else if self.crouching {
+            let standing = Self::aabb_at(self.position, self.config.half_width, self.config.height);
+            if !brickmap.overlaps_solid(&standing) {
+                self.crouching = false;
+            }
+        }
+    }
+
+    /// Moves along one axis up to the first contact, returns whether one was hit.
+    fn move_axis(brickmap: &BrickMap, aabb: Aabb, axis: usize, distance: f32) -> (Aabb, bool) {
+        if distance == 0.0 {
+            return (aabb, false);
+        }
+        let mut velocity = na::Vector3::zeros();
+        velocity[axis] = distance;
+
+        match brickmap.sweep_aabb(&aabb, velocity) {
+            Some(contact) => {
+                let free = (contact.time * distance.abs() - SKIN).max(0.0);
+                velocity[axis] = free * distance.signum();
+                (aabb.translated(velocity), true)
+            }
+            None => (aabb.translated(velocity), false),
+        }
+    }
+
+    /// Moves along x then z, sliding along whatever blocks one of them.
+    fn slide(brickmap: &BrickMap, aabb: Aabb, delta: na::Vector2<f32>) -> (Aabb, bool) {
+        let (aabb, blocked_x) = Self::move_axis(brickmap, aabb, 0, delta.x);
+        let (aabb, blocked_z) = Self::move_axis(brickmap, aabb, 2, delta.y);
+        (aabb, blocked_x || blocked_z)
+    }
+
+    /// Retries a blocked move lifted by the step height and puts the box back down after.
+    fn step_up(&self, brickmap: &BrickMap, aabb: Aabb, delta: na::Vector2<f32>) -> Option<Aabb> {
+        let lift = self.config.step_height + SKIN;
+        let (raised, ceiling) = Self::move_axis(brickmap, aabb, 1, lift);
+        if ceiling {
+            return None;
+        }
+        let (moved, _) = Self::slide(brickmap, raised, delta);
+        let (lowered, landed) = Self::move_axis(brickmap, moved, 1, -lift);
+        // only a step if there is ground under it
+        landed.then_some(lowered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::TraceBrick;
+
+    const DT: f32 = 1.0 / 60.0;
+
+    /// Flat floor with its top at y 1, on a 4 x 4 x 4 brick map.
+    fn floor() -> BrickMap {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let mut full = TraceBrick::empty();
+        full.data_mut().fill(0xFF);
+        for x in 0..4 {
+            for z in 0..4 {
+                brickmap.set_brick(full, na::Point3::new(x, 0, z));
+            }
+        }
+        brickmap
+    }
+
+    /// Sets the voxels `min..max` of the brick at `brick`.
+    fn add_voxels(brickmap: &BrickMap, brick: [u32; 3], min: [u32; 3], max: [u32; 3]) {
+        let at = na::Point3::from(brick);
+        let mut trace = brickmap
+            .get_brick(brickmap.get_handle(at))
+            .unwrap_or(TraceBrick::empty());
+        for x in min[0]..max[0] {
+            for y in min[1]..max[1] {
+                for z in min[2]..max[2] {
+                    trace.set(x, y, z, true);
+                }
+            }
+        }
+        brickmap.set_brick(trace, at);
+    }
+
+    fn run(
+        brickmap: &BrickMap,
+        controller: &mut CharacterController,
+        input: ControllerInput,
+        ticks: usize,
+    ) {
+        for _ in 0..ticks {
+            controller.tick(brickmap, &input, na::Vector3::x(), DT);
+        }
+    }
+
+    fn forward() -> ControllerInput {
+        ControllerInput {
+            movement: na::Vector2::new(0.0, 1.0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_falls_lands_and_jumps() {
+        let brickmap = floor();
+        let mut controller =
+            CharacterController::new(na::Point3::new(1.5, 2.0, 1.5), Default::default());
+
+        run(&brickmap, &mut controller, Default::default(), 60);
+        assert!(controller.on_ground);
+        assert!((controller.position.y - 1.0).abs() < 1e-3);
+        assert_eq!(controller.velocity.y, 0.0);
+
+        let jump = ControllerInput {
+            jump: true,
+            ..Default::default()
+        };
+        run(&brickmap, &mut controller, jump, 1);
+        assert!(!controller.on_ground);
+        let mut peak = controller.position.y;
+        for _ in 0..120 {
+            run(&brickmap, &mut controller, Default::default(), 1);
+            peak = peak.max(controller.position.y);
+        }
+        let expected = 7.5f32.powi(2) / (2.0 * 25.0);
+        assert!((peak - 1.0 - expected).abs() < 0.15, "peak {peak}");
+        assert!(controller.on_ground);
+    }
+
+    #[test]
+    fn test_steps_and_walls() {
+        let brickmap = floor();
+        // a single voxel ledge at x 2.., a wall of two voxels at x 3..
+        for z in 0..4 {
+            add_voxels(&brickmap, [2, 1, z], [0, 0, 0], [8, 1, 8]);
+            add_voxels(&brickmap, [3, 1, z], [0, 0, 0], [8, 3, 8]);
+        }
+
+        let mut controller =
+            CharacterController::new(na::Point3::new(1.5, 1.0, 1.5), Default::default());
+        run(&brickmap, &mut controller, forward(), 20);
+        assert!(controller.position.x > 2.0);
+        assert!((controller.position.y - 1.125).abs() < 1e-3);
+
+        // the wall is higher than a step, walking into it slides along it
+        let diagonal = ControllerInput {
+            movement: na::Vector2::new(1.0, 1.0),
+            ..Default::default()
+        };
+        let z = controller.position.z;
+        run(&brickmap, &mut controller, diagonal, 20);
+        assert!((controller.aabb().max.x - 3.0).abs() < 1e-3);
+        assert!(controller.position.z < z - 0.5);
+        assert_eq!(controller.velocity.x, 0.0);
+    }
+
+    #[test]
+    fn test_crouch_under_ceiling() {
+        let brickmap = floor();
+        // a ceiling at 1.25 above the floor from x 2
+        for x in 2..4 {
+            for z in 0..4 {
+                add_voxels(&brickmap, [x, 2, z], [0, 2, 0], [8, 3, 8]);
+            }
+        }
+
+        let mut controller =
+            CharacterController::new(na::Point3::new(1.5, 1.0, 1.5), Default::default());
+        run(&brickmap, &mut controller, forward(), 30);
+        assert!(controller.aabb().max.x <= 2.0 + 1e-3);
+
+        let crouched = ControllerInput {
+            crouch: true,
+            ..forward()
+        };
+        run(&brickmap, &mut controller, crouched, 30);
+        assert!(controller.position.x > 2.5);
+
+        // there is no room to stand up
+        run(&brickmap, &mut controller, Default::default(), 5);
+        assert!(controller.crouching);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let brickmap = floor();
+        add_voxels(&brickmap, [2, 1, 1], [0, 0, 0], [8, 1, 8]);
+        let inputs = [
+            forward(),
+            ControllerInput {
+                movement: na::Vector2::new(-0.3, 1.0),
+                jump: true,
+                crouch: false,
+            },
+            ControllerInput {
+                movement: na::Vector2::new(1.0, 0.0),
+                jump: false,
+                crouch: true,
+            },
+        ];
+
+        let replay = || {
+            let mut controller =
+                CharacterController::new(na::Point3::new(1.2, 1.6, 1.3), Default::default());
+            for tick in 0..300 {
+                let input = inputs[(tick / 25) % inputs.len()];
+                controller.tick(&brickmap, &input, na::Vector3::new(1.0, -0.4, 0.2), DT);
+            }
+            (controller.position, controller.velocity)
+        };
+
+        let (position, velocity) = replay();
+        assert_eq!(replay(), (position, velocity));
+        assert_eq!(position.x.to_bits(), replay().0.x.to_bits());
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    brick::{BrickMap, MaterialBrick, TraceBrick},
+    material::MaterialId,
+    palette::PaletteRegistry,
+    raytrace::{intersect_box, Ray},
+};
+
+/// Either a node offset or a uniform material, `MaterialId::EMPTY` doubles as the empty handle.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DagHandle(pub u32);
+
+impl DagHandle {
+    pub const EMPTY: Self = Self(0);
+    const NODE_BIT: u32 = 1 << 31;
+    const VALUE_MASK: u32 = 0x7FFF_FFFF;
+
+    pub fn node(offset: u32) -> Self {
+        Self(Self::NODE_BIT | offset)
+    }
+
+    pub fn material(material: MaterialId) -> Self {
+        Self(material.0 & Self::VALUE_MASK)
+    }
+
+    pub fn is_node(&self) -> bool {
+        (self.0 & Self::NODE_BIT) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == Self::EMPTY.0
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.0 & Self::VALUE_MASK
+    }
+
+    pub fn get_material(&self) -> MaterialId {
+        if self.is_node() {
+            MaterialId::EMPTY
+        } else {
+            MaterialId(self.0)
+        }
+    }
+}
+
+/// Inner node with 2x2x2 children, indexed `x + y * 2 + z * 4`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DagNode {
+    pub children: [DagHandle; 8],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DagHit {
+    /// Distance in world units (bricks), like [`crate::raytrace::RayHit`].
+    pub distance: f32,
+    pub normal: na::Vector3<f32>,
+    /// Voxel relative to the dag origin.
+    pub voxel: na::Point3<u32>,
+    pub material: MaterialId,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DagStats {
+    /// Voxels per axis.
+    pub size: u32,
+    /// Unique nodes after merging.
+    pub nodes: usize,
+    /// Nodes the same region needs as a plain sparse octree.
+    pub tree_nodes: usize,
+    pub dag_bytes: usize,
+    /// Bytes the region used before conversion, handles plus unique trace and material bricks
+    /// when built from a brickmap, one [`MaterialId`] per voxel otherwise.
+    pub source_bytes: usize,
+}
+
+impl DagStats {
+    pub fn compression_ratio(&self) -> f32 {
+        self.source_bytes as f32 / self.dag_bytes.max(1) as f32
+    }
+
+    /// How many octree nodes every stored node stands in for.
+    pub fn merge_ratio(&self) -> f32 {
+        self.tree_nodes as f32 / self.nodes.max(1) as f32
+    }
+}
+
+/// Sparse voxel DAG over a cubic region, identical subtrees are stored once.
+///
+/// Meant for static far-field terrain: it is built once from a [`BrickMap`] region or a sampler
+/// and never edited, changes need a rebuild. Only the CPU side exists so far, building, point and
+/// ray queries and the compression stats. The renderer doesn't trace it and building leaves the
+/// source bricks in place, so it doesn't yet let more of the world stay resident.
+#[derive(Debug, Clone)]
+pub struct VoxelDag {
+    pub root: DagHandle,
+    pub nodes: Vec<DagNode>,
+    size: u32,
+    tree_nodes: usize,
+    source_bytes: usize,
+}
+
+struct DagBuilder {
+    nodes: Vec<DagNode>,
+    lookup: HashMap<DagNode, DagHandle>,
+    tree_nodes: usize,
+}
+
+impl DagBuilder {
+    fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            lookup: HashMap::new(),
+            tree_nodes: 0,
+        }
+    }
+
+    fn insert(&mut self, node: DagNode) -> DagHandle {
+        let first = node.children[0];
+        if !first.is_node() && node.children.iter().all(|&child| child == first) {
+            return first;
+        }
+
+        self.tree_nodes += 1;
+        let nodes = &mut self.nodes;
+        *self.lookup.entry(node).or_insert_with(|| {
+            let handle = DagHandle::node(nodes.len() as u32);
+            nodes.push(node);
+            handle
+        })
+    }
+
+    /// `uniform` may short cut whole subtrees, it gets the node origin and size in voxels.
+    fn build<S, U>(
+        &mut self,
+        origin: na::Point3<u32>,
+        size: u32,
+        sample: &S,
+        uniform: &U,
+    ) -> DagHandle
+    where
+        S: Fn(na::Point3<u32>) -> MaterialId,
+        U: Fn(na::Point3<u32>, u32) -> Option<MaterialId>,
+    {
+        if let Some(material) = uniform(origin, size) {
+            return DagHandle::material(material);
+        }
+        if size == 1 {
+            return DagHandle::material(sample(origin));
+        }
+
+        let half = size / 2;
+        let mut node = DagNode {
+            children: [DagHandle::EMPTY; 8],
+        };
+        for (i, child) in node.children.iter_mut().enumerate() {
+            let i = i as u32;
+            let offset = na::Vector3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1) * half;
+            *child = self.build(origin + offset, half, sample, uniform);
+        }
+        self.insert(node)
+    }
+}
+
+impl VoxelDag {
+    /// Builds a dag of at least `size` voxels per axis, rounded up to a power of two.
+    /// `sample` gets region local voxel positions.
+    pub fn build<F>(size: u32, sample: F) -> Self
+    where
+        F: Fn(na::Point3<u32>) -> MaterialId,
+    {
+        let size = size.max(1).next_power_of_two();
+        let mut builder = DagBuilder::new();
+        let root = builder.build(na::Point3::origin(), size, &sample, &|_, _| None);
+        let source_bytes = (size as usize).pow(3) * std::mem::size_of::<MaterialId>();
+        Self::from_builder(builder, root, size, source_bytes)
+    }
+
+    /// Builds a dag from `bricks` bricks per axis starting at brick position `origin`.
+    /// Bricks outside of the brickmap are treated as air.
+    pub fn from_brickmap(
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        origin: na::Point3<u32>,
+        bricks: u32,
+    ) -> Self {
+        let view = brickmap.read();
+        let dims = view.dimensions();
+        let size = (bricks.max(1) * 8).next_power_of_two();
+        let voxel_origin = origin.coords * 8;
+        let brick_handle = |at: na::Point3<u32>| {
+            let brick = at.map(|c| c / 8) + origin.coords;
+            let inside = brick.iter().zip(dims.iter()).all(|(&b, &d)| b < d)
+                && at.iter().all(|&c| c < bricks * 8);
+            inside.then(|| view.get_handle(brick))
+        };
+
+        // Skip air and LOD bricks without sampling each of their voxels.
+        let uniform = |at: na::Point3<u32>, size: u32| {
+            if size > 8 {
+                return None;
+            }
+            match brick_handle(at) {
+                None => Some(MaterialId::EMPTY),
+                Some(handle) if handle.is_lod() => Some(MaterialId(handle.get_empty_value())),
+                Some(handle) if !handle.is_data() => Some(MaterialId::EMPTY),
+                Some(_) => None,
+            }
+        };
+        let sample = |at: na::Point3<u32>| view.get_voxel(palettes, at + voxel_origin);
+
+        let mut builder = DagBuilder::new();
+        let root = builder.build(na::Point3::origin(), size, &sample, &uniform);
+
+        // deduplicated bricks are only counted once, like they are stored
+        let mut slots = HashSet::new();
+        let mut source_bytes = 0;
+        for z in 0..bricks {
+            for y in 0..bricks {
+                for x in 0..bricks {
+                    let Some(handle) = brick_handle(na::Point3::new(x, y, z) * 8) else {
+                        continue;
+                    };
+                    source_bytes += std::mem::size_of_val(&handle);
+                    if !handle.is_data() || !slots.insert(handle.get_data_value()) {
+                        continue;
+                    }
+                    if let Some(brick) = view.get_material_brick(handle) {
+                        source_bytes += std::mem::size_of::<TraceBrick>();
+                        source_bytes += material_brick_bytes(brick);
+                    }
+                }
+            }
+        }
+
+        Self::from_builder(builder, root, size, source_bytes)
+    }
+
+    fn from_builder(builder: DagBuilder, root: DagHandle, size: u32, source_bytes: usize) -> Self {
+        Self {
+            root,
+            nodes: builder.nodes,
+            size,
+            tree_nodes: builder.tree_nodes,
+            source_bytes,
+        }
+    }
+
+    /// Voxels per axis.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn stats(&self) -> DagStats {
+        DagStats {
+            size: self.size,
+            nodes: self.nodes.len(),
+            tree_nodes: self.tree_nodes,
+            dag_bytes: std::mem::size_of_val(self.nodes.as_slice())
+                + std::mem::size_of::<DagHandle>(),
+            source_bytes: self.source_bytes,
+        }
+    }
+
+    /// Material at a region local voxel position.
+    pub fn get(&self, at: na::Point3<u32>) -> MaterialId {
+        if at.iter().any(|&c| c >= self.size) {
+            return MaterialId::EMPTY;
+        }
+
+        let mut handle = self.root;
+        let mut size = self.size;
+        while handle.is_node() {
+            size /= 2;
+            let local = at.map(|c| (c / size) & 1);
+            let index = (local.x + local.y * 2 + local.z * 4) as usize;
+            handle = self.nodes[handle.offset() as usize].children[index];
+        }
+
+        handle.get_material()
+    }
+
+    /// Traces a ray given in brick space against the dag placed at brick position `origin`.
+    pub fn trace(&self, origin: na::Point3<u32>, ray: &Ray) -> Option<DagHit> {
+        // work in region local voxel space, so every node has integer bounds
+        let local = Ray {
+            origin: na::Point3::from((ray.origin - origin.cast::<f32>()) * 8.0),
+            max_distance: ray.max_distance * 8.0,
+            ..*ray
+        };
+
+        let size = self.size as f32;
+        let (t_near, t_far, axis) =
+            intersect_box(&local, na::Vector3::zeros(), na::Vector3::repeat(size));
+        let t_far = t_far.min(local.max_distance);
+        if t_near > t_far || t_far < 0.0 {
+            return None;
+        }
+
+        let (t, entry_axis) = if t_near > 0.0 {
+            (t_near, Some(axis))
+        } else {
+            (0.0, None)
+        };
+
+        let mut hit = self.trace_node(
+            &local,
+            self.root,
+            na::Point3::origin(),
+            self.size,
+            t,
+            t_far,
+            entry_axis,
+        )?;
+        hit.distance /= 8.0;
+        Some(hit)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn trace_node(
+        &self,
+        ray: &Ray,
+        handle: DagHandle,
+        origin: na::Point3<u32>,
+        size: u32,
+        t_enter: f32,
+        t_exit: f32,
+        entry_axis: Option<usize>,
+    ) -> Option<DagHit> {
+        if handle.is_empty() {
+            return None;
+        }
+
+        if !handle.is_node() {
+            let mut normal = na::Vector3::zeros();
+            if let Some(axis) = entry_axis {
+                normal[axis] = -ray.direction[axis].signum();
+            }
+            let position = ray.at(t_enter + 1e-4);
+            let voxel = origin.coords.zip_map(&position.coords, |min, p| {
+                (p.floor().max(0.0) as u32).clamp(min, min + size - 1)
+            });
+            return Some(DagHit {
+                distance: t_enter,
+                normal,
+                voxel: voxel.into(),
+                material: handle.get_material(),
+            });
+        }
+
+        let node = &self.nodes[handle.offset() as usize];
+        let half = size / 2;
+        let mut order = [(0.0f32, 0.0f32, 0usize, None); 8];
+        let mut count = 0;
+
+        for (i, child) in node.children.iter().enumerate() {
+            if child.is_empty() {
+                continue;
+            }
+            let i = i as u32;
+            let min = origin + na::Vector3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1) * half;
+            let (t_near, t_far, axis) = intersect_box(
+                ray,
+                min.coords.cast::<f32>(),
+                (min.coords + na::Vector3::repeat(half)).cast::<f32>(),
+            );
+            let t0 = t_near.max(t_enter);
+            let t1 = t_far.min(t_exit);
+            // children only touched along an edge or corner are skipped
+            if t0 < t1 {
+                let axis = if t_near >= t_enter {
+                    Some(axis)
+                } else {
+                    entry_axis
+                };
+                order[count] = (t0, t1, i as usize, axis);
+                count += 1;
+            }
+        }
+
+        let order = &mut order[..count];
+        order.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+
+        for &(t0, t1, i, axis) in order.iter() {
+            let i = i as u32;
+            let min = origin + na::Vector3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1) * half;
+            let hit = self.trace_node(ray, node.children[i as usize], min, half, t0, t1, axis);
+            if hit.is_some() {
+                return hit;
+            }
+        }
+
+        None
+    }
+}
+
+/// Bytes a packed material brick takes, meta word included.
+fn material_brick_bytes(brick: &MaterialBrick) -> usize {
+    std::mem::size_of::<u32>() + brick.data().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brick::ExpandedBrick, material::ExpandedMaterialMapping, raytrace::trace_ray};
+
+    fn scene(palettes: &PaletteRegistry) -> BrickMap {
+        let mut mapping = ExpandedMaterialMapping::new();
+        for i in 0..4 {
+            mapping.insert(i, MaterialId(i as u32));
+        }
+
+        let mut stone = ExpandedBrick::empty();
+        let mut surface = ExpandedBrick::empty();
+        for z in 0..8 {
+            for y in 0..8 {
+                for x in 0..8 {
+                    stone.set(x, y, z, 2);
+                    let height = 3 + (x + z) % 3;
+                    let value = match y {
+                        y if y < height => 3,
+                        y if y == height => 1,
+                        _ => 0,
+                    };
+                    surface.set(x, y, z, value);
+                }
+            }
+        }
+
+        let brickmap = BrickMap::new(na::Vector3::new(16, 16, 16));
+        for z in 0..16 {
+            for x in 0..16 {
+                for y in 0..3 {
+                    brickmap.set_expanded_brick(
+                        na::Point3::new(x, y, z),
+                        &stone,
+                        &mapping,
+                        palettes,
+                    );
+                }
+                brickmap.set_expanded_brick(na::Point3::new(x, 3, z), &surface, &mapping, palettes);
+            }
+        }
+        brickmap.set_lod(na::Point3::new(5, 6, 5), MaterialId(1));
+        brickmap
+    }
+
+    #[test]
+    fn test_uniform_collapses() {
+        let dag = VoxelDag::build(64, |_| MaterialId(3));
+        assert_eq!(dag.root, DagHandle::material(MaterialId(3)));
+        assert!(dag.nodes.is_empty());
+        assert_eq!(dag.get(na::Point3::new(10, 20, 30)), MaterialId(3));
+    }
+
+    #[test]
+    fn test_brickmap_round_trip_and_ratio() {
+        let palettes = PaletteRegistry::new();
+        let brickmap = scene(&palettes);
+        let dag = VoxelDag::from_brickmap(&brickmap, &palettes, na::Point3::new(0, 0, 0), 16);
+
+        let view = brickmap.read();
+        for z in 0..128 {
+            for y in 0..64 {
+                for x in 0..128 {
+                    let at = na::Point3::new(x, y, z);
+                    assert_eq!(dag.get(at), view.get_voxel(&palettes, at), "{:?}", at);
+                }
+            }
+        }
+
+        let stats = dag.stats();
+        assert!(stats.tree_nodes > stats.nodes);
+        assert!(stats.compression_ratio() > 10.0, "{:?}", stats);
+    }
+
+    #[test]
+    fn test_trace_matches_brickmap() {
+        let palettes = PaletteRegistry::new();
+        let brickmap = scene(&palettes);
+        let dag = VoxelDag::from_brickmap(&brickmap, &palettes, na::Point3::new(0, 0, 0), 16);
+
+        let mut hits = 0;
+        for i in 0..256 {
+            let f = i as f32;
+            let ray = Ray::new(
+                na::Point3::new(1.0 + (f * 0.37) % 14.0, 9.0, 1.0 + (f * 0.53) % 14.0),
+                na::Vector3::new((f * 0.7).sin() * 0.6, -1.0, (f * 0.3).cos() * 0.6),
+            );
+            let expected = trace_ray(&brickmap, &ray);
+            let hit = dag.trace(na::Point3::origin(), &ray);
+            assert_eq!(expected.is_some(), hit.is_some(), "ray {}", i);
+
+            if let (Some(expected), Some(hit)) = (expected, hit) {
+                assert!(
+                    (expected.distance - hit.distance).abs() < 1e-3,
+                    "ray {} {:?} {:?}",
+                    i,
+                    expected,
+                    hit
+                );
+                hits += 1;
+            }
+        }
+        assert!(hits > 128);
+    }
+}
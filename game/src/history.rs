@@ -0,0 +1,520 @@
+use std::{collections::HashSet, collections::VecDeque, fmt, mem};
+
+use crate::{
+    brick::{BrickMap, BrickMapView, ExpandedBrick, TraceBrick, BRICK_SIZE},
+    brush::BrickEdit,
+    material::{ExpandedMaterialMapping, MaterialId},
+    palette::{PaletteId, PaletteRegistry},
+};
+
+/// A brick could not be captured, its material brick points at a palette that isn't registered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingPalette {
+    pub at: na::Point3<u32>,
+    pub palette: PaletteId,
+}
+
+impl fmt::Display for MissingPalette {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "brick {:?} uses unknown palette {:?}",
+            self.at, self.palette
+        )
+    }
+}
+
+impl std::error::Error for MissingPalette {}
+
+/// Content of one brick as it was captured, material bricks keep their packed form.
+#[derive(Debug, Clone)]
+pub enum BrickState {
+    Empty,
+    Lod(MaterialId),
+    /// Data brick without a material brick.
+    Occupancy(TraceBrick),
+    Voxels {
+        /// Bits per palette index, see [`crate::MaterialBrick::size_from_meta`].
+        bits: u8,
+        data: Box<[u8]>,
+        palette: Box<[MaterialId]>,
+    },
+}
+
+impl BrickState {
+    pub fn capture(
+        view: &BrickMapView,
+        palettes: &PaletteRegistry,
+        at: na::Point3<u32>,
+    ) -> Result<Self, MissingPalette> {
+        let handle = view.get_handle(at);
+        if handle.is_lod() {
+            return Ok(Self::Lod(MaterialId(handle.get_empty_value())));
+        }
+        if !handle.is_data() {
+            return Ok(Self::Empty);
+        }
+
+        let state = match view.get_material_brick(handle) {
+            Some(material) => {
+                let palette = PaletteId(material.meta_value());
+                let Some(materials) = palettes.get_palette(palette) else {
+                    return Err(MissingPalette { at, palette });
+                };
+                Self::Voxels {
+                    bits: material.size_from_meta() as u8,
+                    data: material.data().into(),
+                    palette: materials.into(),
+                }
+            }
+            None => Self::Occupancy(
+                view.get_brick(handle)
+                    .copied()
+                    .unwrap_or(TraceBrick::empty()),
+            ),
+        };
+        Ok(state)
+    }
+
+    /// Palette indices as voxel values and the palette as mapping, `None` for anything but
+    /// [`Self::Voxels`].
+    pub fn to_expanded(&self) -> Option<(ExpandedBrick, ExpandedMaterialMapping)> {
+        let Self::Voxels {
+            bits,
+            data,
+            palette,
+        } = self
+        else {
+            return None;
+        };
+
+        let bits = *bits as usize;
+        let mask = (1u16 << bits) - 1;
+        let mut expanded = ExpandedBrick::empty();
+        // values never straddle a byte since the packed words are little endian
+        for (i, voxel) in expanded.data_mut().iter_mut().enumerate() {
+            let bit = i * bits;
+            *voxel = ((data[bit / 8] as u16 >> (bit % 8)) & mask) as u8;
+        }
+
+        let mut mapping = ExpandedMaterialMapping::new();
+        mapping.insert(0, MaterialId::EMPTY);
+        for (index, material) in palette.iter().enumerate().skip(1) {
+            mapping.insert(index as u8, *material);
+        }
+
+        Some((expanded, mapping))
+    }
+
+    /// Like [`Self::to_expanded`], also turning [`Self::Occupancy`] into `material` wherever it is
+    /// solid, for maps that need a material for every voxel.
+    pub fn to_expanded_or(
+        &self,
+        material: MaterialId,
+    ) -> Option<(ExpandedBrick, ExpandedMaterialMapping)> {
+        let Self::Occupancy(trace) = self else {
+            return self.to_expanded();
+        };
+
+        let mut expanded = ExpandedBrick::empty();
+        for z in 0..BRICK_SIZE {
+            for y in 0..BRICK_SIZE {
+                for x in 0..BRICK_SIZE {
+                    if trace.get(x, y, z) {
+                        expanded.set(x, y, z, 1);
+                    }
+                }
+            }
+        }
+
+        let mut mapping = ExpandedMaterialMapping::new();
+        mapping.insert(0, MaterialId::EMPTY);
+        mapping.insert(1, material);
+        Some((expanded, mapping))
+    }
+
+    /// Heap and inline bytes the state takes in the history.
+    pub fn memory(&self) -> usize {
+        let heap = match self {
+            Self::Voxels { data, palette, .. } => {
+                data.len() + palette.len() * mem::size_of::<MaterialId>()
+            }
+            _ => 0,
+        };
+        mem::size_of::<BrickSnapshot>() + heap
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BrickSnapshot {
+    pub at: na::Point3<u32>,
+    pub state: BrickState,
+}
+
+/// A named group of edits, holding the state of every brick it touched from before it ran.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub name: String,
+    bricks: Vec<BrickSnapshot>,
+    recorded: HashSet<na::Point3<u32>>,
+    memory: usize,
+}
+
+impl Transaction {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            bricks: Vec::new(),
+            recorded: HashSet::new(),
+            memory: 0,
+        }
+    }
+
+    fn push(&mut self, snapshot: BrickSnapshot) {
+        self.memory += snapshot.state.memory();
+        self.bricks.push(snapshot);
+    }
+
+    pub fn len(&self) -> usize {
+        self.bricks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bricks.is_empty()
+    }
+
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+}
+
+/// Undo and redo stacks of world edits.
+///
+/// Edits are grouped with [`Self::begin`] and [`Self::commit`], every position is recorded with
+/// [`Self::record`] before it is written. Undo and redo hand back the bricks to write, through
+/// [`BrickMap::restore`] or its GPU counterpart, and keep the state they replace for the
+/// opposite direction. Once both stacks exceed `budget` bytes the oldest transactions are
+/// dropped, the latest one is always kept.
+#[derive(Debug)]
+pub struct EditHistory {
+    undo: VecDeque<Transaction>,
+    redo: Vec<Transaction>,
+    open: Option<Transaction>,
+    budget: usize,
+}
+
+impl EditHistory {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            open: None,
+            budget,
+        }
+    }
+
+    /// Starts a transaction, committing the open one.
+    pub fn begin(&mut self, name: &str) {
+        self.commit();
+        self.open = Some(Transaction::new(name));
+    }
+
+    /// Captures the current state of `positions` into the open transaction, positions it already
+    /// holds keep their first state. Starts an unnamed transaction when none is open. Records
+    /// nothing when a brick can't be captured, the edit shouldn't be applied then.
+    pub fn record(
+        &mut self,
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        positions: impl IntoIterator<Item = na::Point3<u32>>,
+    ) -> Result<(), MissingPalette> {
+        let transaction = self.open.get_or_insert_with(|| Transaction::new("Edit"));
+        let view = brickmap.read();
+        let mut snapshots = Vec::new();
+        for at in positions {
+            if !transaction.recorded.insert(at) {
+                continue;
+            }
+            match BrickState::capture(&view, palettes, at) {
+                Ok(state) => snapshots.push(BrickSnapshot { at, state }),
+                Err(err) => {
+                    transaction.recorded.remove(&at);
+                    for snapshot in snapshots.iter() {
+                        transaction.recorded.remove(&snapshot.at);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        for snapshot in snapshots {
+            transaction.push(snapshot);
+        }
+        Ok(())
+    }
+
+    pub fn record_edits(
+        &mut self,
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        edits: &[BrickEdit],
+    ) -> Result<(), MissingPalette> {
+        self.record(brickmap, palettes, edits.iter().map(|edit| edit.at))
+    }
+
+    /// Closes the open transaction, a new edit invalidates everything that could be redone.
+    pub fn commit(&mut self) {
+        let Some(transaction) = self.open.take() else {
+            return;
+        };
+        if transaction.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        self.undo.push_back(transaction);
+        self.trim();
+    }
+
+    /// Reverts the latest transaction, returns the bricks to write. The transaction stays on the
+    /// undo stack when the current state can't be captured for redoing it.
+    pub fn undo(
+        &mut self,
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+    ) -> Result<Option<Vec<BrickSnapshot>>, MissingPalette> {
+        self.commit();
+        let Some(transaction) = self.undo.back() else {
+            return Ok(None);
+        };
+        let inverse = Self::inverse(transaction, brickmap, palettes)?;
+        let transaction = self.undo.pop_back().unwrap();
+        self.redo.push(inverse);
+        self.trim();
+        Ok(Some(transaction.bricks))
+    }
+
+    /// Applies the latest undone transaction again, returns the bricks to write, see
+    /// [`Self::undo`].
+    pub fn redo(
+        &mut self,
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+    ) -> Result<Option<Vec<BrickSnapshot>>, MissingPalette> {
+        self.commit();
+        let Some(transaction) = self.redo.last() else {
+            return Ok(None);
+        };
+        let inverse = Self::inverse(transaction, brickmap, palettes)?;
+        let transaction = self.redo.pop().unwrap();
+        self.undo.push_back(inverse);
+        self.trim();
+        Ok(Some(transaction.bricks))
+    }
+
+    /// Captures the current state of the positions of `transaction`.
+    fn inverse(
+        transaction: &Transaction,
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+    ) -> Result<Transaction, MissingPalette> {
+        let mut inverse = Transaction::new(&transaction.name);
+        let view = brickmap.read();
+        for snapshot in transaction.bricks.iter() {
+            let state = BrickState::capture(&view, palettes, snapshot.at)?;
+            inverse.recorded.insert(snapshot.at);
+            inverse.push(BrickSnapshot {
+                at: snapshot.at,
+                state,
+            });
+        }
+        Ok(inverse)
+    }
+
+    /// Drops the oldest transactions until both stacks fit the budget, the redo entries furthest
+    /// away from the current state go once no undo is left.
+    fn trim(&mut self) {
+        while self.memory() > self.budget && self.undo.len() + self.redo.len() > 1 {
+            if self.undo.pop_front().is_none() {
+                self.redo.remove(0);
+            }
+        }
+    }
+
+    /// Forgets every transaction, for when the map was replaced as a whole.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.open = None;
+    }
+
+    pub fn memory(&self) -> usize {
+        self.undo
+            .iter()
+            .chain(self.redo.iter())
+            .chain(self.open.iter())
+            .map(Transaction::memory)
+            .sum()
+    }
+
+    pub fn undo_name(&self) -> Option<&str> {
+        self.undo.back().map(|t| t.name.as_str())
+    }
+
+    pub fn redo_name(&self) -> Option<&str> {
+        self.redo.last().map(|t| t.name.as_str())
+    }
+
+    pub fn undo_len(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn redo_len(&self) -> usize {
+        self.redo.len()
+    }
+}
+
+impl BrickMap {
+    /// Writes bricks handed back by [`EditHistory::undo`] and [`EditHistory::redo`].
+    pub fn restore(&self, bricks: &[BrickSnapshot], palettes: &PaletteRegistry) {
+        for snapshot in bricks {
+            match &snapshot.state {
+                BrickState::Empty => {
+                    self.set_empty(snapshot.at);
+                }
+                BrickState::Lod(material) => {
+                    self.set_lod(snapshot.at, *material);
+                }
+                BrickState::Occupancy(trace) => {
+                    self.set_brick(*trace, snapshot.at);
+                }
+                state @ BrickState::Voxels { .. } => {
+                    let (expanded, mapping) = state.to_expanded().unwrap();
+                    self.set_expanded_brick(snapshot.at, &expanded, &mapping, palettes);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brush::{Brush, BrushOp, Shape};
+
+    fn voxels(brickmap: &BrickMap, palettes: &PaletteRegistry) -> Vec<MaterialId> {
+        let dims = brickmap.dimensions() * 8;
+        let view = brickmap.read();
+        (0..dims.z)
+            .flat_map(|z| (0..dims.y).flat_map(move |y| (0..dims.x).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| view.get_voxel(palettes, na::Point3::new(x, y, z)))
+            .collect()
+    }
+
+    fn edit(
+        history: &mut EditHistory,
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        name: &str,
+        brush: Brush,
+    ) {
+        history.begin(name);
+        let edits = brush.plan(brickmap, palettes).edits;
+        history.record_edits(brickmap, palettes, &edits).unwrap();
+        brickmap.apply_edits(&edits, palettes);
+        history.commit();
+    }
+
+    #[test]
+    fn test_undo_redo_round_trip() {
+        let brickmap = BrickMap::new(na::Vector3::new(3, 3, 3));
+        let palettes = PaletteRegistry::new();
+        let mut history = EditHistory::new(usize::MAX);
+        brickmap.set_lod(na::Point3::new(1, 0, 1), MaterialId(3));
+
+        let initial = voxels(&brickmap, &palettes);
+        let sphere = Shape::Sphere {
+            center: na::Point3::new(1.5, 1.0, 1.5),
+            radius: 1.2,
+        };
+        edit(
+            &mut history,
+            &brickmap,
+            &palettes,
+            "Place",
+            Brush::new(sphere.clone(), BrushOp::Union, MaterialId(1)),
+        );
+        let placed = voxels(&brickmap, &palettes);
+        edit(
+            &mut history,
+            &brickmap,
+            &palettes,
+            "Paint",
+            Brush::new(sphere, BrushOp::Paint, MaterialId(2)),
+        );
+        let painted = voxels(&brickmap, &palettes);
+        assert_ne!(placed, painted);
+        assert_eq!(history.undo_name(), Some("Paint"));
+
+        let restore = history.undo(&brickmap, &palettes).unwrap().unwrap();
+        brickmap.restore(&restore, &palettes);
+        assert_eq!(voxels(&brickmap, &palettes), placed);
+
+        let restore = history.undo(&brickmap, &palettes).unwrap().unwrap();
+        brickmap.restore(&restore, &palettes);
+        assert_eq!(voxels(&brickmap, &palettes), initial);
+        assert!(brickmap.get_handle(na::Point3::new(1, 0, 1)).is_lod());
+        assert!(history.undo(&brickmap, &palettes).unwrap().is_none());
+
+        let restore = history.redo(&brickmap, &palettes).unwrap().unwrap();
+        brickmap.restore(&restore, &palettes);
+        assert_eq!(voxels(&brickmap, &palettes), placed);
+        assert_eq!(history.redo_name(), Some("Paint"));
+        assert!(brickmap.validate(&palettes).is_empty());
+    }
+
+    #[test]
+    fn test_budget_drops_oldest() {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 1, 1));
+        let palettes = PaletteRegistry::new();
+        let mut history = EditHistory::new(1);
+
+        for x in 0..4 {
+            let cube = Shape::Box {
+                center: na::Point3::new(x as f32 + 0.5, 0.5, 0.5),
+                half_extents: na::Vector3::repeat(0.5),
+            };
+            let brush = Brush::new(cube, BrushOp::Union, MaterialId(1));
+            edit(&mut history, &brickmap, &palettes, "Place", brush);
+        }
+
+        assert_eq!(history.undo_len(), 1);
+        let restore = history.undo(&brickmap, &palettes).unwrap().unwrap();
+        brickmap.restore(&restore, &palettes);
+        assert!(brickmap.is_empty(na::Point3::new(3, 0, 0)));
+        assert!(!brickmap.is_empty(na::Point3::new(2, 0, 0)));
+    }
+
+    #[test]
+    fn test_missing_palette_records_nothing() {
+        let brickmap = BrickMap::new(na::Vector3::new(2, 1, 1));
+        let palettes = PaletteRegistry::new();
+        let mut history = EditHistory::new(usize::MAX);
+        let cube = Shape::Box {
+            center: na::Point3::new(1.0, 0.5, 0.5),
+            half_extents: na::Vector3::repeat(0.75),
+        };
+        let brush = Brush::new(cube, BrushOp::Union, MaterialId(1));
+        edit(&mut history, &brickmap, &palettes, "Place", brush);
+
+        let unknown = PaletteRegistry::new();
+        let positions = [na::Point3::new(0, 0, 0), na::Point3::new(1, 0, 0)];
+        history.begin("Paint");
+        let err = history.record(&brickmap, &unknown, positions).unwrap_err();
+        assert_eq!(err.at, na::Point3::new(0, 0, 0));
+        history.commit();
+        assert_eq!(history.undo_name(), Some("Place"));
+
+        assert!(history.undo(&brickmap, &unknown).is_err());
+        assert_eq!(history.undo_len(), 1);
+        assert!(history.undo(&brickmap, &palettes).unwrap().is_some());
+    }
+}
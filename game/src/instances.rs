@@ -0,0 +1,504 @@
+use crate::{
+    brick::BrickMap,
+    collision::Aabb,
+    objects::{trace_volume, transform_box, volume_to_world},
+    raytrace::{intersect_box, Ray, RayHit},
+    Transform,
+};
+
+/// Most items kept in a single BVH leaf.
+const LEAF_SIZE: usize = 2;
+
+/// Node of a [`Bvh`]. Children of a node are stored next to each other.
+#[derive(Debug, Clone, Copy)]
+pub struct BvhNode {
+    pub bounds: Aabb,
+    /// Left child of an inner node, the right one follows it. First entry of
+    /// [`Bvh::indices`] for a leaf.
+    pub first: u32,
+    /// Items in a leaf, 0 for inner nodes.
+    pub count: u32,
+}
+
+impl BvhNode {
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+/// Bounding volume hierarchy over a list of boxes, split at the median of the longest axis.
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    /// Items in leaf order, leaves address ranges of it.
+    indices: Vec<u32>,
+}
+
+impl Bvh {
+    /// Builds the hierarchy over `bounds`, items are addressed by their index in it.
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            indices: (0..bounds.len() as u32).collect(),
+        };
+        if bounds.is_empty() {
+            return bvh;
+        }
+
+        let centroids: Vec<_> = bounds.iter().map(|b| b.center()).collect();
+        bvh.nodes.push(BvhNode {
+            bounds: bounds[0],
+            first: 0,
+            count: 0,
+        });
+        bvh.subdivide(bounds, &centroids, 0, 0, bounds.len());
+        bvh
+    }
+
+    fn subdivide(
+        &mut self,
+        bounds: &[Aabb],
+        centroids: &[na::Point3<f32>],
+        node: usize,
+        first: usize,
+        count: usize,
+    ) {
+        let items = &mut self.indices[first..first + count];
+        let (node_bounds, centroid_bounds) = items[1..].iter().fold(
+            (
+                bounds[items[0] as usize],
+                Aabb::new(centroids[items[0] as usize], centroids[items[0] as usize]),
+            ),
+            |(node_bounds, centroid_bounds), &i| {
+                let c = centroids[i as usize];
+                (
+                    node_bounds.union(&bounds[i as usize]),
+                    centroid_bounds.union(&Aabb::new(c, c)),
+                )
+            },
+        );
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = extent.imax();
+
+        // stacked items can't be told apart by splitting, keep them in one leaf
+        if count <= LEAF_SIZE || extent[axis] <= 0.0 {
+            self.nodes[node] = BvhNode {
+                bounds: node_bounds,
+                first: first as u32,
+                count: count as u32,
+            };
+            return;
+        }
+
+        let mid = count / 2;
+        items.select_nth_unstable_by(mid, |&a, &b| {
+            centroids[a as usize][axis].total_cmp(&centroids[b as usize][axis])
+        });
+
+        let left = self.nodes.len();
+        let placeholder = self.nodes[node];
+        self.nodes.extend([placeholder, placeholder]);
+        self.nodes[node] = BvhNode {
+            bounds: node_bounds,
+            first: left as u32,
+            count: 0,
+        };
+        self.subdivide(bounds, centroids, left, first, mid);
+        self.subdivide(bounds, centroids, left + 1, first + mid, count - mid);
+    }
+
+    pub fn nodes(&self) -> &[BvhNode] {
+        &self.nodes
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    /// Calls `f` with the items of every leaf overlapping `aabb`, a superset of the items
+    /// whose own box overlaps it.
+    pub fn query_aabb<F: FnMut(u32)>(&self, aabb: &Aabb, mut f: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node as usize];
+            if !node.bounds.overlaps(aabb) {
+                continue;
+            }
+            if node.is_leaf() {
+                let items = &self.indices[node.first as usize..(node.first + node.count) as usize];
+                items.iter().for_each(|&item| f(item));
+            } else {
+                stack.extend([node.first, node.first + 1]);
+            }
+        }
+    }
+
+    /// Calls `f` with the items of every leaf the ray enters before `ray.max_distance`, nearer
+    /// nodes first. `f` gets the distance the ray enters the leaf at and returns the new
+    /// maximum distance, so hits found on the way cull everything behind them.
+    pub fn query_ray<F: FnMut(u32, f32) -> f32>(&self, ray: &Ray, mut f: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let enter = |node: &BvhNode| {
+            let (near, far, _) = intersect_box(ray, node.bounds.min.coords, node.bounds.max.coords);
+            (near <= far && far >= 0.0).then_some(near.max(0.0))
+        };
+
+        let mut max_distance = ray.max_distance;
+        let mut stack = Vec::new();
+        if let Some(near) = enter(&self.nodes[0]) {
+            stack.push((0, near));
+        }
+        while let Some((node, near)) = stack.pop() {
+            if near > max_distance {
+                continue;
+            }
+            let node = &self.nodes[node as usize];
+            if node.is_leaf() {
+                for &item in &self.indices[node.first as usize..(node.first + node.count) as usize]
+                {
+                    max_distance = max_distance.min(f(item, near));
+                }
+                continue;
+            }
+
+            let children = [node.first, node.first + 1]
+                .map(|child| (child, enter(&self.nodes[child as usize])));
+            let [(a, a_near), (b, b_near)] = children;
+            // the farther child goes first onto the stack so the nearer one is popped next
+            match (a_near, b_near) {
+                (Some(a_near), Some(b_near)) if a_near <= b_near => {
+                    stack.extend([(b, b_near), (a, a_near)]);
+                }
+                (Some(a_near), Some(b_near)) => stack.extend([(a, a_near), (b, b_near)]),
+                (Some(a_near), None) => stack.push((a, a_near)),
+                (None, Some(b_near)) => stack.push((b, b_near)),
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+/// Index of a model in an [`InstanceTable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ModelId(pub u32);
+
+/// Index of an instance in an [`InstanceTable`], stays valid until the instance is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InstanceId(pub u32);
+
+/// A placed copy of a model. Like a [`VoxelObject`](crate::objects::VoxelObject) the model is
+/// centered on the position of the transform.
+#[derive(Debug, Clone, Copy)]
+pub struct Instance {
+    pub model: ModelId,
+    pub transform: Transform,
+}
+
+/// Copies of shared voxel models placed in the world, each only stores its transform.
+///
+/// Changes mark the [`Bvh`] out of date, [`InstanceTable::update`] rebuilds it once for any
+/// number of changes. Queries before that miss the changes.
+#[derive(Default)]
+pub struct InstanceTable {
+    models: Vec<BrickMap>,
+    instances: Vec<Option<Instance>>,
+    free: Vec<u32>,
+    bvh: Bvh,
+    /// Instance of every BVH item.
+    bvh_instances: Vec<InstanceId>,
+    dirty: bool,
+}
+
+impl InstanceTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_model(&mut self, brickmap: BrickMap) -> ModelId {
+        self.models.push(brickmap);
+        ModelId(self.models.len() as u32 - 1)
+    }
+
+    pub fn model(&self, id: ModelId) -> Option<&BrickMap> {
+        self.models.get(id.0 as usize)
+    }
+
+    pub fn models(&self) -> &[BrickMap] {
+        &self.models
+    }
+
+    /// Places a copy of `instance.model`, `None` if the table doesn't know the model.
+    pub fn insert(&mut self, instance: Instance) -> Option<InstanceId> {
+        self.model(instance.model)?;
+        self.dirty = true;
+        let id = match self.free.pop() {
+            Some(index) => {
+                self.instances[index as usize] = Some(instance);
+                index
+            }
+            None => {
+                self.instances.push(Some(instance));
+                self.instances.len() as u32 - 1
+            }
+        };
+        Some(InstanceId(id))
+    }
+
+    pub fn remove(&mut self, id: InstanceId) -> Option<Instance> {
+        let instance = self.instances.get_mut(id.0 as usize)?.take()?;
+        self.free.push(id.0);
+        self.dirty = true;
+        Some(instance)
+    }
+
+    /// Moves an instance, returns false if it doesn't exist.
+    pub fn set_transform(&mut self, id: InstanceId, transform: Transform) -> bool {
+        match self.instances.get_mut(id.0 as usize) {
+            Some(Some(instance)) => {
+                instance.transform = transform;
+                self.dirty = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn get(&self, id: InstanceId) -> Option<&Instance> {
+        self.instances.get(id.0 as usize)?.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (InstanceId, &Instance)> {
+        self.instances
+            .iter()
+            .enumerate()
+            .filter_map(|(index, instance)| Some((InstanceId(index as u32), instance.as_ref()?)))
+    }
+
+    /// Maps model coordinates of the instance, in bricks, to world space.
+    pub fn to_world(&self, instance: &Instance) -> na::Matrix4<f32> {
+        let size = self.models[instance.model.0 as usize].dimensions();
+        volume_to_world(&instance.transform, size)
+    }
+
+    /// World box around the whole model of the instance.
+    pub fn bounds(&self, instance: &Instance) -> Aabb {
+        let size = self.models[instance.model.0 as usize].dimensions();
+        transform_box(
+            &self.to_world(instance),
+            &Aabb::new(na::Point3::origin(), size.cast::<f32>().into()),
+        )
+    }
+
+    /// Rebuilds the BVH after changes, returns whether it did.
+    pub fn update(&mut self) -> bool {
+        if !self.dirty {
+            return false;
+        }
+
+        let (ids, bounds): (Vec<_>, Vec<_>) = self
+            .iter()
+            .map(|(id, instance)| (id, self.bounds(instance)))
+            .unzip();
+        self.bvh = Bvh::build(&bounds);
+        self.bvh_instances = ids;
+        self.dirty = false;
+        true
+    }
+
+    pub fn bvh(&self) -> &Bvh {
+        &self.bvh
+    }
+
+    /// Instance of a BVH item, see [`Bvh::indices`].
+    pub fn bvh_instance(&self, item: u32) -> InstanceId {
+        self.bvh_instances[item as usize]
+    }
+
+    /// Closest instance hit, the ray descends into the bricks of the model in its local space.
+    /// The hit is in world space except for `brick` and `voxel`, which address the model.
+    pub fn trace_ray(&self, ray: &Ray) -> Option<(InstanceId, RayHit)> {
+        let mut best: Option<(InstanceId, RayHit)> = None;
+        self.bvh.query_ray(ray, |item, _| {
+            let id = self.bvh_instance(item);
+            let max_distance = best.map_or(ray.max_distance, |(_, hit)| hit.distance);
+            let Some(instance) = self.get(id) else {
+                return max_distance;
+            };
+
+            let to_local = self
+                .to_world(instance)
+                .try_inverse()
+                .unwrap_or(na::Matrix4::identity());
+            let model = &self.models[instance.model.0 as usize];
+            match trace_volume(model, &to_local, &ray.with_max_distance(max_distance)) {
+                Some(hit) => {
+                    best = Some((id, hit));
+                    hit.distance
+                }
+                None => max_distance,
+            }
+        });
+        best
+    }
+
+    /// Instances with solid voxels overlapping the box. Rotated instances are tested with the
+    /// local box around it, which may report overlaps close to a solid voxel.
+    pub fn overlapping(&self, aabb: &Aabb) -> Vec<InstanceId> {
+        let mut hits = Vec::new();
+        self.bvh.query_aabb(aabb, |item| {
+            let id = self.bvh_instance(item);
+            let Some(instance) = self.get(id) else {
+                return;
+            };
+            let to_local = self
+                .to_world(instance)
+                .try_inverse()
+                .unwrap_or(na::Matrix4::identity());
+            let model = &self.models[instance.model.0 as usize];
+            if model.overlaps_solid(&transform_box(&to_local, aabb)) {
+                hits.push(id);
+            }
+        });
+        hits.sort_unstable();
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::TraceBrick;
+
+    fn cube(min: [f32; 3], size: f32) -> Aabb {
+        let min = na::Point3::from(min);
+        Aabb::new(min, min + na::Vector3::repeat(size))
+    }
+
+    /// One brick model with a single full brick.
+    fn block() -> BrickMap {
+        let brickmap = BrickMap::new(na::Vector3::new(1, 1, 1));
+        let mut brick = TraceBrick::empty();
+        brick.data_mut().fill(0xFF);
+        brickmap.set_brick(brick, na::Point3::origin());
+        brickmap
+    }
+
+    fn at(position: [f32; 3]) -> Transform {
+        let mut transform = Transform::identity();
+        transform.position(&na::Vector3::from(position));
+        transform
+    }
+
+    #[test]
+    fn test_bvh_queries_match_brute_force() {
+        // a 10 x 10 grid of unit boxes with a gap of one between them
+        let boxes: Vec<_> = (0..100)
+            .map(|i| cube([(i % 10) as f32 * 2.0, 0.0, (i / 10) as f32 * 2.0], 1.0))
+            .collect();
+        let bvh = Bvh::build(&boxes);
+
+        let mut indices = bvh.indices().to_vec();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..100).collect::<Vec<_>>());
+        for node in bvh.nodes().iter().filter(|node| node.is_leaf()) {
+            assert!(node.count as usize <= LEAF_SIZE);
+        }
+
+        let probe = cube([3.5, -1.0, 3.5], 3.0);
+        let mut found = Vec::new();
+        bvh.query_aabb(&probe, |item| found.push(item));
+        let expected: Vec<u32> = (0..100)
+            .filter(|&i| boxes[i as usize].overlaps(&probe))
+            .collect();
+        assert_eq!(expected.len(), 4);
+        assert!(expected.iter().all(|item| found.contains(item)));
+        assert!(found.len() <= expected.len() * LEAF_SIZE);
+
+        // along the second row every box of it is reported, leaves may bring neighbours along
+        let ray = Ray::new(na::Point3::new(-1.0, 0.5, 2.5), na::Vector3::x());
+        let mut entered = Vec::new();
+        bvh.query_ray(&ray, |item, _| {
+            entered.push(item);
+            f32::INFINITY
+        });
+        let mut row: Vec<_> = entered
+            .iter()
+            .copied()
+            .filter(|item| (10..20).contains(item))
+            .collect();
+        row.sort_unstable();
+        assert_eq!(row, (10..20).collect::<Vec<_>>());
+        assert!(entered.len() < 2 * row.len());
+
+        // reporting a hit at the entry culls everything behind it
+        let mut visited = 0;
+        bvh.query_ray(&ray, |_, near| {
+            visited += 1;
+            near
+        });
+        assert!(visited < row.len());
+    }
+
+    #[test]
+    fn test_instances_share_models() {
+        let mut table = InstanceTable::new();
+        let model = table.add_model(block());
+        let a = table
+            .insert(Instance {
+                model,
+                transform: at([2.5, 0.5, 0.5]),
+            })
+            .unwrap();
+        let b = table
+            .insert(Instance {
+                model,
+                transform: at([5.5, 0.5, 0.5]),
+            })
+            .unwrap();
+        assert!(table
+            .insert(Instance {
+                model: ModelId(7),
+                transform: at([0.0; 3]),
+            })
+            .is_none());
+        assert!(table.update());
+        assert!(!table.update());
+
+        let ray = Ray::new(na::Point3::new(0.0, 0.5, 0.5), na::Vector3::x());
+        let (id, hit) = table.trace_ray(&ray).unwrap();
+        assert_eq!(id, a);
+        assert!((hit.distance - 2.0).abs() < 1e-3);
+        assert_eq!(hit.normal, -na::Vector3::x());
+
+        // moving the first one out of the way uncovers the second
+        table.set_transform(a, at([2.5, 5.5, 0.5]));
+        table.update();
+        let (id, hit) = table.trace_ray(&ray).unwrap();
+        assert_eq!(id, b);
+        assert!((hit.distance - 5.0).abs() < 1e-3);
+
+        let probe = Aabb::from_center(na::Point3::new(5.5, 1.2, 0.5), na::Vector3::repeat(0.3));
+        assert_eq!(table.overlapping(&probe), vec![b]);
+
+        table.remove(b);
+        table.update();
+        assert!(table.trace_ray(&ray).is_none());
+        assert!(table.overlapping(&probe).is_empty());
+        assert_eq!(table.len(), 1);
+    }
+}
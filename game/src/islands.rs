@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use crate::{
+    brick::{BrickMap, ExpandedBrick, BRICK_SIZE, NEIGHBOURS},
+    brush::BrickEdit,
+    material::MaterialId,
+    palette::PaletteRegistry,
+};
+
+/// Solid voxels connected to each other but not to an anchor, in voxels (8 per brick).
+#[derive(Debug, Clone)]
+pub struct Island {
+    pub voxels: Vec<(na::Point3<u32>, MaterialId)>,
+    pub min: na::Point3<u32>,
+    /// Exclusive.
+    pub max: na::Point3<u32>,
+}
+
+impl Island {
+    pub fn len(&self) -> usize {
+        self.voxels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.voxels.is_empty()
+    }
+
+    /// Bricks to write for deleting the island, apply with [`BrickMap::apply_edits`].
+    pub fn plan_remove(&self, brickmap: &BrickMap, palettes: &PaletteRegistry) -> Vec<BrickEdit> {
+        let mut bricks: HashMap<na::Point3<u32>, Vec<usize>> = HashMap::new();
+        for (voxel, _) in self.voxels.iter() {
+            let local = voxel.map(|c| c % BRICK_SIZE);
+            bricks
+                .entry(voxel.map(|c| c / BRICK_SIZE))
+                .or_default()
+                .push(ExpandedBrick::index(local.x, local.y, local.z));
+        }
+
+        let view = brickmap.read();
+        let mut edits: Vec<_> = bricks
+            .into_iter()
+            .filter_map(|(brick, indices)| {
+                let handle = view.get_handle(brick);
+                let before = BrickEdit::decode(&view, palettes, handle);
+                let mut voxels = before.clone();
+                for index in indices {
+                    voxels[index] = MaterialId::EMPTY;
+                }
+                BrickEdit::diff(brick, handle, &before, voxels)
+            })
+            .collect();
+        edits.sort_unstable_by_key(|edit| (edit.at.z, edit.at.y, edit.at.x));
+        edits
+    }
+}
+
+/// Finds the solid voxels in `min..max` that lost their connection to an anchor, 6-connected.
+///
+/// The world floor and voxels made of one of `anchors` hold a component in place. The search
+/// can't see past the region, so a component reaching a side of it that isn't the edge of the
+/// map counts as held too. Pick a region with some margin around an edit.
+pub fn find_islands(
+    brickmap: &BrickMap,
+    palettes: &PaletteRegistry,
+    min: na::Point3<u32>,
+    max: na::Point3<u32>,
+    anchors: &[MaterialId],
+) -> Vec<Island> {
+    let dims = brickmap.dimensions() * BRICK_SIZE;
+    let max: na::Point3<u32> = max.coords.inf(&dims).into();
+    let size = max
+        .coords
+        .zip_map(&min.coords, |max, min| max.saturating_sub(min));
+    if size.iter().any(|&c| c == 0) {
+        return Vec::new();
+    }
+
+    let index = |p: na::Vector3<u32>| (p.x + p.y * size.x + p.z * size.x * size.y) as usize;
+    let mut materials = Vec::with_capacity((size.x * size.y * size.z) as usize);
+    {
+        let view = brickmap.read();
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    materials.push(view.get_voxel(palettes, na::Point3::new(x, y, z)));
+                }
+            }
+        }
+    }
+
+    // sides of the region that border more voxels outside of it
+    let open_low = min.coords.map(|c| c > 0);
+    let open_high = max.coords.zip_map(&dims, |c, d| c < d);
+
+    let mut visited = vec![false; materials.len()];
+    let mut islands = Vec::new();
+    let mut stack = Vec::new();
+
+    for start in 0..materials.len() {
+        if visited[start] || materials[start] == MaterialId::EMPTY {
+            continue;
+        }
+
+        let mut component = Vec::new();
+        let mut held = false;
+        visited[start] = true;
+        stack.push(start as u32);
+
+        while let Some(current) = stack.pop() {
+            let current = current as usize;
+            let local = na::Vector3::new(
+                current as u32 % size.x,
+                (current as u32 / size.x) % size.y,
+                current as u32 / (size.x * size.y),
+            );
+            let material = materials[current];
+            component.push((min + local, material));
+
+            held |= min.y + local.y == 0 || anchors.contains(&material);
+            for axis in 0..3 {
+                held |= (local[axis] == 0 && open_low[axis])
+                    || (local[axis] == size[axis] - 1 && open_high[axis]);
+            }
+
+            for offset in NEIGHBOURS {
+                let neighbour = local.cast::<i32>() + na::Vector3::from(offset);
+                if (0..3).any(|axis| neighbour[axis] < 0 || neighbour[axis] >= size[axis] as i32) {
+                    continue;
+                }
+                let next = index(neighbour.map(|c| c as u32));
+                if !visited[next] && materials[next] != MaterialId::EMPTY {
+                    visited[next] = true;
+                    stack.push(next as u32);
+                }
+            }
+        }
+
+        if held {
+            continue;
+        }
+
+        let (low, high) = component.iter().fold(
+            (na::Point3::from(dims), na::Point3::origin()),
+            |(low, high), (p, _)| (low.inf(p), high.sup(&(p + na::Vector3::repeat(1)))),
+        );
+        islands.push(Island {
+            voxels: component,
+            min: low,
+            max: high,
+        });
+    }
+
+    islands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brush::fill;
+
+    const STONE: MaterialId = MaterialId(1);
+    const BEDROCK: MaterialId = MaterialId(2);
+
+    #[test]
+    fn test_cut_pillar_floats() {
+        let brickmap = BrickMap::new(na::Vector3::new(2, 2, 2));
+        let palettes = PaletteRegistry::new();
+        fill(&brickmap, &palettes, [0, 0, 0], [16, 1, 16], STONE);
+        fill(&brickmap, &palettes, [4, 1, 4], [6, 12, 6], STONE);
+
+        let region = (na::Point3::origin(), na::Point3::new(16, 16, 16));
+        assert!(find_islands(&brickmap, &palettes, region.0, region.1, &[]).is_empty());
+
+        // dig through the pillar, everything above the cut floats
+        fill(
+            &brickmap,
+            &palettes,
+            [4, 5, 4],
+            [6, 6, 6],
+            MaterialId::EMPTY,
+        );
+        let islands = find_islands(&brickmap, &palettes, region.0, region.1, &[]);
+        assert_eq!(islands.len(), 1);
+        assert_eq!(islands[0].len(), 2 * 6 * 2);
+        assert_eq!(islands[0].min, na::Point3::new(4, 6, 4));
+        assert_eq!(islands[0].max, na::Point3::new(6, 12, 6));
+
+        let edits = islands[0].plan_remove(&brickmap, &palettes);
+        brickmap.apply_edits(&edits, &palettes);
+        assert_eq!(
+            brickmap.get_voxel(&palettes, na::Point3::new(4, 8, 4)),
+            MaterialId::EMPTY
+        );
+        assert_eq!(
+            brickmap.get_voxel(&palettes, na::Point3::new(4, 4, 4)),
+            STONE
+        );
+        assert!(find_islands(&brickmap, &palettes, region.0, region.1, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_anchors_and_region_sides() {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 2, 2));
+        let palettes = PaletteRegistry::new();
+        // a bridge hanging from a bedrock voxel and a free block next to it
+        fill(&brickmap, &palettes, [2, 8, 2], [3, 9, 3], BEDROCK);
+        fill(&brickmap, &palettes, [3, 8, 2], [12, 9, 3], STONE);
+        fill(&brickmap, &palettes, [2, 12, 2], [4, 14, 4], STONE);
+
+        let all = find_islands(
+            &brickmap,
+            &palettes,
+            na::Point3::origin(),
+            na::Point3::new(32, 16, 16),
+            &[BEDROCK],
+        );
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].len(), 8);
+
+        // without the anchor the bridge floats too
+        let unanchored = find_islands(
+            &brickmap,
+            &palettes,
+            na::Point3::origin(),
+            na::Point3::new(32, 16, 16),
+            &[],
+        );
+        assert_eq!(unanchored.len(), 2);
+
+        // the bridge leaves a region that ends inside the map, it may be held outside of it
+        let clipped = find_islands(
+            &brickmap,
+            &palettes,
+            na::Point3::new(4, 0, 0),
+            na::Point3::new(8, 16, 16),
+            &[],
+        );
+        assert!(clipped.is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+/// Order of the handles of a [`crate::BrickMap`] in memory. Mirrored by `brick_handle_index` in
+/// `raytrace.wgsl` and `sdf.wgsl`, which pick the layout from a push constant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HandleLayout {
+    /// x-major, `x + y * dims.x + z * dims.x * dims.y`.
+    #[default]
+    Linear,
+    /// Z-order curve over the power of two bounds of each axis. An axis drops out of the
+    /// interleaving once its bits are used up, so flat maps don't pay for a full cube.
+    Morton,
+}
+
+impl HandleLayout {
+    /// Value of the `handle_layout` push constant.
+    pub fn as_raw(self) -> u32 {
+        match self {
+            Self::Linear => 0,
+            Self::Morton => 1,
+        }
+    }
+}
+
+/// Precomputed index math for a layout and map size.
+#[derive(Debug, Clone)]
+pub(crate) struct HandleIndexer {
+    size: na::Vector3<u32>,
+    layout: HandleLayout,
+    /// Morton only, the spread out bits of every coordinate per axis. Or-ing one entry per
+    /// axis gives the index.
+    spread: [Vec<u32>; 3],
+}
+
+impl HandleIndexer {
+    pub fn new(size: na::Vector3<u32>, layout: HandleLayout) -> Self {
+        let spread = match layout {
+            HandleLayout::Linear => Default::default(),
+            HandleLayout::Morton => {
+                let bits = Self::axis_bits(size);
+                [0, 1, 2].map(|axis| {
+                    (0..size[axis])
+                        .map(|coord| Self::spread(coord, axis, bits))
+                        .collect()
+                })
+            }
+        };
+
+        Self {
+            size,
+            layout,
+            spread,
+        }
+    }
+
+    pub fn layout(&self) -> HandleLayout {
+        self.layout
+    }
+
+    /// Bits needed to address every coordinate of each axis.
+    fn axis_bits(size: na::Vector3<u32>) -> [u32; 3] {
+        [0, 1, 2].map(|axis| u32::BITS - (size[axis].max(1) - 1).leading_zeros())
+    }
+
+    /// Position of the bits of `coord` in a Morton index, x takes the lowest bit of every round.
+    fn spread(coord: u32, axis: usize, bits: [u32; 3]) -> u32 {
+        let rounds = bits.into_iter().max().unwrap_or(0);
+        let mut index = 0;
+        let mut out = 0;
+        for bit in 0..rounds {
+            for (other, &axis_bits) in bits.iter().enumerate() {
+                if bit >= axis_bits {
+                    continue;
+                }
+                if other == axis {
+                    index |= ((coord >> bit) & 1) << out;
+                }
+                out += 1;
+            }
+        }
+        index
+    }
+
+    /// Number of handles to allocate, Morton pads every axis to a power of two.
+    pub fn len(&self) -> usize {
+        match self.layout {
+            HandleLayout::Linear => (self.size.x * self.size.y * self.size.z) as usize,
+            HandleLayout::Morton => 1 << Self::axis_bits(self.size).iter().sum::<u32>(),
+        }
+    }
+
+    #[inline]
+    pub fn index(&self, at: na::Point3<u32>) -> usize {
+        match self.layout {
+            HandleLayout::Linear => {
+                (at.x + (at.y * self.size.x) + (at.z * self.size.x * self.size.y)) as usize
+            }
+            HandleLayout::Morton => {
+                (self.spread[0][at.x as usize]
+                    | self.spread[1][at.y as usize]
+                    | self.spread[2][at.z as usize]) as usize
+            }
+        }
+    }
+
+    /// Inverse of [`Self::index`], padding indices of the Morton layout map outside the size.
+    pub fn position(&self, index: usize) -> na::Point3<u32> {
+        let index = index as u32;
+        match self.layout {
+            HandleLayout::Linear => na::Point3::new(
+                index % self.size.x,
+                (index / self.size.x) % self.size.y,
+                index / (self.size.x * self.size.y),
+            ),
+            HandleLayout::Morton => {
+                let bits = Self::axis_bits(self.size);
+                let rounds = bits.into_iter().max().unwrap_or(0);
+                let mut position = [0u32; 3];
+                let mut out = 0;
+                for bit in 0..rounds {
+                    for (axis, &axis_bits) in bits.iter().enumerate() {
+                        if bit < axis_bits {
+                            position[axis] |= ((index >> out) & 1) << bit;
+                            out += 1;
+                        }
+                    }
+                }
+                na::Point3::from(position)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        brick::TraceBrick,
+        raytrace::{trace_ray, Ray},
+        BrickMap,
+    };
+
+    #[test]
+    fn test_morton_round_trip() {
+        let size = na::Vector3::new(12, 5, 16);
+        let indexer = HandleIndexer::new(size, HandleLayout::Morton);
+        assert_eq!(indexer.len(), 16 * 8 * 16);
+        assert_eq!(indexer.index(na::Point3::new(1, 0, 0)), 1);
+        assert_eq!(indexer.index(na::Point3::new(0, 1, 0)), 2);
+        assert_eq!(indexer.index(na::Point3::new(0, 0, 1)), 4);
+        assert_eq!(indexer.index(na::Point3::new(1, 1, 1)), 7);
+
+        let mut seen = vec![false; indexer.len()];
+        for z in 0..size.z {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let at = na::Point3::new(x, y, z);
+                    let index = indexer.index(at);
+                    assert!(!seen[index]);
+                    seen[index] = true;
+                    assert_eq!(indexer.position(index), at);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_layouts_trace_the_same() {
+        let size = na::Vector3::new(6, 3, 9);
+        let maps = [HandleLayout::Linear, HandleLayout::Morton]
+            .map(|layout| BrickMap::with_layout(size, layout));
+        let mut brick = TraceBrick::empty();
+        brick.data_mut().fill(0xFF);
+        for at in [
+            (0, 0, 0),
+            (5, 1, 8),
+            (2, 0, 4),
+            (3, 2, 1),
+            (0, 1, 5),
+            (5, 2, 2),
+        ] {
+            for map in &maps {
+                map.set_brick(brick, na::Point3::new(at.0, at.1, at.2));
+            }
+        }
+
+        let mut hits = 0;
+        for i in 0..64 {
+            let angle = i as f32 / 64.0 * std::f32::consts::TAU;
+            let ray = Ray::new(
+                na::Point3::new(3.0, 1.5, 4.5),
+                na::Vector3::new(angle.cos(), (angle * 3.0).sin() * 0.3, angle.sin()),
+            );
+            let [linear, morton] = maps.each_ref().map(|map| trace_ray(map, &ray));
+            assert_eq!(linear.map(|hit| hit.brick), morton.map(|hit| hit.brick));
+            hits += linear.is_some() as u32;
+        }
+        assert!(hits > 0);
+    }
+}
@@ -1,14 +1,31 @@
 extern crate nalgebra as na;
 
+pub mod ao;
 pub mod brick;
-mod brickmap_edit;
+pub mod brush;
 mod camera;
+pub mod clipboard;
+pub mod collision;
+pub mod controller;
+pub mod dag;
 mod dense;
+pub mod history;
 mod input;
+pub mod instances;
+pub mod islands;
+pub mod layout;
+pub mod light;
 pub mod material;
+pub mod mip;
+pub mod objects;
 pub mod octree;
 pub mod palette;
+pub mod pbr;
+pub mod point_lights;
 pub mod raytrace;
+pub mod render;
+pub mod sun;
+pub mod transparency;
 pub mod worldgen;
 
 pub use brick::{BrickHandle, BrickMap, MaterialBrick};
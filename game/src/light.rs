@@ -0,0 +1,611 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::{
+    brick::{
+        BrickHandle, BrickMap, BrickMapView, ExpandedBrick, BRICK_SIZE, BRICK_VOLUME, NEIGHBOURS,
+    },
+    material::{MaterialRegistry, PbrMaterial},
+    palette::PaletteRegistry,
+};
+
+/// Light fades out within 15 voxels, an edit can't change it more than two bricks away.
+const RELIGHT_MARGIN: u32 = 2;
+
+pub const MAX_LIGHT: u8 = 15;
+/// Spots lit at or below this level count as dark.
+pub const DARK_LEVEL: u8 = 7;
+
+/// Light of a single voxel, both channels range from 0 to [`MAX_LIGHT`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LightLevel {
+    /// Light from the sky, full straight below open sky and fading by one per voxel sideways.
+    pub sky: u8,
+    /// Light from emissive materials, fading by one per voxel.
+    pub block: u8,
+}
+
+impl LightLevel {
+    pub const DARK: Self = Self { sky: 0, block: 0 };
+    pub const SKY: Self = Self {
+        sky: MAX_LIGHT,
+        block: 0,
+    };
+
+    pub fn max(self) -> u8 {
+        self.sky.max(self.block)
+    }
+
+    pub fn is_dark(self) -> bool {
+        self.max() <= DARK_LEVEL
+    }
+
+    fn pack(self) -> u8 {
+        (self.sky << 4) | self.block
+    }
+
+    fn unpack(packed: u8) -> Self {
+        Self {
+            sky: packed >> 4,
+            block: packed & 0xF,
+        }
+    }
+}
+
+/// Block light a voxel of `material` gives off, from its brightest emissive channel.
+pub fn emission_level(material: &PbrMaterial) -> u8 {
+    let strength = material.emissive[..3].iter().fold(0.0f32, |a, &b| a.max(b));
+    (strength.clamp(0.0, 1.0) * MAX_LIGHT as f32).round() as u8
+}
+
+/// Whether the palette of the brick behind `handle` holds a material with a non zero level in
+/// `emission`, indexed by material id, see [`BrickMapView::brick_palette`].
+pub(crate) fn may_emit(
+    view: &BrickMapView,
+    palettes: &PaletteRegistry,
+    handle: BrickHandle,
+    emission: &[u8],
+) -> bool {
+    view.brick_palette(palettes, handle).iter().any(|material| {
+        emission
+            .get(material.0 as usize)
+            .is_some_and(|&level| level > 0)
+    })
+}
+
+/// Light of the 8³ voxels of a brick, indexed like [`ExpandedBrick`]. Every byte holds the
+/// skylight in its high and the block light in its low nibble.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightBrick {
+    raw: [u8; BRICK_VOLUME],
+}
+
+impl LightBrick {
+    pub fn uniform(level: LightLevel) -> Self {
+        Self {
+            raw: [level.pack(); BRICK_VOLUME],
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn get(&self, local: na::Point3<u32>) -> LightLevel {
+        LightLevel::unpack(self.raw[ExpandedBrick::index(local.x, local.y, local.z)])
+    }
+
+    pub fn set(&mut self, local: na::Point3<u32>, level: LightLevel) {
+        self.raw[ExpandedBrick::index(local.x, local.y, local.z)] = level.pack();
+    }
+
+    fn uniform_level(&self) -> Option<LightLevel> {
+        let first = self.raw[0];
+        self.raw
+            .iter()
+            .all(|&packed| packed == first)
+            .then(|| LightLevel::unpack(first))
+    }
+}
+
+/// Most bricks are open sky or buried in the dark, only bricks along surfaces store voxels.
+#[derive(Debug, Clone, PartialEq)]
+enum LightCell {
+    Uniform(LightLevel),
+    Voxels(Box<LightBrick>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Channel {
+    Sky,
+    Block,
+}
+
+impl Channel {
+    fn get(self, level: LightLevel) -> u8 {
+        match self {
+            Self::Sky => level.sky,
+            Self::Block => level.block,
+        }
+    }
+
+    fn with(self, level: LightLevel, value: u8) -> LightLevel {
+        match self {
+            Self::Sky => LightLevel {
+                sky: value,
+                ..level
+            },
+            Self::Block => LightLevel {
+                block: value,
+                ..level
+            },
+        }
+    }
+}
+
+/// Light levels of every voxel of a [`BrickMap`], kept per brick.
+///
+/// Skylight shines straight down every column until it hits a solid voxel and floods sideways
+/// from there, block light floods out of voxels with an emissive material. Solid voxels stay dark
+/// unless they emit. Build it once with [`LightMap::compute`] and call [`LightMap::update`] with
+/// the bricks of every edit, [`LightMap::take_dirty`] then tells which bricks got new light.
+#[derive(Debug, Clone)]
+pub struct LightMap {
+    /// In bricks.
+    size: na::Vector3<u32>,
+    cells: Vec<LightCell>,
+    dirty: HashSet<na::Point3<u32>>,
+}
+
+impl LightMap {
+    /// Completely dark map of `size` bricks.
+    pub fn new(size: na::Vector3<u32>) -> Self {
+        Self {
+            size,
+            cells: vec![LightCell::Uniform(LightLevel::DARK); (size.x * size.y * size.z) as usize],
+            dirty: HashSet::new(),
+        }
+    }
+
+    /// Lights the whole map.
+    pub fn compute(
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+    ) -> Self {
+        let mut light = Self::new(brickmap.dimensions());
+        light.relight(
+            &brickmap.read(),
+            palettes,
+            materials,
+            na::Point3::origin(),
+            na::Point3::from(light.size),
+        );
+        light
+    }
+
+    /// Relights around `changed` bricks after their voxels were edited.
+    pub fn update(
+        &mut self,
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+        changed: &[na::Point3<u32>],
+    ) {
+        let Some(first) = changed.first() else {
+            return;
+        };
+        let (min, max) = changed.iter().fold((*first, *first), |(min, max), at| {
+            (min.inf(at), max.sup(at))
+        });
+
+        let min = min.map(|c| c.saturating_sub(RELIGHT_MARGIN));
+        let max: na::Point3<u32> = (max.coords.add_scalar(RELIGHT_MARGIN + 1))
+            .inf(&self.size)
+            .into();
+        // opening or closing a column changes the skylight all the way down
+        let min = na::Point3::new(min.x, 0, min.z);
+        self.relight(&brickmap.read(), palettes, materials, min, max);
+    }
+
+    /// In bricks.
+    pub fn dimensions(&self) -> na::Vector3<u32> {
+        self.size
+    }
+
+    /// Light at a voxel position, in voxels (8 per brick), open sky outside of the map.
+    pub fn get(&self, voxel: na::Point3<u32>) -> LightLevel {
+        let brick = voxel.map(|c| c / BRICK_SIZE);
+        if (0..3).any(|axis| brick[axis] >= self.size[axis]) {
+            return LightLevel::SKY;
+        }
+        match &self.cells[self.cell_index(brick)] {
+            LightCell::Uniform(level) => *level,
+            LightCell::Voxels(light) => light.get(voxel.map(|c| c % BRICK_SIZE)),
+        }
+    }
+
+    /// Light at `position` in world units.
+    pub fn sample(&self, position: na::Point3<f32>) -> LightLevel {
+        if position.iter().any(|&c| c < 0.0) {
+            return LightLevel::SKY;
+        }
+        self.get((position * BRICK_SIZE as f32).map(|c| c as u32))
+    }
+
+    pub fn is_dark(&self, position: na::Point3<f32>) -> bool {
+        self.sample(position).is_dark()
+    }
+
+    /// Light of every voxel of the brick at `at`.
+    pub fn brick(&self, at: na::Point3<u32>) -> LightBrick {
+        match &self.cells[self.cell_index(at)] {
+            LightCell::Uniform(level) => LightBrick::uniform(*level),
+            LightCell::Voxels(light) => **light,
+        }
+    }
+
+    /// Bricks whose light changed since the last call, sorted.
+    pub fn take_dirty(&mut self) -> Vec<na::Point3<u32>> {
+        let mut dirty: Vec<_> = self.dirty.drain().collect();
+        dirty.sort_unstable_by_key(|at| (at.z, at.y, at.x));
+        dirty
+    }
+
+    fn cell_index(&self, at: na::Point3<u32>) -> usize {
+        (at.x + at.y * self.size.x + at.z * self.size.x * self.size.y) as usize
+    }
+
+    fn set(&mut self, voxel: na::Point3<u32>, level: LightLevel) {
+        let index = self.cell_index(voxel.map(|c| c / BRICK_SIZE));
+        let cell = &mut self.cells[index];
+        if let LightCell::Uniform(uniform) = cell {
+            if *uniform == level {
+                return;
+            }
+            *cell = LightCell::Voxels(Box::new(LightBrick::uniform(*uniform)));
+        }
+        if let LightCell::Voxels(light) = cell {
+            light.set(voxel.map(|c| c % BRICK_SIZE), level);
+        }
+    }
+
+    /// Recomputes the bricks `min..max` from scratch. Light outside of the region is taken as is
+    /// and flows back in over its sides.
+    fn relight(
+        &mut self,
+        view: &BrickMapView,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+        min: na::Point3<u32>,
+        max: na::Point3<u32>,
+    ) {
+        let bricks: Vec<_> = (min.z..max.z)
+            .flat_map(|z| (min.y..max.y).flat_map(move |y| (min.x..max.x).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| na::Point3::new(x, y, z))
+            .collect();
+        let before: Vec<_> = bricks
+            .iter()
+            .map(|&at| {
+                let index = self.cell_index(at);
+                std::mem::replace(&mut self.cells[index], LightCell::Uniform(LightLevel::DARK))
+            })
+            .collect();
+
+        self.light_columns(view, min, max);
+        let mut block = self.light_emitters(view, palettes, materials, &bricks);
+
+        let mut sky = VecDeque::new();
+        for &at in bricks.iter() {
+            match &self.cells[self.cell_index(at)] {
+                LightCell::Voxels(light) => {
+                    sky.extend(
+                        (0..BRICK_VOLUME as u32)
+                            .map(|i| na::Point3::new(i % 8, (i / 8) % 8, i / 64))
+                            .filter(|&local| light.get(local).sky > 1)
+                            .map(|local| at * BRICK_SIZE + local.coords),
+                    );
+                }
+                // open sky only spills into bricks that aren't open sky as well
+                LightCell::Uniform(level) if level.sky > 1 => {
+                    for offset in NEIGHBOURS {
+                        let next = at.cast::<i32>() + na::Vector3::from(offset);
+                        let inside = (0..3).all(|axis| {
+                            next[axis] >= min[axis] as i32 && next[axis] < max[axis] as i32
+                        });
+                        if !inside
+                            || self.cells[self.cell_index(next.map(|c| c as u32))]
+                                == LightCell::Uniform(*level)
+                        {
+                            continue;
+                        }
+                        sky.extend(brick_face(at, offset));
+                    }
+                }
+                LightCell::Uniform(_) => {}
+            }
+        }
+
+        // light from outside of the region
+        for axis in 0..3 {
+            for (side, inside) in [(-1, min[axis] > 0), (1, max[axis] < self.size[axis])] {
+                if !inside {
+                    continue;
+                }
+                let mut from = min * BRICK_SIZE;
+                let mut to = max * BRICK_SIZE;
+                from[axis] = if side < 0 { from[axis] - 1 } else { to[axis] };
+                to[axis] = from[axis] + 1;
+                for z in from.z..to.z {
+                    for y in from.y..to.y {
+                        for x in from.x..to.x {
+                            let voxel = na::Point3::new(x, y, z);
+                            let level = self.get(voxel);
+                            if level.sky > 1 {
+                                sky.push_back(voxel);
+                            }
+                            if level.block > 1 {
+                                block.push_back(voxel);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        self.flood(view, Channel::Sky, sky, min, max);
+        self.flood(view, Channel::Block, block, min, max);
+
+        for (at, before) in bricks.into_iter().zip(before) {
+            let index = self.cell_index(at);
+            if let LightCell::Voxels(light) = &self.cells[index] {
+                if let Some(level) = light.uniform_level() {
+                    self.cells[index] = LightCell::Uniform(level);
+                }
+            }
+            if self.cells[index] != before {
+                self.dirty.insert(at);
+            }
+        }
+    }
+
+    /// Full skylight straight down every voxel column of the region.
+    fn light_columns(&mut self, view: &BrickMapView, min: na::Point3<u32>, max: na::Point3<u32>) {
+        for bz in min.z..max.z {
+            for bx in min.x..max.x {
+                // voxel columns still open to the sky, `x + z * 8`
+                let mut open = [true; 64];
+                if max.y < self.size.y {
+                    for (i, open) in open.iter_mut().enumerate() {
+                        let i = i as u32;
+                        let above = na::Point3::new(bx * 8 + i % 8, max.y * 8, bz * 8 + i / 8);
+                        *open = self.get(above).sky == MAX_LIGHT;
+                    }
+                }
+
+                for by in (min.y..max.y).rev() {
+                    let at = na::Point3::new(bx, by, bz);
+                    let index = self.cell_index(at);
+                    let handle = view.get_handle(at);
+                    let brick = view.get_brick(handle).filter(|brick| !brick.is_empty());
+
+                    let lit = open.iter().filter(|&&open| open).count();
+                    if handle.is_lod() || lit == 0 {
+                        open = [false; 64];
+                        continue;
+                    }
+                    let Some(brick) = brick else {
+                        if lit == open.len() {
+                            self.cells[index] = LightCell::Uniform(LightLevel::SKY);
+                            continue;
+                        }
+                        let mut light = LightBrick::uniform(LightLevel::DARK);
+                        for (i, _) in open.iter().enumerate().filter(|(_, open)| **open) {
+                            let i = i as u32;
+                            for y in 0..BRICK_SIZE {
+                                light.set(na::Point3::new(i % 8, y, i / 8), LightLevel::SKY);
+                            }
+                        }
+                        self.cells[index] = LightCell::Voxels(Box::new(light));
+                        continue;
+                    };
+
+                    let mut light = LightBrick::uniform(LightLevel::DARK);
+                    for y in (0..BRICK_SIZE).rev() {
+                        for (i, open) in open.iter_mut().enumerate().filter(|(_, open)| **open) {
+                            let (x, z) = (i as u32 % 8, i as u32 / 8);
+                            if brick.get(x, y, z) {
+                                *open = false;
+                            } else {
+                                light.set(na::Point3::new(x, y, z), LightLevel::SKY);
+                            }
+                        }
+                    }
+                    self.cells[index] = LightCell::Voxels(Box::new(light));
+                }
+            }
+        }
+    }
+
+    /// Lights the emissive voxels of `bricks` and returns them to flood from.
+    fn light_emitters(
+        &mut self,
+        view: &BrickMapView,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+        bricks: &[na::Point3<u32>],
+    ) -> VecDeque<na::Point3<u32>> {
+        let mut sources = VecDeque::new();
+        let emission: Vec<_> = materials.materials().iter().map(emission_level).collect();
+        if emission.iter().all(|&level| level == 0) {
+            return sources;
+        }
+        let emits = |material: u32| emission.get(material as usize).copied().unwrap_or(0);
+
+        for &at in bricks {
+            let handle = view.get_handle(at);
+            if !may_emit(view, palettes, handle, &emission) {
+                continue;
+            }
+
+            for z in 0..BRICK_SIZE {
+                for y in 0..BRICK_SIZE {
+                    for x in 0..BRICK_SIZE {
+                        let local = na::Point3::new(x, y, z);
+                        let level = emits(view.voxel_material(palettes, handle, local).0);
+                        if level > 0 {
+                            let voxel = at * BRICK_SIZE + local.coords;
+                            self.set(voxel, Channel::Block.with(self.get(voxel), level));
+                            sources.push_back(voxel);
+                        }
+                    }
+                }
+            }
+        }
+        sources
+    }
+
+    /// Spreads `channel` from `queue` through the air of the region, one level less per voxel.
+    fn flood(
+        &mut self,
+        view: &BrickMapView,
+        channel: Channel,
+        mut queue: VecDeque<na::Point3<u32>>,
+        min: na::Point3<u32>,
+        max: na::Point3<u32>,
+    ) {
+        let min = (min * BRICK_SIZE).cast::<i32>();
+        let max = (max * BRICK_SIZE).cast::<i32>();
+        while let Some(voxel) = queue.pop_front() {
+            let level = channel.get(self.get(voxel));
+            if level <= 1 {
+                continue;
+            }
+
+            for offset in NEIGHBOURS {
+                let next = voxel.cast::<i32>() + na::Vector3::from(offset);
+                if (0..3).any(|axis| next[axis] < min[axis] || next[axis] >= max[axis]) {
+                    continue;
+                }
+                let next = next.map(|c| c as u32);
+                let current = self.get(next);
+                if channel.get(current) >= level - 1 || view.is_voxel_solid(next) {
+                    continue;
+                }
+                self.set(next, channel.with(current, level - 1));
+                queue.push_back(next);
+            }
+        }
+    }
+}
+
+/// Voxels of the side of the brick at `at` facing `offset`.
+fn brick_face(at: na::Point3<u32>, offset: [i32; 3]) -> impl Iterator<Item = na::Point3<u32>> {
+    let axis = offset.iter().position(|&c| c != 0).unwrap_or(0);
+    let layer = if offset[axis] > 0 { BRICK_SIZE - 1 } else { 0 };
+    (0..BRICK_SIZE * BRICK_SIZE).map(move |i| {
+        let mut local = na::Vector3::new(i % 8, i / 8, 0);
+        local.swap_rows(axis, 2);
+        local[axis] = layer;
+        at * BRICK_SIZE + local
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brush::fill, material::MaterialId};
+
+    fn assert_same_light(a: &LightMap, b: &LightMap) {
+        let dims = a.dimensions() * BRICK_SIZE;
+        for z in 0..dims.z {
+            for y in 0..dims.y {
+                for x in 0..dims.x {
+                    let voxel = na::Point3::new(x, y, z);
+                    assert_eq!(a.get(voxel), b.get(voxel), "{voxel:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_skylight_under_overhang() {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 3, 2));
+        let palettes = PaletteRegistry::new();
+        let materials = MaterialRegistry::new();
+        materials.register_default_materials();
+        let stone = materials.get_material_id("stone").unwrap();
+
+        fill(&brickmap, &palettes, [0, 0, 0], [32, 4, 16], stone);
+        // roof over the right half, open towards the left
+        fill(&brickmap, &palettes, [16, 10, 0], [32, 12, 16], stone);
+
+        let mut light = LightMap::compute(&brickmap, &palettes, &materials);
+        assert_eq!(light.get(na::Point3::new(4, 4, 8)), LightLevel::SKY);
+        assert_eq!(light.get(na::Point3::new(20, 12, 8)), LightLevel::SKY);
+        assert_eq!(light.get(na::Point3::new(4, 2, 8)), LightLevel::DARK);
+        // fading by one per voxel under the roof
+        assert_eq!(light.get(na::Point3::new(16, 5, 8)).sky, MAX_LIGHT - 1);
+        assert_eq!(light.get(na::Point3::new(20, 5, 8)).sky, MAX_LIGHT - 5);
+        assert!(light.is_dark(na::Point3::new(31.5 / 8.0, 5.5 / 8.0, 1.0)));
+        assert!(!light.is_dark(na::Point3::new(0.5, 1.0, 1.0)));
+
+        // a hole in the roof lights up the floor below it
+        light.take_dirty();
+        let changed = fill(
+            &brickmap,
+            &palettes,
+            [26, 10, 6],
+            [28, 12, 8],
+            MaterialId::EMPTY,
+        );
+        light.update(&brickmap, &palettes, &materials, &changed);
+        assert_eq!(light.get(na::Point3::new(26, 4, 6)), LightLevel::SKY);
+        assert!(!light.take_dirty().is_empty());
+        assert_same_light(&light, &LightMap::compute(&brickmap, &palettes, &materials));
+    }
+
+    #[test]
+    fn test_block_light_updates_incrementally() {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 2, 4));
+        let palettes = PaletteRegistry::new();
+        let materials = MaterialRegistry::new();
+        materials.register_default_materials();
+        let stone = materials.get_material_id("stone").unwrap();
+        let lamp = materials.register_named_material(
+            "lamp",
+            PbrMaterial::new([1.0; 4], 0.0, 0.5, [1.0, 0.8, 0.5], 1.0),
+        );
+
+        // a closed cave lit by a single lamp
+        fill(&brickmap, &palettes, [0, 0, 0], [32, 16, 32], stone);
+        fill(
+            &brickmap,
+            &palettes,
+            [4, 4, 4],
+            [28, 12, 28],
+            MaterialId::EMPTY,
+        );
+        let changed = fill(&brickmap, &palettes, [8, 4, 8], [9, 5, 9], lamp);
+
+        let mut light = LightMap::new(brickmap.dimensions());
+        light.update(&brickmap, &palettes, &materials, &changed);
+        assert_eq!(light.get(na::Point3::new(8, 4, 8)).block, MAX_LIGHT);
+        assert_eq!(light.get(na::Point3::new(9, 4, 8)).block, MAX_LIGHT - 1);
+        assert_eq!(light.get(na::Point3::new(11, 5, 8)).block, MAX_LIGHT - 4);
+        assert_eq!(light.get(na::Point3::new(8, 4, 27)).block, 0);
+        assert_eq!(light.get(na::Point3::new(9, 4, 8)).sky, 0);
+        assert_same_light(&light, &LightMap::compute(&brickmap, &palettes, &materials));
+
+        let changed = fill(
+            &brickmap,
+            &palettes,
+            [8, 4, 8],
+            [9, 5, 9],
+            MaterialId::EMPTY,
+        );
+        light.update(&brickmap, &palettes, &materials, &changed);
+        assert_eq!(light.get(na::Point3::new(9, 4, 8)), LightLevel::DARK);
+        assert_same_light(&light, &LightMap::compute(&brickmap, &palettes, &materials));
+    }
+}
@@ -20,6 +20,14 @@ pub struct PbrMaterial {
     pub emissive: [f32; 4],
 }
 
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct MaterialStats {
+    pub materials: usize,
+    pub named: usize,
+    /// Size of the material table as uploaded to the GPU.
+    pub bytes: usize,
+}
+
 #[derive(Debug)]
 pub struct MaterialRegistry {
     materials: RwLock<Vec<PbrMaterial>>,
@@ -47,6 +55,10 @@ impl MaterialRegistry {
             "snow",
             PbrMaterial::new([0.95, 0.95, 0.95, 1.0], 0.0, 0.3, [0.0; 3], 1.0),
         );
+        self.register_named_material("water", PbrMaterial::water());
+        self.register_named_material("glass", PbrMaterial::glass([0.9, 0.95, 1.0]));
+        self.register_named_material("ice", PbrMaterial::ice());
+        self.register_named_material("leaves", PbrMaterial::leaves([0.2, 0.45, 0.1], 0.6));
     }
 
     pub fn register_material(&self, material: PbrMaterial) -> MaterialId {
@@ -75,6 +87,16 @@ impl MaterialRegistry {
         self.name_to_id.read().get(name).copied()
     }
 
+    /// Name the material was registered under, the first one if it has several.
+    pub fn get_material_name(&self, id: MaterialId) -> Option<String> {
+        self.name_to_id
+            .read()
+            .iter()
+            .filter(|(_, &other)| other == id)
+            .map(|(name, _)| name.clone())
+            .min()
+    }
+
     pub fn get_material(&self, id: MaterialId) -> Option<PbrMaterial> {
         self.materials.read().get(id.0 as usize).copied()
     }
@@ -84,6 +106,15 @@ impl MaterialRegistry {
             .and_then(|id| self.get_material(id))
     }
 
+    pub fn stats(&self) -> MaterialStats {
+        let materials = self.materials.read().len();
+        MaterialStats {
+            materials,
+            named: self.name_to_id.read().len(),
+            bytes: materials * std::mem::size_of::<PbrMaterial>(),
+        }
+    }
+
     pub fn materials(&self) -> &[PbrMaterial] {
         unsafe { self.materials.data_ptr().as_ref().unwrap() }
     }
@@ -114,6 +145,10 @@ impl ExpandedMaterialMapping {
         Some(())
     }
 
+    pub fn insert(&mut self, voxel: u8, material: MaterialId) {
+        self.voxel_to_id.insert(voxel, material);
+    }
+
     pub fn get(&self, name: &str) -> u8 {
         self.string_to_voxel.get(name).copied().unwrap()
     }
@@ -238,4 +273,28 @@ impl PbrMaterial {
     pub fn dead_grass() -> Self {
         PbrMaterial::grass(1.0)
     }
+
+    /// Translucent, rays pass its voxels tinted by the color, see [`crate::transparency`].
+    pub fn glass(tint: [f32; 3]) -> Self {
+        Self::new([tint[0], tint[1], tint[2], 0.15], 0.0, 0.05, [0.0; 3], 1.0)
+    }
+
+    pub fn water() -> Self {
+        Self::new([0.1, 0.35, 0.5, 0.3], 0.0, 0.1, [0.0; 3], 1.0)
+    }
+
+    pub fn ice() -> Self {
+        Self::new([0.75, 0.9, 1.0, 0.5], 0.0, 0.2, [0.0; 3], 1.0)
+    }
+
+    /// Alpha tested, about `density` of its voxels are kept and the rest is cut out.
+    pub fn leaves(color: [f32; 3], density: f32) -> Self {
+        Self::new(
+            [color[0], color[1], color[2], 1.0],
+            0.0,
+            0.9,
+            [0.0; 3],
+            1.0 - density.clamp(0.01, 1.0),
+        )
+    }
 }
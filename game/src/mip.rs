@@ -0,0 +1,168 @@
+use crate::brick::MaterialBrick;
+
+/// Coarse levels of a single brick, level 0 is the full 8³ brick itself.
+///
+/// Only occupancy is stored, a coarse cell takes the material of its first solid voxel, looked
+/// up from the material brick on demand. On the GPU it lives directly behind the packed material
+/// data, see `get_mip_offset` in `raytrace.wgsl`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct BrickMip {
+    /// 4³ occupancy, one bit per cell.
+    occupancy4: [u8; 8],
+    /// 2³ occupancy, one bit per cell.
+    occupancy2: u8,
+}
+
+impl BrickMip {
+    /// Number of levels including the full resolution brick.
+    pub const LEVELS: u32 = 3;
+    pub const EMPTY: Self = Self::empty();
+
+    pub const fn empty() -> Self {
+        Self {
+            occupancy4: [0; 8],
+            occupancy2: 0,
+        }
+    }
+
+    /// Cells per axis at `level`.
+    pub const fn size(level: u32) -> u32 {
+        8 >> level
+    }
+
+    /// Level to trace at `distance`, every `mip_distance` units drops one level.
+    /// A non positive or infinite `mip_distance` always picks the full brick.
+    pub fn level_for_distance(distance: f32, mip_distance: f32) -> u32 {
+        if mip_distance <= 0.0 || !mip_distance.is_finite() {
+            return 0;
+        }
+        (distance.max(0.0) / mip_distance)
+            .floor()
+            .min((Self::LEVELS - 1) as f32) as u32
+    }
+
+    /// Builds all coarse levels from the palette indices of `brick`. A cell is solid when any
+    /// voxel below it is.
+    pub fn from_material_brick(brick: &MaterialBrick) -> Self {
+        let mut mip = Self::empty();
+
+        for level in 1..Self::LEVELS {
+            let size = Self::size(level);
+            for z in 0..size {
+                for y in 0..size {
+                    for x in 0..size {
+                        if Self::palette_index(brick, level, x, y, z) != 0 {
+                            mip.set(level, x, y, z);
+                        }
+                    }
+                }
+            }
+        }
+
+        mip
+    }
+
+    fn index(level: u32, x: u32, y: u32, z: u32) -> usize {
+        let size = Self::size(level);
+        (x + y * size + z * size * size) as usize
+    }
+
+    /// Marks a cell solid, level must be 1 or 2.
+    fn set(&mut self, level: u32, x: u32, y: u32, z: u32) {
+        let index = Self::index(level, x, y, z);
+        match level {
+            1 => self.occupancy4[index / 8] |= 1 << (index % 8),
+            2 => self.occupancy2 |= 1 << index,
+            _ => panic!("Invalid mip level: {}", level),
+        }
+    }
+
+    /// Occupancy of a cell at a coarse level, level must be 1 or 2.
+    pub fn get(&self, level: u32, x: u32, y: u32, z: u32) -> bool {
+        let index = Self::index(level, x, y, z);
+        match level {
+            1 => self.occupancy4[index / 8] & (1 << (index % 8)) != 0,
+            2 => self.occupancy2 & (1 << index) != 0,
+            _ => panic!("Invalid mip level: {}", level),
+        }
+    }
+
+    /// Palette index of the first solid voxel below a cell of `brick` at `level`, in x, y, z
+    /// order, 0 for empty cells. Mirrors `get_mip_material` in `raytrace.wgsl`.
+    pub fn palette_index(brick: &MaterialBrick, level: u32, x: u32, y: u32, z: u32) -> u8 {
+        let span = 8 / Self::size(level);
+        (0..span * span * span)
+            .map(|i| {
+                let (dx, dy, dz) = (i % span, (i / span) % span, i / (span * span));
+                brick.get(x * span + dx, y * span + dy, z * span + dz)
+            })
+            .find(|&value| value != 0)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brick::ExpandedBrick, material::ExpandedMaterialMapping, material::MaterialId};
+
+    #[test]
+    fn test_mip_occupancy_and_material() {
+        let mut mapping = ExpandedMaterialMapping::new();
+        mapping.insert(1, MaterialId(10));
+        mapping.insert(2, MaterialId(20));
+
+        let mut expanded = ExpandedBrick::empty();
+        // Lower 4³ octant: 2 with a single 1 on top.
+        for z in 0..4 {
+            for y in 0..2 {
+                for x in 0..4 {
+                    expanded.set(x, y, z, 2);
+                }
+            }
+        }
+        expanded.set(0, 2, 0, 1);
+        // A lone voxel in the far corner.
+        expanded.set(7, 7, 7, 1);
+
+        let (brick, palette) = expanded.compress(&mapping);
+        let mip = BrickMip::from_material_brick(&brick);
+
+        assert!(mip.get(1, 0, 0, 0));
+        assert!(mip.get(1, 1, 0, 1));
+        assert!(!mip.get(1, 2, 0, 0));
+        assert!(mip.get(1, 3, 3, 3));
+        assert_eq!(
+            palette[BrickMip::palette_index(&brick, 1, 0, 0, 0) as usize],
+            MaterialId(20)
+        );
+        assert_eq!(
+            palette[BrickMip::palette_index(&brick, 1, 0, 1, 0) as usize],
+            MaterialId(10)
+        );
+        assert_eq!(
+            palette[BrickMip::palette_index(&brick, 1, 3, 3, 3) as usize],
+            MaterialId(10)
+        );
+
+        assert!(mip.get(2, 0, 0, 0));
+        assert!(mip.get(2, 1, 1, 1));
+        assert!(!mip.get(2, 1, 0, 0));
+        assert_eq!(
+            palette[BrickMip::palette_index(&brick, 2, 0, 0, 0) as usize],
+            MaterialId(20)
+        );
+        assert_eq!(BrickMip::palette_index(&brick, 2, 1, 0, 0), 0);
+    }
+
+    #[test]
+    fn test_level_for_distance() {
+        assert_eq!(BrickMip::level_for_distance(10.0, f32::INFINITY), 0);
+        assert_eq!(BrickMip::level_for_distance(10.0, 0.0), 0);
+        assert_eq!(BrickMip::level_for_distance(10.0, 16.0), 0);
+        assert_eq!(BrickMip::level_for_distance(20.0, 16.0), 1);
+        assert_eq!(BrickMip::level_for_distance(500.0, 16.0), 2);
+        assert_eq!(std::mem::size_of::<BrickMip>(), 9);
+    }
+}
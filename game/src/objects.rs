@@ -0,0 +1,517 @@
+use crate::{
+    brick::BrickMap,
+    clipboard::VoxelVolume,
+    collision::{Aabb, SKIN},
+    instances::{InstanceId, InstanceTable},
+    material::MaterialRegistry,
+    palette::PaletteRegistry,
+    raytrace::{self, Ray, RayHit},
+    Transform,
+};
+
+/// Speed below which a body resting on the ground stops bouncing.
+const REST_SPEED: f32 = 0.05;
+
+/// Velocity state of a [`VoxelObject`], advanced by [`VoxelObject::step`].
+#[derive(Debug, Clone, Copy)]
+pub struct RigidBody {
+    /// World units per second.
+    pub velocity: na::Vector3<f32>,
+    /// World axis scaled by radians per second.
+    pub angular_velocity: na::Vector3<f32>,
+    /// Downwards acceleration, 0 for bodies that float.
+    pub gravity: f32,
+    /// Part of the velocity into a surface kept after bouncing off it.
+    pub restitution: f32,
+    /// Part of the sliding and spinning velocity lost on every contact.
+    pub friction: f32,
+    /// Moved by the owner only, [`VoxelObject::step`] leaves it in place.
+    pub kinematic: bool,
+}
+
+impl Default for RigidBody {
+    fn default() -> Self {
+        Self {
+            velocity: na::Vector3::zeros(),
+            angular_velocity: na::Vector3::zeros(),
+            gravity: 25.0,
+            restitution: 0.3,
+            friction: 0.2,
+            kinematic: false,
+        }
+    }
+}
+
+impl RigidBody {
+    pub fn kinematic() -> Self {
+        Self {
+            gravity: 0.0,
+            kinematic: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Small movable voxel model with its own brick volume.
+///
+/// The volume is centered on `transform.position` and rotated and scaled around its center,
+/// one brick of the volume spans one world unit at scale 1.
+pub struct VoxelObject {
+    pub transform: Transform,
+    pub body: RigidBody,
+    brickmap: BrickMap,
+}
+
+impl VoxelObject {
+    /// Empty object of `size` bricks, fill it through [`VoxelObject::brickmap`].
+    pub fn new(size: na::Vector3<u32>, transform: Transform, body: RigidBody) -> Self {
+        Self {
+            transform,
+            body,
+            brickmap: BrickMap::new(size),
+        }
+    }
+
+    /// Object holding `volume`, see [`VoxelVolume::to_brickmap`].
+    pub fn from_volume(
+        volume: &VoxelVolume,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+        transform: Transform,
+        body: RigidBody,
+    ) -> Self {
+        Self {
+            transform,
+            body,
+            brickmap: volume.to_brickmap(palettes, materials),
+        }
+    }
+
+    pub fn brickmap(&self) -> &BrickMap {
+        &self.brickmap
+    }
+
+    /// Size of the volume in bricks.
+    pub fn size(&self) -> na::Vector3<u32> {
+        self.brickmap.dimensions()
+    }
+
+    /// Maps volume coordinates, in bricks, to world space.
+    pub fn to_world(&self) -> na::Matrix4<f32> {
+        volume_to_world(&self.transform, self.size())
+    }
+
+    /// Maps world space to volume coordinates, in bricks.
+    pub fn to_local(&self) -> na::Matrix4<f32> {
+        self.to_world()
+            .try_inverse()
+            .unwrap_or(na::Matrix4::identity())
+    }
+
+    /// World box around the whole volume, solid or not.
+    pub fn bounds(&self) -> Aabb {
+        let size = self.size().cast::<f32>();
+        transform_box(
+            &self.to_world(),
+            &Aabb::new(na::Point3::origin(), size.into()),
+        )
+    }
+
+    /// Traces a world space ray against the volume, the hit is in world space except for
+    /// `brick` and `voxel`, which address the volume.
+    pub fn trace(&self, ray: &Ray) -> Option<RayHit> {
+        trace_volume(&self.brickmap, &self.to_local(), ray)
+    }
+
+    /// Whether the box overlaps a solid voxel of the volume. Rotated objects are tested with
+    /// the local box around it, which may report overlaps close to a solid voxel.
+    pub fn overlaps_solid(&self, aabb: &Aabb) -> bool {
+        if !self.bounds().overlaps(aabb) {
+            return false;
+        }
+
+        let local = transform_box(&self.to_local(), aabb);
+        self.brickmap.overlaps_solid(&local)
+    }
+
+    /// Integrates the body over `dt` and collides the world bounds of the object with `world`.
+    ///
+    /// The response works on the bounds rather than single voxels: the object bounces off and
+    /// slides along the world, while rotation is integrated without being swept.
+    pub fn step(&mut self, world: &BrickMap, dt: f32) {
+        let body = &mut self.body;
+        if body.kinematic {
+            return;
+        }
+
+        body.velocity.y -= body.gravity * dt;
+        let spin = na::UnitQuaternion::from_scaled_axis(body.angular_velocity * dt);
+        self.transform.rotation = spin * self.transform.rotation;
+
+        // axis by axis, so a contact on one only stops the motion along it
+        let mut bounds = self.bounds();
+        for axis in [1, 0, 2] {
+            let mut delta = na::Vector3::zeros();
+            delta[axis] = self.body.velocity[axis] * dt;
+            if delta[axis] == 0.0 {
+                continue;
+            }
+
+            let travelled = match world.sweep_aabb(&bounds, delta) {
+                Some(contact) => {
+                    let body = &mut self.body;
+                    let speed = body.velocity[axis];
+                    // resting bodies pick up gravity * dt every step, that alone mustn't bounce
+                    let rest = REST_SPEED + body.gravity * dt;
+                    body.velocity[axis] = if speed.abs() * body.restitution > rest {
+                        -speed * body.restitution
+                    } else {
+                        0.0
+                    };
+                    for other in (0..3).filter(|&other| other != axis) {
+                        body.velocity[other] *= 1.0 - body.friction;
+                    }
+                    body.angular_velocity *= 1.0 - body.friction;
+
+                    let distance = contact.time * delta[axis].abs();
+                    (distance - SKIN).max(0.0) * delta[axis].signum()
+                }
+                None => delta[axis],
+            };
+
+            self.transform.position[axis] += travelled;
+            let mut offset = na::Vector3::zeros();
+            offset[axis] = travelled;
+            bounds = bounds.translated(offset);
+        }
+    }
+}
+
+/// Maps coordinates of a volume of `size` bricks to world space, with the volume centered on
+/// the position of `transform`.
+pub(crate) fn volume_to_world(transform: &Transform, size: na::Vector3<u32>) -> na::Matrix4<f32> {
+    let center = size.cast::<f32>() / 2.0;
+    transform.to_homogeneous() * na::Matrix4::new_translation(&-center)
+}
+
+/// Traces a world space ray against a volume placed with the inverse of `to_local`.
+pub(crate) fn trace_volume(
+    brickmap: &BrickMap,
+    to_local: &na::Matrix4<f32>,
+    ray: &Ray,
+) -> Option<RayHit> {
+    let direction = to_local.transform_vector(&ray.direction);
+    // world distance per local distance, the ray is renormalized in the volume
+    let stretch = direction.norm();
+    if stretch <= f32::EPSILON {
+        return None;
+    }
+
+    let local = Ray {
+        origin: to_local.transform_point(&ray.origin),
+        direction: direction / stretch,
+        max_distance: ray.max_distance * stretch,
+        mode: ray.mode,
+        mip_distance: ray.mip_distance * stretch,
+    };
+    let mut hit = raytrace::trace_ray(brickmap, &local)?;
+
+    hit.distance /= stretch;
+    hit.position = ray.at(hit.distance);
+    // normals transform with the inverse transpose
+    hit.normal = (to_local.fixed_view::<3, 3>(0, 0).transpose() * hit.normal)
+        .try_normalize(f32::EPSILON)
+        .unwrap_or(na::Vector3::zeros());
+    Some(hit)
+}
+
+/// Box around `aabb` after transforming it by `matrix`.
+pub(crate) fn transform_box(matrix: &na::Matrix4<f32>, aabb: &Aabb) -> Aabb {
+    let corners = (0..8).map(|corner| {
+        let p = na::Point3::from(na::Vector3::from_fn(|axis, _| {
+            if (corner >> axis) & 1 != 0 {
+                aabb.max[axis]
+            } else {
+                aabb.min[axis]
+            }
+        }));
+        matrix.transform_point(&p)
+    });
+    let first = matrix.transform_point(&aabb.min);
+    corners.fold(Aabb::new(first, first), |bounds, p| {
+        Aabb::new(bounds.min.inf(&p), bounds.max.sup(&p))
+    })
+}
+
+/// Index of an object in [`VoxelObjects`], stays valid until the object is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectId(pub u32);
+
+/// All dynamic objects of a world, removed slots are reused.
+#[derive(Default)]
+pub struct VoxelObjects {
+    objects: Vec<Option<VoxelObject>>,
+    free: Vec<u32>,
+}
+
+impl VoxelObjects {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, object: VoxelObject) -> ObjectId {
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index as usize] = Some(object);
+                ObjectId(index)
+            }
+            None => {
+                self.objects.push(Some(object));
+                ObjectId(self.objects.len() as u32 - 1)
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: ObjectId) -> Option<VoxelObject> {
+        let object = self.objects.get_mut(id.0 as usize)?.take()?;
+        self.free.push(id.0);
+        Some(object)
+    }
+
+    pub fn get(&self, id: ObjectId) -> Option<&VoxelObject> {
+        self.objects.get(id.0 as usize)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: ObjectId) -> Option<&mut VoxelObject> {
+        self.objects.get_mut(id.0 as usize)?.as_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ObjectId, &VoxelObject)> {
+        self.objects
+            .iter()
+            .enumerate()
+            .filter_map(|(index, object)| Some((ObjectId(index as u32), object.as_ref()?)))
+    }
+
+    /// Advances every object by `dt`, see [`VoxelObject::step`].
+    pub fn step(&mut self, world: &BrickMap, dt: f32) {
+        for object in self.objects.iter_mut().flatten() {
+            object.step(world, dt);
+        }
+    }
+}
+
+/// Hit of a [`Scene`] ray, `object` and `instance` are both `None` for the world.
+#[derive(Debug, Clone, Copy)]
+pub struct SceneHit {
+    pub hit: RayHit,
+    pub object: Option<ObjectId>,
+    pub instance: Option<InstanceId>,
+}
+
+/// The world brickmap together with the objects moving through it and optionally the
+/// instances placed in it.
+#[derive(Clone, Copy)]
+pub struct Scene<'a> {
+    pub world: &'a BrickMap,
+    pub objects: &'a VoxelObjects,
+    pub instances: Option<&'a InstanceTable>,
+}
+
+impl<'a> Scene<'a> {
+    pub fn new(world: &'a BrickMap, objects: &'a VoxelObjects) -> Self {
+        Self {
+            world,
+            objects,
+            instances: None,
+        }
+    }
+
+    pub fn with_instances(mut self, instances: &'a InstanceTable) -> Self {
+        self.instances = Some(instances);
+        self
+    }
+
+    /// Closest hit among the world, the instances and all objects.
+    pub fn trace_ray(&self, ray: &Ray) -> Option<SceneHit> {
+        let mut best = raytrace::trace_ray(self.world, ray).map(|hit| SceneHit {
+            hit,
+            object: None,
+            instance: None,
+        });
+
+        if let Some(instances) = self.instances {
+            let max_distance = best.map_or(ray.max_distance, |best| best.hit.distance);
+            if let Some((id, hit)) = instances.trace_ray(&ray.with_max_distance(max_distance)) {
+                best = Some(SceneHit {
+                    hit,
+                    object: None,
+                    instance: Some(id),
+                });
+            }
+        }
+
+        for (id, object) in self.objects.iter() {
+            let max_distance = best.map_or(ray.max_distance, |best| best.hit.distance);
+            let bounds = object.bounds();
+            let (near, far, _) = raytrace::intersect_box(ray, bounds.min.coords, bounds.max.coords);
+            if near > far || far < 0.0 || near > max_distance {
+                continue;
+            }
+
+            let ray = ray.with_max_distance(max_distance);
+            if let Some(hit) = object.trace(&ray) {
+                best = Some(SceneHit {
+                    hit,
+                    object: Some(id),
+                    instance: None,
+                });
+            }
+        }
+
+        best
+    }
+
+    /// Whether the box overlaps solid voxels of the world, of an instance or of any object.
+    pub fn overlaps_solid(&self, aabb: &Aabb) -> bool {
+        self.world.overlaps_solid(aabb)
+            || self
+                .instances
+                .is_some_and(|instances| !instances.overlapping(aabb).is_empty())
+            || self
+                .objects
+                .iter()
+                .any(|(_, object)| object.overlaps_solid(aabb))
+    }
+
+    /// Objects whose solid voxels overlap the box.
+    pub fn overlapping_objects(&self, aabb: &Aabb) -> Vec<ObjectId> {
+        self.objects
+            .iter()
+            .filter(|(_, object)| object.overlaps_solid(aabb))
+            .map(|(id, _)| id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brick::TraceBrick, instances::Instance};
+
+    fn full_brick() -> TraceBrick {
+        let mut brick = TraceBrick::empty();
+        brick.data_mut().fill(0xFF);
+        brick
+    }
+
+    /// Floor of full bricks at y 0 on an 8 x 4 x 8 map.
+    fn world() -> BrickMap {
+        let brickmap = BrickMap::new(na::Vector3::new(8, 4, 8));
+        for x in 0..8 {
+            for z in 0..8 {
+                brickmap.set_brick(full_brick(), na::Point3::new(x, 0, z));
+            }
+        }
+        brickmap
+    }
+
+    /// Single full brick object at `position`.
+    fn cube(position: na::Vector3<f32>, body: RigidBody) -> VoxelObject {
+        let mut transform = Transform::identity();
+        transform.position(&position);
+        let object = VoxelObject::new(na::Vector3::new(1, 1, 1), transform, body);
+        object
+            .brickmap()
+            .set_brick(full_brick(), na::Point3::origin());
+        object
+    }
+
+    #[test]
+    fn test_trace_transformed() {
+        let mut object = cube(na::Vector3::new(4.0, 2.5, 4.0), RigidBody::kinematic());
+        object.transform.scale(2.0);
+        object.transform.rotate_around(&na::Vector3::y_axis(), 45.0);
+
+        // scaled by 2 and turned 45° around y, the corner sits sqrt(2) from the center and the
+        // faces next to it run diagonally
+        let ray = Ray::new(na::Point3::new(0.0, 2.5, 4.1), na::Vector3::x());
+        let hit = object.trace(&ray).unwrap();
+        assert!((hit.distance - (4.1 - 2f32.sqrt())).abs() < 1e-3, "{hit:?}");
+        assert!(hit.normal.x < 0.0 && hit.normal.y.abs() < 1e-4);
+        assert!((hit.normal.norm() - 1.0).abs() < 1e-4);
+
+        let miss = Ray::new(na::Point3::new(0.0, 4.0, 4.0), na::Vector3::x());
+        assert!(object.trace(&miss).is_none());
+    }
+
+    #[test]
+    fn test_scene_queries() {
+        let world = world();
+        let mut objects = VoxelObjects::new();
+        let id = objects.insert(cube(
+            na::Vector3::new(4.5, 2.5, 4.5),
+            RigidBody::kinematic(),
+        ));
+        let scene = Scene::new(&world, &objects);
+
+        let down = Ray::new(na::Point3::new(4.5, 3.9, 4.5), -na::Vector3::y());
+        let hit = scene.trace_ray(&down).unwrap();
+        assert_eq!(hit.object, Some(id));
+        assert!((hit.hit.distance - 0.9).abs() < 1e-3);
+
+        let beside = Ray::new(na::Point3::new(2.5, 3.9, 4.5), -na::Vector3::y());
+        let hit = scene.trace_ray(&beside).unwrap();
+        assert_eq!(hit.object, None);
+        assert!((hit.hit.distance - 2.9).abs() < 1e-3);
+
+        let probe = Aabb::from_center(na::Point3::new(4.5, 3.1, 4.5), na::Vector3::repeat(0.2));
+        assert!(scene.overlaps_solid(&probe));
+        assert_eq!(scene.overlapping_objects(&probe), vec![id]);
+        assert!(!scene.overlaps_solid(&probe.translated(na::Vector3::new(0.0, 0.5, 0.0))));
+
+        // an instance of the same block in the way of the second ray
+        let mut instances = InstanceTable::new();
+        let model =
+            instances.add_model(cube(na::Vector3::zeros(), RigidBody::kinematic()).brickmap);
+        let mut transform = Transform::identity();
+        transform.position(&na::Vector3::new(2.5, 2.5, 4.5));
+        let instance = instances.insert(Instance { model, transform });
+        instances.update();
+        let hit = scene.with_instances(&instances).trace_ray(&beside).unwrap();
+        assert_eq!((hit.object, hit.instance), (None, instance));
+        assert!((hit.hit.distance - 0.9).abs() < 1e-3);
+
+        objects.remove(id);
+        assert!(objects.is_empty());
+        assert_eq!(
+            objects.insert(cube(na::Vector3::zeros(), RigidBody::default())),
+            id
+        );
+    }
+
+    #[test]
+    fn test_falls_onto_world() {
+        let world = world();
+        let mut object = cube(na::Vector3::new(4.0, 3.0, 4.0), RigidBody::default());
+        object.body.velocity.x = 1.0;
+        for _ in 0..240 {
+            object.step(&world, 1.0 / 60.0);
+        }
+
+        // resting with its bottom on the floor at y 1, sliding slowed down by friction
+        let bounds = object.bounds();
+        assert!((bounds.min.y - 1.0).abs() < 1e-3, "{bounds:?}");
+        assert_eq!(object.body.velocity.y, 0.0);
+        assert!(object.body.velocity.x < 1.0);
+        assert!(object.transform.position.x > 4.0);
+    }
+}
@@ -1,12 +1,19 @@
-use crate::{material::MaterialId, palette::PaletteId};
+use crate::{
+    brick::{BrickMap, ExpandedBrick},
+    material::{ExpandedMaterialMapping, MaterialId},
+    palette::{PaletteId, PaletteRegistry},
+    raytrace::{intersect_box, Dda, Ray},
+};
 
-#[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct SexagintaQuattourHandle(pub u32);
 
 impl SexagintaQuattourHandle {
     pub const EMPTY: Self = Self(0);
     pub const HANDLE: Self = Self(1 << 31);
     pub const MATERIAL: Self = Self(1 << 30);
+    const VALUE_MASK: u32 = 0x3FFF_FFFF;
 }
 
 impl SexagintaQuattourHandle {
@@ -16,59 +23,57 @@ impl SexagintaQuattourHandle {
     pub fn material(material: MaterialId) -> Self {
         Self(Self::MATERIAL.0 | material.0)
     }
-}
 
-pub struct SexagintaQuattourNode {
-    pub meta: u32,
-}
+    pub fn is_empty(&self) -> bool {
+        self.0 == Self::EMPTY.0
+    }
 
-impl SexagintaQuattourNode {
-    const EMPTY: Self = Self { meta: 0 };
-    pub fn new(children: u8) -> Self {
-        let mut new = Self::EMPTY;
-        new.set_children(children);
-        new
+    pub fn is_handle(&self) -> bool {
+        (self.0 & Self::HANDLE.0) != 0
     }
 
-    pub fn set_children(&mut self, children: u8) {
-        self.meta = (self.meta & 0xFFFFFF00) | (children as u32);
+    pub fn is_material(&self) -> bool {
+        !self.is_handle() && (self.0 & Self::MATERIAL.0) != 0
     }
 
-    pub fn get_children(&self) -> u8 {
-        (self.meta & 0xFF) as u8
+    pub fn offset(&self) -> u32 {
+        self.0 & Self::VALUE_MASK
     }
 
-    pub fn get_child_nodes(&self) -> [SexagintaQuattourHandle; 8] {
-        let children_ptr = unsafe {
-            (self as *const Self as *const u8).add(std::mem::size_of::<u32>())
-                as *const SexagintaQuattourHandle
-        };
+    pub fn get_material(&self) -> MaterialId {
+        if self.is_material() {
+            MaterialId(self.0 & Self::VALUE_MASK)
+        } else {
+            MaterialId::EMPTY
+        }
+    }
+}
 
-        let mut children = [SexagintaQuattourHandle::EMPTY; 8];
+/// Inner node with 4x4x4 children, indexed `x + y * 4 + z * 16`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SexagintaQuattourNode {
+    /// One bit per non empty child.
+    pub mask: [u32; 2],
+    pub children: [SexagintaQuattourHandle; 64],
+}
 
-        unsafe {
-            std::ptr::copy_nonoverlapping(children_ptr, children.as_mut_ptr(), 8);
-        }
+impl SexagintaQuattourNode {
+    const EMPTY: Self = Self {
+        mask: [0; 2],
+        children: [SexagintaQuattourHandle::EMPTY; 64],
+    };
 
-        children
+    pub fn new() -> Self {
+        Self::EMPTY
     }
 
-    pub fn get_child_nodes_mut(&mut self) -> &mut [SexagintaQuattourHandle; 8] {
-        unsafe {
-            let children_ptr = (self as *mut Self as *mut u8).add(std::mem::size_of::<u32>())
-                as *mut &mut [SexagintaQuattourHandle; 8];
-            &mut *children_ptr
-        }
+    pub fn get_children(&self) -> u64 {
+        self.mask[0] as u64 | ((self.mask[1] as u64) << 32)
     }
 
     pub fn get_child_indexed(&self, index: u8) -> Option<SexagintaQuattourHandle> {
-        if index >= 64 {
-            return None;
-        }
-
-        let octant = index / 8;
-        let children = self.get_child_nodes();
-        Some(children[octant as usize])
+        self.children.get(index as usize).copied()
     }
 
     pub fn set_child_indexed(&mut self, index: u8, handle: SexagintaQuattourHandle) -> bool {
@@ -76,9 +81,14 @@ impl SexagintaQuattourNode {
             return false;
         }
 
-        let octant = index / 8;
-        let children = self.get_child_nodes_mut();
-        children[octant as usize] = handle;
+        let word = (index / 32) as usize;
+        let bit = 1 << (index % 32);
+        if handle.is_empty() {
+            self.mask[word] &= !bit;
+        } else {
+            self.mask[word] |= bit;
+        }
+        self.children[index as usize] = handle;
         true
     }
 
@@ -93,6 +103,15 @@ impl SexagintaQuattourNode {
     }
 }
 
+impl Default for SexagintaQuattourNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 4x4x4 voxels stored as 6 bit indices into a palette.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct UncompressedSexagintaQuattourOuterNode {
     pub palette: PaletteId,
     pub voxels: [u32; 12],
@@ -111,8 +130,9 @@ impl UncompressedSexagintaQuattourOuterNode {
 
     #[inline]
     pub fn set_index(&mut self, position: u8, value: u8) {
-        let array_pos = (position * 6 / 32) as usize;
-        let bit_pos = (position * 6 % 32) as u32;
+        let bit = position as u32 * 6;
+        let array_pos = (bit / 32) as usize;
+        let bit_pos = bit % 32;
 
         self.voxels[array_pos] &= !(0x3f << bit_pos);
         self.voxels[array_pos] |= (value as u32) << bit_pos;
@@ -126,8 +146,9 @@ impl UncompressedSexagintaQuattourOuterNode {
 
     #[inline]
     pub fn get_index(&self, position: u8) -> u8 {
-        let array_pos = (position * 6 / 32) as usize;
-        let bit_pos = (position * 6 % 32) as u32;
+        let bit = position as u32 * 6;
+        let array_pos = (bit / 32) as usize;
+        let bit_pos = bit % 32;
 
         if bit_pos <= 26 {
             ((self.voxels[array_pos] >> bit_pos) & 0x3f) as u8
@@ -140,3 +161,516 @@ impl UncompressedSexagintaQuattourOuterNode {
         }
     }
 }
+
+impl Default for UncompressedSexagintaQuattourOuterNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SexagintaQuattourHit {
+    /// Distance in world units (bricks), like [`crate::raytrace::RayHit`].
+    pub distance: f32,
+    pub normal: na::Vector3<f32>,
+    /// Voxel relative to the tree origin.
+    pub voxel: na::Point3<u32>,
+    pub material: MaterialId,
+}
+
+/// A 64³ voxel region: two levels of [`SexagintaQuattourNode`]s over 4³ outer nodes.
+#[derive(Debug, Clone)]
+pub struct SexagintaQuattourTree {
+    pub root: SexagintaQuattourHandle,
+    pub nodes: Vec<SexagintaQuattourNode>,
+    pub outer: Vec<UncompressedSexagintaQuattourOuterNode>,
+}
+
+impl SexagintaQuattourTree {
+    /// Voxels per axis.
+    pub const SIZE: u32 = 64;
+    /// Bricks per axis.
+    pub const BRICKS: u32 = Self::SIZE / 8;
+    /// Root, inner, outer.
+    const LEVELS: u32 = 3;
+
+    fn node_size(level: u32) -> u32 {
+        Self::SIZE >> (2 * level)
+    }
+
+    fn dense_index(at: na::Point3<u32>) -> usize {
+        (at.x + at.y * Self::SIZE + at.z * Self::SIZE * Self::SIZE) as usize
+    }
+
+    /// Builds a tree by sampling every voxel of the region, `sample` gets region local positions.
+    pub fn build<F>(palettes: &PaletteRegistry, sample: F) -> Self
+    where
+        F: Fn(na::Point3<u32>) -> MaterialId,
+    {
+        let mut dense = vec![MaterialId::EMPTY; (Self::SIZE * Self::SIZE * Self::SIZE) as usize];
+        for z in 0..Self::SIZE {
+            for y in 0..Self::SIZE {
+                for x in 0..Self::SIZE {
+                    let at = na::Point3::new(x, y, z);
+                    dense[Self::dense_index(at)] = sample(at);
+                }
+            }
+        }
+
+        let mut tree = Self {
+            root: SexagintaQuattourHandle::EMPTY,
+            nodes: Vec::new(),
+            outer: Vec::new(),
+        };
+        tree.root = tree.build_node(&dense, palettes, 0, na::Point3::origin());
+        tree
+    }
+
+    /// Builds a tree from the 8³ bricks starting at brick position `origin`.
+    pub fn from_brickmap(
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        origin: na::Point3<u32>,
+    ) -> Self {
+        let view = brickmap.read();
+        let voxel_origin = origin.coords * 8;
+        Self::build(palettes, |at| view.get_voxel(palettes, at + voxel_origin))
+    }
+
+    fn build_node(
+        &mut self,
+        dense: &[MaterialId],
+        palettes: &PaletteRegistry,
+        level: u32,
+        origin: na::Point3<u32>,
+    ) -> SexagintaQuattourHandle {
+        let child_size = Self::node_size(level) / 4;
+        let child_origin =
+            |i: u32| origin + na::Vector3::new(i % 4, (i / 4) % 4, i / 16) * child_size;
+
+        if level == Self::LEVELS - 1 {
+            let materials: Vec<MaterialId> = (0..64)
+                .map(|i| dense[Self::dense_index(child_origin(i))])
+                .collect();
+            if materials.iter().all(|&m| m == materials[0]) {
+                return Self::uniform(materials[0]);
+            }
+
+            let mut palette = materials.clone();
+            palette.sort_unstable_by_key(|m| m.0);
+            palette.dedup();
+
+            let mut node = UncompressedSexagintaQuattourOuterNode::new();
+            for (i, material) in materials.iter().enumerate() {
+                let index = palette.binary_search_by_key(&material.0, |m| m.0).unwrap();
+                node.set_index(i as u8, index as u8);
+            }
+            node.palette = palettes.register_palette(palette);
+
+            let offset = self.outer.len() as u32;
+            self.outer.push(node);
+            return SexagintaQuattourHandle::handle(offset);
+        }
+
+        let mut node = SexagintaQuattourNode::new();
+        for i in 0..64 {
+            let child = self.build_node(dense, palettes, level + 1, child_origin(i));
+            node.set_child_indexed(i as u8, child);
+        }
+
+        let first = node.children[0];
+        if !first.is_handle() && node.children.iter().all(|&child| child == first) {
+            return first;
+        }
+
+        let offset = self.nodes.len() as u32;
+        self.nodes.push(node);
+        SexagintaQuattourHandle::handle(offset)
+    }
+
+    fn uniform(material: MaterialId) -> SexagintaQuattourHandle {
+        if material == MaterialId::EMPTY {
+            SexagintaQuattourHandle::EMPTY
+        } else {
+            SexagintaQuattourHandle::material(material)
+        }
+    }
+
+    fn outer_material(
+        &self,
+        palettes: &PaletteRegistry,
+        handle: SexagintaQuattourHandle,
+        index: u8,
+    ) -> MaterialId {
+        let node = &self.outer[handle.offset() as usize];
+        let palette_index = node.get_index(index) as usize;
+        palettes
+            .palette_data()
+            .get(node.palette.0 as usize + palette_index)
+            .copied()
+            .unwrap_or(MaterialId::EMPTY)
+    }
+
+    /// Material at a region local voxel position.
+    pub fn get(&self, palettes: &PaletteRegistry, at: na::Point3<u32>) -> MaterialId {
+        if at.iter().any(|&c| c >= Self::SIZE) {
+            return MaterialId::EMPTY;
+        }
+
+        let mut handle = self.root;
+        for level in 0..Self::LEVELS {
+            if !handle.is_handle() {
+                return handle.get_material();
+            }
+
+            let child_size = Self::node_size(level) / 4;
+            let local = at.map(|c| (c / child_size) % 4);
+            let index = (local.x + local.y * 4 + local.z * 16) as u8;
+
+            if level == Self::LEVELS - 1 {
+                return self.outer_material(palettes, handle, index);
+            }
+            handle = self.nodes[handle.offset() as usize].children[index as usize];
+        }
+
+        MaterialId::EMPTY
+    }
+
+    /// Traces a ray given in brick space against the tree placed at brick position `origin`.
+    pub fn trace(
+        &self,
+        palettes: &PaletteRegistry,
+        origin: na::Point3<u32>,
+        ray: &Ray,
+    ) -> Option<SexagintaQuattourHit> {
+        // work in region local voxel space, so every level has integer cell sizes
+        let local = Ray {
+            origin: na::Point3::from((ray.origin - origin.cast::<f32>()) * 8.0),
+            direction: ray.direction,
+            max_distance: ray.max_distance * 8.0,
+            mode: ray.mode,
+            mip_distance: f32::INFINITY,
+        };
+
+        let size = Self::SIZE as f32;
+        let (t_near, t_far, axis) =
+            intersect_box(&local, na::Vector3::zeros(), na::Vector3::repeat(size));
+        let t_far = t_far.min(local.max_distance);
+        if t_near > t_far || t_far < 0.0 {
+            return None;
+        }
+
+        let (t, entry_axis) = if t_near > 0.0 {
+            (t_near, Some(axis))
+        } else {
+            (0.0, None)
+        };
+
+        let mut hit = self.trace_node(
+            palettes,
+            &local,
+            self.root,
+            0,
+            na::Vector3::zeros(),
+            t,
+            t_far,
+            entry_axis,
+        )?;
+        hit.distance /= 8.0;
+        Some(hit)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn trace_node(
+        &self,
+        palettes: &PaletteRegistry,
+        ray: &Ray,
+        handle: SexagintaQuattourHandle,
+        level: u32,
+        origin: na::Vector3<i32>,
+        t_enter: f32,
+        t_exit: f32,
+        entry_axis: Option<usize>,
+    ) -> Option<SexagintaQuattourHit> {
+        let node_size = Self::node_size(level) as i32;
+
+        if handle.is_empty() {
+            return None;
+        }
+
+        if handle.is_material() {
+            let mut normal = na::Vector3::zeros();
+            if let Some(axis) = entry_axis {
+                normal[axis] = -ray.direction[axis].signum();
+            }
+            let position = ray.at(t_enter + 1e-4);
+            let voxel = na::Point3::from(origin.zip_map(&position.coords, |min, p| {
+                (p.floor() as i32).clamp(min, min + node_size - 1) as u32
+            }));
+            return Some(SexagintaQuattourHit {
+                distance: t_enter,
+                normal,
+                voxel,
+                material: handle.get_material(),
+            });
+        }
+
+        let child_size = node_size / 4;
+        let min = origin / child_size;
+        let max = min + na::Vector3::repeat(4);
+        let mut dda = Dda::new(ray, t_enter, 1.0 / child_size as f32, min, max);
+        let mut t = t_enter;
+
+        while t <= t_exit {
+            let local = dda.cell - min;
+            if local.iter().any(|&c| !(0..4).contains(&c)) {
+                break;
+            }
+            let index = (local.x + local.y * 4 + local.z * 16) as u8;
+            let axis = dda.axis.or(entry_axis);
+
+            if level == Self::LEVELS - 1 {
+                let material = self.outer_material(palettes, handle, index);
+                if material != MaterialId::EMPTY {
+                    return Some(SexagintaQuattourHit {
+                        distance: t,
+                        normal: dda.normal(entry_axis),
+                        voxel: dda.cell.map(|c| c as u32).into(),
+                        material,
+                    });
+                }
+            } else {
+                let child = self.nodes[handle.offset() as usize].children[index as usize];
+                let hit = self.trace_node(
+                    palettes,
+                    ray,
+                    child,
+                    level + 1,
+                    dda.cell * child_size,
+                    t,
+                    dda.exit().min(t_exit),
+                    axis,
+                );
+                if hit.is_some() {
+                    return hit;
+                }
+            }
+
+            t = dda.step();
+        }
+
+        None
+    }
+
+    /// Expands the tree back into one material per voxel, `x + y * 64 + z * 64 * 64`.
+    pub fn expand(&self, palettes: &PaletteRegistry) -> Vec<MaterialId> {
+        let mut dense = vec![MaterialId::EMPTY; (Self::SIZE * Self::SIZE * Self::SIZE) as usize];
+        for z in 0..Self::SIZE {
+            for y in 0..Self::SIZE {
+                for x in 0..Self::SIZE {
+                    let at = na::Point3::new(x, y, z);
+                    dense[Self::dense_index(at)] = self.get(palettes, at);
+                }
+            }
+        }
+        dense
+    }
+
+    /// Writes the region back as regular bricks starting at brick position `origin`.
+    pub fn write_to_brickmap(
+        &self,
+        brickmap: &BrickMap,
+        palettes: &PaletteRegistry,
+        origin: na::Point3<u32>,
+    ) {
+        let dense = self.expand(palettes);
+        let dims = brickmap.dimensions();
+
+        for bz in 0..Self::BRICKS {
+            for by in 0..Self::BRICKS {
+                for bx in 0..Self::BRICKS {
+                    let at = origin + na::Vector3::new(bx, by, bz);
+                    if at.x >= dims.x || at.y >= dims.y || at.z >= dims.z {
+                        continue;
+                    }
+
+                    let mut mapping = ExpandedMaterialMapping::new();
+                    mapping.insert(0, MaterialId::EMPTY);
+                    let mut local = vec![MaterialId::EMPTY];
+                    let mut brick = ExpandedBrick::empty();
+
+                    for z in 0..8 {
+                        for y in 0..8 {
+                            for x in 0..8 {
+                                let voxel = na::Point3::new(bx * 8 + x, by * 8 + y, bz * 8 + z);
+                                let material = dense[Self::dense_index(voxel)];
+                                let value = match local.iter().position(|&m| m == material) {
+                                    Some(value) => value,
+                                    None => {
+                                        local.push(material);
+                                        mapping.insert((local.len() - 1) as u8, material);
+                                        local.len() - 1
+                                    }
+                                };
+                                brick.set(x, y, z, value as u8);
+                            }
+                        }
+                    }
+
+                    if brick.is_empty() {
+                        let handle = brickmap.get_handle(at);
+                        if handle.is_data() || handle.is_lod() {
+                            brickmap.set_empty(at);
+                        }
+                    } else {
+                        brickmap.set_expanded_brick(at, &brick, &mapping, palettes);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raytrace::trace_ray;
+
+    fn materials() -> (ExpandedMaterialMapping, PaletteRegistry) {
+        let mut mapping = ExpandedMaterialMapping::new();
+        for i in 0..6 {
+            mapping.insert(i, MaterialId(i as u32));
+        }
+        (mapping, PaletteRegistry::new())
+    }
+
+    fn scene(mapping: &ExpandedMaterialMapping, palettes: &PaletteRegistry) -> BrickMap {
+        let brickmap = BrickMap::new(na::Vector3::new(16, 16, 16));
+        for bz in 0..8 {
+            for bx in 0..8 {
+                for by in 0..3 {
+                    let mut brick = ExpandedBrick::empty();
+                    for z in 0..8 {
+                        for x in 0..8 {
+                            let height = ((bx * 8 + x) as f32 * 0.2).sin() * 4.0
+                                + ((bz * 8 + z) as f32 * 0.15).cos() * 4.0
+                                + 12.0;
+                            for y in 0..8 {
+                                let world_y = (by * 8 + y) as f32;
+                                let value = if world_y > height {
+                                    0
+                                } else if world_y > height - 1.0 {
+                                    4
+                                } else if world_y > height - 4.0 {
+                                    3
+                                } else {
+                                    2 + ((x + y + z) % 2) as u8 * 3
+                                };
+                                brick.set(x, y, z, value);
+                            }
+                        }
+                    }
+                    if !brick.is_empty() {
+                        let at = na::Point3::new(bx + 4, by, bz + 2);
+                        brickmap.set_expanded_brick(at, &brick, mapping, palettes);
+                    }
+                }
+            }
+        }
+        brickmap.set_lod(na::Point3::new(6, 5, 6), MaterialId(1));
+        brickmap
+    }
+
+    #[test]
+    fn test_outer_node_packing() {
+        let mut node = UncompressedSexagintaQuattourOuterNode::new();
+        let value = |i: u8| ((i as u32 * 7) % 64) as u8;
+        for i in 0..64u8 {
+            node.set_index(i, value(i));
+        }
+        for i in 0..64u8 {
+            assert_eq!(node.get_index(i), value(i));
+        }
+    }
+
+    #[test]
+    fn test_uniform_collapses() {
+        let palettes = PaletteRegistry::new();
+        let tree = SexagintaQuattourTree::build(&palettes, |_| MaterialId(3));
+        assert_eq!(tree.root, SexagintaQuattourHandle::material(MaterialId(3)));
+        assert!(tree.nodes.is_empty() && tree.outer.is_empty());
+
+        let empty = SexagintaQuattourTree::build(&palettes, |_| MaterialId::EMPTY);
+        assert!(empty.root.is_empty());
+    }
+
+    #[test]
+    fn test_round_trip_brickmap() {
+        let (mapping, palettes) = materials();
+        let brickmap = scene(&mapping, &palettes);
+        let origin = na::Point3::new(4, 0, 2);
+        let tree = SexagintaQuattourTree::from_brickmap(&brickmap, &palettes, origin);
+
+        let view = brickmap.read();
+        let voxel_origin = origin.coords * 8;
+        for z in 0..64 {
+            for y in 0..64 {
+                for x in 0..64 {
+                    let at = na::Point3::new(x, y, z);
+                    assert_eq!(
+                        tree.get(&palettes, at),
+                        view.get_voxel(&palettes, at + voxel_origin),
+                        "{:?}",
+                        at
+                    );
+                }
+            }
+        }
+
+        let copy = BrickMap::new(na::Vector3::new(16, 16, 16));
+        tree.write_to_brickmap(&copy, &palettes, origin);
+        let copy_view = copy.read();
+        for z in 0..64 {
+            for y in 0..64 {
+                for x in 0..64 {
+                    let at = na::Point3::new(x, y, z) + voxel_origin;
+                    assert_eq!(
+                        copy_view.get_voxel(&palettes, at),
+                        view.get_voxel(&palettes, at)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_trace_matches_brickmap() {
+        let (mapping, palettes) = materials();
+        let brickmap = scene(&mapping, &palettes);
+        let origin = na::Point3::new(4, 0, 2);
+        let tree = SexagintaQuattourTree::from_brickmap(&brickmap, &palettes, origin);
+
+        let mut hits = 0;
+        for i in 0..128 {
+            let f = i as f32;
+            let ray = Ray::new(
+                na::Point3::new(5.0 + (f * 0.37) % 6.0, 3.5, 3.0 + (f * 0.53) % 6.0),
+                na::Vector3::new((f * 0.7).sin() * 0.5, -1.0, (f * 0.3).cos() * 0.5),
+            );
+
+            let expected = trace_ray(&brickmap, &ray);
+            let hit = tree.trace(&palettes, origin, &ray);
+            assert_eq!(expected.is_some(), hit.is_some(), "ray {}", i);
+
+            if let (Some(expected), Some(hit)) = (expected, hit) {
+                hits += 1;
+                assert!((expected.distance - hit.distance).abs() < 1e-3, "ray {}", i);
+                assert_eq!(expected.normal, hit.normal, "ray {}", i);
+                let world = hit.voxel.coords + origin.coords * 8;
+                let material = brickmap.get_voxel(&palettes, world.into());
+                assert_eq!(material, hit.material);
+            }
+        }
+        assert!(hits > 64);
+    }
+}
@@ -10,6 +10,21 @@ impl PaletteId {
     pub const EMPTY: Self = Self(0);
 }
 
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct PaletteStats {
+    /// Palettes that are registered and not deallocated.
+    pub live_palettes: usize,
+    /// Deallocated palettes waiting to be reused.
+    pub free_palettes: usize,
+    /// Material ids in the palette buffer, free ones included.
+    pub entries: usize,
+    pub bytes: usize,
+    /// Bytes held by deallocated palettes.
+    pub free_bytes: usize,
+    /// Fraction of the palette buffer held by deallocated palettes.
+    pub fragmentation: f32,
+}
+
 #[derive(Debug)]
 pub struct PaletteRegistry {
     palette_data: RwLock<Vec<MaterialId>>,
@@ -98,6 +113,45 @@ impl PaletteRegistry {
         }
     }
 
+    pub fn stats(&self) -> PaletteStats {
+        let entries = self.palette_data.read().len();
+        let (free_palettes, free_entries) = self
+            .freelist
+            .read()
+            .iter()
+            .fold((0, 0), |(count, total), (&size, ids)| {
+                (count + ids.len(), total + size as usize * ids.len())
+            });
+        let size = std::mem::size_of::<MaterialId>();
+
+        PaletteStats {
+            live_palettes: self.live_palettes().len(),
+            free_palettes,
+            entries,
+            bytes: entries * size,
+            free_bytes: free_entries * size,
+            fragmentation: if entries == 0 {
+                0.0
+            } else {
+                free_entries as f32 / entries as f32
+            },
+        }
+    }
+
+    /// Sizes of all registered palettes that haven't been deallocated.
+    pub fn live_palettes(&self) -> HashMap<PaletteId, u32> {
+        let palette_map = self.palette_map.read();
+        let freelist = self.freelist.read();
+        let mut live = palette_map
+            .values()
+            .copied()
+            .collect::<HashMap<PaletteId, u32>>();
+        for id in freelist.values().flatten() {
+            live.remove(id);
+        }
+        live
+    }
+
     pub fn palette_data(&self) -> &[MaterialId] {
         unsafe { self.palette_data.data_ptr().as_ref().unwrap() }
     }
@@ -0,0 +1,182 @@
+use std::f32::consts::PI;
+
+use crate::material::PbrMaterial;
+
+/// Reflectance of non metals at normal incidence, about 4% for most of them.
+const DIELECTRIC_F0: f32 = 0.04;
+/// Ambient light reaching a surface facing straight down, relative to one facing the sky.
+pub const GROUND_AMBIENT: f32 = 0.5;
+/// Scales the linear color before tone mapping, see `present.wgsl`.
+pub const EXPOSURE: f32 = 1.0;
+
+/// Share of light reflected at `cos_theta` between the half vector and the view direction.
+pub fn fresnel_schlick(cos_theta: f32, f0: [f32; 3]) -> [f32; 3] {
+    let t = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+    f0.map(|f| f + (1.0 - f) * t)
+}
+
+/// GGX normal distribution, how many microfacets face the half vector.
+pub fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d).max(f32::EPSILON)
+}
+
+/// Smith shadowing and masking of the microfacets, with the Schlick-GGX term for direct light.
+pub fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    let g1 = |x: f32| x / (x * (1.0 - k) + k);
+    g1(n_dot_v) * g1(n_dot_l)
+}
+
+/// Cook-Torrance BRDF times the cosine term, the light reflected towards `to_eye` per unit of
+/// light arriving from `to_light`. Scaled by π so a white Lambert surface facing the light
+/// reflects exactly the light's intensity. Mirrors `cook_torrance` in `raytrace.wgsl`.
+pub fn cook_torrance(
+    material: &PbrMaterial,
+    normal: &na::Vector3<f32>,
+    to_eye: &na::Vector3<f32>,
+    to_light: &na::Vector3<f32>,
+) -> [f32; 3] {
+    let n_dot_l = normal.dot(to_light);
+    if n_dot_l <= 0.0 {
+        return [0.0; 3];
+    }
+    let n_dot_v = normal.dot(to_eye).max(1e-4);
+    let half = (to_eye + to_light).normalize();
+    let n_dot_h = normal.dot(&half).max(0.0);
+    // a perfectly smooth surface would only reflect in one exact direction
+    let roughness = material.roughness.max(0.05);
+
+    let albedo = [material.color[0], material.color[1], material.color[2]];
+    let f0 = albedo.map(|c| DIELECTRIC_F0 + (c - DIELECTRIC_F0) * material.metallic);
+    let fresnel = fresnel_schlick(half.dot(to_eye), f0);
+    let specular = distribution_ggx(n_dot_h, roughness)
+        * geometry_smith(n_dot_v, n_dot_l, roughness)
+        / (4.0 * n_dot_v * n_dot_l);
+
+    [0, 1, 2].map(|i| {
+        let diffuse = (1.0 - fresnel[i]) * (1.0 - material.metallic) * albedo[i] / PI;
+        (diffuse + fresnel[i] * specular) * n_dot_l * PI
+    })
+}
+
+/// Share of the ambient light reaching a surface facing `normal`, a sky above a darker ground.
+pub fn sky_ambient(normal: &na::Vector3<f32>) -> f32 {
+    GROUND_AMBIENT + (1.0 - GROUND_AMBIENT) * (normal.y * 0.5 + 0.5)
+}
+
+/// Maps linear HDR color to `0..=1` with the ACES filmic fit, what `present.wgsl` does to the
+/// color view.
+pub fn tonemap(color: [f32; 3]) -> [f32; 3] {
+    color.map(|c| {
+        let x = (c * EXPOSURE).max(0.0);
+        (x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use super::*;
+
+    const RAYTRACE_SHADER: &str = include_str!("../../client/src/shaders/raytrace.wgsl");
+
+    /// Fields of the WGSL struct `name` with their offset and size under the storage layout
+    /// rules, only knows the scalar and vector types the shared structs use.
+    fn wgsl_fields(source: &str, name: &str) -> Vec<(String, String, usize, usize)> {
+        let start = source.find(&format!("struct {name} {{")).unwrap();
+        let body = &source[start..];
+        let body = &body[body.find('{').unwrap() + 1..body.find('}').unwrap()];
+
+        let mut offset = 0usize;
+        let mut fields = Vec::new();
+        for line in body.lines() {
+            let line = line
+                .split("//")
+                .next()
+                .unwrap()
+                .trim()
+                .trim_end_matches(',');
+            let Some((field, ty)) = line.split_once(':') else {
+                continue;
+            };
+            let ty = ty.trim().to_string();
+            let (size, align) = match ty.as_str() {
+                "f32" | "u32" | "i32" => (4, 4),
+                "vec2<f32>" | "vec2<u32>" => (8, 8),
+                "vec3<f32>" | "vec3<u32>" => (12, 16),
+                "vec4<f32>" | "vec4<u32>" => (16, 16),
+                _ => panic!("unknown WGSL type {ty}"),
+            };
+            offset = offset.next_multiple_of(align);
+            fields.push((field.trim().to_string(), ty, offset, size));
+            offset += size;
+        }
+        fields
+    }
+
+    #[test]
+    fn test_material_layout_matches_shader() {
+        let fields = wgsl_fields(RAYTRACE_SHADER, "PbrMaterial");
+        let layout: Vec<_> = fields
+            .iter()
+            .map(|(name, ty, offset, size)| (name.as_str(), ty.as_str(), *offset, *size))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                ("color", "vec4<f32>", 0, 16),
+                ("metallic", "f32", 16, 4),
+                ("roughness", "f32", 20, 4),
+                ("_padding1", "vec2<f32>", 24, 8),
+                ("emissive", "vec4<f32>", 32, 16),
+            ]
+        );
+
+        let (_, _, offset, size) = fields.last().unwrap();
+        assert_eq!(
+            mem::size_of::<PbrMaterial>(),
+            (offset + size).next_multiple_of(16)
+        );
+        assert_eq!(mem::offset_of!(PbrMaterial, color), 0);
+        assert_eq!(mem::offset_of!(PbrMaterial, metallic), 16);
+        assert_eq!(mem::offset_of!(PbrMaterial, roughness), 20);
+        assert_eq!(mem::offset_of!(PbrMaterial, emissive), 32);
+
+        let material = PbrMaterial::new([0.1, 0.2, 0.3, 1.0], 0.4, 0.5, [0.6, 0.7, 0.8], 0.9);
+        let floats: &[f32] = bytemuck::cast_slice(bytemuck::bytes_of(&material));
+        assert_eq!(&floats[..6], &[0.1, 0.2, 0.3, 1.0, 0.4, 0.5]);
+        assert_eq!(&floats[8..], &[0.6, 0.7, 0.8, 0.9]);
+    }
+
+    #[test]
+    fn test_cook_torrance() {
+        let up = na::Vector3::y();
+        let rough = PbrMaterial::new([1.0; 4], 0.0, 1.0, [0.0; 3], 1.0);
+        // a rough white surface lit and seen head on reflects about all of the light
+        let [r, g, b] = cook_torrance(&rough, &up, &up, &up);
+        assert!((r - 1.0).abs() < 0.1 && r == g && g == b);
+        assert_eq!(cook_torrance(&rough, &up, &up, &-up), [0.0; 3]);
+
+        // metals tint their highlight and have no diffuse part
+        let gold = PbrMaterial::metal([1.0, 0.8, 0.3], 0.3);
+        let [r, _, b] = cook_torrance(&gold, &up, &up, &up);
+        assert!(r > b);
+        let away = na::Vector3::new(1.0, 1.0, 0.0).normalize();
+        let off_angle = cook_torrance(&gold, &up, &away, &away);
+        assert!(off_angle[0] < r * 0.1);
+
+        // smoother surfaces concentrate the highlight
+        let glossy = PbrMaterial::metal([1.0; 3], 0.2);
+        let matte = PbrMaterial::metal([1.0; 3], 0.8);
+        assert!(cook_torrance(&glossy, &up, &up, &up)[0] > cook_torrance(&matte, &up, &up, &up)[0]);
+
+        let [dark, mid, bright] = tonemap([0.0, 0.5, 8.0]);
+        assert!(dark == 0.0 && mid > 0.5 && mid < bright && bright <= 1.0);
+        assert_eq!(sky_ambient(&up), 1.0);
+        assert_eq!(sky_ambient(&-up), GROUND_AMBIENT);
+    }
+}
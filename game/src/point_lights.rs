@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+
+use crate::{
+    brick::{BrickMap, BrickMapView, BRICK_SIZE},
+    collision::Aabb,
+    light::{emission_level, may_emit},
+    material::MaterialRegistry,
+    palette::PaletteRegistry,
+    raytrace::Ray,
+    sun::SHADOW_BIAS,
+    transparency::shadow_transmittance,
+};
+
+/// Edge of a light grid cell in world units, used by the client.
+pub const GRID_CELL_SIZE: f32 = 4.0;
+/// Shadow rays stop this short of a light, so the emissive voxels it was extracted from don't
+/// shadow it.
+pub const LIGHT_CLEARANCE: f32 = 0.25;
+/// Most lights a grid cell keeps, the strongest ones. Matches `MAX_CELL_LIGHTS` in raytrace.wgsl.
+pub const MAX_CELL_LIGHTS: usize = 16;
+
+/// Local light, matches `PointLight` in raytrace.wgsl.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PointLight {
+    pub position: [f32; 3],
+    /// Distance in world units at which the light has faded out completely.
+    pub radius: f32,
+    pub color: [f32; 3],
+    pub intensity: f32,
+}
+
+impl PointLight {
+    pub fn new(position: na::Point3<f32>, color: [f32; 3], radius: f32, intensity: f32) -> Self {
+        Self {
+            position: *position.coords.as_ref(),
+            radius,
+            color,
+            intensity,
+        }
+    }
+
+    pub fn position(&self) -> na::Point3<f32> {
+        na::Point3::from(self.position)
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb::from_center(self.position(), na::Vector3::repeat(self.radius))
+    }
+
+    /// Whether the light reaches into `aabb`.
+    pub fn reaches(&self, aabb: &Aabb) -> bool {
+        self.distance_to(aabb) < self.radius
+    }
+
+    /// Brightest the light gets anywhere in `aabb`, ranks the lights of a full grid cell.
+    pub fn peak_strength(&self, aabb: &Aabb) -> f32 {
+        let brightest = self.color.into_iter().fold(0.0, f32::max);
+        self.intensity * brightest * self.attenuation(self.distance_to(aabb))
+    }
+
+    fn distance_to(&self, aabb: &Aabb) -> f32 {
+        let position = self.position();
+        let closest =
+            position
+                .coords
+                .zip_zip_map(&aabb.min.coords, &aabb.max.coords, |c, min, max| {
+                    c.clamp(min, max)
+                });
+        (closest - position.coords).norm()
+    }
+
+    /// Smooth falloff from 1 at the light to 0 at its radius.
+    pub fn attenuation(&self, distance: f32) -> f32 {
+        let x = (distance / self.radius).clamp(0.0, 1.0);
+        (1.0 - x * x) * (1.0 - x * x)
+    }
+
+    /// Light arriving at a surface at `position` facing `normal`, shadows not included.
+    pub fn irradiance(&self, position: na::Point3<f32>, normal: &na::Vector3<f32>) -> [f32; 3] {
+        let to_light = self.position() - position;
+        let distance = to_light.norm();
+        if distance <= 0.0 || distance >= self.radius {
+            return [0.0; 3];
+        }
+        let lambert = normal.dot(&(to_light / distance)).max(0.0);
+        let strength = self.intensity * lambert * self.attenuation(distance);
+        self.color.map(|c| c * strength)
+    }
+
+    /// Ray from a surface point towards the light, stopping [`LIGHT_CLEARANCE`] short of it.
+    pub fn shadow_ray(&self, position: na::Point3<f32>, normal: &na::Vector3<f32>) -> Ray {
+        let origin = position + normal * SHADOW_BIAS;
+        let to_light = self.position() - origin;
+        Ray::any(origin, to_light, to_light.norm() - LIGHT_CLEARANCE)
+    }
+
+    /// Share of the light reaching a surface point per color channel, 0 where the map blocks it,
+    /// see [`shadow_transmittance`].
+    pub fn transmittance(
+        &self,
+        view: &BrickMapView,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+        position: na::Point3<f32>,
+        normal: &na::Vector3<f32>,
+    ) -> [f32; 3] {
+        let ray = self.shadow_ray(position, normal);
+        shadow_transmittance(view, palettes, materials, &ray)
+    }
+}
+
+/// A light for the emissive voxels of every brick in `min..max`, per brick and material. It sits
+/// at the center of those voxels and reaches as far as their block light does.
+pub fn extract_emissive_lights(
+    brickmap: &BrickMap,
+    palettes: &PaletteRegistry,
+    materials: &MaterialRegistry,
+    min: na::Point3<u32>,
+    max: na::Point3<u32>,
+) -> Vec<PointLight> {
+    let emission: Vec<_> = materials.materials().iter().map(emission_level).collect();
+    let mut lights = Vec::new();
+    if emission.iter().all(|&level| level == 0) {
+        return lights;
+    }
+
+    let view = brickmap.read();
+    let max: na::Point3<u32> = max.coords.inf(&view.dimensions()).into();
+    for z in min.z..max.z {
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                let at = na::Point3::new(x, y, z);
+                let handle = view.get_handle(at);
+                if !may_emit(&view, palettes, handle, &emission) {
+                    continue;
+                }
+
+                // voxel position sum and count per emissive material
+                let mut emitters: HashMap<u32, (na::Vector3<f32>, u32)> = HashMap::new();
+                for local in
+                    (0..BRICK_SIZE.pow(3)).map(|i| na::Point3::new(i % 8, (i / 8) % 8, i / 64))
+                {
+                    let material = view.voxel_material(palettes, handle, local);
+                    if emission
+                        .get(material.0 as usize)
+                        .is_some_and(|&level| level > 0)
+                    {
+                        let entry = emitters.entry(material.0).or_default();
+                        entry.0 += local.coords.cast::<f32>().add_scalar(0.5);
+                        entry.1 += 1;
+                    }
+                }
+
+                let mut emitters: Vec<_> = emitters.into_iter().collect();
+                emitters.sort_unstable_by_key(|(material, _)| *material);
+                for (material, (sum, count)) in emitters {
+                    let emissive = materials.materials()[material as usize].emissive;
+                    let center = at.cast::<f32>() + sum / (count * BRICK_SIZE) as f32;
+                    let radius = emission[material as usize] as f32 / BRICK_SIZE as f32;
+                    let color = [emissive[0], emissive[1], emissive[2]];
+                    lights.push(PointLight::new(center, color, radius, 1.0));
+                }
+            }
+        }
+    }
+    lights
+}
+
+/// Lights binned into a coarse grid over the map, a point only has to look at the lights that
+/// reach its cell instead of all of them.
+#[derive(Debug, Clone, Default)]
+pub struct LightGrid {
+    /// Cells per axis.
+    size: na::Vector3<u32>,
+    /// Edge of a cell in world units.
+    cell_size: f32,
+    /// First entry in `indices` and light count per cell, `x + y * size.x + z * size.x * size.y`.
+    cells: Vec<[u32; 2]>,
+    /// Light indices of all cells back to back.
+    indices: Vec<u32>,
+}
+
+impl LightGrid {
+    /// Bins `lights` into cells of `cell_size` covering a map of `dimensions` bricks. A cell
+    /// reached by more than [`MAX_CELL_LIGHTS`] keeps the strongest, the shader reads no more.
+    pub fn build(dimensions: na::Vector3<u32>, cell_size: f32, lights: &[PointLight]) -> Self {
+        let size = dimensions.map(|c| ((c as f32 / cell_size).ceil() as u32).max(1));
+        let cell_count = (size.x * size.y * size.z) as usize;
+        let mut binned = vec![Vec::new(); cell_count];
+
+        for (index, light) in lights.iter().enumerate() {
+            let bounds = light.bounds();
+            let min = bounds.min.map(|c| (c / cell_size).floor().max(0.0) as u32);
+            let max = bounds.max.map(|c| (c / cell_size).floor().max(0.0) as u32);
+            let max = max.coords.zip_map(&size, |c, s| c.min(s - 1));
+            for z in min.z..=max.z {
+                for y in min.y..=max.y {
+                    for x in min.x..=max.x {
+                        let cell = na::Point3::new(x, y, z);
+                        let cell_min = cell.cast::<f32>() * cell_size;
+                        let cell_box = Aabb::new(cell_min, cell_min.map(|c| c + cell_size));
+                        if light.reaches(&cell_box) {
+                            binned[(x + y * size.x + z * size.x * size.y) as usize]
+                                .push((index as u32, light.peak_strength(&cell_box)));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut cells = Vec::with_capacity(cell_count);
+        let mut indices = Vec::new();
+        for mut cell in binned {
+            if cell.len() > MAX_CELL_LIGHTS {
+                // stable, equally strong lights keep their order
+                cell.sort_by(|a, b| b.1.total_cmp(&a.1));
+                cell.truncate(MAX_CELL_LIGHTS);
+            }
+            cells.push([indices.len() as u32, cell.len() as u32]);
+            indices.extend(cell.into_iter().map(|(index, _)| index));
+        }
+
+        Self {
+            size,
+            cell_size,
+            cells,
+            indices,
+        }
+    }
+
+    pub fn size(&self) -> na::Vector3<u32> {
+        self.size
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn cells(&self) -> &[[u32; 2]] {
+        &self.cells
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    /// Cell holding `position`, `None` outside of the grid.
+    pub fn cell(&self, position: na::Point3<f32>) -> Option<na::Point3<u32>> {
+        let cell = position.map(|c| (c / self.cell_size).floor());
+        (0..3)
+            .all(|axis| cell[axis] >= 0.0 && cell[axis] < self.size[axis] as f32)
+            .then(|| cell.map(|c| c as u32))
+    }
+
+    /// Indices of the lights that may reach `position`.
+    pub fn lights_at(&self, position: na::Point3<f32>) -> &[u32] {
+        let Some(cell) = self.cell(position) else {
+            return &[];
+        };
+        let index = cell.x + cell.y * self.size.x + cell.z * self.size.x * self.size.y;
+        let [first, count] = self.cells[index as usize];
+        &self.indices[first as usize..(first + count) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        brush::{Brush, BrushOp, Shape},
+        material::{MaterialId, PbrMaterial},
+    };
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_grid_binning_matches_brute_force() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let dimensions = na::Vector3::new(16, 8, 12);
+        let lights: Vec<_> = (0..64)
+            .map(|_| {
+                let position = na::Point3::new(
+                    rng.gen_range(-2.0..18.0),
+                    rng.gen_range(-2.0..10.0),
+                    rng.gen_range(-2.0..14.0),
+                );
+                PointLight::new(position, [1.0; 3], rng.gen_range(0.5..5.0), 1.0)
+            })
+            .collect();
+        let grid = LightGrid::build(dimensions, 4.0, &lights);
+        assert_eq!(grid.size(), na::Vector3::new(4, 2, 3));
+
+        for _ in 0..512 {
+            let point = na::Point3::new(
+                rng.gen_range(0.0..16.0),
+                rng.gen_range(0.0..8.0),
+                rng.gen_range(0.0..12.0),
+            );
+            let binned = grid.lights_at(point);
+            let cell = grid.cell(point).unwrap().cast::<f32>() * 4.0;
+            let cell_box = Aabb::new(cell, cell.map(|c| c + 4.0));
+            let weakest = binned
+                .iter()
+                .map(|&index| lights[index as usize].peak_strength(&cell_box))
+                .fold(f32::INFINITY, f32::min);
+            for (index, light) in lights.iter().enumerate() {
+                if (light.position() - point).norm() < light.radius
+                    && !binned.contains(&(index as u32))
+                {
+                    // only a full cell drops lights, and only weaker ones
+                    assert_eq!(binned.len(), MAX_CELL_LIGHTS, "{index} at {point:?}");
+                    assert!(light.peak_strength(&cell_box) <= weakest);
+                }
+            }
+        }
+
+        // no light is binned into a cell it can't reach
+        for (i, &[first, count]) in grid.cells().iter().enumerate() {
+            let i = i as u32;
+            let cell = na::Point3::new(i % 4, (i / 4) % 2, i / 8).cast::<f32>() * 4.0;
+            let cell_box = Aabb::new(cell, cell.map(|c| c + 4.0));
+            for &index in &grid.indices()[first as usize..(first + count) as usize] {
+                assert!(lights[index as usize].reaches(&cell_box));
+            }
+        }
+        assert!(grid.lights_at(na::Point3::new(-1.0, 0.0, 0.0)).is_empty());
+
+        // a crowded cell keeps its strongest lights
+        let crowd: Vec<_> = (0..24)
+            .map(|i| PointLight::new(na::Point3::new(2.0, 2.0, 2.0), [1.0; 3], 1.0, i as f32))
+            .collect();
+        let grid = LightGrid::build(dimensions, 4.0, &crowd);
+        let mut kept = grid.lights_at(na::Point3::new(2.0, 2.0, 2.0)).to_vec();
+        kept.sort_unstable();
+        assert_eq!(kept, (8..24).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_emissive_lights_and_shadows() {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 2, 2));
+        let palettes = PaletteRegistry::new();
+        let materials = MaterialRegistry::new();
+        materials.register_default_materials();
+        let stone = materials.get_material_id("stone").unwrap();
+        let lamp = materials.register_named_material(
+            "lamp",
+            PbrMaterial::new([1.0; 4], 0.0, 0.5, [1.0, 0.5, 0.25], 1.0),
+        );
+
+        let fill = |center: [f32; 3], half_extents: [f32; 3], material: MaterialId| {
+            let cube = Shape::Box {
+                center: na::Point3::from(center),
+                half_extents: na::Vector3::from(half_extents),
+            };
+            brickmap.apply_brush(&Brush::new(cube, BrushOp::Union, material), &palettes);
+        };
+        fill([2.0, 0.25, 1.0], [2.0, 0.25, 1.0], stone);
+        // a 2³ voxel lamp standing on the floor, a wall right of it
+        fill([0.5, 0.625, 0.5], [0.125, 0.125, 0.125], lamp);
+        fill([1.25, 1.0, 1.0], [0.125, 1.0, 1.0], stone);
+
+        let lights = extract_emissive_lights(
+            &brickmap,
+            &palettes,
+            &materials,
+            na::Point3::origin(),
+            na::Point3::new(4, 2, 2),
+        );
+        assert_eq!(lights.len(), 1);
+        let light = lights[0];
+        assert_eq!(light.position(), na::Point3::new(0.5, 0.625, 0.5));
+        assert_eq!(light.radius, 15.0 / 8.0);
+        assert_eq!(light.color, [1.0, 0.5, 0.25]);
+
+        let up = na::Vector3::y();
+        let near = na::Point3::new(0.875, 0.5, 0.5);
+        let behind_wall = na::Point3::new(1.75, 0.5, 0.5);
+        let view = brickmap.read();
+        assert!(light.irradiance(near, &up)[0] > 0.0);
+        let transmittance =
+            |position| light.transmittance(&view, &palettes, &materials, position, &up);
+        assert_eq!(transmittance(near), [1.0; 3]);
+        assert!(light.irradiance(behind_wall, &up)[0] > 0.0);
+        assert_eq!(transmittance(behind_wall), [0.0; 3]);
+        assert_eq!(
+            light.irradiance(na::Point3::new(3.5, 0.5, 0.5), &up),
+            [0.0; 3]
+        );
+    }
+}
@@ -1,229 +1,484 @@
-// use crate::{
-//     brick::{BrickHandle, BrickMap, TraceBrick},
-//     Camera,
-// };
-//
-// use nalgebra as na;
-//
-// #[derive(Debug, Clone, Copy)]
-// pub struct RayHit {
-//     pub position: na::Point3<f32>,
-//     pub normal: na::Vector3<f32>,
-//     pub distance: f32,
-//     pub handle: BrickHandle,
-//     pub pos: na::Point3<f32>,
-//     pub brick_pos: na::Point3<u32>,
-//     pub voxel_local_pos: Option<na::Point3<u32>>,
-//     pub mask: na::Vector3<f32>,
-// }
-//
-// fn step_mask(side_dist: &na::Vector3<f32>) -> na::Vector3<f32> {
-//     let mut mask = na::Vector3::zeros();
-//
-//     let less_than_yzx = na::Vector3::new(
-//         side_dist.x < side_dist.y,
-//         side_dist.y < side_dist.z,
-//         side_dist.z < side_dist.x,
-//     );
-//
-//     let less_than_zxy = na::Vector3::new(
-//         side_dist.x <= side_dist.z,
-//         side_dist.y <= side_dist.x,
-//         side_dist.z <= side_dist.y,
-//     );
-//
-//     mask.x = if less_than_yzx.x && less_than_zxy.x {
-//         1.0
-//     } else {
-//         0.0
-//     };
-//     mask.y = if less_than_yzx.y && less_than_zxy.y {
-//         1.0
-//     } else {
-//         0.0
-//     };
-//     mask.z = if less_than_yzx.z && less_than_zxy.z {
-//         1.0
-//     } else {
-//         0.0
-//     };
-//
-//     // If no mask was set, set z to true (fallback case)
-//     if mask.x == 0.0 && mask.y == 0.0 && mask.z == 0.0 {
-//         mask.z = 1.0;
-//     }
-//
-//     mask
-// }
-//
-// fn trace_brick(
-//     ray_pos: na::Point3<f32>,
-//     ray_dir: na::Vector3<f32>,
-//     world_mask: na::Vector3<f32>,
-//     brick: &TraceBrick,
-// ) -> Option<(na::Point3<f32>, na::Vector3<f32>)> {
-//     // Clamp starting position to brick bounds
-//     let ray_pos = na::Point3::new(
-//         ray_pos.x.clamp(0.0001, 7.9999),
-//         ray_pos.y.clamp(0.0001, 7.9999),
-//         ray_pos.z.clamp(0.0001, 7.9999),
-//     );
-//
-//     let mut map_pos = ray_pos.map(|x| x.floor());
-//     let ray_sign = ray_dir.map(|x| x.signum());
-//     let delta_dist = ray_dir.map(|x| 1.0 / x);
-//
-//     // Calculate initial side distances
-//     let mut side_dist = ((map_pos - ray_pos)
-//         + na::Vector3::from_element(0.5)
-//         + (ray_sign.component_mul(&na::Vector3::from_element(0.5))))
-//     .component_mul(&delta_dist);
-//
-//     let mut mask = world_mask;
-//
-//     while map_pos.x <= 7.0
-//         && map_pos.x >= 0.0
-//         && map_pos.y <= 7.0
-//         && map_pos.y >= 0.0
-//         && map_pos.z <= 7.0
-//         && map_pos.z >= 0.0
-//     {
-//         let x = map_pos.x as u32;
-//         let y = map_pos.y as u32;
-//         let z = map_pos.z as u32;
-//
-//         if brick.get(x, y, z) {
-//             return Some((na::Point3::from(map_pos.map(|x| x / 8.0)), mask));
-//         }
-//
-//         mask = step_mask(&side_dist);
-//         map_pos += mask.component_mul(&ray_sign);
-//         side_dist += mask.component_mul(&ray_sign).component_mul(&delta_dist);
-//     }
-//
-//     None
-// }
-//
-// pub fn trace_world(
-//     brickmap: &BrickMap,
-//     ray_pos: na::Point3<f32>,
-//     ray_dir: na::Vector3<f32>,
-//     max_steps: u32,
-// ) -> Option<RayHit> {
-//     let mut map_pos = ray_pos.coords.map(|x| x.floor()); // floor() each component
-//     let ray_sign = ray_dir.map(|x| x.signum());
-//     let delta_dist = ray_dir.map(|x| 1.0 / x);
-//
-//     let mut side_dist = ((map_pos - ray_pos.coords)
-//         + na::Vector3::from_element(0.5)
-//         + ray_sign.component_mul(&na::Vector3::from_element(0.5)))
-//     .component_mul(&delta_dist);
-//
-//     let mut mask = step_mask(&side_dist);
-//
-//     let dims = brickmap.dimensions();
-//     for _ in 0..max_steps {
-//         let brick_pos = na::Point3::new(map_pos.x as u32, map_pos.y as u32, map_pos.z as u32);
-//
-//         if brick_pos.x >= dims.x || brick_pos.y >= dims.y || brick_pos.z >= dims.z {
-//             return None;
-//         }
-//
-//         let handle = brickmap.get_handle(brick_pos);
-//         if handle.is_data() {
-//             let brick = brickmap.get_brick(handle).unwrap();
-//
-//             // Calculate intersection with brick bounds
-//             let sub = ((map_pos - ray_pos.coords) + na::Vector3::from_element(0.5)
-//                 - ray_sign.component_mul(&na::Vector3::from_element(0.5)))
-//             .component_mul(&delta_dist);
-//
-//             let d = sub.x.max(sub.y.max(sub.z));
-//
-//             let intersect = ray_pos + (ray_dir * d);
-//             let mut sub_space = intersect - na::Point3::from(map_pos); // Handle case where ray starts inside brick
-//
-//             if map_pos == ray_pos.coords.map(|x| x.floor()) {
-//                 sub_space = ray_pos - na::Point3::from(map_pos);
-//             }
-//
-//             let local_pos = na::Point3::from(sub_space * 8.0);
-//
-//             if let Some((hit_pos, mask)) = trace_brick(local_pos, ray_dir, mask, &brick) {
-//                 let pos = ray_pos + ray_dir * d;
-//                 let normal = -mask.component_mul(&ray_sign);
-//
-//                 return Some(RayHit {
-//                     position: hit_pos,
-//                     normal,
-//                     distance: d,
-//                     pos,
-//                     handle,
-//                     brick_pos,
-//                     voxel_local_pos: Some(na::Point3::new(
-//                         (hit_pos.x * 8.0) as u32,
-//                         (hit_pos.y * 8.0) as u32,
-//                         (hit_pos.z * 8.0) as u32,
-//                     )),
-//                     mask,
-//                 });
-//             }
-//         } else if handle.is_lod() {
-//             let sub = ((map_pos - ray_pos.coords) + na::Vector3::from_element(0.5)
-//                 - ray_sign.component_mul(&na::Vector3::from_element(0.5)))
-//             .component_mul(&delta_dist);
-//
-//             let d = sub.x.max(sub.y.max(sub.z));
-//             let pos = ray_pos + ray_dir * d;
-//             let normal = -mask.component_mul(&ray_sign);
-//
-//             return Some(RayHit {
-//                 position: na::Point3::from(map_pos.map(|x| x / 8.0)),
-//                 normal,
-//                 distance: d,
-//                 handle,
-//                 pos,
-//                 brick_pos,
-//                 voxel_local_pos: None,
-//                 mask,
-//             });
-//         }
-//
-//         mask = step_mask(&side_dist);
-//         map_pos += mask.component_mul(&ray_sign);
-//         side_dist += mask.component_mul(&ray_sign).component_mul(&delta_dist);
-//     }
-//
-//     None
-// }
-//
-// pub fn cast_center_ray(camera: &Camera, brickmap: &BrickMap, max_steps: u32) -> Option<RayHit> {
-//     let origin = camera.position;
-//     let direction = camera.rotation * -na::Vector3::z_axis();
-//
-//     trace_world(brickmap, origin, *direction, max_steps)
-// }
-//
-// pub fn cast_screen_ray(
-//     camera: &Camera,
-//     screen_pos: na::Point2<f32>,
-//     screen_size: na::Vector2<f32>,
-//     brickmap: &BrickMap,
-//     max_steps: u32,
-// ) -> Option<RayHit> {
-//     let ndc = na::Point2::new(
-//         (2.0 * screen_pos.x) / screen_size.x - 1.0,
-//         1.0 - (2.0 * screen_pos.y) / screen_size.y, // Flip Y coordinate
-//     );
-//
-//     let fov = camera.fov.to_radians();
-//     let aspect_ratio = screen_size.x / screen_size.y;
-//     let tan_fov = (fov * 0.5).tan();
-//
-//     let ray_dir =
-//         na::Vector3::new(ndc.x * aspect_ratio * tan_fov, ndc.y * tan_fov, -1.0).normalize();
-//
-//     let world_ray_dir = camera.rotation * ray_dir;
-//
-//     trace_world(brickmap, camera.position, world_ray_dir, max_steps)
-// }
+use rayon::prelude::*;
+
+use crate::{
+    brick::{BrickHandle, BrickMap, BrickMapView, TraceBrick, BRICK_SIZE},
+    mip::BrickMip,
+};
+
+const EPSILON: f32 = 1e-5;
+/// Empty handle value the SDF pass writes for "no solid brick in range".
+const MAX_SDF_DISTANCE: u32 = 0x1FFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RayMode {
+    /// Resolve the nearest surface with its normal and voxel.
+    Closest,
+    /// Stop at the first occupied cell without resolving the surface normal.
+    /// Meant for shadow and line of sight checks.
+    Any,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: na::Point3<f32>,
+    pub direction: na::Vector3<f32>,
+    pub max_distance: f32,
+    pub mode: RayMode,
+    /// Distance after which data bricks are traced one mip level coarser, see
+    /// [`BrickMip::level_for_distance`]. Infinite by default, always tracing full bricks.
+    pub mip_distance: f32,
+}
+
+impl Ray {
+    pub fn new(origin: na::Point3<f32>, direction: na::Vector3<f32>) -> Self {
+        Self {
+            origin,
+            direction: direction.normalize(),
+            max_distance: f32::INFINITY,
+            mode: RayMode::Closest,
+            mip_distance: f32::INFINITY,
+        }
+    }
+
+    pub fn any(origin: na::Point3<f32>, direction: na::Vector3<f32>, max_distance: f32) -> Self {
+        Self {
+            origin,
+            direction: direction.normalize(),
+            max_distance,
+            mode: RayMode::Any,
+            mip_distance: f32::INFINITY,
+        }
+    }
+
+    pub fn with_max_distance(mut self, max_distance: f32) -> Self {
+        self.max_distance = max_distance;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: RayMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_mip_distance(mut self, mip_distance: f32) -> Self {
+        self.mip_distance = mip_distance;
+        self
+    }
+
+    pub fn at(&self, t: f32) -> na::Point3<f32> {
+        self.origin + self.direction * t
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RayHit {
+    pub distance: f32,
+    pub position: na::Point3<f32>,
+    pub normal: na::Vector3<f32>,
+    pub handle: BrickHandle,
+    pub brick: na::Point3<u32>,
+    /// Cell inside `brick` at `level`, `None` when an LOD handle was hit.
+    pub voxel: Option<na::Point3<u32>>,
+    /// Mip level the brick was traced at, 0 for full 8³ bricks.
+    pub level: u32,
+}
+
+/// Grid walker shared by the brick and voxel level, `scale` is cells per world unit.
+pub(crate) struct Dda {
+    pub cell: na::Vector3<i32>,
+    pub step: na::Vector3<i32>,
+    pub t_max: na::Vector3<f32>,
+    pub t_delta: na::Vector3<f32>,
+    pub axis: Option<usize>,
+}
+
+impl Dda {
+    pub fn new(
+        ray: &Ray,
+        t: f32,
+        scale: f32,
+        min: na::Vector3<i32>,
+        max: na::Vector3<i32>,
+    ) -> Self {
+        let pos = ray.at(t + EPSILON).coords * scale;
+        let mut cell = pos.map(|x| x.floor() as i32);
+        let mut step = na::Vector3::zeros();
+        let mut t_max = na::Vector3::zeros();
+        let mut t_delta = na::Vector3::zeros();
+
+        for i in 0..3 {
+            cell[i] = cell[i].clamp(min[i], max[i] - 1);
+            let dir = ray.direction[i];
+            if dir > 0.0 {
+                step[i] = 1;
+                t_delta[i] = 1.0 / (dir * scale);
+                t_max[i] = ((cell[i] + 1) as f32 / scale - ray.origin[i]) / dir;
+            } else if dir < 0.0 {
+                step[i] = -1;
+                t_delta[i] = -1.0 / (dir * scale);
+                t_max[i] = (cell[i] as f32 / scale - ray.origin[i]) / dir;
+            } else {
+                t_delta[i] = f32::INFINITY;
+                t_max[i] = f32::INFINITY;
+            }
+        }
+
+        Self {
+            cell,
+            step,
+            t_max,
+            t_delta,
+            axis: None,
+        }
+    }
+
+    /// Moves into the next cell and returns the distance at which it was entered.
+    pub fn step(&mut self) -> f32 {
+        let axis = self.t_max.imin();
+        let t = self.t_max[axis];
+        self.cell[axis] += self.step[axis];
+        self.t_max[axis] += self.t_delta[axis];
+        self.axis = Some(axis);
+        t
+    }
+
+    pub fn exit(&self) -> f32 {
+        self.t_max.min()
+    }
+
+    pub fn normal(&self, fallback: Option<usize>) -> na::Vector3<f32> {
+        let mut normal = na::Vector3::zeros();
+        if let Some(axis) = self.axis.or(fallback) {
+            normal[axis] = -self.step[axis] as f32;
+        }
+        normal
+    }
+}
+
+pub(crate) fn intersect_box(
+    ray: &Ray,
+    min: na::Vector3<f32>,
+    max: na::Vector3<f32>,
+) -> (f32, f32, usize) {
+    let mut t_near = f32::NEG_INFINITY;
+    let mut t_far = f32::INFINITY;
+    let mut axis = 0;
+
+    for i in 0..3 {
+        if ray.direction[i] == 0.0 {
+            // parallel to the slab, 0 * inf would poison the interval with NaN
+            if ray.origin[i] < min[i] || ray.origin[i] >= max[i] {
+                return (f32::INFINITY, f32::NEG_INFINITY, axis);
+            }
+            continue;
+        }
+        let inv = 1.0 / ray.direction[i];
+        let t1 = (min[i] - ray.origin[i]) * inv;
+        let t2 = (max[i] - ray.origin[i]) * inv;
+        let (t1, t2) = if t1 < t2 { (t1, t2) } else { (t2, t1) };
+        if t1 > t_near {
+            t_near = t1;
+            axis = i;
+        }
+        t_far = t_far.min(t2);
+    }
+
+    (t_near, t_far, axis)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn trace_brick(
+    ray: &Ray,
+    brick: &TraceBrick,
+    mip: Option<&BrickMip>,
+    level: u32,
+    brick_pos: na::Vector3<i32>,
+    t_enter: f32,
+    t_exit: f32,
+    entry_axis: Option<usize>,
+    steps: &mut u32,
+) -> Option<(f32, na::Vector3<i32>, na::Vector3<f32>)> {
+    let size = BrickMip::size(level) as i32;
+    let min = brick_pos * size;
+    let max = min + na::Vector3::repeat(size);
+    let mut dda = Dda::new(ray, t_enter, size as f32, min, max);
+    let mut t = t_enter;
+
+    while t <= t_exit {
+        *steps += 1;
+        let local = dda.cell - min;
+        if local.iter().any(|&c| !(0..size).contains(&c)) {
+            break;
+        }
+        let (x, y, z) = (local.x as u32, local.y as u32, local.z as u32);
+        let solid = match mip {
+            Some(mip) if level > 0 => mip.get(level, x, y, z),
+            _ => brick.get(x, y, z),
+        };
+        if solid {
+            return Some((t, local, dda.normal(entry_axis)));
+        }
+        t = dda.step();
+    }
+
+    None
+}
+
+/// Traces a single ray against an already locked view of the brickmap.
+pub fn trace_view(view: &BrickMapView, ray: &Ray) -> Option<RayHit> {
+    trace_view_steps(view, ray).0
+}
+
+/// Like [`trace_view`], also returning the number of brick and voxel steps taken.
+pub fn trace_view_steps(view: &BrickMapView, ray: &Ray) -> (Option<RayHit>, u32) {
+    let mut steps = 0;
+    let hit = trace(view, ray, &mut steps);
+    (hit, steps)
+}
+
+fn trace(view: &BrickMapView, ray: &Ray, steps: &mut u32) -> Option<RayHit> {
+    let dims = view.dimensions();
+    let (t_near, t_far, near_axis) = intersect_box(ray, na::Vector3::zeros(), dims.cast::<f32>());
+
+    let t_far = t_far.min(ray.max_distance);
+    if t_near > t_far || t_far < 0.0 {
+        return None;
+    }
+
+    let (mut t, entry_axis) = if t_near > 0.0 {
+        (t_near, Some(near_axis))
+    } else {
+        (0.0, None)
+    };
+
+    let grid_max = dims.cast::<i32>();
+    let mut dda = Dda::new(ray, t, 1.0, na::Vector3::zeros(), grid_max);
+
+    while t <= t_far {
+        *steps += 1;
+        if dda
+            .cell
+            .iter()
+            .zip(grid_max.iter())
+            .any(|(&c, &m)| c < 0 || c >= m)
+        {
+            break;
+        }
+
+        let brick_pos = na::Point3::from(dda.cell.map(|c| c as u32));
+        let handle = view.get_handle(brick_pos);
+        let axis = dda.axis.or(entry_axis);
+
+        if let Some(brick) = view.get_brick(handle) {
+            // Any ray is blocked as soon as it enters a solid brick, no need to walk its voxels
+            if ray.mode == RayMode::Any && brick.is_full() {
+                let local = (ray.at(t).coords - dda.cell.cast::<f32>()) * BRICK_SIZE as f32;
+                let voxel = local.map(|c| (c as u32).min(BRICK_SIZE - 1));
+                return Some(RayHit {
+                    distance: t,
+                    position: ray.at(t),
+                    normal: na::Vector3::zeros(),
+                    handle,
+                    brick: brick_pos,
+                    voxel: Some(voxel.into()),
+                    level: 0,
+                });
+            }
+            let t_exit = dda.exit().min(t_far);
+            let mip = view.get_mip(handle);
+            let level = match mip {
+                Some(_) => BrickMip::level_for_distance(t, ray.mip_distance),
+                None => 0,
+            };
+            if let Some((t_hit, voxel, normal)) =
+                trace_brick(ray, brick, mip, level, dda.cell, t, t_exit, axis, steps)
+            {
+                return Some(RayHit {
+                    distance: t_hit,
+                    position: ray.at(t_hit),
+                    normal: match ray.mode {
+                        RayMode::Closest => normal,
+                        RayMode::Any => na::Vector3::zeros(),
+                    },
+                    handle,
+                    brick: brick_pos,
+                    voxel: Some(voxel.map(|c| c as u32).into()),
+                    level,
+                });
+            }
+        } else if handle.is_lod() {
+            return Some(RayHit {
+                distance: t,
+                position: ray.at(t),
+                normal: match ray.mode {
+                    RayMode::Closest => dda.normal(entry_axis),
+                    RayMode::Any => na::Vector3::zeros(),
+                },
+                handle,
+                brick: brick_pos,
+                voxel: None,
+                level: 0,
+            });
+        } else {
+            let sdf = handle.get_empty_value();
+            if sdf > 1 && sdf != MAX_SDF_DISTANCE {
+                t += (sdf - 1) as f32;
+                dda = Dda::new(ray, t, 1.0, na::Vector3::zeros(), grid_max);
+                continue;
+            }
+        }
+
+        t = dda.step();
+    }
+
+    None
+}
+
+pub fn trace_ray(brickmap: &BrickMap, ray: &Ray) -> Option<RayHit> {
+    trace_view(&brickmap.read(), ray)
+}
+
+/// Traces all rays in parallel, holding the brickmap read locks once for the whole batch.
+pub fn trace_many(brickmap: &BrickMap, rays: &[Ray]) -> Vec<Option<RayHit>> {
+    let view = brickmap.read();
+    rays.par_iter().map(|ray| trace_view(&view, ray)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn floor_map() -> BrickMap {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let mut brick = TraceBrick::empty();
+        for x in 0..8 {
+            for z in 0..8 {
+                brick.set(x, 0, z, true);
+            }
+        }
+        for x in 0..4 {
+            for z in 0..4 {
+                brickmap.set_brick(brick, na::Point3::new(x, 0, z));
+            }
+        }
+        brickmap
+    }
+
+    #[test]
+    fn test_closest_hit_from_above() {
+        let brickmap = floor_map();
+        let ray = Ray::new(na::Point3::new(1.3, 3.0, 2.7), -na::Vector3::y());
+        let hit = trace_ray(&brickmap, &ray).unwrap();
+
+        assert!((hit.distance - (3.0 - 0.125)).abs() < 1e-3);
+        assert_eq!(hit.normal, na::Vector3::y());
+        assert_eq!(hit.brick, na::Point3::new(1, 0, 2));
+        assert_eq!(hit.voxel, Some(na::Point3::new(2, 0, 5)));
+    }
+
+    #[test]
+    fn test_ray_from_outside_and_max_distance() {
+        let brickmap = floor_map();
+        let ray = Ray::new(na::Point3::new(-2.0, 0.05, 1.5), na::Vector3::x());
+        let hit = trace_ray(&brickmap, &ray).unwrap();
+        assert!((hit.distance - 2.0).abs() < 1e-3);
+        assert_eq!(hit.normal, -na::Vector3::x());
+
+        let short = ray.with_max_distance(1.5);
+        assert!(trace_ray(&brickmap, &short).is_none());
+    }
+
+    #[test]
+    fn test_mip_level_by_distance() {
+        use crate::{
+            brick::ExpandedBrick,
+            material::{ExpandedMaterialMapping, MaterialId},
+            palette::PaletteRegistry,
+        };
+
+        let palettes = PaletteRegistry::new();
+        let mut mapping = ExpandedMaterialMapping::new();
+        mapping.insert(1, MaterialId(7));
+
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let mut brick = ExpandedBrick::empty();
+        for x in 0..8 {
+            for z in 0..8 {
+                brick.set(x, 0, z, 1);
+            }
+        }
+        brickmap.set_expanded_brick(na::Point3::new(1, 0, 2), &brick, &mapping, &palettes);
+
+        let ray = Ray::new(na::Point3::new(1.3, 3.0, 2.7), -na::Vector3::y());
+        let full = trace_ray(&brickmap, &ray).unwrap();
+        assert_eq!(full.level, 0);
+        assert!((full.distance - 2.875).abs() < 1e-3);
+
+        let coarse = trace_ray(&brickmap, &ray.with_mip_distance(1.0)).unwrap();
+        assert_eq!(coarse.level, 2);
+        assert!((coarse.distance - 2.5).abs() < 1e-3);
+        assert_eq!(coarse.voxel, Some(na::Point3::new(0, 0, 1)));
+
+        let view = brickmap.read();
+        let material = view.material_at_level(&palettes, coarse.handle, coarse.voxel.unwrap(), 2);
+        assert_eq!(material, MaterialId(7));
+        assert_eq!(
+            view.get_voxel_at_level(&palettes, na::Point3::new(4, 1, 8), 1),
+            MaterialId::EMPTY
+        );
+        assert_eq!(
+            view.get_voxel_at_level(&palettes, na::Point3::new(4, 0, 8), 1),
+            MaterialId(7)
+        );
+    }
+
+    #[test]
+    fn test_trace_many_matches_single() {
+        let brickmap = floor_map();
+        brickmap.set_lod(na::Point3::new(3, 3, 3), crate::material::MaterialId(2));
+
+        let rays: Vec<_> = (0..64)
+            .map(|i| {
+                let origin = na::Point3::new(0.1 + i as f32 * 0.06, 3.5, 0.2);
+                let dir = na::Vector3::new(0.3, -1.0, 0.5 + i as f32 * 0.01);
+                Ray::new(origin, dir)
+            })
+            .chain([
+                Ray::any(na::Point3::new(3.5, 3.5, 0.5), na::Vector3::z(), 10.0),
+                Ray::any(na::Point3::new(0.5, 2.5, 0.5), na::Vector3::x(), 10.0),
+            ])
+            .collect();
+
+        let batch = trace_many(&brickmap, &rays);
+        for (ray, hit) in rays.iter().zip(batch.iter()) {
+            let single = trace_ray(&brickmap, ray);
+            assert_eq!(single.map(|h| h.distance), hit.map(|h| h.distance));
+        }
+
+        let lod = batch[64].unwrap();
+        assert!(lod.voxel.is_none());
+        assert_eq!(lod.brick, na::Point3::new(3, 3, 3));
+        assert!(batch[65].is_none());
+    }
+
+    #[test]
+    fn test_any_stops_at_full_brick() {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let mut brick = TraceBrick::empty();
+        brick.data_mut().fill(u8::MAX);
+        brickmap.set_brick(brick, na::Point3::new(2, 1, 1));
+
+        let origin = na::Point3::new(0.5, 1.3, 1.6);
+        let (closest, closest_steps) =
+            trace_view_steps(&brickmap.read(), &Ray::new(origin, na::Vector3::x()));
+        let (any, any_steps) =
+            trace_view_steps(&brickmap.read(), &Ray::any(origin, na::Vector3::x(), 10.0));
+
+        let (closest, any) = (closest.unwrap(), any.unwrap());
+        assert!((any.distance - 1.5).abs() < 1e-3);
+        assert_eq!(any.distance, closest.distance);
+        assert_eq!(any.voxel, closest.voxel);
+        assert_eq!(any.voxel, Some(na::Point3::new(0, 2, 4)));
+        assert!(any_steps < closest_steps);
+    }
+}
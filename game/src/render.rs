@@ -0,0 +1,368 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use rayon::prelude::*;
+
+use crate::{
+    ao::ambient_occlusion,
+    brick::{BrickMap, BrickMapView},
+    material::MaterialRegistry,
+    palette::PaletteRegistry,
+    pbr::tonemap,
+    raytrace::{trace_view_steps, Ray},
+    sun::Sun,
+    transparency::{composite, trace_transparent},
+    Camera,
+};
+
+/// Ray budget the present shader normalizes the step view against, see `raytrace.wgsl`.
+const MAX_RAY_STEPS: u32 = 256;
+
+/// Same order as the present shader's image array and the debug UI combo box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    Color = 0,
+    Normals = 1,
+    Depth = 2,
+    Steps = 3,
+}
+
+impl RenderMode {
+    pub fn from_index(index: u32) -> Option<Self> {
+        match index {
+            0 => Some(Self::Color),
+            1 => Some(Self::Normals),
+            2 => Some(Self::Depth),
+            3 => Some(Self::Steps),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Tightly packed RGBA8 rows, top row first.
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let index = ((y * self.width + x) * 4) as usize;
+        let mut pixel = [0; 4];
+        pixel.copy_from_slice(&self.data[index..index + 4]);
+        pixel
+    }
+
+    pub fn write_png<W: std::io::Write>(&self, writer: W) -> Result<(), png::EncodingError> {
+        let mut encoder = png::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)?;
+        writer.finish()
+    }
+
+    pub fn encode_png(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut bytes = Vec::new();
+        self.write_png(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), png::EncodingError> {
+        let file = File::create(path)?;
+        self.write_png(BufWriter::new(file))
+    }
+}
+
+/// CPU mirror of `raytrace.wgsl` + `present.wgsl`, renders a brickmap without a GPU.
+pub struct CpuRenderer<'a> {
+    brickmap: &'a BrickMap,
+    materials: &'a MaterialRegistry,
+    palettes: &'a PaletteRegistry,
+    mip_distance: f32,
+    sun: Sun,
+    ambient_occlusion: bool,
+    refraction: bool,
+}
+
+impl<'a> CpuRenderer<'a> {
+    /// Renders unlit, see [`CpuRenderer::with_sun`].
+    pub fn new(
+        brickmap: &'a BrickMap,
+        materials: &'a MaterialRegistry,
+        palettes: &'a PaletteRegistry,
+    ) -> Self {
+        Self {
+            brickmap,
+            materials,
+            palettes,
+            mip_distance: f32::INFINITY,
+            sun: Sun::unlit(),
+            ambient_occlusion: false,
+            refraction: false,
+        }
+    }
+
+    /// Traces distant bricks at coarser mip levels, the CPU side of the shader's `mip_distance`.
+    pub fn with_mip_distance(mut self, mip_distance: f32) -> Self {
+        self.mip_distance = mip_distance;
+        self
+    }
+
+    /// Shades the color view with `sun` and its shadow rays.
+    pub fn with_sun(mut self, sun: Sun) -> Self {
+        self.sun = sun;
+        self
+    }
+
+    /// Darkens the ambient light in creases and corners, see [`ambient_occlusion`].
+    pub fn with_ambient_occlusion(mut self, enabled: bool) -> Self {
+        self.ambient_occlusion = enabled;
+        self
+    }
+
+    /// Bends rays passing translucent materials, see [`trace_transparent`].
+    pub fn with_refraction(mut self, enabled: bool) -> Self {
+        self.refraction = enabled;
+        self
+    }
+
+    /// Builds the primary ray for a pixel the same way the raytrace shader does.
+    pub fn camera_ray(camera: &Camera, x: u32, y: u32, width: u32, height: u32) -> Ray {
+        let inverse = Self::inverse_view_projection(camera);
+        Self::pixel_ray(camera, &inverse, x, y, width, height)
+    }
+
+    fn inverse_view_projection(camera: &Camera) -> na::Matrix4<f32> {
+        camera
+            .view_projection_matrix()
+            .try_inverse()
+            .unwrap_or(na::Matrix4::identity())
+    }
+
+    /// [`Self::camera_ray`] with the inverse view projection computed once per frame.
+    fn pixel_ray(
+        camera: &Camera,
+        inverse: &na::Matrix4<f32>,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Ray {
+        let ndc = na::Vector2::new(
+            x as f32 / width as f32 * 2.0 - 1.0,
+            y as f32 / height as f32 * 2.0 - 1.0,
+        );
+        let clip = na::Vector4::new(ndc.x, ndc.y, 1.0, 1.0);
+        let world = inverse * clip;
+        let world = na::Point3::from(world.xyz() / world.w);
+
+        Ray::new(camera.position, world - camera.position)
+    }
+
+    pub fn render(&self, camera: &Camera, width: u32, height: u32, mode: RenderMode) -> Image {
+        let mut image = Image::new(width, height);
+        let view = self.brickmap.read();
+        let max_distance = view.dimensions().cast::<f32>().norm();
+        let inverse = Self::inverse_view_projection(camera);
+
+        image
+            .data
+            .par_chunks_mut((width * 4) as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for x in 0..width {
+                    let ray = Self::pixel_ray(camera, &inverse, x, y as u32, width, height)
+                        .with_mip_distance(self.mip_distance);
+
+                    let color = match mode {
+                        RenderMode::Color => self.color(&view, &ray),
+                        RenderMode::Normals => {
+                            let (hit, _) = trace_view_steps(&view, &ray);
+                            // opaque black on a miss, like the shader's normal image
+                            let color = hit.map(|hit| normal_color(&hit.normal));
+                            Some(color.unwrap_or([0.0, 0.0, 0.0, 1.0]))
+                        }
+                        RenderMode::Depth => {
+                            let (hit, _) = trace_view_steps(&view, &ray);
+                            let depth = hit
+                                .map(|hit| (hit.distance / max_distance).clamp(0.0, 1.0))
+                                .unwrap_or(1.0);
+                            Some([depth, depth, depth, 1.0])
+                        }
+                        RenderMode::Steps => {
+                            let (_, steps) = trace_view_steps(&view, &ray);
+                            let max_steps = (MAX_RAY_STEPS * 6) as f32;
+                            let intensity = (steps as f32 / max_steps).clamp(0.0, 1.0).sqrt();
+                            Some([intensity, intensity, intensity, 1.0])
+                        }
+                    };
+
+                    let color = color.unwrap_or([0.0; 4]);
+                    let pixel = &mut row[(x * 4) as usize..(x * 4 + 4) as usize];
+                    for (out, channel) in pixel.iter_mut().zip(color) {
+                        *out = (channel.clamp(0.0, 1.0) * 255.0).round() as u8;
+                    }
+                }
+            });
+
+        image
+    }
+
+    /// Shades the surfaces `ray` shows through translucent and cut out voxels, `None` when it
+    /// passes all of them and leaves the map.
+    fn color(&self, view: &BrickMapView, ray: &Ray) -> Option<[f32; 4]> {
+        let (surfaces, left) =
+            trace_transparent(view, self.palettes, self.materials, ray, self.refraction);
+        if surfaces.is_empty() {
+            return None;
+        }
+
+        let color = composite(&surfaces, |surface| {
+            let occlusion = if self.ambient_occlusion {
+                ambient_occlusion(view, &surface.hit)
+            } else {
+                1.0
+            };
+            let sunlight = self
+                .sun
+                .sunlight(view, self.palettes, self.materials, &surface.hit);
+            let [r, g, b, _] = self.sun.shade(
+                &surface.hit,
+                &surface.material,
+                &-surface.direction,
+                occlusion,
+                sunlight,
+            );
+            [r, g, b]
+        });
+        let [r, g, b] = tonemap(color);
+        Some([r, g, b, 1.0 - left.iter().sum::<f32>() / 3.0])
+    }
+}
+
+/// Face colors used by the normal view of the raytrace shader.
+fn normal_color(normal: &na::Vector3<f32>) -> [f32; 4] {
+    let color = if normal.x > 0.0 {
+        [1.0, 0.0, 0.0]
+    } else if normal.x < 0.0 {
+        [0.0, 1.0, 1.0]
+    } else if normal.y > 0.0 {
+        [0.0, 1.0, 0.0]
+    } else if normal.y < 0.0 {
+        [1.0, 0.0, 1.0]
+    } else if normal.z > 0.0 {
+        [0.0, 0.0, 1.0]
+    } else if normal.z < 0.0 {
+        [1.0, 1.0, 0.0]
+    } else {
+        [0.0, 0.0, 0.0]
+    };
+
+    [color[0], color[1], color[2], 1.0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{brick::ExpandedBrick, material::ExpandedMaterialMapping};
+
+    fn scene() -> (BrickMap, MaterialRegistry, PaletteRegistry) {
+        let materials = MaterialRegistry::new();
+        materials.register_default_materials();
+        let palettes = PaletteRegistry::new();
+        let mut mapping = ExpandedMaterialMapping::new();
+        mapping.add_from_registry(&materials, "air", 0);
+        mapping.add_from_registry(&materials, "grass", 1);
+
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let mut brick = ExpandedBrick::empty();
+        for x in 0..8 {
+            for z in 0..8 {
+                brick.set(x, 7, z, 1);
+            }
+        }
+        for x in 0..4 {
+            for z in 0..4 {
+                brickmap.set_expanded_brick(na::Point3::new(x, 0, z), &brick, &mapping, &palettes);
+            }
+        }
+
+        (brickmap, materials, palettes)
+    }
+
+    fn camera() -> Camera {
+        let mut camera = Camera::new(
+            na::Point3::new(2.0, 3.0, 2.0),
+            na::UnitQuaternion::identity(),
+            1.0,
+            1.0,
+            60.0,
+            1.0,
+            0.1,
+            100.0,
+        );
+        camera.look_at(na::Point3::new(2.0, 0.0, 2.01), &-na::Vector3::y_axis());
+        camera
+    }
+
+    #[test]
+    fn test_render_modes() {
+        let (brickmap, materials, palettes) = scene();
+        let renderer = CpuRenderer::new(&brickmap, &materials, &palettes);
+        let camera = camera();
+
+        let grass = materials.get_material_by_name("grass").unwrap().color;
+        let [r, g, b] = tonemap([grass[0], grass[1], grass[2]]);
+        let expected = [r, g, b, grass[3]].map(|c| (c * 255.0).round() as u8);
+
+        let color = renderer.render(&camera, 16, 16, RenderMode::Color);
+        assert_eq!(color.pixel(8, 8), expected);
+
+        let normals = renderer.render(&camera, 16, 16, RenderMode::Normals);
+        assert_eq!(normals.pixel(8, 8), [0, 255, 0, 255]);
+
+        let mut sky = self::camera();
+        sky.look_at(na::Point3::new(2.0, 10.0, 2.01), &na::Vector3::y_axis());
+        let misses = renderer.render(&sky, 16, 16, RenderMode::Normals);
+        assert_eq!(misses.pixel(8, 8), [0, 0, 0, 255]);
+
+        let depth = renderer.render(&camera, 16, 16, RenderMode::Depth);
+        let [d, _, _, a] = depth.pixel(8, 8);
+        assert!(d > 0 && d < 255);
+        assert_eq!(a, 255);
+
+        let png = color.encode_png().unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+
+        // the sun straight above lights the flat, rough floor about as much as full ambient
+        let sun = Sun::new(na::Vector3::y(), 0.75, 0.25);
+        let lit = CpuRenderer::new(&brickmap, &materials, &palettes)
+            .with_sun(sun)
+            .render(&camera, 16, 16, RenderMode::Color);
+        let pixel = lit.pixel(8, 8);
+        for (lit, unlit) in pixel.into_iter().zip(expected) {
+            assert!(lit.abs_diff(unlit) <= 8, "{pixel:?} vs {expected:?}");
+        }
+    }
+
+    #[test]
+    fn test_render_miss_is_clear() {
+        let (brickmap, materials, palettes) = scene();
+        let renderer = CpuRenderer::new(&brickmap, &materials, &palettes);
+        let mut camera = camera();
+        camera.look_at(na::Point3::new(2.0, 10.0, 2.01), &-na::Vector3::y_axis());
+
+        let color = renderer.render(&camera, 8, 8, RenderMode::Color);
+        assert!(color.data.iter().all(|&c| c == 0));
+    }
+}
@@ -0,0 +1,176 @@
+use std::f32::consts::TAU;
+
+use crate::{
+    brick::BrickMapView,
+    material::{MaterialRegistry, PbrMaterial},
+    palette::PaletteRegistry,
+    pbr::{cook_torrance, sky_ambient},
+    raytrace::{Ray, RayHit},
+    transparency::shadow_transmittance,
+};
+
+/// Shadow rays start this far off the surface along its normal so they don't hit their own
+/// voxel, a fraction of a voxel.
+pub const SHADOW_BIAS: f32 = 1e-3;
+/// Keeps the sun path off the xy plane, axis aligned light makes for flat looking voxels.
+const SUN_TILT: f32 = 0.35;
+const DAY_AMBIENT: f32 = 0.25;
+const NIGHT_AMBIENT: f32 = 0.05;
+
+/// Directional light, the CPU side of the `sun_*` and `ambient` push constants of
+/// `raytrace.wgsl`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sun {
+    /// Unit vector pointing towards the sun.
+    pub direction: na::Vector3<f32>,
+    pub intensity: f32,
+    /// Light reaching surfaces in shadow or facing away from the sun.
+    pub ambient: f32,
+}
+
+impl Sun {
+    pub fn new(direction: na::Vector3<f32>, intensity: f32, ambient: f32) -> Self {
+        Self {
+            direction: direction.normalize(),
+            intensity,
+            ambient,
+        }
+    }
+
+    /// No sun and full ambient, surfaces keep their material color.
+    pub fn unlit() -> Self {
+        Self::new(na::Vector3::y(), 0.0, 1.0)
+    }
+
+    /// Sun `hours` into a day. It rises towards +x at 6, stands highest at 12 and sets towards
+    /// -x at 18, at night only a dim ambient is left.
+    pub fn at_time(hours: f32) -> Self {
+        let angle = (hours - 6.0) / 24.0 * TAU;
+        let direction = na::Vector3::new(angle.cos(), angle.sin(), SUN_TILT).normalize();
+        // fade out around the horizon instead of switching off at sunset
+        let daylight = (direction.y * 4.0).clamp(0.0, 1.0);
+        Self {
+            direction,
+            intensity: daylight,
+            ambient: NIGHT_AMBIENT + (DAY_AMBIENT - NIGHT_AMBIENT) * daylight,
+        }
+    }
+
+    /// Ray from a surface point towards the sun.
+    pub fn shadow_ray(&self, position: na::Point3<f32>, normal: &na::Vector3<f32>) -> Ray {
+        Ray::any(
+            position + normal * SHADOW_BIAS,
+            self.direction,
+            f32::INFINITY,
+        )
+    }
+
+    /// Share of the sun reaching `hit` per color channel. 0 behind the surface or where the map
+    /// blocks it, translucent voxels on the way tint it, see [`shadow_transmittance`].
+    pub fn sunlight(
+        &self,
+        view: &BrickMapView,
+        palettes: &PaletteRegistry,
+        materials: &MaterialRegistry,
+        hit: &RayHit,
+    ) -> [f32; 3] {
+        if self.intensity <= 0.0 || self.direction.dot(&hit.normal) <= 0.0 {
+            return [0.0; 3];
+        }
+        let ray = self.shadow_ray(hit.position, &hit.normal);
+        shadow_transmittance(view, palettes, materials, &ray)
+    }
+
+    /// Lights the `material` of `hit` seen from `to_eye` the way the raytrace shader does, in
+    /// linear HDR color: Cook-Torrance for the [`Self::sunlight`] reaching it, the sky ambient
+    /// scaled by `occlusion` (1 for surfaces open to the sky) and the material's own glow.
+    pub fn shade(
+        &self,
+        hit: &RayHit,
+        material: &PbrMaterial,
+        to_eye: &na::Vector3<f32>,
+        occlusion: f32,
+        sunlight: [f32; 3],
+    ) -> [f32; 4] {
+        let direct = cook_torrance(material, &hit.normal, to_eye, &self.direction);
+        let ambient = self.ambient * occlusion * sky_ambient(&hit.normal);
+        let [r, g, b] = [0, 1, 2].map(|i| {
+            material.color[i] * ambient
+                + direct[i] * sunlight[i] * self.intensity
+                + material.emissive[i]
+        });
+        [r, g, b, material.color[3]]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        brush::{Brush, BrushOp, Shape},
+        material::MaterialId,
+        raytrace::trace_ray,
+        BrickMap,
+    };
+
+    const STONE: MaterialId = MaterialId(1);
+
+    #[test]
+    fn test_shadow_under_roof() {
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let palettes = PaletteRegistry::new();
+        let floor = Shape::Box {
+            center: na::Point3::new(2.0, 0.5, 2.0),
+            half_extents: na::Vector3::new(2.0, 0.5, 2.0),
+        };
+        let roof = Shape::Box {
+            center: na::Point3::new(3.0, 2.5, 2.0),
+            half_extents: na::Vector3::new(1.0, 0.25, 2.0),
+        };
+        brickmap.apply_brush(&Brush::new(floor, BrushOp::Union, STONE), &palettes);
+        brickmap.apply_brush(&Brush::new(roof, BrushOp::Union, STONE), &palettes);
+
+        let sun = Sun::new(na::Vector3::new(0.0, 1.0, 0.1), 1.0, 0.2);
+        let down = -na::Vector3::y();
+        let open = trace_ray(&brickmap, &Ray::new(na::Point3::new(1.0, 2.0, 2.0), down)).unwrap();
+        let covered =
+            trace_ray(&brickmap, &Ray::new(na::Point3::new(3.0, 2.0, 2.0), down)).unwrap();
+        assert_eq!(open.normal, na::Vector3::y());
+        assert_eq!(covered.normal, na::Vector3::y());
+
+        let view = brickmap.read();
+        // unregistered materials are opaque
+        let materials = MaterialRegistry::new();
+        let sunlight = |sun: &Sun, hit| sun.sunlight(&view, &palettes, &materials, hit);
+        assert_eq!(sunlight(&sun, &open), [1.0; 3]);
+        assert_eq!(sunlight(&sun, &covered), [0.0; 3]);
+        // the floor itself must not shadow a surface the sun grazes
+        let grazing = Sun::new(na::Vector3::new(1.0, 0.05, 0.0), 1.0, 0.2);
+        assert_eq!(sunlight(&grazing, &open), [1.0; 3]);
+
+        let material = PbrMaterial::new([0.5, 0.5, 0.5, 1.0], 0.0, 1.0, [0.0; 3], 1.0);
+        let up = na::Vector3::y();
+        let lit = sun.shade(&open, &material, &up, 1.0, sunlight(&sun, &open));
+        let shadowed = sun.shade(&covered, &material, &up, 1.0, sunlight(&sun, &covered));
+        assert!((shadowed[0] - 0.5 * sun.ambient).abs() < 1e-6);
+        assert!(lit[0] > shadowed[0]);
+        assert_eq!(lit[3], 1.0);
+    }
+
+    #[test]
+    fn test_day_night_cycle() {
+        let noon = Sun::at_time(12.0);
+        assert!(noon.direction.y > 0.9);
+        assert_eq!(noon.intensity, 1.0);
+
+        let midnight = Sun::at_time(0.0);
+        assert!(midnight.direction.y < 0.0);
+        assert_eq!(midnight.intensity, 0.0);
+        assert!(midnight.ambient < noon.ambient);
+
+        let morning = Sun::at_time(8.0);
+        let evening = Sun::at_time(16.0);
+        assert!(morning.direction.x > 0.0 && evening.direction.x < 0.0);
+        assert!((morning.direction.y - evening.direction.y).abs() < 1e-5);
+    }
+}
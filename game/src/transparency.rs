@@ -0,0 +1,642 @@
+use crate::{
+    brick::{BrickHandle, BrickMapView, ExpandedBrick, BRICK_SIZE},
+    material::{ExpandedMaterialMapping, MaterialId, MaterialRegistry, PbrMaterial},
+    palette::PaletteRegistry,
+    raytrace::{trace_view, Ray, RayHit},
+};
+
+/// Surfaces a ray passes at most before it gives up, mirrors `raytrace.wgsl`. A run of voxels
+/// of one translucent material or of cut out voxels counts once per [`MAX_PASS_VOXELS`].
+pub const MAX_TRANSPARENT_STEPS: u32 = 64;
+/// Voxels a ray walks through one run of see-through voxels before tracing on, see
+/// [`MAX_TRANSPARENT_STEPS`].
+pub const MAX_PASS_VOXELS: u32 = 256;
+/// Absorption per voxel of path through a translucent material of full alpha, per color
+/// channel the tint lets through less of. Light fades exponentially with the path length.
+pub const ABSORPTION: f32 = 0.15;
+/// One refractive index for all translucent materials, close to water and ice.
+pub const REFRACTIVE_INDEX: f32 = 1.33;
+/// Rays leave a voxel this far past its side, a fraction of a voxel.
+const EXIT_BIAS: f32 = 1e-4;
+
+/// How rays treat the voxels of a material, from its alpha `color[3]` and the alpha cutoff in
+/// `emissive[3]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opacity {
+    Opaque,
+    /// Rays pass and get tinted, the alpha is the share of light the surface reflects.
+    Translucent(f32),
+    /// A cutoff below 1, voxels whose [`coverage`] is below it are cut out, the rest is opaque.
+    AlphaTested(f32),
+}
+
+impl Opacity {
+    pub fn of(material: &PbrMaterial) -> Self {
+        let alpha = material.color[3];
+        let cutoff = material.emissive[3];
+        if cutoff > 0.0 && cutoff < 1.0 {
+            Self::AlphaTested(cutoff)
+        } else if alpha < 1.0 {
+            Self::Translucent(alpha.max(0.0))
+        } else {
+            Self::Opaque
+        }
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        matches!(self, Self::Opaque)
+    }
+}
+
+/// Stable noise in `0..1` per voxel position, stands in for the alpha texture of alpha tested
+/// materials. Same hash as `voxel_coverage` in `raytrace.wgsl`.
+pub fn coverage(voxel: na::Point3<u32>) -> f32 {
+    let mut hash = voxel.x.wrapping_mul(0x8da6_b343)
+        ^ voxel.y.wrapping_mul(0xd816_3841)
+        ^ voxel.z.wrapping_mul(0xcb1a_b31f);
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x7feb_352d);
+    hash ^= hash >> 15;
+    (hash >> 8) as f32 / (1 << 24) as f32
+}
+
+/// Whether the voxel at `voxel` of an alpha tested `material` is cut out.
+pub fn is_cut(material: &PbrMaterial, voxel: na::Point3<u32>) -> bool {
+    match Opacity::of(material) {
+        Opacity::AlphaTested(cutoff) => material.color[3] * coverage(voxel) < cutoff,
+        _ => false,
+    }
+}
+
+/// Whether a voxel of the brick behind `handle` has a material that isn't opaque, what
+/// [`crate::brick::TraceBrick::is_transparent`] caches. LOD bricks always count as opaque.
+/// Only bricks whose palette holds such a material are scanned voxel by voxel.
+pub fn has_transparent_voxels(
+    view: &BrickMapView,
+    palettes: &PaletteRegistry,
+    materials: &MaterialRegistry,
+    handle: BrickHandle,
+) -> bool {
+    let is_transparent = |material| is_transparent(materials, material);
+    if !view
+        .brick_palette(palettes, handle)
+        .iter()
+        .any(|&material| is_transparent(material))
+    {
+        return false;
+    }
+
+    let Some(brick) = view.get_brick(handle) else {
+        return false;
+    };
+    (0..BRICK_SIZE.pow(3)).any(|i| {
+        let local = na::Point3::new(i % 8, i / 8 % 8, i / 64);
+        brick.get(local.x, local.y, local.z)
+            && is_transparent(view.voxel_material(palettes, handle, local))
+    })
+}
+
+/// Like [`has_transparent_voxels`] for a brick before it is stored, from its voxels and the
+/// mapping they are compressed with, without locking the map.
+pub fn expanded_has_transparent_voxels(
+    brick: &ExpandedBrick,
+    mapping: &ExpandedMaterialMapping,
+    materials: &MaterialRegistry,
+) -> bool {
+    let mut seen = [false; 256];
+    brick.data().iter().any(|&value| {
+        let first = !std::mem::replace(&mut seen[value as usize], true);
+        first && value != 0 && is_transparent(materials, mapping.material(value))
+    })
+}
+
+fn is_transparent(materials: &MaterialRegistry, material: MaterialId) -> bool {
+    materials
+        .materials()
+        .get(material.0 as usize)
+        .is_some_and(|material| !Opacity::of(material).is_opaque())
+}
+
+/// A surface a ray showed, with the light that still reaches the eye from it.
+#[derive(Debug, Clone, Copy)]
+pub struct Surface {
+    pub hit: RayHit,
+    pub material: PbrMaterial,
+    /// Direction of the ray where it hit, bent by refraction.
+    pub direction: na::Vector3<f32>,
+    /// Left of the light after the surfaces in front of it.
+    pub transmittance: [f32; 3],
+    /// Share of the surface's own shading in the pixel, 1 for the opaque surface at the end.
+    pub alpha: f32,
+}
+
+/// Traces through translucent and cut out voxels. Returns the translucent surfaces passed front
+/// to back and the opaque one behind them, if any, plus the light left past the last surface.
+/// Only full resolution voxels are see-through, mips and LOD bricks are opaque. The material
+/// of every hit decides, the GPU skips the lookup for bricks without
+/// [`crate::brick::TraceBrick::is_transparent`]. Runs of one translucent material are crossed
+/// in one walk and absorb by their length, see [`ABSORPTION`]. With `refraction` rays bend
+/// entering and leaving translucent materials. Shadow rays use [`shadow_transmittance`].
+pub fn trace_transparent(
+    view: &BrickMapView,
+    palettes: &PaletteRegistry,
+    materials: &MaterialRegistry,
+    ray: &Ray,
+    refraction: bool,
+) -> (Vec<Surface>, [f32; 3]) {
+    let mut surfaces = Vec::new();
+    let mut transmittance = [1.0; 3];
+    let mut ray = *ray;
+    // translucent material the ray is inside of
+    let mut medium = None;
+    // the voxel a walk stopped in front of, taken as the next hit instead of tracing again
+    let mut next = None;
+
+    for _ in 0..MAX_TRANSPARENT_STEPS {
+        let Some(hit) = next.take().or_else(|| trace_view(view, &ray)) else {
+            break;
+        };
+        let (id, material, voxel, opacity) = hit_opacity(view, palettes, materials, &hit);
+
+        match opacity {
+            Opacity::AlphaTested(_) if is_cut(&material, voxel) => medium = None,
+            Opacity::Opaque | Opacity::AlphaTested(_) => {
+                surfaces.push(Surface {
+                    hit,
+                    material,
+                    direction: ray.direction,
+                    transmittance,
+                    alpha: 1.0,
+                });
+                return (surfaces, [0.0; 3]);
+            }
+            Opacity::Translucent(alpha) => {
+                if medium != Some(id) {
+                    surfaces.push(Surface {
+                        hit,
+                        material,
+                        direction: ray.direction,
+                        transmittance,
+                        alpha,
+                    });
+                    transmittance = transmittance.map(|t| t * (1.0 - alpha));
+                    if refraction {
+                        let bent = refract(&ray.direction, &hit.normal, 1.0 / REFRACTIVE_INDEX);
+                        ray = Ray::new(hit.position, bent);
+                    }
+                }
+                medium = Some(id);
+            }
+        }
+
+        let walk = pass_voxels(
+            view,
+            palettes,
+            materials,
+            voxel,
+            hit.position,
+            &ray.direction,
+            medium,
+            f32::INFINITY,
+        );
+        if medium.is_some() {
+            absorb(&mut transmittance, &material, walk.distance);
+        }
+        if walk.end == WalkEnd::Voxel {
+            next = Some(walk_hit(view, &ray, &walk));
+            continue;
+        }
+        let mut direction = ray.direction;
+        if medium.take().is_some() && refraction {
+            direction = refract(&direction, &walk.normal, REFRACTIVE_INDEX);
+        }
+        ray = Ray::new(walk.position + direction * EXIT_BIAS, direction);
+    }
+
+    (surfaces, transmittance)
+}
+
+/// Light left of a shadow ray by the time it reaches its `max_distance` or leaves the map, 0
+/// once an opaque voxel blocks it. Passes cut out voxels and gets tinted by translucent ones
+/// like [`trace_transparent`], without bending.
+pub fn shadow_transmittance(
+    view: &BrickMapView,
+    palettes: &PaletteRegistry,
+    materials: &MaterialRegistry,
+    ray: &Ray,
+) -> [f32; 3] {
+    let mut transmittance = [1.0; 3];
+    let mut medium = None;
+    let mut origin = ray.origin;
+    let mut next = None;
+
+    for _ in 0..MAX_TRANSPARENT_STEPS {
+        let hit = match next.take() {
+            Some(hit) => hit,
+            None => {
+                let travelled = (origin - ray.origin).norm();
+                let rest = Ray::any(origin, ray.direction, ray.max_distance - travelled);
+                let Some(hit) = trace_view(view, &rest) else {
+                    return transmittance;
+                };
+                hit
+            }
+        };
+        let (id, material, voxel, opacity) = hit_opacity(view, palettes, materials, &hit);
+        match opacity {
+            Opacity::AlphaTested(_) if is_cut(&material, voxel) => medium = None,
+            Opacity::Opaque | Opacity::AlphaTested(_) => return [0.0; 3],
+            Opacity::Translucent(alpha) => {
+                if medium != Some(id) {
+                    transmittance = transmittance.map(|t| t * (1.0 - alpha));
+                }
+                medium = Some(id);
+            }
+        }
+
+        let rest = ray.max_distance - (hit.position - ray.origin).norm();
+        let walk = pass_voxels(
+            view,
+            palettes,
+            materials,
+            voxel,
+            hit.position,
+            &ray.direction,
+            medium,
+            rest,
+        );
+        if medium.is_some() {
+            absorb(&mut transmittance, &material, walk.distance);
+        }
+        match walk.end {
+            WalkEnd::Blocked => return transmittance,
+            WalkEnd::Voxel => next = Some(walk_hit(view, ray, &walk)),
+            WalkEnd::Air => {
+                medium = None;
+                origin = walk.position + ray.direction * EXIT_BIAS;
+            }
+        }
+    }
+
+    // too many surfaces to pass, as good as blocked
+    [0.0; 3]
+}
+
+/// Scales `transmittance` by what is left after `distance` world units through `material`.
+fn absorb(transmittance: &mut [f32; 3], material: &PbrMaterial, distance: f32) {
+    let alpha = material.color[3].max(0.0);
+    let voxels = distance * BRICK_SIZE as f32;
+    for (t, tint) in transmittance.iter_mut().zip(material.color) {
+        *t *= (-alpha * ABSORPTION * (1.0 - tint) * voxels).exp();
+    }
+}
+
+/// How [`pass_voxels`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WalkEnd {
+    /// The next voxel is empty or outside of the map.
+    Air,
+    /// The next voxel is solid and needs a look of its own.
+    Voxel,
+    /// The walk reached its maximum distance.
+    Blocked,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Walk {
+    /// Where the walk left the last voxel it passed.
+    position: na::Point3<f32>,
+    /// Normal of the side it left through, against the ray.
+    normal: na::Vector3<f32>,
+    /// The voxel behind `position`, in voxels (8 per brick).
+    next: na::Vector3<i64>,
+    distance: f32,
+    end: WalkEnd,
+}
+
+/// Walks the voxel grid from `voxel`, entered at `position`, through the voxels a ray passes
+/// without showing a new surface: those of the translucent `medium` while inside one, cut out
+/// voxels otherwise. Stops after [`MAX_PASS_VOXELS`] with [`WalkEnd::Voxel`], the caller picks
+/// the walk up again from there. Mirrors `pass_voxels` in `raytrace.wgsl`.
+#[allow(clippy::too_many_arguments)]
+fn pass_voxels(
+    view: &BrickMapView,
+    palettes: &PaletteRegistry,
+    materials: &MaterialRegistry,
+    voxel: na::Point3<u32>,
+    position: na::Point3<f32>,
+    direction: &na::Vector3<f32>,
+    medium: Option<MaterialId>,
+    max_distance: f32,
+) -> Walk {
+    let size = 1.0 / BRICK_SIZE as f32;
+    let mut side = na::Vector3::from_fn(|i, _| {
+        let min = voxel[i] as f32 * size;
+        match direction[i] {
+            d if d > 0.0 => (min + size - position[i]) / d,
+            d if d < 0.0 => (min - position[i]) / d,
+            _ => f32::INFINITY,
+        }
+    });
+    let delta = direction.map(|d| (size / d).abs());
+
+    let mut walk = Walk {
+        position,
+        normal: na::Vector3::zeros(),
+        next: voxel.coords.cast::<i64>(),
+        distance: 0.0,
+        end: WalkEnd::Voxel,
+    };
+    for _ in 0..MAX_PASS_VOXELS {
+        // x before y before z on ties, like the shader
+        let axis = side.imin();
+        let t = side[axis];
+        if t >= max_distance {
+            walk.position = position + direction * max_distance;
+            walk.distance = max_distance;
+            walk.end = WalkEnd::Blocked;
+            return walk;
+        }
+        walk.next[axis] += direction[axis].signum() as i64;
+        side[axis] += delta[axis];
+        walk.position = position + direction * t;
+        walk.normal = na::Vector3::zeros();
+        walk.normal[axis] = -direction[axis].signum();
+        walk.distance = t;
+        match passes(view, palettes, materials, walk.next, medium) {
+            None => {
+                walk.end = WalkEnd::Air;
+                return walk;
+            }
+            Some(false) => return walk,
+            Some(true) => {}
+        }
+    }
+    walk
+}
+
+/// Whether a walk through `medium` passes `voxel`, `None` when it is empty.
+fn passes(
+    view: &BrickMapView,
+    palettes: &PaletteRegistry,
+    materials: &MaterialRegistry,
+    voxel: na::Vector3<i64>,
+    medium: Option<MaterialId>,
+) -> Option<bool> {
+    if voxel.iter().any(|&c| c < 0 || c > u32::MAX as i64) {
+        return None;
+    }
+    let voxel = na::Point3::from(voxel.map(|c| c as u32));
+    if !view.is_voxel_solid(voxel) {
+        return None;
+    }
+    let handle = view.get_handle(voxel.map(|c| c / BRICK_SIZE));
+    if handle.is_lod() {
+        return Some(false);
+    }
+    let id = view.voxel_material(palettes, handle, voxel.map(|c| c % BRICK_SIZE));
+    let passes = match medium {
+        Some(medium) => id == medium,
+        None => materials
+            .get_material(id)
+            .is_some_and(|material| is_cut(&material, voxel)),
+    };
+    Some(passes)
+}
+
+/// The solid voxel a walk stopped in front of as a hit of `ray`, LOD bricks keep no voxel like
+/// in [`trace_view`].
+fn walk_hit(view: &BrickMapView, ray: &Ray, walk: &Walk) -> RayHit {
+    let voxel = walk.next.map(|c| c as u32);
+    let brick = na::Point3::from(voxel.map(|c| c / BRICK_SIZE));
+    let handle = view.get_handle(brick);
+    RayHit {
+        distance: (walk.position - ray.origin).norm(),
+        position: walk.position,
+        normal: walk.normal,
+        handle,
+        brick,
+        voxel: (!handle.is_lod()).then(|| na::Point3::from(voxel.map(|c| c % BRICK_SIZE))),
+        level: 0,
+    }
+}
+
+/// Material of the voxel `hit` stopped at, that voxel in voxels (8 per brick) and how rays
+/// treat it. Mips, LOD bricks and materials missing from the registry are opaque.
+fn hit_opacity(
+    view: &BrickMapView,
+    palettes: &PaletteRegistry,
+    materials: &MaterialRegistry,
+    hit: &RayHit,
+) -> (MaterialId, PbrMaterial, na::Point3<u32>, Opacity) {
+    let id = view.material_at_level(
+        palettes,
+        hit.handle,
+        hit.voxel.unwrap_or(na::Point3::origin()),
+        hit.level,
+    );
+    let Some(material) = materials.get_material(id) else {
+        return (
+            id,
+            bytemuck::Zeroable::zeroed(),
+            na::Point3::origin(),
+            Opacity::Opaque,
+        );
+    };
+    match hit.voxel {
+        Some(voxel) if hit.level == 0 => (
+            id,
+            material,
+            hit.brick * BRICK_SIZE + voxel.coords,
+            Opacity::of(&material),
+        ),
+        _ => (id, material, na::Point3::origin(), Opacity::Opaque),
+    }
+}
+
+/// Front to back blend of the surfaces of [`trace_transparent`], `shade` lights one of them.
+pub fn composite(surfaces: &[Surface], mut shade: impl FnMut(&Surface) -> [f32; 3]) -> [f32; 3] {
+    surfaces.iter().fold([0.0; 3], |color, surface| {
+        let shaded = shade(surface);
+        [0, 1, 2].map(|i| color[i] + surface.transmittance[i] * surface.alpha * shaded[i])
+    })
+}
+
+/// Bends `direction` through a surface facing `normal` against it, `eta` is the ratio of the
+/// refractive indices. Reflects when the light can't leave the denser side.
+fn refract(direction: &na::Vector3<f32>, normal: &na::Vector3<f32>, eta: f32) -> na::Vector3<f32> {
+    let cos_i = -normal.dot(direction);
+    let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+    if k < 0.0 {
+        return direction + normal * (2.0 * cos_i);
+    }
+    (direction * eta + normal * (eta * cos_i - k.sqrt())).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        brush::{Brush, BrushOp, Shape},
+        BrickMap,
+    };
+
+    fn scene(top: &str) -> (BrickMap, MaterialRegistry, PaletteRegistry) {
+        let materials = MaterialRegistry::new();
+        materials.register_default_materials();
+        let palettes = PaletteRegistry::new();
+        let stone = materials.get_material_id("stone").unwrap();
+        let top = materials.get_material_id(top).unwrap();
+
+        let brickmap = BrickMap::new(na::Vector3::new(4, 4, 4));
+        let floor = Shape::Box {
+            center: na::Point3::new(2.0, 0.5, 2.0),
+            half_extents: na::Vector3::new(2.0, 0.5, 2.0),
+        };
+        let layer = Shape::Box {
+            center: na::Point3::new(2.0, 1.5, 2.0),
+            half_extents: na::Vector3::new(2.0, 0.5, 2.0),
+        };
+        brickmap.apply_brush(&Brush::new(floor, BrushOp::Union, stone), &palettes);
+        brickmap.apply_brush(&Brush::new(layer, BrushOp::Union, top), &palettes);
+        (brickmap, materials, palettes)
+    }
+
+    #[test]
+    fn test_rays_pass_translucent_voxels() {
+        let (brickmap, materials, palettes) = scene("water");
+        let water = materials.get_material_by_name("water").unwrap();
+        let view = brickmap.read();
+        let transparent = |at| {
+            let handle = view.get_handle(at);
+            has_transparent_voxels(&view, &palettes, &materials, handle)
+        };
+        assert!(transparent(na::Point3::new(1, 1, 1)));
+        assert!(!transparent(na::Point3::new(1, 0, 1)));
+
+        let mut brick = ExpandedBrick::empty();
+        brick.set(2, 3, 4, 1);
+        let mut mapping = ExpandedMaterialMapping::new();
+        mapping.insert(1, materials.get_material_id("water").unwrap());
+        assert!(expanded_has_transparent_voxels(
+            &brick, &mapping, &materials
+        ));
+        mapping.insert(1, materials.get_material_id("stone").unwrap());
+        assert!(!expanded_has_transparent_voxels(
+            &brick, &mapping, &materials
+        ));
+
+        let down = Ray::new(na::Point3::new(1.3, 3.0, 1.7), -na::Vector3::y());
+        let (surfaces, left) = trace_transparent(&view, &palettes, &materials, &down, false);
+        assert_eq!(surfaces.len(), 2);
+        assert_eq!(left, [0.0; 3]);
+        let (surface, floor) = (&surfaces[0], &surfaces[1]);
+        assert_eq!(surface.alpha, water.color[3]);
+        assert!((surface.hit.position.y - 2.0).abs() < 1e-4);
+        assert!((floor.hit.position.y - 1.0).abs() < 1e-4);
+        // eight voxels of blue water take more red than blue
+        let [r, _, b] = floor.transmittance;
+        assert!(r < b && b < 1.0 - water.color[3]);
+        let color = composite(&surfaces, |_| [1.0; 3]);
+        assert!(color.iter().all(|&c| c > 0.0 && c <= 1.0));
+        // light from above reaches the lakebed tinted the same way
+        let up = na::Vector3::y();
+        let shadow = Ray::any(floor.hit.position, up, f32::INFINITY);
+        let shadow = shadow_transmittance(&view, &palettes, &materials, &shadow);
+        for (shadow, seen) in shadow.into_iter().zip(floor.transmittance) {
+            assert!(shadow > 0.0 && (shadow - seen).abs() < 1e-4);
+        }
+
+        // a slanted ray bends towards the normal entering the water
+        let slanted = Ray::new(
+            na::Point3::new(1.03, 3.0, 2.05),
+            na::Vector3::new(1.0, -1.0, 0.0),
+        );
+        let (straight, _) = trace_transparent(&view, &palettes, &materials, &slanted, false);
+        let (bent, _) = trace_transparent(&view, &palettes, &materials, &slanted, true);
+        assert_eq!(bent.len(), 2);
+        assert!(bent[1].direction.x < straight[1].direction.x);
+        assert!(bent[1].hit.position.x < straight[1].hit.position.x);
+    }
+
+    #[test]
+    fn test_deep_water_absorbs_by_depth() {
+        let materials = MaterialRegistry::new();
+        materials.register_default_materials();
+        let palettes = PaletteRegistry::new();
+        let water_id = materials.get_material_id("water").unwrap();
+        let water = materials.get_material(water_id).unwrap();
+
+        // 19 bricks of water above a stone floor, far more voxels than surfaces a ray passes
+        let brickmap = BrickMap::new(na::Vector3::new(1, 20, 1));
+        let floor = Shape::Box {
+            center: na::Point3::new(0.5, 0.5, 0.5),
+            half_extents: na::Vector3::repeat(0.5),
+        };
+        let lake = Shape::Box {
+            center: na::Point3::new(0.5, 10.5, 0.5),
+            half_extents: na::Vector3::new(0.5, 9.5, 0.5),
+        };
+        let stone = materials.get_material_id("stone").unwrap();
+        brickmap.apply_brush(&Brush::new(floor, BrushOp::Union, stone), &palettes);
+        brickmap.apply_brush(&Brush::new(lake, BrushOp::Union, water_id), &palettes);
+        let view = brickmap.read();
+
+        let down = Ray::new(na::Point3::new(0.3, 21.0, 0.6), -na::Vector3::y());
+        let (surfaces, _) = trace_transparent(&view, &palettes, &materials, &down, false);
+        assert_eq!(surfaces.len(), 2);
+        let seen = surfaces[1].transmittance;
+        let shadow = Ray::any(surfaces[1].hit.position, na::Vector3::y(), f32::INFINITY);
+        let shadow = shadow_transmittance(&view, &palettes, &materials, &shadow);
+
+        let alpha = water.color[3];
+        for i in 0..3 {
+            let depth = 19.0 * 8.0;
+            let expected =
+                (1.0 - alpha) * (-alpha * ABSORPTION * (1.0 - water.color[i]) * depth).exp();
+            assert!(expected > 0.0);
+            assert!((seen[i] - expected).abs() < 1e-4);
+            assert!((shadow[i] - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_alpha_tested_voxels_are_cut() {
+        let leaves = PbrMaterial::leaves([0.2, 0.5, 0.1], 0.5);
+        assert_eq!(Opacity::of(&leaves), Opacity::AlphaTested(0.5));
+        assert_eq!(Opacity::of(&PbrMaterial::stone(0.5)), Opacity::Opaque);
+        let voxels = (0..4096).map(|i| na::Point3::new(i % 16, i / 16 % 16, i / 256));
+        let cut = voxels.filter(|&voxel| is_cut(&leaves, voxel)).count();
+        assert!((cut as f32 / 4096.0 - 0.5).abs() < 0.05);
+
+        let (brickmap, materials, palettes) = scene("leaves");
+        let leaves_id = materials.get_material_id("leaves").unwrap();
+        let view = brickmap.read();
+        let mut below_top = 0;
+        for x in 0..8 {
+            let origin = na::Point3::new(1.0 + (x as f32 + 0.5) / 8.0, 3.0, 1.5);
+            let ray = Ray::new(origin, -na::Vector3::y());
+            let (surfaces, left) = trace_transparent(&view, &palettes, &materials, &ray, false);
+            assert_eq!((surfaces.len(), left), (1, [0.0; 3]));
+
+            // cut voxels are skipped, the first kept one stops the ray
+            let hit = surfaces[0].hit;
+            let id = view.material_at_level(&palettes, hit.handle, hit.voxel.unwrap(), 0);
+            assert_eq!(id, leaves_id);
+            assert_eq!(surfaces[0].alpha, 1.0);
+            assert!(!is_cut(
+                &surfaces[0].material,
+                hit.brick * 8 + hit.voxel.unwrap().coords
+            ));
+            if hit.position.y < 2.0 - 1e-3 {
+                below_top += 1;
+                // the cut voxels above let the light back out
+                let up = na::Vector3::y();
+                let shadow = Ray::any(hit.position + up * 1e-3, up, f32::INFINITY);
+                let shadow = shadow_transmittance(&view, &palettes, &materials, &shadow);
+                assert_eq!(shadow, [1.0; 3]);
+            }
+        }
+        assert!(below_top > 0);
+    }
+}